@@ -0,0 +1,6 @@
+fn main() -> std::io::Result<()> {
+    prost_build::compile_protos(
+        &["proto/telemetry.proto", "proto/sparkplug_b.proto"],
+        &["proto/"],
+    )
+}
@@ -0,0 +1,183 @@
+use crate::backend::Backend;
+use crate::config::ConfigError;
+use crate::gcp_iot::message;
+use crate::idempotency::IdempotencyStore;
+use crate::publisher::QosPolicy;
+use crate::transport::{Message, MqttTransport};
+use crate::utils::{Iso8601Utc, SystemTime};
+use color_eyre::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+/// Progress of a command, acknowledged in this order except when it's rejected, in which case only
+/// failed is sent
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AckStatus {
+    Accepted,
+    Started,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Ack<'a> {
+    /// the id the command was sent with
+    pub id: &'a str,
+    pub status: AckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// outcome of a completed command, e.g. how much of a cycle was run before it was stopped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    pub timestamp: String,
+}
+
+/// Publishes the status of the commands received from the cloud on the ack events topic, with the
+/// QoS of QOS_ACK.
+///
+/// Only commands sent with an id are acknowledged, the cloud has no way to match the others
+#[derive(Clone)]
+pub struct Acknowledger {
+    backend: Backend,
+    client: Arc<dyn MqttTransport>,
+    qos: i32,
+    /// where the idempotency keys of the commands are recorded once they complete
+    idempotency: Option<Arc<Mutex<IdempotencyStore>>>,
+}
+
+impl Acknowledger {
+    pub fn new(
+        backend: Backend,
+        client: impl MqttTransport + 'static,
+    ) -> Result<Self, ConfigError> {
+        let qos = QosPolicy::from_settings(backend.settings())?.ack;
+        Ok(Self {
+            backend,
+            client: Arc::new(client),
+            qos,
+            idempotency: None,
+        })
+    }
+
+    /// Conclude the idempotency keys the dispatcher accepts commands with in the store, so only
+    /// the keys of completed commands are kept
+    pub fn with_idempotency(mut self, idempotency: Arc<Mutex<IdempotencyStore>>) -> Self {
+        self.idempotency = Some(idempotency);
+        self
+    }
+
+    /// Publish the status of the command, failing to do so is only logged since the command has
+    /// been handled either way
+    pub async fn send(&self, id: Option<&str>, status: AckStatus, error: Option<String>) {
+        self.settle(id, status).await;
+        self.publish(id, status, error, None).await
+    }
+
+    /// Acknowledge the command as completed, with its outcome
+    pub async fn complete(&self, id: Option<&str>, result: Value) {
+        self.settle(id, AckStatus::Completed).await;
+        self.publish(id, AckStatus::Completed, None, Some(result))
+            .await
+    }
+
+    /// Acknowledge a duplicate delivery of a command as completed, leaving the key of the command
+    /// delivered first as it is
+    pub async fn duplicate(&self, id: Option<&str>) {
+        let outcome = json!({ "duplicate": true });
+        self.publish(id, AckStatus::Completed, None, Some(outcome))
+            .await
+    }
+
+    /// Acknowledge the outcome of a command, completed with its result or failed with its error
+    pub async fn conclude(&self, id: Option<&str>, result: Result<Option<Value>>) {
+        match result {
+            Ok(Some(outcome)) => self.complete(id, outcome).await,
+            Ok(None) => self.send(id, AckStatus::Completed, None).await,
+            Err(e) => {
+                error!("Command failed: {e:?}");
+                self.send(id, AckStatus::Failed, Some(e.to_string())).await;
+            }
+        }
+    }
+
+    /// Conclude the idempotency key of a command that completed or failed, before it's
+    /// acknowledged so a delivery following the ack is recognized. Failing to record it is only
+    /// logged since the command has been handled either way
+    async fn settle(&self, id: Option<&str>, status: AckStatus) {
+        let (idempotency, id) = match (&self.idempotency, id) {
+            (Some(idempotency), Some(id)) => (idempotency, id),
+            _ => return,
+        };
+        let completed = match status {
+            AckStatus::Completed => true,
+            AckStatus::Failed => false,
+            AckStatus::Accepted | AckStatus::Started => return,
+        };
+        if let Err(e) = idempotency.lock().await.conclude(id, completed).await {
+            error!("Failed to record the idempotency key of command {id}: {e}");
+        }
+    }
+
+    async fn publish(
+        &self,
+        id: Option<&str>,
+        status: AckStatus,
+        error: Option<String>,
+        result: Option<Value>,
+    ) {
+        let id = match id {
+            Some(id) => id,
+            None => return,
+        };
+
+        let ack = Ack {
+            id,
+            status,
+            error,
+            result,
+            timestamp: SystemTime::iso8601_now(),
+        };
+
+        let payload = match message::seal(&self.backend.device_id(), &ack) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Failed to encode the ack of command {id}: {e}");
+                return;
+            }
+        };
+
+        // ack is a single level, unwrap is safe
+        let topic = self.backend.event_topic("ack").unwrap();
+        if let Err(e) = self
+            .client
+            .publish(Message::new(topic, payload, self.qos))
+            .await
+        {
+            warn!("Failed to acknowledge command {id}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn errors_are_only_serialized_on_failure() {
+        let ack = Ack {
+            id: "42",
+            status: AckStatus::Started,
+            error: None,
+            result: None,
+            timestamp: "2022-03-01T00:00:00+00:00".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&ack).unwrap(),
+            r#"{"id":"42","status":"started","timestamp":"2022-03-01T00:00:00+00:00"}"#
+        );
+    }
+}
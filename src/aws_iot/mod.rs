@@ -0,0 +1,70 @@
+use crate::backend::{required, CloudError};
+use crate::config::Settings;
+use crate::gcp_iot::topic::{InvalidLevel, Topic};
+use crate::session::Session;
+use crate::tls::{self, Tls, TlsConfig};
+use crate::transport::{self, Client, ConnectOptions, Message, MessageStream};
+use color_eyre::Result;
+
+pub mod shadow;
+
+pub fn thing_name(settings: &Settings) -> Result<String, CloudError> {
+    required(settings, "THING_NAME")
+}
+
+/// AWS doesn't impose any layout on custom topics, so by default we use one similar to the Google IoT
+/// topics, prefixed by the thing name
+pub fn topic_prefix(settings: &Settings, thing_name: &str) -> String {
+    match settings.get("AWS_TOPIC_PREFIX") {
+        Some(prefix) => prefix.to_string(),
+        None => format!("tvilling/{thing_name}"),
+    }
+}
+
+/// Topic a component's events are published to, can be overridden per component with
+/// AWS_<COMPONENT>_TOPIC, e.g. AWS_FEEDER_TOPIC
+pub fn event_topic(
+    settings: &Settings,
+    topic_prefix: &str,
+    component: &str,
+) -> Result<String, InvalidLevel> {
+    match settings.get(&format!("AWS_{}_TOPIC", component.to_uppercase())) {
+        Some(topic) => Ok(topic.to_string()),
+        None => Topic::prefixed(topic_prefix).event(component),
+    }
+}
+
+fn get_ssl_ops(settings: &Settings) -> Result<Tls> {
+    // AWS IoT authenticates with mutual TLS, the certificate has to be attached to the thing
+    let root_ca = required(settings, "AWS_ROOT_CA")?;
+    let cert = required(settings, "AWS_CERTIFICATE")?;
+    let pri_key = required(settings, "AWS_PRIVATE_KEY")?;
+
+    Ok(TlsConfig::from_settings(settings)?
+        .builder()
+        .trust_store(root_ca)
+        .key_store(cert)
+        .private_key(pri_key))
+}
+
+pub fn aws_connect_options(settings: &Settings, will: Option<Message>) -> Result<ConnectOptions> {
+    let endpoint = required(settings, "AWS_ENDPOINT")?;
+    let session = Session::from_settings(settings);
+
+    Ok(ConnectOptions {
+        clean_session: session.clean(),
+        tls: Some(get_ssl_ops(settings)?),
+        will,
+        persistence_dir: session.persistence_dir(),
+        ..ConnectOptions::new(format!("ssl://{endpoint}:8883"), thing_name(settings)?)
+    })
+}
+
+pub async fn connect(
+    settings: &Settings,
+    will: Option<Message>,
+) -> Result<(Client, MessageStream)> {
+    transport::connect(&aws_connect_options(settings, will)?)
+        .await
+        .map_err(tls::connect_error)
+}
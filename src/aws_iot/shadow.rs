@@ -0,0 +1,61 @@
+use crate::transport::{Message, MqttTransport, QOS_1};
+use async_trait::async_trait;
+use color_eyre::Result;
+use serde::Serialize;
+
+pub fn update_topic(thing_name: &str) -> String {
+    format!("$aws/things/{thing_name}/shadow/update")
+}
+
+pub fn delta_topic(thing_name: &str) -> String {
+    format!("$aws/things/{thing_name}/shadow/update/delta")
+}
+
+#[derive(Serialize)]
+struct ReportedState<T> {
+    reported: T,
+}
+
+/// Shadow update document, only the reported section is ever written by the twin, the desired
+/// section belongs to the cloud
+#[derive(Serialize)]
+pub struct ShadowDocument<T> {
+    state: ReportedState<T>,
+}
+
+impl<T: Serialize> ShadowDocument<T> {
+    pub fn reported(state: T) -> Self {
+        Self {
+            state: ReportedState { reported: state },
+        }
+    }
+}
+
+#[async_trait]
+pub trait AwsShadow {
+    async fn update_shadow(&self, thing_name: &str, reported: serde_json::Value) -> Result<()>;
+}
+
+#[async_trait]
+impl<T: MqttTransport + ?Sized> AwsShadow for T {
+    async fn update_shadow(&self, thing_name: &str, reported: serde_json::Value) -> Result<()> {
+        let document = serde_json::to_string(&ShadowDocument::reported(reported))?;
+        self.publish(Message::new(update_topic(thing_name), document, QOS_1))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn shadow_document_wraps_reported_state() {
+        let document = ShadowDocument::reported(json!({ "count": 5 }));
+        let json = serde_json::to_value(&document).unwrap();
+
+        assert_eq!(json, json!({ "state": { "reported": { "count": 5 } } }));
+    }
+}
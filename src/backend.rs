@@ -0,0 +1,430 @@
+use crate::aws_iot::shadow::AwsShadow;
+use crate::aws_iot::{self, aws_connect_options};
+use crate::config::{ConfigError, Settings};
+use crate::encoding::Encoding;
+use crate::gcp_iot::endpoint::Endpoints;
+use crate::gcp_iot::gateway::{Gateway, GatewayControl};
+use crate::gcp_iot::key_source::KeySource;
+use crate::gcp_iot::message;
+use crate::gcp_iot::topic::{InvalidLevel, Topic};
+use crate::gcp_iot::{self, gcp_connect_options, ConnectError};
+use crate::mqtt_broker;
+use crate::rotation::CredentialFiles;
+use crate::tls;
+use crate::transport::{Client, Message, MessageStream, MqttTransport, QOS_1};
+use color_eyre::Result;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{error, instrument};
+
+/// Time the messages in flight are given to be delivered on disconnect
+const DEFAULT_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Components whose events get their own topic
+const COMPONENTS: [&str; 6] = ["feeder", "robot", "piston", "program", "conveyor", "alarms"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum CloudError {
+    #[error("Error: Missing {0} in environment variables")]
+    Missing(&'static str),
+    /// a setting only needed because another one is set
+    #[error("Error: Missing {variable} in environment variables, required by {required_by}")]
+    MissingFor {
+        variable: &'static str,
+        required_by: &'static str,
+    },
+    #[error("Error: {variable} cannot be parsed as {expected}")]
+    Invalid {
+        variable: &'static str,
+        expected: &'static str,
+    },
+    #[error("Error: Unknown IOT_BACKEND {0}, expected gcp, aws or mqtt")]
+    UnknownBackend(String),
+}
+
+/// A setting the backend can't do without
+pub fn required(settings: &Settings, variable: &'static str) -> Result<String, CloudError> {
+    settings
+        .get(variable)
+        .map(str::to_string)
+        .ok_or(CloudError::Missing(variable))
+}
+
+/// Whether the setting names a topic, e.g. MQTT_TOPIC_PREFIX or AWS_FEEDER_TOPIC. The clients of
+/// the backend and the mirror are subscribed and publish with them from startup on
+pub fn is_topic_setting(variable: &str) -> bool {
+    variable.ends_with("_TOPIC") || variable.ends_with("_TOPIC_PREFIX")
+}
+
+/// Time the messages in flight are given to be delivered on disconnect, DISCONNECT_TIMEOUT seconds
+pub fn disconnect_timeout(settings: &Settings) -> Result<Duration, ConfigError> {
+    Ok(settings
+        .parse("DISCONNECT_TIMEOUT", "seconds")?
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_DISCONNECT_TIMEOUT))
+}
+
+/// The IoT service the twin reports to, selected at startup with IOT_BACKEND ("gcp", "aws" or
+/// "mqtt" for a self hosted broker), defaulting to Google IoT Core. Each keeps the settings it
+/// connects with, every reconnection reads them again
+#[derive(Debug, Clone)]
+pub enum Backend {
+    Gcp {
+        device_id: String,
+        /// set when the twin runs as a gateway proxying a device per component
+        gateway: Option<Gateway>,
+        endpoints: Endpoints,
+        settings: Settings,
+    },
+    Aws {
+        thing_name: String,
+        topic_prefix: String,
+        event_topics: HashMap<&'static str, String>,
+        settings: Settings,
+    },
+    Mqtt {
+        topic_prefix: String,
+        settings: Settings,
+    },
+}
+
+impl Backend {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let backend = settings.get("IOT_BACKEND").unwrap_or("gcp");
+        // messages are sealed in the configured version until the cloud negotiated one
+        message::configure_version(settings)?;
+
+        match backend {
+            "gcp" => {
+                let device_id = required(settings, "DEVICE_ID")?;
+                let gateway = Gateway::from_settings(settings, &device_id);
+
+                Ok(Self::Gcp {
+                    device_id,
+                    gateway,
+                    endpoints: Endpoints::from_settings(settings)?,
+                    settings: settings.clone(),
+                })
+            }
+            "aws" => {
+                let thing_name = aws_iot::thing_name(settings)?;
+                let topic_prefix = aws_iot::topic_prefix(settings, &thing_name);
+                let event_topics = COMPONENTS
+                    .into_iter()
+                    .map(|component| {
+                        let topic = aws_iot::event_topic(settings, &topic_prefix, component)?;
+                        Ok((component, topic))
+                    })
+                    .collect::<Result<_>>()?;
+
+                Ok(Self::Aws {
+                    thing_name,
+                    topic_prefix,
+                    event_topics,
+                    settings: settings.clone(),
+                })
+            }
+            "mqtt" => Ok(Self::Mqtt {
+                topic_prefix: mqtt_broker::topic_prefix(settings),
+                settings: settings.clone(),
+            }),
+            other => Err(CloudError::UnknownBackend(other.to_string()).into()),
+        }
+    }
+
+    /// The settings the backend was configured from
+    pub fn settings(&self) -> &Settings {
+        match self {
+            Backend::Gcp { settings, .. }
+            | Backend::Aws { settings, .. }
+            | Backend::Mqtt { settings, .. } => settings,
+        }
+    }
+
+    /// Connect to the backend, returning the client with the stream of the messages it receives
+    #[instrument(skip_all, fields(device_id = %self.device_id()))]
+    pub async fn connect(&self) -> Result<(Client, MessageStream)> {
+        let will = Some(self.status_message("offline"));
+        let settings = self.settings();
+        let (client, stream) = match self {
+            Backend::Gcp { endpoints, .. } => gcp_iot::connect(settings, endpoints, will).await?,
+            Backend::Aws { .. } => aws_iot::connect(settings, will).await?,
+            Backend::Mqtt { .. } => mqtt_broker::connect(settings, will).await?,
+        };
+
+        self.on_connected(&client).await?;
+        Ok((client, stream))
+    }
+
+    /// Reconnect an existing client with fresh connect options, then restore the subscriptions in
+    /// case the session is clean
+    #[instrument(skip_all, fields(device_id = %self.device_id()))]
+    pub async fn reconnect(&self, client: &dyn MqttTransport) -> Result<()> {
+        let will = Some(self.status_message("offline"));
+        let settings = self.settings();
+        let connect_options = match self {
+            Backend::Gcp { endpoints, .. } => {
+                gcp_connect_options(settings, endpoints.current(), will).await?
+            }
+            Backend::Aws { .. } => aws_connect_options(settings, will)?,
+            Backend::Mqtt { .. } => {
+                mqtt_broker::get_connect_ops(settings, client.mqtt_version(), will)?
+            }
+        };
+
+        match (self, client.reconnect(&connect_options).await) {
+            (Backend::Gcp { endpoints, .. }, Ok(_)) => endpoints.record_success(),
+            (Backend::Gcp { endpoints, .. }, Err(e)) => {
+                endpoints.record_failure();
+                return Err(ConnectError::refused(e).into());
+            }
+            (_, connected) => {
+                connected.map_err(tls::connect_error)?;
+            }
+        }
+        self.on_connected(client).await
+    }
+
+    /// Subscribe to every topic the twin listens on, attach the proxied devices in gateway mode and
+    /// announce the twin is online
+    async fn on_connected(&self, client: &dyn MqttTransport) -> Result<()> {
+        client.subscribe(&self.config_topic(), QOS_1).await?;
+        client
+            .subscribe(&self.commands_topic_filter(), QOS_1)
+            .await?;
+
+        if let Backend::Gcp {
+            gateway: Some(gateway),
+            ..
+        } = self
+        {
+            client.subscribe(&gateway.errors_topic(), QOS_1).await?;
+            for device_id in gateway.device_ids() {
+                client.attach_device(device_id).await?;
+            }
+        }
+
+        client.publish(self.status_message("online")).await?;
+        Ok(())
+    }
+
+    /// Id the twin's messages to the cloud are sent from, the device id, thing name or client id
+    pub fn device_id(&self) -> String {
+        match self {
+            Backend::Gcp { device_id, .. } => device_id.clone(),
+            Backend::Aws { thing_name, .. } => thing_name.clone(),
+            Backend::Mqtt { settings, .. } => mqtt_broker::client_id(settings),
+        }
+    }
+
+    /// Topic the liveness of the twin is published on, "online" once connected and "offline" as the
+    /// last will when the connection is lost without a clean disconnect
+    pub fn status_topic(&self) -> String {
+        match self {
+            // status is a single level, unwrap is safe
+            Backend::Gcp { .. } => self.topic().event("status").unwrap(),
+            Backend::Aws { .. } | Backend::Mqtt { .. } => self.topic().status(),
+        }
+    }
+
+    /// The topics of the device, below /devices/{device_id} for Google IoT and below the topic
+    /// prefix otherwise
+    fn topic(&self) -> Topic {
+        match self {
+            Backend::Gcp { device_id, .. } => Topic::device(device_id),
+            Backend::Aws { topic_prefix, .. } | Backend::Mqtt { topic_prefix, .. } => {
+                Topic::prefixed(topic_prefix)
+            }
+        }
+    }
+
+    fn status_message(&self, status: &str) -> Message {
+        // a status can always be encoded
+        let payload = message::seal(&self.device_id(), &json!({ "status": status })).unwrap();
+
+        match self {
+            // Google IoT doesn't support retained messages, dashboards have to rely on the latest
+            // status event instead
+            Backend::Gcp { .. } => Message::new(self.status_topic(), payload, QOS_1),
+            _ => Message::new_retained(self.status_topic(), payload, QOS_1),
+        }
+    }
+
+    /// Files the credentials of the connection are read from, the targets of a key rotation
+    pub fn credential_files(&self) -> CredentialFiles {
+        let settings = self.settings();
+        let path = |key: &str| settings.get(key).map(PathBuf::from);
+
+        match self {
+            Backend::Gcp { .. } => CredentialFiles {
+                private_key: match KeySource::from_settings(settings) {
+                    Ok(KeySource::Path(path)) => Some(path),
+                    _ => None,
+                },
+                certificate: None,
+                ca_certificate: path("CA_CERTIFICATE"),
+            },
+            Backend::Aws { .. } => CredentialFiles {
+                private_key: path("AWS_PRIVATE_KEY"),
+                certificate: path("AWS_CERTIFICATE"),
+                ca_certificate: path("AWS_ROOT_CA"),
+            },
+            Backend::Mqtt { .. } => CredentialFiles {
+                private_key: path("MQTT_CLIENT_KEY"),
+                certificate: path("MQTT_CLIENT_CERTIFICATE"),
+                ca_certificate: path("MQTT_CA_CERTIFICATE"),
+            },
+        }
+    }
+
+    /// Topic the cloud sends the device configuration on
+    pub fn config_topic(&self) -> String {
+        self.topic().config()
+    }
+
+    /// Topic filter matching every commands subfolder
+    pub fn commands_topic_filter(&self) -> String {
+        self.topic().commands_filter()
+    }
+
+    /// Whether commands are received on the topic, the commands topic itself or any of its subfolders
+    pub fn is_command_topic(&self, topic: &str) -> bool {
+        topic == self.topic().commands() || self.command_subfolder(topic).is_some()
+    }
+
+    /// Returns the commands subfolder the topic belongs to, e.g. start for
+    /// /devices/{device_id}/commands/start
+    pub fn command_subfolder<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        self.topic().command_subfolder(topic)
+    }
+
+    /// Topic the events of the given component are published to, the component has to be a
+    /// single topic level
+    pub fn event_topic(&self, component: &str) -> Result<String, InvalidLevel> {
+        match self {
+            Backend::Gcp {
+                gateway: Some(gateway),
+                ..
+            } => gateway.event_topic(component),
+            Backend::Aws { event_topics, .. } => match event_topics.get(component) {
+                Some(topic) => Ok(topic.clone()),
+                None => self.topic().event(component),
+            },
+            _ => self.topic().event(component),
+        }
+    }
+
+    /// Report a message the twin couldn't handle back to the cloud, on the errors events topic. For
+    /// a payload failing validation, path points to the offending field
+    pub async fn report_error(
+        &self,
+        client: &dyn MqttTransport,
+        topic: &str,
+        error: &str,
+        path: Option<&str>,
+    ) -> Result<()> {
+        let mut payload = json!({ "topic": topic, "error": error });
+        if let Some(path) = path {
+            payload["path"] = json!(path);
+        }
+        let payload = message::seal(&self.device_id(), &payload)?;
+        let msg = Message::new(self.event_topic("errors")?, payload, QOS_1);
+        client.publish(msg).await?;
+        Ok(())
+    }
+
+    /// Cleanly disconnect, announcing the twin is offline and detaching the proxied devices when
+    /// running as a gateway. Failing to do either is only logged, the client is disconnected
+    /// regardless
+    #[instrument(skip_all, fields(device_id = %self.device_id()))]
+    pub async fn disconnect(&self, client: &dyn MqttTransport) -> Result<()> {
+        if let Backend::Gcp {
+            gateway: Some(gateway),
+            ..
+        } = self
+        {
+            for device_id in gateway.device_ids() {
+                if let Err(e) = client.detach_device(device_id).await {
+                    error!("Failed to detach {device_id}: {e}");
+                }
+            }
+        }
+
+        // the last will isn't sent on a clean disconnect
+        if let Err(e) = client.publish(self.status_message("offline")).await {
+            error!("Failed to announce the twin is offline: {e}");
+        }
+        // checked when the settings were loaded
+        let timeout = disconnect_timeout(self.settings()).unwrap_or(DEFAULT_DISCONNECT_TIMEOUT);
+        client.disconnect(timeout).await
+    }
+
+    /// Report the state of the twin to the backend's device state store, for Google IoT this is the
+    /// device state, for AWS it's the reported section of the device shadow, and for a plain broker
+    /// it's a retained state message. Shadows are JSON documents so the encoding doesn't apply to AWS,
+    /// nor does the message envelope since the shadow document belongs to AWS
+    pub async fn report_state(
+        &self,
+        client: &dyn MqttTransport,
+        state: serde_json::Value,
+        encoding: Encoding,
+    ) -> Result<()> {
+        match self {
+            Backend::Gcp { .. } => {
+                let msg = Message::new(
+                    self.topic().state(),
+                    self.encode_state(&state, encoding)?,
+                    QOS_1,
+                );
+                client.publish(msg).await?;
+                Ok(())
+            }
+            Backend::Aws { thing_name, .. } => client.update_shadow(thing_name, state).await,
+            Backend::Mqtt { .. } => {
+                let msg = Message::new_retained(
+                    self.topic().state(),
+                    self.encode_state(&state, encoding)?,
+                    QOS_1,
+                );
+                client.publish(msg).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// JSON state documents are sent in the negotiated message envelope, the binary encodings have
+    /// their own schema
+    fn encode_state(&self, state: &serde_json::Value, encoding: Encoding) -> Result<Vec<u8>> {
+        match encoding {
+            Encoding::Json => Ok(message::seal(&self.device_id(), state)?),
+            _ => encoding.encode_state(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_subfolder_is_extracted_from_topic() {
+        let settings = Settings::default();
+        let backend = Backend::Gcp {
+            device_id: "pi".to_string(),
+            gateway: None,
+            endpoints: Endpoints::from_settings(&settings).unwrap(),
+            settings,
+        };
+
+        assert_eq!(backend.commands_topic_filter(), "/devices/pi/commands/#");
+        assert_eq!(
+            backend.command_subfolder("/devices/pi/commands/start"),
+            Some("start")
+        );
+        assert_eq!(backend.command_subfolder("/devices/pi/config"), None);
+        assert!(backend.is_command_topic("/devices/pi/commands"));
+        assert!(!backend.is_command_topic("/devices/pi/config"));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::config::{ConfigError, Settings};
+use crate::encoding::Encoding;
+use crate::publisher::Outbound;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(1);
+
+struct Batch {
+    started: Instant,
+    outbounds: Vec<Outbound>,
+}
+
+/// Accumulates outbound messages per topic so they can be published as a single payload, a JSON
+/// array or a protobuf EventBatch depending on the encoding.
+///
+/// A batch is published once it holds BATCH_MAX_EVENTS messages or BATCH_WINDOW milliseconds after
+/// its first message, whichever comes first. Batching is disabled with the default max of 1, in
+/// which case messages pass through untouched
+pub struct Batcher {
+    max_events: usize,
+    window: Duration,
+    encoding: Encoding,
+    batches: HashMap<String, Batch>,
+}
+
+impl Batcher {
+    pub fn new(max_events: usize, window: Duration, encoding: Encoding) -> Self {
+        Self {
+            max_events: max_events.max(1),
+            window,
+            encoding,
+            batches: HashMap::new(),
+        }
+    }
+
+    pub fn from_settings(settings: &Settings, encoding: Encoding) -> Result<Self, ConfigError> {
+        let max_events = settings
+            .parse("BATCH_MAX_EVENTS", "unsigned integer")?
+            .unwrap_or(1);
+        let window = settings
+            .positive("BATCH_WINDOW", "milliseconds")?
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_WINDOW);
+
+        Ok(Self::new(max_events, window, encoding))
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Adds the message to the batch of its topic, returning the batch if it's now full
+    pub fn push(&mut self, outbound: Outbound) -> Option<Outbound> {
+        if self.max_events == 1 {
+            return Some(outbound);
+        }
+
+        let topic = outbound.topic.clone();
+        let batch = self.batches.entry(topic.clone()).or_insert_with(|| Batch {
+            started: Instant::now(),
+            outbounds: Vec::with_capacity(self.max_events),
+        });
+        batch.outbounds.push(outbound);
+
+        if batch.outbounds.len() >= self.max_events {
+            let encoding = self.encoding;
+            self.batches
+                .remove(&topic)
+                .map(|batch| combine(encoding, batch))
+        } else {
+            None
+        }
+    }
+
+    /// Removes and returns the batches that have been open for longer than the window
+    pub fn take_expired(&mut self, now: Instant) -> Vec<Outbound> {
+        let expired: Vec<_> = self
+            .batches
+            .iter()
+            .filter(|(_, batch)| now.duration_since(batch.started) >= self.window)
+            .map(|(topic, _)| topic.clone())
+            .collect();
+
+        let encoding = self.encoding;
+        expired
+            .into_iter()
+            .filter_map(|topic| self.batches.remove(&topic))
+            .map(|batch| combine(encoding, batch))
+            .collect()
+    }
+
+    /// Removes and returns every open batch, used to flush on shutdown
+    pub fn take_all(&mut self) -> Vec<Outbound> {
+        let encoding = self.encoding;
+        self.batches
+            .drain()
+            .map(|(_, batch)| combine(encoding, batch))
+            .collect()
+    }
+}
+
+/// Merges the messages of a batch into one message, published with the highest QoS of its messages
+fn combine(encoding: Encoding, batch: Batch) -> Outbound {
+    let mut outbounds = batch.outbounds.into_iter();
+    // batches are only created when pushing a message, so they are never empty
+    let mut combined = outbounds.next().unwrap();
+    let mut payloads = vec![combined.payload];
+
+    for outbound in outbounds {
+        combined.qos = combined.qos.max(outbound.qos);
+        payloads.push(outbound.payload);
+    }
+
+    combined.payload = encoding.batch(payloads);
+    combined
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn full_batches_are_published_as_an_array() {
+        let mut batcher = Batcher::new(3, Duration::from_secs(60), Encoding::Json);
+
+        assert!(batcher.push(Outbound::test("feeder", 0)).is_none());
+        assert!(batcher.push(Outbound::test("robot", 1)).is_none());
+        assert!(batcher.push(Outbound::test("feeder", 2)).is_none());
+
+        let batch = batcher.push(Outbound::test("feeder", 3)).unwrap();
+        assert_eq!(batch.payload, b"[0,2,3]");
+        assert_eq!(batch.sequence, 0);
+
+        let remaining = batcher.take_all();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].payload, b"[1]");
+    }
+
+    #[tokio::test]
+    async fn batches_expire_after_the_window() {
+        let mut batcher = Batcher::new(10, Duration::from_millis(100), Encoding::Json);
+        batcher.push(Outbound::test("feeder", 0));
+
+        assert!(batcher.take_expired(Instant::now()).is_empty());
+        let later = Instant::now() + Duration::from_millis(100);
+        assert_eq!(batcher.take_expired(later).len(), 1);
+    }
+
+    #[test]
+    fn batching_is_disabled_with_a_single_event() {
+        let mut batcher = Batcher::new(1, Duration::from_secs(1), Encoding::Json);
+
+        let outbound = batcher.push(Outbound::test("feeder", 0)).unwrap();
+        assert_eq!(outbound.payload, b"0");
+    }
+
+    #[test]
+    fn empty_windows_are_rejected() {
+        let settings = Settings::new([("BATCH_MAX_EVENTS", "10"), ("BATCH_WINDOW", "0")]);
+
+        assert!(Batcher::from_settings(&settings, Encoding::Json).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use futures::future;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Asks whatever is waiting on the cell to give up, e.g. on an emergency stop or when the twin is
+/// shut down. Clones share the cancellation, a child token is cancelled with its parent but can be
+/// cancelled on its own, so every command can be interrupted without shutting down the twin
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    /// the token itself first, then its parent and the parent's own
+    chain: Vec<Arc<Inner>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self {
+            chain: vec![Arc::new(Inner::default())],
+        }
+    }
+
+    /// A token cancelled along with this one
+    pub fn child_token(&self) -> Self {
+        let mut chain = vec![Arc::new(Inner::default())];
+        chain.extend(self.chain.iter().cloned());
+        Self { chain }
+    }
+
+    pub fn cancel(&self) {
+        let inner = &self.chain[0];
+        inner.cancelled.store(true, Ordering::SeqCst);
+        inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.chain
+            .iter()
+            .any(|inner| inner.cancelled.load(Ordering::SeqCst))
+    }
+
+    /// Completes once the token or any of its parents is cancelled
+    pub async fn cancelled(&self) {
+        // registered before the check, so a cancel in between isn't missed
+        let notified: Vec<_> = self
+            .chain
+            .iter()
+            .map(|inner| Box::pin(inner.notify.notified()))
+            .collect();
+        if self.is_cancelled() {
+            return;
+        }
+        future::select_all(notified).await;
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A wait given up since its token was cancelled
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error: Cancelled before it was done")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn children_are_cancelled_with_their_parent() {
+        let parent = CancellationToken::new();
+        let first = parent.child_token();
+        let second = parent.child_token();
+
+        first.cancel();
+        assert!(first.is_cancelled());
+        assert!(!parent.is_cancelled());
+        assert!(!second.is_cancelled());
+
+        let waiting = tokio::spawn({
+            let second = second.clone();
+            async move { second.cancelled().await }
+        });
+        parent.cancel();
+        time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("The child wasn't woken by its parent")
+            .unwrap();
+        assert!(second.is_cancelled());
+    }
+}
@@ -0,0 +1,112 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// The digital twin of the manufacturing cell. Runs the twin when no command is given
+#[derive(Debug, Parser)]
+#[clap(name = "tvilling", version)]
+pub struct Cli {
+    /// Settings file to read, instead of TVILLING_CONFIG or tvilling.toml
+    #[clap(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// IoT service to report to, gcp, aws or mqtt
+    #[clap(long, global = true)]
+    pub backend: Option<String>,
+    /// Id of the device on the backend
+    #[clap(long, global = true)]
+    pub device_id: Option<String>,
+    /// Override any setting by its environment variable, e.g. --set CONVEYOR_SPEED=50
+    #[clap(
+        long = "set",
+        global = true,
+        value_name = "VARIABLE=VALUE",
+        parse(try_from_str = parse_override)
+    )]
+    pub overrides: Vec<(String, String)>,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the twin, following the cell and taking commands from the cloud
+    Run,
+    /// Validate the settings, reporting every problem found with them
+    CheckConfig,
+    /// Inspect the GPIO lines of the board
+    Gpio {
+        #[clap(subcommand)]
+        command: GpioCommand,
+    },
+    /// Publish a test event to the backend, to check the connection and the credentials
+    PublishTest {
+        /// Component the event is published for, which picks its topic
+        #[clap(long, default_value = "test")]
+        component: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum GpioCommand {
+    /// List the lines of the chip, with the settings wiring components to them
+    List {
+        #[clap(long, default_value = "/dev/gpiochip0", value_name = "PATH")]
+        chip: PathBuf,
+    },
+}
+
+fn parse_override(value: &str) -> Result<(String, String), String> {
+    let (variable, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Expected VARIABLE=VALUE, got {value}"))?;
+    Ok((variable.to_string(), value.to_string()))
+}
+
+impl Cli {
+    /// The flags override the settings file and the environment, they're given as the variables
+    /// of the settings they override
+    pub fn variables(&self) -> Vec<(String, String)> {
+        let flags = [
+            (
+                "TVILLING_CONFIG",
+                self.config.as_ref().map(|path| path.display().to_string()),
+            ),
+            ("IOT_BACKEND", self.backend.clone()),
+            ("DEVICE_ID", self.device_id.clone()),
+        ];
+        flags
+            .into_iter()
+            .filter_map(|(variable, value)| Some((variable.to_string(), value?)))
+            .chain(self.overrides.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flags_are_parsed_with_the_subcommand() {
+        let cli = Cli::parse_from([
+            "tvilling",
+            "check-config",
+            "--backend",
+            "mqtt",
+            "--set",
+            "CONVEYOR_SPEED=50",
+            "--set",
+            "FEEDER_POLICY=balance",
+        ]);
+
+        assert!(matches!(cli.command, Some(Command::CheckConfig)));
+        assert_eq!(cli.backend.as_deref(), Some("mqtt"));
+        assert_eq!(
+            cli.overrides,
+            [
+                ("CONVEYOR_SPEED".to_string(), "50".to_string()),
+                ("FEEDER_POLICY".to_string(), "balance".to_string())
+            ]
+        );
+        assert!(Cli::try_parse_from(["tvilling", "--set", "CONVEYOR_SPEED"]).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use crate::config::{ConfigError, Settings};
+use std::io::{self, Write};
+
+/// Compression applied to outbound payloads, selected with PAYLOAD_COMPRESSION ("gzip" or "zstd").
+///
+/// The cloud function has to know how to decode a payload, so compressed messages are published to
+/// an extra topic level named after the encoding, e.g. /devices/pi/events/feeder/gzip, or with a
+/// content-encoding user property when connected with MQTT v5
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        match settings.get("PAYLOAD_COMPRESSION") {
+            Some("gzip") => Ok(Self::Gzip),
+            Some("zstd") => Ok(Self::Zstd),
+            Some("none") | None => Ok(Self::None),
+            Some(other) => Err(ConfigError::invalid(
+                "PAYLOAD_COMPRESSION",
+                "gzip, zstd or none",
+                other,
+            )),
+        }
+    }
+
+    /// Name of the content encoding, None if payloads are sent as is
+    pub fn encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+
+    pub fn compress(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(payload.to_vec()),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(payload)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(payload, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    const PAYLOAD: &[u8] = br#"[{"component":"feeder","event":"MaterialPickedUp"}]"#;
+
+    #[test]
+    fn gzip_payloads_round_trip() {
+        let compressed = Compression::Gzip.compress(PAYLOAD).unwrap();
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, PAYLOAD);
+    }
+
+    #[test]
+    fn zstd_payloads_round_trip() {
+        let compressed = Compression::Zstd.compress(PAYLOAD).unwrap();
+
+        let decompressed = zstd::decode_all(compressed.as_slice()).unwrap();
+        assert_eq!(decompressed, PAYLOAD);
+    }
+
+    #[test]
+    fn uncompressed_payloads_are_untouched() {
+        assert_eq!(Compression::None.compress(PAYLOAD).unwrap(), PAYLOAD);
+        assert_eq!(Compression::None.encoding(), None);
+    }
+}
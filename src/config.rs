@@ -0,0 +1,879 @@
+use crate::backend;
+use crate::batcher::Batcher;
+use crate::compression::Compression;
+use crate::dispatcher::QueuePolicy;
+use crate::encoding::Encoding;
+use crate::manufacturing_components::conveyor;
+use crate::manufacturing_components::feeder::FeederPolicy;
+use crate::manufacturing_components::interlock::Interlock;
+use crate::publisher::QosPolicy;
+use crate::rate_limiter::RateLimiter;
+use crate::reconnect::Backoff;
+use crate::utils;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Settings file read when TVILLING_CONFIG doesn't name another one, it's optional
+const DEFAULT_PATH: &str = "tvilling.toml";
+
+/// Time between the checks of the file for changes
+const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time the cycle waits for a material to be picked up before it's taken as stalled
+const DEFAULT_SENSOR_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Every problem found with the settings, reported together at startup rather than one at a time
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid configuration{}:{}", Located(.path), Listed(.problems))]
+pub struct ConfigError {
+    path: Option<PathBuf>,
+    problems: Vec<String>,
+}
+
+impl ConfigError {
+    /// A setting which can't be parsed
+    pub fn invalid(variable: &str, expected: &str, value: &str) -> Self {
+        Self {
+            path: None,
+            problems: vec![format!(
+                "{variable} cannot be parsed as {expected}: {value}"
+            )],
+        }
+    }
+
+    /// A setting needed for the reason given
+    pub fn missing(variable: &str, reason: &str) -> Self {
+        Self {
+            path: None,
+            problems: vec![format!("Missing {variable}, {reason}")],
+        }
+    }
+}
+
+struct Located<'a>(&'a Option<PathBuf>);
+
+impl Display for Located<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(path) => write!(f, " in {}", path.display()),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Listed<'a>(&'a [String]);
+
+impl Display for Listed<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for problem in self.0 {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Every setting by its environment variable, those of the environment the twin started in over
+/// those of the file. The components are configured from them rather than from the environment,
+/// which is only read once
+#[derive(Debug, Clone, Default)]
+pub struct Settings(Arc<BTreeMap<String, String>>);
+
+impl Settings {
+    pub fn new<K: Into<String>, V: Into<String>>(
+        variables: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        let variables = variables
+            .into_iter()
+            .map(|(variable, value)| (variable.into(), value.into()))
+            .collect();
+        Self(Arc::new(variables))
+    }
+
+    /// The variables of the environment, overridden by those given, e.g. by the flags. Those which
+    /// aren't unicode can't be settings and are left out
+    pub fn from_env(overrides: &[(String, String)]) -> Self {
+        let variables = env::vars_os()
+            .filter_map(|(variable, value)| {
+                Some((variable.into_string().ok()?, value.into_string().ok()?))
+            })
+            .chain(overrides.iter().cloned());
+        Self::new(variables)
+    }
+
+    /// The setting of the variable, None when it isn't set
+    pub fn get(&self, variable: &str) -> Option<&str> {
+        self.0.get(variable).map(String::as_str)
+    }
+
+    /// The setting of the variable parsed, None when it isn't set
+    pub fn parse<T: FromStr>(
+        &self,
+        variable: &str,
+        expected: &str,
+    ) -> Result<Option<T>, ConfigError> {
+        self.get(variable)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| ConfigError::invalid(variable, expected, value))
+            })
+            .transpose()
+    }
+
+    /// The setting of the variable parsed like parse, failing unless it's above zero, e.g. the period
+    /// of a timer or the rate it's derived from
+    pub fn positive<T: FromStr + PartialOrd + Default>(
+        &self,
+        variable: &str,
+        expected: &str,
+    ) -> Result<Option<T>, ConfigError> {
+        match self.parse::<T>(variable, expected)? {
+            Some(value) if value > T::default() => Ok(Some(value)),
+            // NaN isn't above zero either
+            Some(_) => Err(ConfigError::invalid(
+                variable,
+                &format!("{expected} above 0"),
+                self.get(variable).unwrap_or_default(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// The setting of the variable needed for the reason given
+    pub fn require(&self, variable: &str, reason: &str) -> Result<&str, ConfigError> {
+        self.get(variable)
+            .ok_or_else(|| ConfigError::missing(variable, reason))
+    }
+
+    /// Every setting with its variable, in the order of the variables
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(variable, value)| (variable.as_str(), value.as_str()))
+    }
+}
+
+/// Identity of the device on the backend it reports to
+#[derive(Debug, Clone, Default)]
+pub struct Device {
+    /// gcp, aws or mqtt
+    pub backend: String,
+    pub device_id: Option<String>,
+    pub project_id: Option<String>,
+    pub region: Option<String>,
+    pub registry_id: Option<String>,
+    pub thing_name: Option<String>,
+    pub aws_endpoint: Option<String>,
+    pub broker_uri: Option<String>,
+}
+
+/// Paths of the keys and certificates the twin authenticates with, each checked to exist
+#[derive(Debug, Clone, Default)]
+pub struct Keys {
+    /// TLS key of the connection to Cloud IoT
+    pub private_key: Option<PathBuf>,
+    /// signs the JWT authenticating to Cloud IoT
+    pub jwt_private_key: Option<PathBuf>,
+    pub aws_root_ca: Option<PathBuf>,
+    pub aws_certificate: Option<PathBuf>,
+    pub aws_private_key: Option<PathBuf>,
+    pub mqtt_ca_certificate: Option<PathBuf>,
+    pub mqtt_client_certificate: Option<PathBuf>,
+    pub mqtt_client_key: Option<PathBuf>,
+}
+
+/// GPIO lines of the components every cell has, no two of them may be the same line
+#[derive(Debug, Clone, Default)]
+pub struct Lines {
+    pub material: u32,
+    /// the second feeder, when the cell has one
+    pub feeder_b: Option<u32>,
+    pub robot: u32,
+    pub piston: u32,
+    pub piston_output: u32,
+    pub conveyor: u32,
+    pub conveyor_output: u32,
+    pub program_control: u32,
+}
+
+/// Parameters of the cycles run by the programs
+#[derive(Debug, Clone)]
+pub struct Cycle {
+    /// materials processed between progress events
+    pub progress_every: u32,
+    /// time a feeder is waited on before the cycle is stalled
+    pub sensor_timeout: Duration,
+    /// in percent of the rated speed of the drive
+    pub conveyor_speed: u8,
+}
+
+/// Settings of the twin, read from the TOML or YAML file TVILLING_CONFIG, tvilling.toml by
+/// default, where each setting is overridden by its environment variable when that's set. The
+/// file has a section for the identity of the device, the keys, the lines and the cycles, e.g.
+///
+/// ```toml
+/// [device]
+/// backend = "gcp"
+/// device_id = "cell-1"
+///
+/// [lines]
+/// material = 17
+///
+/// [env]
+/// FEEDER_POLICY = "alternate"
+/// ```
+///
+/// The settings of the other components go in the env section by their environment variable, the
+/// components are configured from them through settings
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub device: Device,
+    pub keys: Keys,
+    pub lines: Lines,
+    pub cycle: Cycle,
+    /// the file the settings were read from, or would be read from once there's one
+    path: PathBuf,
+    /// whether the file was named by TVILLING_CONFIG rather than the default one
+    required: bool,
+    /// how often the file is polled for changes, CONFIG_WATCH_INTERVAL in seconds
+    watch_interval: Duration,
+    /// every setting by its environment variable, the components are configured from them
+    pub settings: Settings,
+    /// the variables of the environment the twin started in, which override the file
+    environment: Settings,
+}
+
+/// A value of the file, whatever its type in the file it's parsed like its environment variable
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl Display for Scalar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scalar::Bool(value) => write!(f, "{value}"),
+            Scalar::Integer(value) => write!(f, "{value}"),
+            Scalar::Float(value) => write!(f, "{value}"),
+            Scalar::Text(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+type Sections = BTreeMap<String, BTreeMap<String, Scalar>>;
+
+/// The typed settings, by section, key and environment variable
+const SETTINGS: &[(&str, &[(&str, &str)])] = &[
+    (
+        "device",
+        &[
+            ("backend", "IOT_BACKEND"),
+            ("device_id", "DEVICE_ID"),
+            ("project_id", "PROJECT_ID"),
+            ("region", "REGION"),
+            ("registry_id", "REGISTRY_ID"),
+            ("thing_name", "THING_NAME"),
+            ("aws_endpoint", "AWS_ENDPOINT"),
+            ("broker_uri", "MQTT_BROKER_URI"),
+        ],
+    ),
+    (
+        "keys",
+        &[
+            ("private_key", "PRIVATE_KEY"),
+            ("jwt_private_key", "JWT_PRIVATE_KEY_PATH"),
+            ("aws_root_ca", "AWS_ROOT_CA"),
+            ("aws_certificate", "AWS_CERTIFICATE"),
+            ("aws_private_key", "AWS_PRIVATE_KEY"),
+            ("mqtt_ca_certificate", "MQTT_CA_CERTIFICATE"),
+            ("mqtt_client_certificate", "MQTT_CLIENT_CERTIFICATE"),
+            ("mqtt_client_key", "MQTT_CLIENT_KEY"),
+        ],
+    ),
+    (
+        "lines",
+        &[
+            ("material", "MATERIAL_LINE"),
+            ("feeder_b", "FEEDER_B_LINE"),
+            ("robot", "ROBOT_LINE"),
+            ("piston", "PISTON_LINE"),
+            ("piston_output", "PISTON_OUTPUT_LINE"),
+            ("conveyor", "CONVEYOR_LINE"),
+            ("conveyor_output", "CONVEYOR_OUTPUT_LINE"),
+            ("program_control", "PROGRAM_CONTROL"),
+        ],
+    ),
+    (
+        "cycle",
+        &[
+            ("progress_every", "PROGRESS_EVERY"),
+            ("sensor_timeout", "SENSOR_TIMEOUT"),
+            ("conveyor_speed", "CONVEYOR_SPEED"),
+        ],
+    ),
+];
+
+/// Checks the settings of a component the way it reads them
+type Check = fn(&Settings) -> Result<(), ConfigError>;
+
+/// The settings of the other components which can be checked without their devices, read the way
+/// the components read them
+const CHECKS: &[Check] = &[
+    |settings| FeederPolicy::from_settings(settings).map(drop),
+    |settings| Interlock::from_settings(settings).map(drop),
+    |settings| QueuePolicy::from_settings(settings).map(drop),
+    |settings| QosPolicy::from_settings(settings).map(drop),
+    |settings| Backoff::from_settings(settings).map(drop),
+    |settings| Compression::from_settings(settings).map(drop),
+    |settings| Batcher::from_settings(settings, Encoding::from_settings(settings)?).map(drop),
+    |settings| Encoding::from_setting(settings, "STATE_PAYLOAD_ENCODING").map(drop),
+    |settings| Encoding::from_setting(settings, "MIRROR_PAYLOAD_ENCODING").map(drop),
+    |settings| RateLimiter::from_settings(settings).map(drop),
+    |settings| backend::disconnect_timeout(settings).map(drop),
+];
+
+/// Looks the settings up, the environment first, collecting the problems with them
+struct Resolver<'a> {
+    sections: &'a Sections,
+    environment: &'a Settings,
+    variables: BTreeMap<String, String>,
+    problems: Vec<String>,
+}
+
+impl Resolver<'_> {
+    fn variable(section: &str, key: &str) -> &'static str {
+        SETTINGS
+            .iter()
+            .find(|(name, _)| *name == section)
+            .and_then(|(_, keys)| keys.iter().find(|(name, _)| *name == key))
+            .map(|(_, variable)| *variable)
+            .expect("Every setting looked up is listed")
+    }
+
+    fn raw(&mut self, section: &str, key: &str) -> Option<String> {
+        let variable = Self::variable(section, key);
+        let value = match self.environment.get(variable) {
+            Some(value) => value.to_string(),
+            None => self.sections.get(section)?.get(key)?.to_string(),
+        };
+        self.variables.insert(variable.to_string(), value.clone());
+        Some(value)
+    }
+
+    fn optional<T: FromStr>(&mut self, section: &str, key: &str, expected: &str) -> Option<T> {
+        let value = self.raw(section, key)?;
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                let variable = Self::variable(section, key);
+                self.problems.push(format!(
+                    "{section}.{key} ({variable}) cannot be parsed as {expected}: {value}"
+                ));
+                None
+            }
+        }
+    }
+
+    fn required<T: FromStr + Default>(&mut self, section: &str, key: &str, expected: &str) -> T {
+        if self.raw(section, key).is_none() {
+            let variable = Self::variable(section, key);
+            self.problems
+                .push(format!("Missing {section}.{key} ({variable})"));
+            return T::default();
+        }
+        self.optional(section, key, expected).unwrap_or_default()
+    }
+
+    fn require(&mut self, section: &str, key: &str, set: bool, reason: &str) {
+        if !set {
+            let variable = Self::variable(section, key);
+            self.problems
+                .push(format!("Missing {section}.{key} ({variable}), {reason}"));
+        }
+    }
+
+    fn path(&mut self, key: &str) -> Option<PathBuf> {
+        let path: PathBuf = self.raw("keys", key)?.into();
+        if !path.is_file() {
+            let variable = Self::variable("keys", key);
+            self.problems.push(format!(
+                "keys.{key} ({variable}) is no file: {}",
+                path.display()
+            ));
+        }
+        Some(path)
+    }
+}
+
+impl Config {
+    /// The settings of TVILLING_CONFIG, or of tvilling.toml when there's one, overridden by the
+    /// environment and then by the overrides, e.g. those of the flags
+    pub fn load(overrides: &[(String, String)]) -> Result<Self, ConfigError> {
+        let environment = Settings::from_env(overrides);
+        let (path, required) = match environment.get("TVILLING_CONFIG") {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(DEFAULT_PATH), false),
+        };
+
+        Self::read(path, required, environment)
+    }
+
+    /// The settings of the file again, e.g. once it was edited. The environment the twin started
+    /// in still overrides it. With content, the file is replaced with it once it's found valid
+    pub fn reload(&self, content: Option<&str>) -> Result<Self, ConfigError> {
+        let environment = self.environment.clone();
+        let content = match content {
+            Some(content) => content,
+            None => return Self::read(self.path.clone(), self.required, environment),
+        };
+
+        let error = |problems| ConfigError {
+            path: Some(self.path.clone()),
+            problems,
+        };
+        let mut config = Self::parse(content, &self.path, environment).map_err(error)?;
+        config.required = self.required;
+        utils::write_atomic(&self.path, content)
+            .map_err(|e| error(vec![format!("Unable to write the file, {e}")]))?;
+        Ok(config)
+    }
+
+    fn read(path: PathBuf, required: bool, environment: Settings) -> Result<Self, ConfigError> {
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                let mut config =
+                    Self::parse("", &path, environment).map_err(|problems| ConfigError {
+                        path: None,
+                        problems,
+                    })?;
+                config.required = required;
+                return Ok(config);
+            }
+            Err(e) => {
+                return Err(ConfigError {
+                    problems: vec![format!("Unable to read the file, {e}")],
+                    path: Some(path),
+                })
+            }
+        };
+
+        let mut config =
+            Self::parse(&content, &path, environment).map_err(|problems| ConfigError {
+                path: Some(path.clone()),
+                problems,
+            })?;
+        config.required = required;
+        Ok(config)
+    }
+
+    /// The settings of the file at path with content, YAML when its extension says so and TOML
+    /// otherwise, overridden by the environment. Fails with every problem found
+    pub(crate) fn parse(
+        content: &str,
+        path: &Path,
+        environment: Settings,
+    ) -> Result<Self, Vec<String>> {
+        let yaml = matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("yaml" | "yml")
+        );
+        let mut sections: Sections = if content.trim().is_empty() {
+            Sections::new()
+        } else if yaml {
+            serde_yaml::from_str(content).map_err(|e| vec![e.to_string()])?
+        } else {
+            toml::from_str(content).map_err(|e| vec![e.to_string()])?
+        };
+        let others = sections.remove("env").unwrap_or_default();
+
+        let mut resolver = Resolver {
+            sections: &sections,
+            environment: &environment,
+            variables: BTreeMap::new(),
+            problems: vec![],
+        };
+        for (section, keys) in resolver.sections {
+            match SETTINGS.iter().find(|(name, _)| name == section) {
+                Some((_, known)) => {
+                    for key in keys.keys() {
+                        if !known.iter().any(|(name, _)| name == key) {
+                            resolver
+                                .problems
+                                .push(format!("Unknown setting {section}.{key}"));
+                        }
+                    }
+                }
+                None => resolver.problems.push(format!(
+                    "Unknown section {section}, expected device, keys, lines, cycle or env"
+                )),
+            }
+        }
+
+        let device = Device {
+            backend: resolver
+                .raw("device", "backend")
+                .unwrap_or_else(|| "gcp".to_string()),
+            device_id: resolver.raw("device", "device_id"),
+            project_id: resolver.raw("device", "project_id"),
+            region: resolver.raw("device", "region"),
+            registry_id: resolver.raw("device", "registry_id"),
+            thing_name: resolver.raw("device", "thing_name"),
+            aws_endpoint: resolver.raw("device", "aws_endpoint"),
+            broker_uri: resolver.raw("device", "broker_uri"),
+        };
+        let keys = Keys {
+            private_key: resolver.path("private_key"),
+            jwt_private_key: resolver.path("jwt_private_key"),
+            aws_root_ca: resolver.path("aws_root_ca"),
+            aws_certificate: resolver.path("aws_certificate"),
+            aws_private_key: resolver.path("aws_private_key"),
+            mqtt_ca_certificate: resolver.path("mqtt_ca_certificate"),
+            mqtt_client_certificate: resolver.path("mqtt_client_certificate"),
+            mqtt_client_key: resolver.path("mqtt_client_key"),
+        };
+
+        // what the backend needs to connect
+        match device.backend.as_str() {
+            "gcp" => {
+                let reason = "required by the gcp backend";
+                for (key, set) in [
+                    ("device_id", device.device_id.is_some()),
+                    ("project_id", device.project_id.is_some()),
+                    ("region", device.region.is_some()),
+                    ("registry_id", device.registry_id.is_some()),
+                ] {
+                    resolver.require("device", key, set, reason);
+                }
+                resolver.require("keys", "private_key", keys.private_key.is_some(), reason);
+            }
+            "aws" => {
+                let reason = "required by the aws backend";
+                for (key, set) in [
+                    ("thing_name", device.thing_name.is_some()),
+                    ("aws_endpoint", device.aws_endpoint.is_some()),
+                ] {
+                    resolver.require("device", key, set, reason);
+                }
+                for (key, set) in [
+                    ("aws_root_ca", keys.aws_root_ca.is_some()),
+                    ("aws_certificate", keys.aws_certificate.is_some()),
+                    ("aws_private_key", keys.aws_private_key.is_some()),
+                ] {
+                    resolver.require("keys", key, set, reason);
+                }
+            }
+            "mqtt" => {
+                let set = device.broker_uri.is_some();
+                resolver.require("device", "broker_uri", set, "required by the mqtt backend");
+            }
+            other => resolver.problems.push(format!(
+                "Unknown device.backend (IOT_BACKEND) {other}, expected gcp, aws or mqtt"
+            )),
+        }
+        if keys.mqtt_client_certificate.is_some() && keys.mqtt_client_key.is_none() {
+            resolver.require(
+                "keys",
+                "mqtt_client_key",
+                false,
+                "required by keys.mqtt_client_certificate",
+            );
+        }
+
+        let integer = "unsigned integer";
+        let lines = Lines {
+            material: resolver.required("lines", "material", integer),
+            feeder_b: resolver.optional("lines", "feeder_b", integer),
+            robot: resolver.required("lines", "robot", integer),
+            piston: resolver.required("lines", "piston", integer),
+            piston_output: resolver.required("lines", "piston_output", integer),
+            conveyor: resolver.required("lines", "conveyor", integer),
+            conveyor_output: resolver.required("lines", "conveyor_output", integer),
+            program_control: resolver.required("lines", "program_control", integer),
+        };
+        let mut used = HashMap::new();
+        for (name, line) in [
+            ("material", Some(lines.material)),
+            ("feeder_b", lines.feeder_b),
+            ("robot", Some(lines.robot)),
+            ("piston", Some(lines.piston)),
+            ("piston_output", Some(lines.piston_output)),
+            ("conveyor", Some(lines.conveyor)),
+            ("conveyor_output", Some(lines.conveyor_output)),
+            ("program_control", Some(lines.program_control)),
+        ] {
+            let line = match line {
+                // missing lines are reported already
+                Some(line) if resolver.raw("lines", name).is_some() => line,
+                _ => continue,
+            };
+            if let Some(other) = used.insert(line, name) {
+                resolver.problems.push(format!(
+                    "lines.{other} and lines.{name} are both line {line}"
+                ));
+            }
+        }
+
+        let cycle = Cycle {
+            progress_every: resolver
+                .optional("cycle", "progress_every", integer)
+                .unwrap_or(1),
+            sensor_timeout: resolver
+                .optional("cycle", "sensor_timeout", "milliseconds")
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_SENSOR_TIMEOUT),
+            conveyor_speed: resolver
+                .optional("cycle", "conveyor_speed", "a percentage")
+                .unwrap_or(conveyor::DEFAULT_SPEED),
+        };
+        if cycle.conveyor_speed > 100 {
+            resolver.problems.push(format!(
+                "cycle.conveyor_speed (CONVEYOR_SPEED) is above 100 percent: {}",
+                cycle.conveyor_speed
+            ));
+        }
+
+        let Resolver {
+            mut variables,
+            mut problems,
+            ..
+        } = resolver;
+        for (variable, value) in others {
+            variables
+                .entry(variable)
+                .or_insert_with(|| value.to_string());
+        }
+        variables.extend(
+            environment
+                .iter()
+                .map(|(variable, value)| (variable.to_string(), value.to_string())),
+        );
+        let settings = Settings(Arc::new(variables));
+        // the settings of the other components are checked the way the components read them,
+        // so a bad value fails here rather than once the component is configured
+        for check in CHECKS {
+            if let Err(e) = check(&settings) {
+                problems.extend(e.problems);
+            }
+        }
+        let watch_interval = match settings.positive("CONFIG_WATCH_INTERVAL", "seconds") {
+            Ok(secs) => secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_WATCH_INTERVAL),
+            Err(e) => {
+                problems.extend(e.problems);
+                DEFAULT_WATCH_INTERVAL
+            }
+        };
+
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        Ok(Self {
+            device,
+            keys,
+            lines,
+            cycle,
+            path: path.to_path_buf(),
+            required: false,
+            watch_interval,
+            settings,
+            environment,
+        })
+    }
+
+    /// Take over the settings of reloaded. Returns the variables whose value changed
+    pub fn apply(&mut self, reloaded: Config) -> Vec<String> {
+        let changes = self.changes(&reloaded);
+        *self = reloaded;
+        changes
+    }
+
+    fn changes(&self, reloaded: &Config) -> Vec<String> {
+        // both override the file with the same environment, only the file's settings can differ
+        let mut changes: Vec<String> = self
+            .settings
+            .iter()
+            .chain(reloaded.settings.iter())
+            .map(|(variable, _)| variable)
+            .filter(|variable| self.settings.get(variable) != reloaded.settings.get(variable))
+            .map(str::to_string)
+            .collect();
+        changes.sort();
+        changes.dedup();
+        changes
+    }
+
+    /// Call on_change whenever the file is modified, created or removed, as seen by polling it
+    /// every CONFIG_WATCH_INTERVAL seconds, 5 by default
+    pub fn watch(&self, on_change: impl Fn() + Send + 'static) {
+        let interval = self.watch_interval;
+        let path = self.path.clone();
+        let modified = move || std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+        tokio::task::spawn(async move {
+            let mut last = modified();
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let current = modified();
+                if current != last {
+                    last = current;
+                    on_change();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LINES: &str = r#"
+        [lines]
+        material = 1
+        robot = 2
+        piston = 3
+        piston_output = 4
+        conveyor = 5
+        conveyor_output = 6
+        program_control = 7
+    "#;
+
+    #[test]
+    fn the_environment_overrides_the_file() {
+        let content = format!(
+            "[device]\nbackend = \"mqtt\"\nbroker_uri = \"tcp://localhost:1883\"\n{LINES}\n\
+             [env]\nFEEDER_POLICY = \"alternate\"\n"
+        );
+        let environment = Settings::new([("PISTON_LINE", "9")]);
+
+        let config = Config::parse(&content, Path::new("tvilling.toml"), environment).unwrap();
+        assert_eq!(config.lines.piston, 9);
+        assert_eq!(config.lines.robot, 2);
+        assert_eq!(config.cycle.progress_every, 1);
+        assert_eq!(config.settings.get("PISTON_LINE"), Some("9"));
+        assert_eq!(config.settings.get("FEEDER_POLICY"), Some("alternate"));
+    }
+
+    #[test]
+    fn the_settings_of_the_components_are_checked_at_load() {
+        let content = format!(
+            "[device]\nbackend = \"mqtt\"\nbroker_uri = \"tcp://localhost:1883\"\n{LINES}\n\
+             [env]\nFEEDER_POLICY = \"round-robin\"\n"
+        );
+
+        let problems =
+            Config::parse(&content, Path::new("tvilling.toml"), Settings::default()).unwrap_err();
+        assert_eq!(
+            problems,
+            ["FEEDER_POLICY cannot be parsed as alternate or balance: round-robin"]
+        );
+    }
+
+    #[test]
+    fn a_bad_watch_interval_is_reported_at_load() {
+        let content =
+            format!("[device]\nbackend = \"mqtt\"\nbroker_uri = \"tcp://localhost:1883\"\n{LINES}");
+        let environment = Settings::new([("CONFIG_WATCH_INTERVAL", "soon")]);
+
+        let problems =
+            Config::parse(&content, Path::new("tvilling.toml"), environment).unwrap_err();
+        assert_eq!(
+            problems,
+            ["CONFIG_WATCH_INTERVAL cannot be parsed as seconds: soon"]
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let content = "device:\n  backend: gcp\nlines:\n  material: 1\n  robot: one\n  piston: 1\n";
+
+        let problems =
+            Config::parse(content, Path::new("tvilling.yaml"), Settings::default()).unwrap_err();
+        for expected in [
+            "Missing device.device_id (DEVICE_ID), required by the gcp backend",
+            "lines.robot (ROBOT_LINE) cannot be parsed as unsigned integer: one",
+            "Missing lines.conveyor (CONVEYOR_LINE)",
+            "lines.material and lines.piston are both line 1",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem == expected),
+                "{expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn periods_and_rates_must_be_above_zero() {
+        let settings = Settings::new([
+            ("HEARTBEAT_INTERVAL", "0"),
+            ("VIBRATION_RATE", "50"),
+            ("PUBLISH_RATE", "NaN"),
+        ]);
+        assert!(settings
+            .positive::<u64>("HEARTBEAT_INTERVAL", "milliseconds")
+            .is_err());
+        assert_eq!(
+            settings
+                .positive::<u32>("VIBRATION_RATE", "samples per second")
+                .unwrap(),
+            Some(50)
+        );
+        assert!(settings.positive::<f64>("PUBLISH_RATE", "a rate").is_err());
+        assert_eq!(
+            settings
+                .positive::<u64>("METRICS_INTERVAL", "milliseconds")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn errors_name_the_file_they_come_from() {
+        let error = ConfigError {
+            path: Some("tvilling.toml".into()),
+            problems: vec!["Missing lines.conveyor (CONVEYOR_LINE)".to_string()],
+        };
+        assert_eq!(
+            error.to_string(),
+            "Invalid configuration in tvilling.toml:\n  - Missing lines.conveyor (CONVEYOR_LINE)"
+        );
+
+        let error = ConfigError::invalid("VIBRATION_RATE", "unsigned integer", "fast");
+        assert_eq!(
+            error.to_string(),
+            "Invalid configuration:\n  - VIBRATION_RATE cannot be parsed as unsigned integer: fast"
+        );
+    }
+
+    #[test]
+    fn reloads_report_the_changed_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tvilling.toml");
+        let content =
+            format!("[device]\nbackend = \"mqtt\"\nbroker_uri = \"tcp://localhost:1883\"\n{LINES}");
+        let config = Config::parse(&content, &path, Settings::default()).unwrap();
+
+        // invalid settings leave the file alone
+        assert!(config.reload(Some("[lines]\nmaterial = 1\n")).is_err());
+        assert!(!path.exists());
+
+        let content = format!("{content}\n[cycle]\nconveyor_speed = 50\n");
+        let reloaded = config.reload(Some(&content)).unwrap();
+        assert_eq!(reloaded.cycle.conveyor_speed, 50);
+        assert_eq!(config.changes(&reloaded), ["CONVEYOR_SPEED"]);
+        assert_eq!(reloaded.reload(None).unwrap().cycle.conveyor_speed, 50);
+    }
+}
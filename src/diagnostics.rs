@@ -0,0 +1,137 @@
+use crate::backend::Backend;
+use crate::gcp_iot::message;
+use crate::transport::{Message, MqttTransport, QOS_0};
+use color_eyre::Result;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::warn;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Connection health of the twin, published for fleet operators to spot flaky devices
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Report {
+    /// successful reconnections since startup
+    pub reconnects: u64,
+    /// reconnection attempts that failed since the connection was lost
+    pub failed_reconnect_attempts: u32,
+    pub last_disconnect_reason: Option<String>,
+    /// events that couldn't be published right away and had to be buffered
+    pub publish_failures: u64,
+    /// time for the latest event to be acknowledged by the broker
+    pub round_trip_ms: Option<u64>,
+}
+
+/// Handle shared by the tasks recording connection health, cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    report: Arc<Mutex<Report>>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_disconnect(&self, reason: impl Into<String>) {
+        self.report.lock().unwrap().last_disconnect_reason = Some(reason.into());
+    }
+
+    pub fn record_reconnect(&self) {
+        let mut report = self.report.lock().unwrap();
+        report.reconnects += 1;
+        report.failed_reconnect_attempts = 0;
+    }
+
+    pub fn record_reconnect_failure(&self) {
+        self.report.lock().unwrap().failed_reconnect_attempts += 1;
+    }
+
+    pub fn failed_reconnect_attempts(&self) -> u32 {
+        self.report.lock().unwrap().failed_reconnect_attempts
+    }
+
+    pub fn record_publish_failure(&self) {
+        self.report.lock().unwrap().publish_failures += 1;
+    }
+
+    pub fn record_round_trip(&self, latency: Duration) {
+        self.report.lock().unwrap().round_trip_ms = Some(latency.as_millis() as u64);
+    }
+
+    pub fn report(&self) -> Report {
+        self.report.lock().unwrap().clone()
+    }
+}
+
+/// Spawn a task publishing the diagnostics on the diagnostics events topic every
+/// DIAGNOSTICS_INTERVAL milliseconds, skipped while disconnected since the report would be stale by
+/// the time it's delivered
+pub fn spawn(
+    diagnostics: Diagnostics,
+    backend: Backend,
+    client: impl MqttTransport + 'static,
+) -> Result<JoinHandle<()>> {
+    let interval = backend
+        .settings()
+        .positive("DIAGNOSTICS_INTERVAL", "milliseconds")?
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let topic = backend.event_topic("diagnostics")?;
+    let device_id = backend.device_id();
+
+    Ok(tokio::task::spawn(async move {
+        let mut interval = time::interval(interval);
+
+        loop {
+            interval.tick().await;
+            if !client.is_connected() {
+                continue;
+            }
+
+            let payload = match message::seal(&device_id, &diagnostics.report()) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Failed to encode the diagnostics: {e}");
+                    continue;
+                }
+            };
+
+            if let Err(e) = client.publish(Message::new(&topic, payload, QOS_0)).await {
+                warn!("Failed to publish the diagnostics: {e}");
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_are_shared_between_clones() {
+        let diagnostics = Diagnostics::new();
+        let recorder = diagnostics.clone();
+
+        recorder.record_disconnect("connection lost");
+        recorder.record_reconnect_failure();
+        recorder.record_reconnect();
+        recorder.record_publish_failure();
+        recorder.record_publish_failure();
+        recorder.record_round_trip(Duration::from_millis(42));
+
+        assert_eq!(
+            diagnostics.report(),
+            Report {
+                reconnects: 1,
+                failed_reconnect_attempts: 0,
+                last_disconnect_reason: Some("connection lost".to_string()),
+                publish_failures: 2,
+                round_trip_ms: Some(42),
+            }
+        );
+    }
+}
@@ -0,0 +1,392 @@
+use crate::ack::{AckStatus, Acknowledger};
+use crate::cancellation::CancellationToken;
+use crate::config::{ConfigError, Settings};
+use crate::gcp_iot::message::{
+    Command, EmergencyStopRequest, QueryRequest, ReloadConfigRequest, StartRequest,
+};
+use crate::idempotency::IdempotencyStore;
+use crate::scheduler::Scheduler;
+use crate::state_reporter::SnapshotPublisher;
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Mutex};
+use tracing::{error, info, warn};
+
+/// Commands waiting for the executor, besides the one it runs, with the queue policy
+const DEFAULT_QUEUE_SIZE: usize = 10;
+
+/// A command with the id it was sent with
+pub type Queued = (Option<String>, Command);
+
+/// Hand the command to the executor. It stops taking commands once the twin shuts down, those
+/// arriving afterwards are dropped. Returns whether it was handed over
+fn enqueue(queue: &UnboundedSender<Queued>, queued: Queued) -> bool {
+    match queue.send(queued) {
+        Ok(()) => true,
+        Err(SendError((id, command))) => {
+            warn!(
+                "The executor has stopped, dropped the {} command {}",
+                command.kind(),
+                id.as_deref().unwrap_or("without an id")
+            );
+            false
+        }
+    }
+}
+
+/// What happens to commands received while the executor is busy running another, selected with
+/// COMMAND_QUEUE_POLICY ("queue" or "reject")
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueuePolicy {
+    /// queued up to the given number of commands, COMMAND_QUEUE_SIZE, and run in order
+    Queue(usize),
+    /// rejected, the cloud has to send them again once the executor is done
+    Reject,
+}
+
+impl QueuePolicy {
+    pub fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        match settings.get("COMMAND_QUEUE_POLICY") {
+            Some("queue") | None => {
+                let size = settings.parse("COMMAND_QUEUE_SIZE", "unsigned integer")?;
+                Ok(QueuePolicy::Queue(size.unwrap_or(DEFAULT_QUEUE_SIZE)))
+            }
+            Some("reject") => Ok(QueuePolicy::Reject),
+            Some(other) => Err(ConfigError::invalid(
+                "COMMAND_QUEUE_POLICY",
+                "queue or reject",
+                other,
+            )),
+        }
+    }
+
+    /// Whether another command is taken while the given number are pending, running or queued
+    fn admits(&self, pending: usize) -> bool {
+        match self {
+            QueuePolicy::Queue(size) => pending <= *size,
+            QueuePolicy::Reject => pending == 0,
+        }
+    }
+}
+
+/// Number of commands handed to the executor which it hasn't finished yet
+#[derive(Debug, Clone, Default)]
+pub struct Pending(Arc<AtomicUsize>);
+
+impl Pending {
+    fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn add(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Called by the executor once it's done with a command
+    pub fn done(&self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Routes every command to where it's handled: emergency stops skip ahead of everything else,
+/// controls interrupt the running cycle, queries are
+/// answered right away from the latest twin state, starts scheduled for later are held by the
+/// scheduler, and everything else is run by the executor one after the other, as the queue policy
+/// allows
+pub struct Dispatcher {
+    commands: UnboundedSender<Queued>,
+    controls: UnboundedSender<Queued>,
+    emergency: UnboundedSender<Queued>,
+    state_rx: watch::Receiver<Value>,
+    acks: Acknowledger,
+    policy: QueuePolicy,
+    pending: Pending,
+    scheduler: Scheduler,
+    snapshots: SnapshotPublisher,
+    /// shared with the acks, which record the keys of the commands once they complete
+    idempotency: Arc<Mutex<IdempotencyStore>>,
+    /// cancelled on a stop or an emergency stop, so the command being run gives up whatever it's
+    /// waiting on
+    running: watch::Receiver<CancellationToken>,
+}
+
+/// Raises emergency stops from the cell itself, e.g. its e-stop button, the way the dispatcher
+/// raises those from the cloud
+#[derive(Clone)]
+pub struct EmergencyTrigger {
+    emergency: UnboundedSender<Queued>,
+    running: watch::Receiver<CancellationToken>,
+}
+
+impl EmergencyTrigger {
+    /// Queue the emergency stop ahead of everything, then cancel the running command. It has no
+    /// id, there's no command to acknowledge
+    pub fn trigger(&self, request: EmergencyStopRequest) {
+        enqueue(&self.emergency, (None, Command::EmergencyStop(request)));
+        self.running.borrow().cancel();
+    }
+}
+
+/// Queues reloads of the settings from the twin itself, e.g. when it sees its settings file change.
+/// They're run by the executor between commands, like a reload-config from the cloud
+#[derive(Clone)]
+pub struct ReloadTrigger {
+    commands: UnboundedSender<Queued>,
+    pending: Pending,
+}
+
+impl ReloadTrigger {
+    /// Queue the reload regardless of the queue policy, it has no id, there's no command to
+    /// acknowledge
+    pub fn trigger(&self) {
+        self.pending.add();
+        let reload = (None, Command::ReloadConfig(ReloadConfigRequest::default()));
+        if !enqueue(&self.commands, reload) {
+            self.pending.done();
+        }
+    }
+}
+
+/// The receiving ends of the dispatcher, owned by the executor
+pub struct Queues {
+    pub commands: UnboundedReceiver<Queued>,
+    pub controls: UnboundedReceiver<Queued>,
+    pub emergency: UnboundedReceiver<Queued>,
+    pub pending: Pending,
+}
+
+impl Dispatcher {
+    pub fn new(
+        state_rx: watch::Receiver<Value>,
+        acks: Acknowledger,
+        policy: QueuePolicy,
+        scheduler: Scheduler,
+        snapshots: SnapshotPublisher,
+        idempotency: Arc<Mutex<IdempotencyStore>>,
+        running: watch::Receiver<CancellationToken>,
+    ) -> (Self, Queues) {
+        let (commands, commands_rx) = unbounded_channel();
+        let (controls, controls_rx) = unbounded_channel();
+        let (emergency, emergency_rx) = unbounded_channel();
+        let pending = Pending::default();
+
+        let dispatcher = Self {
+            commands,
+            controls,
+            emergency,
+            state_rx,
+            acks,
+            policy,
+            pending: pending.clone(),
+            scheduler,
+            snapshots,
+            idempotency,
+            running,
+        };
+        let queues = Queues {
+            commands: commands_rx,
+            controls: controls_rx,
+            emergency: emergency_rx,
+            pending,
+        };
+        (dispatcher, queues)
+    }
+
+    /// Raises emergency stops alongside the dispatcher
+    pub fn emergency_trigger(&self) -> EmergencyTrigger {
+        EmergencyTrigger {
+            emergency: self.emergency.clone(),
+            running: self.running.clone(),
+        }
+    }
+
+    /// Queues reloads of the settings alongside the dispatcher
+    pub fn reload_trigger(&self) -> ReloadTrigger {
+        ReloadTrigger {
+            commands: self.commands.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+
+    /// Accept the command and hand it over, or reject it when the executor is too busy for it. A
+    /// command with an idempotency key that completed before or is still running is acknowledged
+    /// as a duplicate without being run again
+    pub async fn dispatch(&mut self, id: Option<String>, key: Option<String>, command: Command) {
+        if let Some(key) = &key {
+            let duplicate = self.idempotency.lock().await.contains(key);
+            if duplicate {
+                info!("Skipping the duplicate command {key}");
+                self.acks.duplicate(id.as_deref()).await;
+                return;
+            }
+        }
+
+        let scheduled = match &command {
+            Command::Start(StartRequest {
+                start_at: Some(start_at),
+                ..
+            }) if *start_at > Utc::now() => Some(*start_at),
+            _ => None,
+        };
+        // the queue policy applies once the start is due, it's queued regardless then
+        if let Some(start_at) = scheduled {
+            self.remember(id.as_deref(), key.as_deref()).await;
+            self.acks
+                .send(id.as_deref(), AckStatus::Accepted, None)
+                .await;
+
+            let commands = self.commands.clone();
+            let pending = self.pending.clone();
+            self.scheduler.hold(id.clone(), start_at, move || {
+                pending.add();
+                if !enqueue(&commands, (id, command)) {
+                    pending.done();
+                }
+            });
+            return;
+        }
+
+        let queued = !command.is_control()
+            && !matches!(
+                command,
+                Command::Query(_) | Command::QueryState | Command::EmergencyStop(_)
+            );
+        if queued && !self.policy.admits(self.pending.get()) {
+            let error = match self.policy {
+                QueuePolicy::Queue(size) => {
+                    format!("The command queue is full, {size} are waiting")
+                }
+                QueuePolicy::Reject => "Another command is running".to_string(),
+            };
+            warn!("Rejected a command: {error}");
+            self.acks
+                .send(id.as_deref(), AckStatus::Failed, Some(error))
+                .await;
+            return;
+        }
+
+        self.remember(id.as_deref(), key.as_deref()).await;
+        self.acks
+            .send(id.as_deref(), AckStatus::Accepted, None)
+            .await;
+
+        match command {
+            Command::Query(request) => {
+                let id = id.as_deref();
+                // the state is borrowed only while it's read, not across the acks
+                let state = query(&self.state_rx.borrow(), &request);
+                match state {
+                    Ok(state) => self.acks.complete(id, state).await,
+                    Err(e) => {
+                        self.acks
+                            .send(id, AckStatus::Failed, Some(e.to_string()))
+                            .await
+                    }
+                }
+            }
+            Command::QueryState => {
+                let id = id.as_deref();
+                match self.snapshots.publish().await {
+                    Ok(()) => self.acks.send(id, AckStatus::Completed, None).await,
+                    Err(e) => {
+                        self.acks
+                            .send(id, AckStatus::Failed, Some(e.to_string()))
+                            .await
+                    }
+                }
+            }
+            // queued before the running command is cancelled, so a cycle sees what interrupted it
+            Command::EmergencyStop(_) => {
+                enqueue(&self.emergency, (id, command));
+                self.running.borrow().cancel();
+            }
+            Command::Stop(_) => {
+                enqueue(&self.controls, (id, command));
+                self.running.borrow().cancel();
+            }
+            command if command.is_control() => {
+                enqueue(&self.controls, (id, command));
+            }
+            command => {
+                self.pending.add();
+                if !enqueue(&self.commands, (id, command)) {
+                    self.pending.done();
+                }
+            }
+        }
+    }
+
+    /// Hold the key of an accepted command until the acks conclude it, failing to do so is only
+    /// logged since the command is run either way
+    async fn remember(&mut self, id: Option<&str>, key: Option<&str>) {
+        if let Some(key) = key {
+            if let Err(e) = self.idempotency.lock().await.accept(id, key).await {
+                error!("Failed to record the idempotency key {key}: {e}");
+            }
+        }
+    }
+}
+
+/// The latest twin state, or the state of the requested component
+fn query(state: &Value, request: &QueryRequest) -> Result<Value> {
+    match &request.component {
+        None => Ok(state.clone()),
+        Some(component) => state
+            .get(component)
+            .cloned()
+            .ok_or_else(|| eyre!("Unknown component {component}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn queue_policy_limits_pending_commands() {
+        // one command running and two queued
+        assert!(QueuePolicy::Queue(2).admits(2));
+        assert!(!QueuePolicy::Queue(2).admits(3));
+
+        assert!(QueuePolicy::Reject.admits(0));
+        assert!(!QueuePolicy::Reject.admits(1));
+    }
+
+    #[test]
+    fn commands_are_dropped_once_the_executor_stopped() {
+        let (commands, queue) = unbounded_channel();
+        drop(queue);
+        let trigger = ReloadTrigger {
+            commands,
+            pending: Pending::default(),
+        };
+
+        trigger.trigger();
+        assert_eq!(trigger.pending.get(), 0);
+    }
+
+    #[test]
+    fn queries_select_the_component() {
+        let state = json!({ "feeder": { "count": 4 } });
+
+        let all = query(&state, &QueryRequest { component: None }).unwrap();
+        assert_eq!(all, state);
+
+        let feeder = QueryRequest {
+            component: Some("feeder".to_string()),
+        };
+        assert_eq!(query(&state, &feeder).unwrap(), json!({ "count": 4 }));
+
+        let robot = QueryRequest {
+            component: Some("robot".to_string()),
+        };
+        assert!(query(&state, &robot).is_err());
+    }
+}
@@ -0,0 +1,799 @@
+use crate::config::{ConfigError, Settings};
+use crate::envelope::Envelope;
+use crate::manufacturing_components::{
+    ambient, analog, camera, can, conveyor, estop, feeder, limit, modbus, piston, program, quality,
+    robot, scanner, stepper, vibration, ComponentEvent,
+};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use prost::Message;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Types generated from proto/telemetry.proto
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/tvilling.rs"));
+}
+
+/// Wire format of events and state documents, selected with PAYLOAD_ENCODING ("json", "protobuf"
+/// or "cbor"), defaulting to JSON so existing cloud functions keep working. The state reports and
+/// the mirror can use their own, with STATE_PAYLOAD_ENCODING and MIRROR_PAYLOAD_ENCODING
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    Protobuf,
+    /// same documents as JSON, encoded with the serde implementations of the components
+    Cbor,
+}
+
+impl Encoding {
+    pub fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        Self::parse(settings, "PAYLOAD_ENCODING")
+    }
+
+    /// The encoding of a connection read from its own variable, e.g. MIRROR_PAYLOAD_ENCODING, the
+    /// one of PAYLOAD_ENCODING when it's not set
+    pub fn from_setting(settings: &Settings, key: &str) -> Result<Self, ConfigError> {
+        match settings.get(key) {
+            Some(_) => Self::parse(settings, key),
+            None => Self::from_settings(settings),
+        }
+    }
+
+    fn parse(settings: &Settings, key: &str) -> Result<Self, ConfigError> {
+        match settings.get(key) {
+            Some("json") | None => Ok(Self::Json),
+            Some("protobuf") => Ok(Self::Protobuf),
+            Some("cbor") => Ok(Self::Cbor),
+            Some(other) => Err(ConfigError::invalid(key, "json, protobuf or cbor", other)),
+        }
+    }
+
+    /// Name used to mark the format of a payload, None for the default JSON format
+    pub fn marker(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Json => None,
+            Encoding::Protobuf => Some("protobuf"),
+            Encoding::Cbor => Some("cbor"),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Protobuf => "application/x-protobuf",
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    pub fn encode_event(&self, envelope: &Envelope) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(envelope)?),
+            Encoding::Protobuf => Ok(proto::Event::from(envelope).encode_to_vec()),
+            Encoding::Cbor => to_cbor(envelope),
+        }
+    }
+
+    pub fn encode_state(&self, state: &Value) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(state)?),
+            Encoding::Protobuf => Ok(proto::TwinState::from(state).encode_to_vec()),
+            Encoding::Cbor => to_cbor(state),
+        }
+    }
+
+    /// Combines encoded events into one payload, a JSON or CBOR array, or an EventBatch
+    pub fn batch(&self, payloads: Vec<Vec<u8>>) -> Vec<u8> {
+        match self {
+            Encoding::Json => {
+                let mut batch = vec![b'['];
+                for (i, payload) in payloads.into_iter().enumerate() {
+                    if i > 0 {
+                        batch.push(b',');
+                    }
+                    batch.extend(payload);
+                }
+                batch.push(b']');
+                batch
+            }
+            Encoding::Protobuf => {
+                // an EventBatch is encoded as each of its events one after the other, as length
+                // delimited field 1, so there is no need to decode the events again
+                let mut batch = Vec::new();
+                for payload in payloads {
+                    prost::encoding::encode_key(
+                        1,
+                        prost::encoding::WireType::LengthDelimited,
+                        &mut batch,
+                    );
+                    prost::encoding::encode_varint(payload.len() as u64, &mut batch);
+                    batch.extend(payload);
+                }
+                batch
+            }
+            Encoding::Cbor => {
+                let mut batch = cbor_array_header(payloads.len());
+                for payload in payloads {
+                    batch.extend(payload);
+                }
+                batch
+            }
+        }
+    }
+}
+
+fn to_cbor<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut cbor = Vec::new();
+    ciborium::ser::into_writer(value, &mut cbor).map_err(|e| eyre!("{e}"))?;
+    Ok(cbor)
+}
+
+/// Header of a definite length CBOR array, major type 4 followed by the length
+fn cbor_array_header(len: usize) -> Vec<u8> {
+    const ARRAY: u8 = 0x80;
+
+    match len {
+        0..=23 => vec![ARRAY | len as u8],
+        24..=0xff => vec![ARRAY | 24, len as u8],
+        0x100..=0xffff => {
+            let mut header = vec![ARRAY | 25];
+            header.extend((len as u16).to_be_bytes());
+            header
+        }
+        _ => {
+            let mut header = vec![ARRAY | 26];
+            header.extend((len as u32).to_be_bytes());
+            header
+        }
+    }
+}
+
+impl From<robot::RobotPosition> for proto::RobotPosition {
+    fn from(position: robot::RobotPosition) -> Self {
+        match position {
+            robot::RobotPosition::Position1 => Self::Position1,
+            robot::RobotPosition::Position15 => Self::Position15,
+            robot::RobotPosition::Position66 => Self::Position66,
+        }
+    }
+}
+
+impl From<program::ProgramState> for proto::ProgramState {
+    fn from(state: program::ProgramState) -> Self {
+        match state {
+            program::ProgramState::Idle => Self::Idle,
+            program::ProgramState::Running => Self::Running,
+            program::ProgramState::Paused => Self::Paused,
+            program::ProgramState::Stopped => Self::Stopped,
+        }
+    }
+}
+
+impl From<&ComponentEvent> for proto::Event {
+    fn from(event: &ComponentEvent) -> Self {
+        use proto::event::Event as Inner;
+
+        let event = match event {
+            ComponentEvent::Feeder(feeder::Event::MaterialPickedUp { feeder, material }) => {
+                Inner::Feeder(proto::FeederEvent {
+                    kind: proto::feeder_event::Kind::MaterialPickedUp as i32,
+                    feeder: feeder.clone(),
+                    material: material.clone().unwrap_or_default(),
+                    ..Default::default()
+                })
+            }
+            ComponentEvent::Feeder(feeder::Event::Refilled {
+                feeder,
+                added,
+                count,
+            }) => Inner::Feeder(proto::FeederEvent {
+                kind: proto::feeder_event::Kind::Refilled as i32,
+                added: *added,
+                count: *count,
+                feeder: feeder.clone(),
+                ..Default::default()
+            }),
+            ComponentEvent::Feeder(feeder::Event::LowSupply {
+                feeder,
+                count,
+                threshold,
+            }) => Inner::Feeder(proto::FeederEvent {
+                kind: proto::feeder_event::Kind::LowSupply as i32,
+                count: *count,
+                feeder: feeder.clone(),
+                threshold: *threshold,
+                ..Default::default()
+            }),
+            ComponentEvent::Robot(robot::Event::PositionReached { robot, position }) => {
+                Inner::Robot(proto::RobotEvent {
+                    position: proto::RobotPosition::from(*position) as i32,
+                    robot: robot.clone(),
+                    ..Default::default()
+                })
+            }
+            ComponentEvent::Robot(robot::Event::IllegalTransition { robot, from, to }) => {
+                Inner::Robot(proto::RobotEvent {
+                    kind: proto::robot_event::Kind::IllegalTransition as i32,
+                    position: proto::RobotPosition::from(*to) as i32,
+                    from: proto::RobotPosition::from(*from) as i32,
+                    robot: robot.clone(),
+                })
+            }
+            ComponentEvent::Piston(piston::Event::OverPressure {
+                piston,
+                pressure,
+                limit,
+            }) => Inner::Piston(proto::PistonEvent {
+                position: proto::PistonPosition::Steady as i32,
+                kind: proto::piston_event::Kind::OverPressure as i32,
+                piston: piston.clone(),
+                pressure: *pressure,
+                limit: *limit,
+            }),
+            ComponentEvent::Piston(event) => {
+                let position = match event {
+                    piston::Event::Depressed => proto::PistonPosition::Depressed,
+                    piston::Event::Steady | piston::Event::OverPressure { .. } => {
+                        proto::PistonPosition::Steady
+                    }
+                };
+                Inner::Piston(proto::PistonEvent {
+                    position: position as i32,
+                    ..Default::default()
+                })
+            }
+            ComponentEvent::Program(program::Event::EmergencyStop { reason }) => {
+                Inner::Program(proto::ProgramEvent {
+                    kind: proto::program_event::Kind::EmergencyStop as i32,
+                    reason: reason.clone().unwrap_or_default(),
+                    ..Default::default()
+                })
+            }
+            ComponentEvent::Program(program::Event::Stalled {
+                component,
+                waited_ms,
+            }) => Inner::Program(proto::ProgramEvent {
+                kind: proto::program_event::Kind::Stalled as i32,
+                component: component.clone(),
+                waited_ms: *waited_ms,
+                ..Default::default()
+            }),
+            ComponentEvent::Program(program::Event::Transition { from, to }) => {
+                Inner::Program(proto::ProgramEvent {
+                    kind: proto::program_event::Kind::Transition as i32,
+                    from: proto::ProgramState::from(*from) as i32,
+                    to: proto::ProgramState::from(*to) as i32,
+                    ..Default::default()
+                })
+            }
+            ComponentEvent::Program(program::Event::Progress {
+                processed,
+                remaining,
+                estimated_completion,
+            }) => Inner::Program(proto::ProgramEvent {
+                kind: proto::program_event::Kind::Progress as i32,
+                processed: *processed,
+                remaining: *remaining,
+                estimated_completion: estimated_completion
+                    .map(|at| at.to_rfc3339())
+                    .unwrap_or_default(),
+                ..Default::default()
+            }),
+            ComponentEvent::Conveyor(event) => {
+                use proto::conveyor_event::Kind;
+
+                let (kind, speed) = match event {
+                    conveyor::Event::Running { speed } => (Kind::Running, *speed),
+                    conveyor::Event::Stopped => (Kind::Stopped, 0),
+                    conveyor::Event::SpeedChanged { speed } => (Kind::SpeedChanged, *speed),
+                    conveyor::Event::ItemDetected => (Kind::ItemDetected, 0),
+                };
+                Inner::Conveyor(proto::ConveyorEvent {
+                    kind: kind as i32,
+                    speed: speed as u32,
+                })
+            }
+            ComponentEvent::Analog(event) => {
+                use proto::analog_event::Kind;
+
+                Inner::Analog(match event {
+                    analog::Event::Sample { input, value, unit } => proto::AnalogEvent {
+                        kind: Kind::Sample as i32,
+                        input: input.clone(),
+                        value: *value,
+                        unit: unit.clone(),
+                        ..Default::default()
+                    },
+                    analog::Event::OutOfRange {
+                        input,
+                        value,
+                        threshold,
+                        bound,
+                    } => proto::AnalogEvent {
+                        kind: Kind::OutOfRange as i32,
+                        input: input.clone(),
+                        value: *value,
+                        threshold: *threshold,
+                        above: *bound == analog::Bound::Above,
+                        ..Default::default()
+                    },
+                })
+            }
+            ComponentEvent::Stepper(event) => {
+                use proto::stepper_event::Kind;
+
+                let (kind, position) = match event {
+                    stepper::Event::Moved { position } => (Kind::Moved, *position),
+                    stepper::Event::Homed => (Kind::Homed, 0),
+                };
+                Inner::Stepper(proto::StepperEvent {
+                    kind: kind as i32,
+                    position,
+                })
+            }
+            ComponentEvent::Limit(event) => {
+                let (switch, engaged) = match event {
+                    limit::Event::Engaged { switch } => (switch, true),
+                    limit::Event::Released { switch } => (switch, false),
+                };
+                Inner::Limit(proto::LimitEvent {
+                    switch: switch.clone(),
+                    engaged,
+                })
+            }
+            ComponentEvent::EStop(event) => {
+                use proto::e_stop_event::Kind;
+
+                let kind = match event {
+                    estop::Event::Asserted => Kind::Asserted,
+                    estop::Event::Released => Kind::Released,
+                    estop::Event::Cleared => Kind::Cleared,
+                };
+                Inner::Estop(proto::EStopEvent { kind: kind as i32 })
+            }
+            ComponentEvent::Vibration(event) => {
+                use proto::vibration_event::{Kind, Metric};
+
+                Inner::Vibration(match event {
+                    vibration::Event::Measured { sensor, rms, peak } => proto::VibrationEvent {
+                        kind: Kind::Measured as i32,
+                        sensor: sensor.clone(),
+                        rms: *rms,
+                        peak: *peak,
+                        ..Default::default()
+                    },
+                    vibration::Event::Anomaly {
+                        sensor,
+                        metric,
+                        value,
+                        threshold,
+                    } => {
+                        // the value goes in the field of the metric that rose above its threshold
+                        let (metric, rms, peak) = match metric {
+                            vibration::Metric::Rms => (Metric::Rms, *value, 0.0),
+                            vibration::Metric::Peak => (Metric::Peak, 0.0, *value),
+                        };
+                        proto::VibrationEvent {
+                            kind: Kind::Anomaly as i32,
+                            sensor: sensor.clone(),
+                            rms,
+                            peak,
+                            metric: metric as i32,
+                            threshold: *threshold,
+                        }
+                    }
+                })
+            }
+            ComponentEvent::Scanner(scanner::Event::Scanned { scanner, code }) => {
+                Inner::Scanner(proto::ScannerEvent {
+                    scanner: scanner.clone(),
+                    code: code.clone(),
+                })
+            }
+            ComponentEvent::Camera(camera::Event::Snapshot {
+                trigger,
+                cycle_id,
+                url,
+            }) => Inner::Camera(proto::CameraEvent {
+                trigger: trigger.as_str().to_string(),
+                cycle_id: cycle_id.clone(),
+                url: url.clone(),
+            }),
+            ComponentEvent::Modbus(modbus::Event::Changed {
+                device,
+                point,
+                value,
+            }) => Inner::Modbus(proto::ModbusEvent {
+                device: device.clone(),
+                point: point.clone(),
+                value: *value as u32,
+            }),
+            ComponentEvent::Can(can::Event::Changed {
+                device,
+                signal,
+                value,
+            }) => Inner::Can(proto::CanEvent {
+                device: device.clone(),
+                signal: signal.clone(),
+                value: *value,
+            }),
+            ComponentEvent::Quality(event) => {
+                use proto::quality_event::Kind;
+
+                Inner::Quality(match event {
+                    quality::Event::Inspected { station, passed } => proto::QualityEvent {
+                        kind: Kind::Inspected as i32,
+                        station: station.clone(),
+                        passed: *passed,
+                        ..Default::default()
+                    },
+                    quality::Event::RejectRateHigh {
+                        station,
+                        rate,
+                        threshold,
+                    } => proto::QualityEvent {
+                        kind: Kind::RejectRateHigh as i32,
+                        station: station.clone(),
+                        rate: *rate,
+                        threshold: *threshold,
+                        ..Default::default()
+                    },
+                })
+            }
+            ComponentEvent::Ambient(ambient::Event::Reading(reading)) => {
+                Inner::Ambient(proto::AmbientEvent {
+                    temperature: reading.temperature,
+                    humidity: reading.humidity,
+                })
+            }
+        };
+
+        Self {
+            event: Some(event),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&Envelope> for proto::Event {
+    fn from(envelope: &Envelope) -> Self {
+        Self {
+            id: envelope.id.clone(),
+            sequence: envelope.sequence,
+            material_id: envelope.material_id.clone().unwrap_or_default(),
+            ..Self::from(&envelope.event)
+        }
+    }
+}
+
+fn text(value: &Value, key: &str) -> String {
+    value[key].as_str().unwrap_or_default().to_string()
+}
+
+/// The twin state is assembled as JSON from the component serializers, so the protobuf state is
+/// converted from that document rather than from the components themselves
+impl From<&Value> for proto::TwinState {
+    fn from(state: &Value) -> Self {
+        let feeder_state = |feeder: &Value| proto::FeederState {
+            name: text(feeder, "name"),
+            count: feeder["count"].as_u64().unwrap_or_default() as u32,
+            update_timestamp: text(feeder, "updateTimestamp"),
+        };
+        let feeder = state.get("feeder").map(feeder_state);
+        let feeder_b = state.get("feeder_b").map(feeder_state);
+
+        let robot_state = |robot: &Value| {
+            let position = match robot["position"].as_str() {
+                Some("position 15") => proto::RobotPosition::Position15,
+                Some("position 66") => proto::RobotPosition::Position66,
+                _ => proto::RobotPosition::Position1,
+            };
+            proto::RobotState {
+                name: text(robot, "name"),
+                position: position as i32,
+                update_timestamp: text(robot, "updateTimestamp"),
+            }
+        };
+        let robot = state.get("robot").map(robot_state);
+        let robots = state["robots"]
+            .as_object()
+            .map(|robots| {
+                robots
+                    .iter()
+                    .map(|(name, robot)| (name.clone(), robot_state(robot)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let interlock = state["interlock"]
+            .as_object()
+            .map(|positions| {
+                positions
+                    .iter()
+                    .map(|(position, holder)| {
+                        (
+                            position.clone(),
+                            holder.as_str().unwrap_or_default().to_string(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let piston = state.get("piston").map(|piston| {
+            let position = match piston["state"].as_str() {
+                Some("depressed") => proto::PistonPosition::Depressed,
+                _ => proto::PistonPosition::Steady,
+            };
+            proto::PistonState {
+                name: text(piston, "name"),
+                position: position as i32,
+                update_timestamp: text(piston, "updateTimestamp"),
+                pressure: piston["pressure"].as_f64().unwrap_or_default() as f32,
+            }
+        });
+
+        let conveyor = state.get("conveyor").map(|conveyor| proto::ConveyorState {
+            name: text(conveyor, "name"),
+            running: conveyor["state"].as_str() == Some("running"),
+            speed: conveyor["speed"].as_u64().unwrap_or_default() as u32,
+            update_timestamp: text(conveyor, "updateTimestamp"),
+        });
+
+        let ambient = state.get("ambient").map(|ambient| proto::AmbientState {
+            name: text(ambient, "name"),
+            temperature: ambient["temperature"].as_f64().unwrap_or_default() as f32,
+            humidity: ambient["humidity"].as_f64().unwrap_or_default() as f32,
+            update_timestamp: text(ambient, "updateTimestamp"),
+        });
+
+        let stepper = state.get("stepper").map(|stepper| proto::StepperState {
+            name: text(stepper, "name"),
+            position: stepper["position"].as_i64().unwrap_or_default(),
+            homed: stepper["homed"].as_bool().unwrap_or_default(),
+            update_timestamp: text(stepper, "updateTimestamp"),
+        });
+
+        let analog = state["analog"]
+            .as_object()
+            .map(|inputs| {
+                inputs
+                    .iter()
+                    .map(|(name, input)| {
+                        let state = proto::AnalogState {
+                            name: text(input, "name"),
+                            value: input["value"].as_f64().unwrap_or_default() as f32,
+                            unit: text(input, "unit"),
+                            update_timestamp: text(input, "updateTimestamp"),
+                        };
+                        (name.clone(), state)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let estop = state.get("estop").map(|estop| proto::EStopState {
+            latched: estop["latched"].as_bool().unwrap_or_default(),
+            asserted: estop["asserted"].as_bool().unwrap_or_default(),
+        });
+
+        let limit = state["limit"]
+            .as_object()
+            .map(|switches| {
+                switches
+                    .iter()
+                    .map(|(name, switch)| {
+                        let state = proto::LimitState {
+                            name: text(switch, "name"),
+                            engaged: switch["engaged"].as_bool().unwrap_or_default(),
+                            update_timestamp: text(switch, "updateTimestamp"),
+                        };
+                        (name.clone(), state)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let vibration = state
+            .get("vibration")
+            .map(|vibration| proto::VibrationState {
+                name: text(vibration, "name"),
+                rms: vibration["rms"].as_f64().unwrap_or_default() as f32,
+                peak: vibration["peak"].as_f64().unwrap_or_default() as f32,
+                update_timestamp: text(vibration, "updateTimestamp"),
+            });
+
+        let scanner = state.get("scanner").map(|scanner| proto::ScannerState {
+            name: text(scanner, "name"),
+            last_code: text(scanner, "lastCode"),
+            update_timestamp: text(scanner, "updateTimestamp"),
+        });
+
+        let modbus = state["modbus"]
+            .as_object()
+            .map(|devices| {
+                devices
+                    .iter()
+                    .map(|(name, device)| {
+                        let values = device["values"]
+                            .as_object()
+                            .map(|values| {
+                                values
+                                    .iter()
+                                    .map(|(point, value)| {
+                                        (point.clone(), value.as_u64().unwrap_or_default() as u32)
+                                    })
+                                    .collect()
+                            })
+                            .unwrap_or_default();
+                        let state = proto::ModbusState {
+                            name: text(device, "name"),
+                            values,
+                            update_timestamp: text(device, "updateTimestamp"),
+                        };
+                        (name.clone(), state)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let can = state["can"]
+            .as_object()
+            .map(|devices| {
+                devices
+                    .iter()
+                    .map(|(name, device)| {
+                        let values = device["values"]
+                            .as_object()
+                            .map(|values| {
+                                values
+                                    .iter()
+                                    .map(|(signal, value)| {
+                                        (signal.clone(), value.as_i64().unwrap_or_default())
+                                    })
+                                    .collect()
+                            })
+                            .unwrap_or_default();
+                        let state = proto::CanState {
+                            name: text(device, "name"),
+                            values,
+                            update_timestamp: text(device, "updateTimestamp"),
+                        };
+                        (name.clone(), state)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let quality = state.get("quality").map(|quality| proto::QualityState {
+            name: text(quality, "name"),
+            inspected: quality["inspected"].as_u64().unwrap_or_default(),
+            rejected: quality["rejected"].as_u64().unwrap_or_default(),
+            reject_rate: quality["rejectRate"].as_f64().unwrap_or_default() as f32,
+            update_timestamp: text(quality, "updateTimestamp"),
+        });
+
+        let counters = Some(proto::ProductionCounters {
+            produced: state["counters"]["produced"].as_u64().unwrap_or_default(),
+            rejected: state["counters"]["rejected"].as_u64().unwrap_or_default(),
+        });
+
+        Self {
+            feeder,
+            robot,
+            piston,
+            conveyor,
+            feeder_b,
+            inventory: state["inventory"]["total"].as_u64().unwrap_or_default() as u32,
+            ambient,
+            analog,
+            stepper,
+            limit,
+            estop,
+            vibration,
+            scanner,
+            modbus,
+            can,
+            quality,
+            counters,
+            robots,
+            interlock,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn envelope(sequence: u64, event: impl Into<ComponentEvent>) -> Envelope {
+        Envelope {
+            id: Uuid::new_v4().to_string(),
+            sequence,
+            material_id: None,
+            event: event.into(),
+        }
+    }
+
+    fn picked_up() -> feeder::Event {
+        feeder::Event::MaterialPickedUp {
+            feeder: "Material feeder".to_string(),
+            material: Some("MAT-0001".to_string()),
+        }
+    }
+
+    #[test]
+    fn connections_fall_back_to_the_payload_encoding() {
+        let settings = Settings::new([
+            ("PAYLOAD_ENCODING", "protobuf"),
+            ("MIRROR_PAYLOAD_ENCODING", "json"),
+        ]);
+        let encoding = |key| Encoding::from_setting(&settings, key).unwrap();
+        assert_eq!(encoding("MIRROR_PAYLOAD_ENCODING"), Encoding::Json);
+        assert_eq!(encoding("STATE_PAYLOAD_ENCODING"), Encoding::Protobuf);
+    }
+
+    #[test]
+    fn protobuf_batches_decode_as_event_batch() {
+        let events = [
+            envelope(0, picked_up()),
+            envelope(
+                1,
+                robot::Event::PositionReached {
+                    robot: "Robot".to_string(),
+                    position: robot::RobotPosition::Position15,
+                },
+            ),
+        ];
+        let payloads = events
+            .iter()
+            .map(|event| Encoding::Protobuf.encode_event(event).unwrap())
+            .collect();
+
+        let batch = Encoding::Protobuf.batch(payloads);
+        let batch = proto::EventBatch::decode(batch.as_slice()).unwrap();
+
+        assert_eq!(batch.events.len(), 2);
+        assert_eq!(batch.events[1], proto::Event::from(&events[1]));
+        assert_eq!(batch.events[1].sequence, 1);
+    }
+
+    #[test]
+    fn json_batches_are_arrays() {
+        let batch = Encoding::Json.batch(vec![b"1".to_vec(), b"2".to_vec()]);
+        assert_eq!(batch, b"[1,2]");
+    }
+
+    #[test]
+    fn cbor_batches_decode_as_arrays() {
+        let events: Vec<_> = (0..30)
+            .map(|sequence| envelope(sequence, picked_up()))
+            .collect();
+        let payloads = events
+            .iter()
+            .map(|event| Encoding::Cbor.encode_event(event).unwrap())
+            .collect();
+
+        let batch = Encoding::Cbor.batch(payloads);
+        let batch: Value = ciborium::de::from_reader(batch.as_slice()).unwrap();
+
+        assert_eq!(batch.as_array().unwrap().len(), 30);
+        assert_eq!(batch[0], serde_json::to_value(&events[0]).unwrap());
+    }
+
+    #[test]
+    fn twin_state_converts_to_protobuf() {
+        let state = json!({
+            "feeder": { "name": "Material feeder", "count": 4 },
+            "feeder_b": { "name": "Feeder B", "count": 2 },
+            "inventory": { "total": 6 },
+        });
+
+        let state = proto::TwinState::from(&state);
+        assert_eq!(state.feeder.unwrap().count, 4);
+        assert_eq!(state.feeder_b.unwrap().count, 2);
+        assert_eq!(state.inventory, 6);
+        assert!(state.robot.is_none());
+    }
+}
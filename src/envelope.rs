@@ -0,0 +1,138 @@
+use crate::manufacturing_components::ComponentEvent;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::warn;
+use uuid::Uuid;
+
+/// A component event with the identity it's published with, so the cloud pipeline can drop the
+/// duplicates published again after a reconnect and detect gaps in the sequence
+#[derive(Debug, Serialize)]
+pub struct Envelope {
+    /// random UUID, kept as a string so every encoding carries it the same way
+    pub id: String,
+    /// increases by one with every event since the twin started
+    pub sequence: u64,
+    /// the material in the cell when the event happened, correlating the events of its journey
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub material_id: Option<String>,
+    #[serde(flatten)]
+    pub event: ComponentEvent,
+}
+
+/// Sending half of the events channel, stamping every event with a unique id, the next sequence
+/// number and the material in the cell, if any
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    tx: UnboundedSender<Envelope>,
+    sequence: Arc<AtomicU64>,
+    /// shared by the clones, so the events of every component are correlated to the material
+    material_id: Arc<Mutex<Option<String>>>,
+}
+
+impl EventSender {
+    /// A material was picked up, the events until it's done with carry the unique id returned
+    pub fn begin_material(&self) -> String {
+        let id = Uuid::new_v4().to_string();
+        *self.material_id.lock().unwrap() = Some(id.clone());
+        id
+    }
+
+    /// The material picked up last is done with, e.g. dropped off
+    pub fn end_material(&self) {
+        self.material_id.lock().unwrap().take();
+    }
+
+    /// Queue the event for the publisher. Once the publisher has stopped, on shutdown, the event is
+    /// logged and dropped, there's nothing left to publish it
+    pub fn send(&self, event: impl Into<ComponentEvent>) {
+        let envelope = Envelope {
+            id: Uuid::new_v4().to_string(),
+            sequence: self.sequence.fetch_add(1, Ordering::SeqCst),
+            material_id: self.material_id.lock().unwrap().clone(),
+            event: event.into(),
+        };
+        if let Err(SendError(envelope)) = self.tx.send(envelope) {
+            warn!(
+                "Event publisher has stopped, dropped event {}: {:?}",
+                envelope.sequence, envelope.event
+            );
+        }
+    }
+}
+
+pub fn channel() -> (EventSender, UnboundedReceiver<Envelope>) {
+    let (tx, rx) = unbounded_channel();
+    let sender = EventSender {
+        tx,
+        sequence: Arc::new(AtomicU64::new(0)),
+        material_id: Arc::new(Mutex::new(None)),
+    };
+    (sender, rx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manufacturing_components::feeder;
+    use crate::manufacturing_components::robot::{self, RobotPosition};
+
+    #[test]
+    fn events_are_stamped_in_order() {
+        let (tx, mut rx) = channel();
+        let other_tx = tx.clone();
+
+        let picked_up = || feeder::Event::MaterialPickedUp {
+            feeder: "Material feeder".to_string(),
+            material: None,
+        };
+        tx.send(picked_up());
+        other_tx.send(picked_up());
+
+        let first = rx.try_recv().unwrap();
+        let second = rx.try_recv().unwrap();
+        assert_eq!((first.sequence, second.sequence), (0, 1));
+        assert_ne!(first.id, second.id);
+
+        let json = serde_json::to_value(&first).unwrap();
+        assert_eq!(json["component"], "feeder");
+        assert_eq!(json["sequence"], 0);
+    }
+
+    #[test]
+    fn events_are_correlated_to_the_material() {
+        let (tx, mut rx) = channel();
+        let robot_tx = tx.clone();
+
+        let material_id = tx.begin_material();
+        let reached = |position| robot::Event::PositionReached {
+            robot: "Robot".to_string(),
+            position,
+        };
+        robot_tx.send(reached(RobotPosition::Position15));
+        tx.end_material();
+        robot_tx.send(reached(RobotPosition::Position1));
+
+        assert_eq!(rx.try_recv().unwrap().material_id, Some(material_id));
+        let idle = rx.try_recv().unwrap();
+        assert_eq!(idle.material_id, None);
+        assert!(serde_json::to_value(&idle)
+            .unwrap()
+            .get("material_id")
+            .is_none());
+    }
+
+    #[test]
+    fn events_sent_after_the_publisher_stopped_are_dropped() {
+        let (tx, rx) = channel();
+        drop(rx);
+
+        tx.send(feeder::Event::Refilled {
+            feeder: "Material feeder".to_string(),
+            added: 10,
+            count: 10,
+        });
+    }
+}
@@ -0,0 +1,140 @@
+use crate::backend::CloudError;
+use crate::config::Settings;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+
+const PRIMARY_HOST: &str = "mqtt.googleapis.com";
+/// Long term support domain, served with its own minimal root CA set
+const LTS_HOST: &str = "mqtt.2030.ltsapis.goog";
+const DEFAULT_FAILOVER_AFTER: u32 = 2;
+
+/// An MQTT bridge endpoint, with the root CAs to verify it. The system trust store is used when no
+/// CA is configured
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub uri: String,
+    pub ca_certificate: Option<String>,
+}
+
+/// The MQTT bridge endpoints the twin connects to, in order of preference.
+///
+/// mqtt.googleapis.com uses the roots in CA_CERTIFICATE and mqtt.2030.ltsapis.goog the ones in
+/// LTS_CA_CERTIFICATE. With GCP_PORT_443 set, each host is also tried on port 443 for networks
+/// blocking port 8883. After ENDPOINT_FAILOVER_AFTER connection failures in a row the next endpoint
+/// is used, cycling back to the first one after the last
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    endpoints: Arc<Vec<Endpoint>>,
+    current: Arc<AtomicUsize>,
+    failures: Arc<AtomicU32>,
+    failover_after: u32,
+}
+
+impl Endpoints {
+    pub fn from_settings(settings: &Settings) -> Result<Self, CloudError> {
+        let port_443 = settings.get("GCP_PORT_443").is_some();
+        let mut endpoints = Vec::new();
+
+        for (host, ca_key) in [
+            (PRIMARY_HOST, "CA_CERTIFICATE"),
+            (LTS_HOST, "LTS_CA_CERTIFICATE"),
+        ] {
+            let ca_certificate = settings.get(ca_key).map(str::to_string);
+            endpoints.push(Endpoint {
+                uri: format!("ssl://{host}:8883"),
+                ca_certificate: ca_certificate.clone(),
+            });
+            if port_443 {
+                endpoints.push(Endpoint {
+                    uri: format!("ssl://{host}:443"),
+                    ca_certificate,
+                });
+            }
+        }
+
+        let failover_after = match settings.get("ENDPOINT_FAILOVER_AFTER") {
+            Some(failures) => failures.parse().map_err(|_| CloudError::Invalid {
+                variable: "ENDPOINT_FAILOVER_AFTER",
+                expected: "unsigned integer",
+            })?,
+            None => DEFAULT_FAILOVER_AFTER,
+        };
+
+        Ok(Self::new(endpoints, failover_after))
+    }
+
+    pub fn new(endpoints: Vec<Endpoint>, failover_after: u32) -> Self {
+        assert!(!endpoints.is_empty(), "At least one endpoint is required");
+
+        Self {
+            endpoints: Arc::new(endpoints),
+            current: Arc::new(AtomicUsize::new(0)),
+            failures: Arc::new(AtomicU32::new(0)),
+            failover_after: failover_after.max(1),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.is_empty()
+    }
+
+    pub fn current(&self) -> &Endpoint {
+        &self.endpoints[self.current.load(Ordering::SeqCst) % self.endpoints.len()]
+    }
+
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+    }
+
+    /// Counts a failed connection, switching to the next endpoint once there have been too many
+    pub fn record_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= self.failover_after {
+            self.failover();
+        }
+    }
+
+    /// Switch to the next endpoint right away
+    pub fn failover(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+        let next = (self.current.load(Ordering::SeqCst) + 1) % self.endpoints.len();
+        self.current.store(next, Ordering::SeqCst);
+        warn!("Failing over to {}", self.endpoints[next].uri);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn endpoint(uri: &str) -> Endpoint {
+        Endpoint {
+            uri: uri.to_string(),
+            ca_certificate: None,
+        }
+    }
+
+    #[test]
+    fn endpoints_cycle_after_repeated_failures() {
+        let endpoints = Endpoints::new(vec![endpoint("primary"), endpoint("lts")], 2);
+        let shared = endpoints.clone();
+
+        shared.record_failure();
+        assert_eq!(endpoints.current().uri, "primary");
+        shared.record_failure();
+        assert_eq!(endpoints.current().uri, "lts");
+
+        shared.record_failure();
+        shared.record_success();
+        shared.record_failure();
+        assert_eq!(endpoints.current().uri, "lts");
+
+        shared.failover();
+        assert_eq!(endpoints.current().uri, "primary");
+    }
+}
@@ -0,0 +1,119 @@
+use super::topic::{InvalidLevel, Topic};
+use crate::config::Settings;
+use crate::transport::{Message, MqttTransport, QOS_1};
+use async_trait::async_trait;
+use color_eyre::Result;
+use std::collections::HashMap;
+use tracing::info;
+
+/// Components that are represented by their own logical device when running as a gateway
+const PROXIED_COMPONENTS: [&str; 4] = ["feeder", "robot", "piston", "conveyor"];
+
+/// A Google IoT gateway, the process connects as the gateway device and proxies a logical device
+/// per component through the same connection.
+///
+/// The proxied devices must be bound to the gateway in the registry, and the gateway is expected to
+/// use association only authentication, so attaching doesn't require a JWT per device
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    gateway_id: String,
+    devices: HashMap<&'static str, String>,
+}
+
+impl Gateway {
+    /// Returns the gateway configuration if GATEWAY_MODE is set. The device of each component is
+    /// read from <COMPONENT>_DEVICE_ID, defaulting to <gateway id>-<component>
+    pub fn from_settings(settings: &Settings, gateway_id: &str) -> Option<Self> {
+        settings.get("GATEWAY_MODE")?;
+
+        let devices = PROXIED_COMPONENTS
+            .into_iter()
+            .map(|component| {
+                let device_id =
+                    match settings.get(&format!("{}_DEVICE_ID", component.to_uppercase())) {
+                        Some(device_id) => device_id.to_string(),
+                        None => format!("{gateway_id}-{component}"),
+                    };
+                (component, device_id)
+            })
+            .collect();
+
+        Some(Self::new(gateway_id, devices))
+    }
+
+    pub fn new(gateway_id: impl Into<String>, devices: HashMap<&'static str, String>) -> Self {
+        Self {
+            gateway_id: gateway_id.into(),
+            devices,
+        }
+    }
+
+    /// Device id of the logical device representing the component
+    pub fn device_id(&self, component: &str) -> Option<&str> {
+        self.devices.get(component).map(String::as_str)
+    }
+
+    pub fn device_ids(&self) -> impl Iterator<Item = &str> {
+        self.devices.values().map(String::as_str)
+    }
+
+    /// Topic the events of a component are published to, components without their own device fall
+    /// back to the gateway's subfolder
+    pub fn event_topic(&self, component: &str) -> Result<String, InvalidLevel> {
+        match self.device_id(component) {
+            Some(device_id) => Ok(Topic::device(device_id).events()),
+            None => Topic::device(&self.gateway_id).event(component),
+        }
+    }
+
+    /// Google IoT reports errors with the attached devices, e.g. a device not bound to the gateway,
+    /// on this topic
+    pub fn errors_topic(&self) -> String {
+        Topic::device(&self.gateway_id).errors()
+    }
+}
+
+#[async_trait]
+pub trait GatewayControl {
+    async fn attach_device(&self, device_id: &str) -> Result<()>;
+    async fn detach_device(&self, device_id: &str) -> Result<()>;
+}
+
+#[async_trait]
+impl<T: MqttTransport + ?Sized> GatewayControl for T {
+    async fn attach_device(&self, device_id: &str) -> Result<()> {
+        // an empty authorization is accepted for association only gateways
+        let msg = Message::new(Topic::device(device_id).attach(), "{}", QOS_1);
+        self.publish(msg).await?;
+        info!("Attached {device_id} to the gateway");
+        Ok(())
+    }
+
+    async fn detach_device(&self, device_id: &str) -> Result<()> {
+        let msg = Message::new(Topic::device(device_id).detach(), "{}", QOS_1);
+        self.publish(msg).await?;
+        info!("Detached {device_id} from the gateway");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn events_are_routed_to_proxied_devices() {
+        let devices = HashMap::from([("feeder", "pi-feeder".to_string())]);
+        let gateway = Gateway::new("pi", devices);
+
+        assert_eq!(
+            gateway.event_topic("feeder").unwrap(),
+            "/devices/pi-feeder/events"
+        );
+        assert_eq!(
+            gateway.event_topic("robot").unwrap(),
+            "/devices/pi/events/robot"
+        );
+        assert_eq!(gateway.errors_topic(), "/devices/pi/errors");
+    }
+}
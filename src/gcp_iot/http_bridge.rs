@@ -0,0 +1,66 @@
+use super::new_password_jwt;
+use crate::backend::{required, CloudError};
+use crate::config::Settings;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde_json::json;
+
+const BRIDGE_URL: &str = "https://cloudiotdevice.googleapis.com/v1";
+
+/// Publishes events through the Cloud IoT Core HTTP bridge, for plants whose firewall blocks MQTT
+/// egress. The bridge authenticates with the same JWT as the MQTT connection
+#[derive(Debug, Clone)]
+pub struct HttpBridge {
+    client: reqwest::Client,
+    /// the key signing the JWT is read from them
+    settings: Settings,
+    /// the audience of the JWT
+    project_id: String,
+    device_path: String,
+}
+
+impl HttpBridge {
+    pub fn from_settings(settings: &Settings) -> Result<Self, CloudError> {
+        let project_id = required(settings, "PROJECT_ID")?;
+        let device_id = required(settings, "DEVICE_ID")?;
+        let registry_id = required(settings, "REGISTRY_ID")?;
+        let region = required(settings, "REGION")?;
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            settings: settings.clone(),
+            device_path: format!(
+                "projects/{project_id}/locations/{region}/registries/{registry_id}/devices/{device_id}"
+            ),
+            project_id,
+        })
+    }
+
+    /// Publish a telemetry event to the given subfolder, the same as publishing on
+    /// /devices/{device_id}/events/{sub_folder} over MQTT
+    pub async fn publish_event(&self, sub_folder: &str, payload: &[u8]) -> Result<()> {
+        let jwt = new_password_jwt(&self.settings, &self.project_id).await?;
+        let body = json!({
+            "binary_data": base64::encode(payload),
+            "sub_folder": sub_folder,
+        });
+
+        let response = self
+            .client
+            .post(format!("{BRIDGE_URL}/{}:publishEvent", self.device_path))
+            .bearer_auth(jwt)
+            .json(&body)
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            let status = response.status();
+            let message = response.text().await.unwrap_or_default();
+            Err(eyre!(
+                "HTTP bridge rejected the event with {status}: {message}"
+            ))
+        }
+    }
+}
@@ -1,10 +1,455 @@
-use serde::Deserialize;
+use crate::backend::CloudError;
+use crate::config::Settings;
+use crate::gcp_iot::schema::{self, Invalid};
+use crate::manufacturing_components::robot::RobotPosition;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Version of the config payload this twin understands, payloads without a version are version 1
+pub const CONFIG_VERSION: u64 = 1;
+
+/// Latest version of the message envelope this twin speaks
+pub const SCHEMA_VERSION: u32 = 2;
+/// Version of the messages exchanged without an envelope, by cloud functions predating it
+pub const LEGACY_SCHEMA_VERSION: u32 = 1;
+
+/// Version the messages to the cloud are sent with, agreed on from the messages the cloud sends.
+/// Zero until the cloud sent one, MESSAGE_SCHEMA_VERSION applies until then
+static NEGOTIATED_VERSION: AtomicU32 = AtomicU32::new(0);
+/// Version the messages to the cloud are sent with until one was negotiated, see configure_version
+static CONFIGURED_VERSION: AtomicU32 = AtomicU32::new(LEGACY_SCHEMA_VERSION);
+
+/// Every message exchanged with the cloud from schema version 2 on, inbound and outbound
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionedMessage<T> {
+    pub schema_version: u32,
+    /// the device the message is from, or addressed to
+    pub device_id: String,
+    pub payload: T,
+}
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+    Malformed(serde_json::Error),
+    UnsupportedVersion(u32),
+}
+
+impl Display for EnvelopeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvelopeError::Malformed(e) => write!(f, "Error: Malformed message envelope, {e}"),
+            EnvelopeError::UnsupportedVersion(version) => write!(
+                f,
+                "Error: Unsupported schema version {version}, expected {LEGACY_SCHEMA_VERSION} to {SCHEMA_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+/// Unwrap a message from the cloud, returning the schema version it was sent with and its payload.
+/// Messages without an envelope are passed on as they are, as version 1. An envelope without a
+/// payload, e.g. for a stop, gives an empty payload
+pub fn open(message: &str) -> Result<(u32, String), EnvelopeError> {
+    let message_value: Value = match serde_json::from_str(message) {
+        Ok(value @ Value::Object(_)) => value,
+        // empty and malformed payloads are left to the routing
+        _ => return Ok((LEGACY_SCHEMA_VERSION, message.to_string())),
+    };
+    if message_value.get("schema_version").is_none() {
+        return Ok((LEGACY_SCHEMA_VERSION, message.to_string()));
+    }
+
+    let envelope: VersionedMessage<Option<Value>> =
+        serde_json::from_value(message_value).map_err(EnvelopeError::Malformed)?;
+    if !(LEGACY_SCHEMA_VERSION..=SCHEMA_VERSION).contains(&envelope.schema_version) {
+        return Err(EnvelopeError::UnsupportedVersion(envelope.schema_version));
+    }
+
+    let payload = match envelope.payload {
+        Some(payload) => payload.to_string(),
+        None => String::new(),
+    };
+    Ok((envelope.schema_version, payload))
+}
+
+/// Agree on the version of the messages to the cloud from the version of a message it sent: the
+/// cloud is answered in the version it speaks, so older cloud functions keep getting the messages
+/// without an envelope
+pub fn negotiate(inbound_version: u32) {
+    NEGOTIATED_VERSION.store(inbound_version.min(SCHEMA_VERSION), Ordering::SeqCst);
+}
+
+/// Version the messages to the cloud are sent with
+pub fn negotiated_version() -> u32 {
+    match NEGOTIATED_VERSION.load(Ordering::SeqCst) {
+        0 => CONFIGURED_VERSION.load(Ordering::SeqCst),
+        version => version,
+    }
+}
+
+/// Read MESSAGE_SCHEMA_VERSION, the version used before the cloud sent anything. Version 1 by
+/// default, older cloud functions can't read envelopes
+pub fn configure_version(settings: &Settings) -> Result<(), CloudError> {
+    let invalid = || CloudError::Invalid {
+        variable: "MESSAGE_SCHEMA_VERSION",
+        expected: "schema version 1 or 2",
+    };
+    let version = match settings.get("MESSAGE_SCHEMA_VERSION") {
+        Some(version) => version.parse().map_err(|_| invalid())?,
+        None => LEGACY_SCHEMA_VERSION,
+    };
+
+    if !(LEGACY_SCHEMA_VERSION..=SCHEMA_VERSION).contains(&version) {
+        return Err(invalid());
+    }
+    CONFIGURED_VERSION.store(version, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Encode a JSON message to the cloud in the negotiated version, in an envelope from the device
+/// from version 2 on
+pub fn seal<T: Serialize>(device_id: &str, payload: &T) -> serde_json::Result<Vec<u8>> {
+    seal_as(negotiated_version(), device_id, payload)
+}
+
+fn seal_as<T: Serialize>(
+    version: u32,
+    device_id: &str,
+    payload: &T,
+) -> serde_json::Result<Vec<u8>> {
+    if version <= LEGACY_SCHEMA_VERSION {
+        return serde_json::to_vec(payload);
+    }
+
+    serde_json::to_vec(&VersionedMessage {
+        schema_version: version,
+        device_id: device_id.to_string(),
+        payload,
+    })
+}
 
 #[derive(Debug, Deserialize)]
 pub struct StartRequest {
     pub count: u32,
+    /// RFC3339 time to hold the start until, e.g. the start of a shift
+    pub start_at: Option<DateTime<Utc>>,
+    /// name of the program to run, the simplified scenario 2 when left out
+    pub scenario: Option<String>,
+    /// parameters of the program, e.g. the line controlling it
+    #[serde(default)]
+    pub parameters: Value,
+}
+
+/// Halts the running program, the payload is optional
+#[derive(Debug, Default, Deserialize)]
+pub struct StopRequest {
+    /// logged with the stop, e.g. the operator stopping the cell
+    pub reason: Option<String>,
+}
+
+/// Drives every output to its safe value right away, ahead of any queued command
+#[derive(Debug, Default, Deserialize)]
+pub struct EmergencyStopRequest {
+    /// published with the e-stop event
+    pub reason: Option<String>,
+}
+
+/// Holds the running cycle, e.g. for a tool change, the payload is optional
+#[derive(Debug, Default, Deserialize)]
+pub struct PauseRequest {
+    /// logged with the pause
+    pub reason: Option<String>,
+}
+
+/// Continues a paused cycle where it left off, the payload is optional
+#[derive(Debug, Default, Deserialize)]
+pub struct ResumeRequest {}
+
+/// Adds materials to the named feeder after an operator restocks it
+#[derive(Debug, Deserialize)]
+pub struct RefillFeeder {
+    pub feeder: String,
+    pub count: u32,
+}
+
+/// Moves the robot arm to a position on the track, completed once it's there
+#[derive(Debug, Deserialize)]
+pub struct MoveRobot {
+    pub position: RobotPosition,
+}
+
+/// Moves the stepper motor to a position, in steps from home, or by a number of steps from where it
+/// is, completed once it's there
+#[derive(Debug, Deserialize)]
+pub struct MoveStepper {
+    pub position: Option<i64>,
+    /// away from home when positive
+    pub steps: Option<i64>,
+}
+
+/// Walks the program a start would run without driving the cell, answered in the ack with the
+/// steps it would go through and how long they would take
+#[derive(Debug, Deserialize)]
+pub struct DryRunRequest {
+    pub count: u32,
+    /// name of the program, the simplified scenario 2 when left out
+    pub scenario: Option<String>,
+    #[serde(default)]
+    pub parameters: Value,
 }
 
+/// Asks for the latest twin state, or the state of a single component, answered in the ack
+#[derive(Debug, Default, Deserialize)]
+pub struct QueryRequest {
+    pub component: Option<String>,
+}
+
+/// New credentials for the connection, as PEM encoded contents. Only the given ones are replaced
+#[derive(Debug, Deserialize)]
+pub struct RotateKeyRequest {
+    pub private_key: Option<String>,
+    pub certificate: Option<String>,
+    pub ca_certificate: Option<String>,
+}
+
+/// Settings of the twin in the format of its settings file, replacing it. The file is re-read as
+/// it is when they're left out
+#[derive(Debug, Default, Deserialize)]
+pub struct ReloadConfigRequest {
+    pub content: Option<String>,
+}
+
+/// Every command type, the "type" field of a command payload
+pub const COMMAND_TYPES: [&str; 17] = [
+    "start",
+    "stop",
+    "emergency-stop",
+    "clear-estop",
+    "pause",
+    "resume",
+    "refill-feeder",
+    "refill",
+    "move-robot",
+    "move-stepper",
+    "home-stepper",
+    "dry-run",
+    "query",
+    "query-state",
+    "rotate-key",
+    "redrive",
+    "reload-config",
+];
+
+/// A command from the cloud, tagged with its type. Commands sent on a subfolder of the commands
+/// topic, e.g. commands/start, default to the type of the subfolder
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Command {
+    /// commands/start
+    Start(StartRequest),
+    /// commands/stop, also interrupts the cycle being run
+    Stop(StopRequest),
+    /// commands/emergency-stop, bypasses the queue and interrupts whatever is waiting on the cell
+    EmergencyStop(EmergencyStopRequest),
+    /// commands/clear-estop, clears the e-stop latched by the button once it's released, carries
+    /// no payload
+    #[serde(rename = "clear-estop")]
+    ClearEStop,
+    /// commands/pause, holds the cycle being run
+    Pause(PauseRequest),
+    /// commands/resume, continues the paused cycle
+    Resume(ResumeRequest),
+    /// commands/refill-feeder, confirmed with a refilled event from the feeder
+    #[serde(alias = "refill")]
+    RefillFeeder(RefillFeeder),
+    /// commands/move-robot, fails when the arm doesn't get there in time
+    MoveRobot(MoveRobot),
+    /// commands/move-stepper
+    MoveStepper(MoveStepper),
+    /// commands/home-stepper, steps towards the limit switch until it's reached, carries no
+    /// payload
+    HomeStepper,
+    /// commands/dry-run, plans the program without requesting its lines or running it
+    DryRun(DryRunRequest),
+    /// commands/query, answered by the dispatcher without waiting for the running cycle
+    Query(QueryRequest),
+    /// commands/query-state, publishes a snapshot of the whole twin on the state-snapshot events
+    /// topic, carries no payload
+    QueryState,
+    /// commands/rotate-key
+    RotateKey(RotateKeyRequest),
+    /// commands/redrive, re-attempts delivery of the dead letters, carries no payload
+    Redrive,
+    /// commands/reload-config, also queued by the twin itself when its settings file changes
+    ReloadConfig(ReloadConfigRequest),
+}
+
+impl Command {
+    /// The type of the command, as in its payload
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Command::Start(_) => "start",
+            Command::Stop(_) => "stop",
+            Command::EmergencyStop(_) => "emergency-stop",
+            Command::ClearEStop => "clear-estop",
+            Command::Pause(_) => "pause",
+            Command::Resume(_) => "resume",
+            Command::RefillFeeder(_) => "refill-feeder",
+            Command::MoveRobot(_) => "move-robot",
+            Command::MoveStepper(_) => "move-stepper",
+            Command::HomeStepper => "home-stepper",
+            Command::DryRun(_) => "dry-run",
+            Command::Query(_) => "query",
+            Command::QueryState => "query-state",
+            Command::RotateKey(_) => "rotate-key",
+            Command::Redrive => "redrive",
+            Command::ReloadConfig(_) => "reload-config",
+        }
+    }
+
+    /// Stops, pauses and resumes control the running cycle rather than waiting for it
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Command::Stop(_) | Command::Pause(_) | Command::Resume(_)
+        )
+    }
+
+    /// Starts and moves drive the cell, which they can't while the e-stop is latched
+    pub fn drives_cell(&self) -> bool {
+        matches!(
+            self,
+            Command::Start(_)
+                | Command::MoveRobot(_)
+                | Command::MoveStepper(_)
+                | Command::HomeStepper
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum RouteError {
+    UnknownType(String),
+    MissingType,
+    Malformed(serde_json::Error),
+    /// the payload doesn't match the schema of its type
+    Invalid(Invalid),
+}
+
+impl RouteError {
+    /// JSON pointer to the field that failed validation
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            RouteError::Invalid(invalid) => Some(&invalid.path),
+            _ => None,
+        }
+    }
+}
+
+impl Display for RouteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteError::UnknownType(command_type) => {
+                write!(f, "Error: Unknown command type {command_type}")
+            }
+            RouteError::MissingType => write!(
+                f,
+                "Error: Missing command type, set the type field or send the command on its subfolder"
+            ),
+            RouteError::Malformed(e) => write!(f, "Error: Malformed command payload, {e}"),
+            RouteError::Invalid(invalid) => write!(f, "{invalid}"),
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// Why a config message from the cloud was rejected, not to be confused with the settings of the
+/// twin, see config::ConfigError
+#[derive(Debug)]
+pub enum ConfigPayloadError {
+    Malformed(serde_json::Error),
+    UnsupportedVersion(Value),
+}
+
+impl Display for ConfigPayloadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigPayloadError::Malformed(e) => write!(f, "Error: Malformed config payload, {e}"),
+            ConfigPayloadError::UnsupportedVersion(version) => write!(
+                f,
+                "Error: Unsupported config version {version}, expected {CONFIG_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigPayloadError {}
+
+/// Validate the payload of a config message before starting the program it requests
+pub fn parse_config(payload: &str) -> Result<StartRequest, ConfigPayloadError> {
+    let config: Value = serde_json::from_str(payload).map_err(ConfigPayloadError::Malformed)?;
+
+    match config.get("version") {
+        None => {}
+        Some(version) if version.as_u64() == Some(CONFIG_VERSION) => {}
+        Some(version) => return Err(ConfigPayloadError::UnsupportedVersion(version.clone())),
+    }
+
+    serde_json::from_value(config).map_err(ConfigPayloadError::Malformed)
+}
+
+/// The id the cloud sent the command with, carried by the "id" field of its JSON payload
+pub fn command_id(payload: &str) -> Option<String> {
+    let payload: Value = serde_json::from_str(payload).ok()?;
+    match payload.get("id")? {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// The key duplicate deliveries of a command are recognized by, its "idempotency_key" field or else
+/// its id
+pub fn idempotency_key(payload: &str) -> Option<String> {
+    let key = serde_json::from_str::<Value>(payload)
+        .ok()
+        .and_then(|payload| payload.get("idempotency_key")?.as_str().map(str::to_string));
+    key.or_else(|| command_id(payload))
+}
+
+/// Parse the payload of a command into a typed command. The type is read from the "type" field,
+/// or taken from the commands subfolder the message was received on. Commands without fields, e.g.
+/// a stop, can be sent with an empty payload. The payload is validated against the schema of its
+/// type before it's deserialized
+pub fn route(subfolder: Option<&str>, payload: &str) -> Result<Command, RouteError> {
+    let mut command: Map<String, Value> = if payload.trim().is_empty() {
+        Map::new()
+    } else {
+        serde_json::from_str(payload).map_err(RouteError::Malformed)?
+    };
+
+    if !command.contains_key("type") {
+        let subfolder = subfolder.ok_or(RouteError::MissingType)?;
+        command.insert("type".to_string(), Value::from(subfolder));
+    }
+    let command = Value::Object(command);
+    if let Some(Value::String(command_type)) = command.get("type") {
+        if !COMMAND_TYPES.contains(&command_type.as_str()) {
+            return Err(RouteError::UnknownType(command_type.clone()));
+        }
+        schema::validate(command_type, &command).map_err(RouteError::Invalid)?;
+    }
+
+    serde_json::from_value(command).map_err(RouteError::Malformed)
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -19,6 +464,17 @@ mod tests {
         let _request: StartRequest = serde_json::from_str(json_msg).unwrap();
     }
 
+    #[test]
+    fn start_at_is_parsed_as_rfc3339() {
+        let json_msg = r#"{ "count": 5, "start_at": "2022-03-14T06:00:00+01:00" }"#;
+
+        let request: StartRequest = serde_json::from_str(json_msg).unwrap();
+        assert_eq!(
+            request.start_at.unwrap().to_rfc3339(),
+            "2022-03-14T05:00:00+00:00"
+        );
+    }
+
     #[test]
     #[should_panic]
     fn negative_count_doesnt_deserialize() {
@@ -30,4 +486,235 @@ mod tests {
 
         let _request: StartRequest = serde_json::from_str(json_msg).unwrap();
     }
+
+    #[test]
+    fn commands_are_routed_by_subfolder() {
+        assert!(matches!(
+            route(Some("start"), r#"{ "count": 5 }"#),
+            Ok(Command::Start(StartRequest { count: 5, .. }))
+        ));
+        assert!(matches!(route(Some("stop"), ""), Ok(Command::Stop(_))));
+        assert!(matches!(
+            route(Some("stop"), r#"{ "reason": "jam" }"#),
+            Ok(Command::Stop(StopRequest { reason: Some(_) }))
+        ));
+        assert!(matches!(
+            route(Some("pause"), r#"{ "reason": "tool change" }"#),
+            Ok(Command::Pause(PauseRequest { reason: Some(_) }))
+        ));
+        assert!(matches!(route(Some("resume"), ""), Ok(Command::Resume(_))));
+        assert!(matches!(
+            route(Some("emergency-stop"), r#"{ "reason": "fire" }"#),
+            Ok(Command::EmergencyStop(EmergencyStopRequest {
+                reason: Some(_)
+            }))
+        ));
+        assert!(matches!(route(Some("redrive"), ""), Ok(Command::Redrive)));
+        assert!(matches!(
+            route(Some("reload-config"), ""),
+            Ok(Command::ReloadConfig(ReloadConfigRequest { content: None }))
+        ));
+        assert!(matches!(
+            route(Some("clear-estop"), ""),
+            Ok(Command::ClearEStop)
+        ));
+        assert!(matches!(
+            route(Some("query-state"), ""),
+            Ok(Command::QueryState)
+        ));
+        assert!(matches!(
+            route(
+                Some("refill-feeder"),
+                r#"{ "feeder": "Material feeder", "count": 3 }"#
+            ),
+            Ok(Command::RefillFeeder(RefillFeeder { count: 3, .. }))
+        ));
+        assert!(matches!(
+            route(Some("move-robot"), r#"{ "position": "position 15" }"#),
+            Ok(Command::MoveRobot(MoveRobot {
+                position: RobotPosition::Position15
+            }))
+        ));
+        assert!(matches!(
+            route(Some("move-robot"), r#"{ "position": "position 2" }"#),
+            Err(RouteError::Invalid(_))
+        ));
+        assert!(matches!(
+            route(Some("move-stepper"), r#"{ "steps": -200 }"#),
+            Ok(Command::MoveStepper(MoveStepper {
+                position: None,
+                steps: Some(-200)
+            }))
+        ));
+        assert!(matches!(
+            route(Some("move-stepper"), r#"{ "position": 10, "steps": 5 }"#),
+            Err(RouteError::Invalid(_))
+        ));
+        assert!(matches!(
+            route(Some("home-stepper"), ""),
+            Ok(Command::HomeStepper)
+        ));
+        assert!(matches!(
+            route(
+                Some("dry-run"),
+                r#"{ "count": 2, "scenario": "scenario-1" }"#
+            ),
+            Ok(Command::DryRun(DryRunRequest { count: 2, .. }))
+        ));
+        assert!(matches!(
+            route(Some("refill-feeder"), r#"{ "count": 3 }"#),
+            Err(RouteError::Invalid(_))
+        ));
+        assert!(matches!(
+            route(Some("restart"), "{}"),
+            Err(RouteError::UnknownType(_))
+        ));
+        assert!(matches!(
+            route(Some("start"), "{}"),
+            Err(RouteError::Invalid(_))
+        ));
+        assert!(matches!(
+            route(Some("start"), "[5]"),
+            Err(RouteError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_commands_report_the_offending_field() {
+        let error = route(Some("start"), r#"{ "count": -5 }"#).unwrap_err();
+        assert_eq!(error.path(), Some("/count"));
+    }
+
+    #[test]
+    fn commands_are_routed_by_type() {
+        assert!(matches!(
+            route(None, r#"{ "type": "start", "count": 5, "id": "a1" }"#),
+            Ok(Command::Start(StartRequest { count: 5, .. }))
+        ));
+        assert!(matches!(
+            route(
+                None,
+                r#"{ "type": "refill", "feeder": "Material feeder", "count": 3 }"#
+            ),
+            Ok(Command::RefillFeeder(_))
+        ));
+        assert!(matches!(
+            route(None, r#"{ "type": "query" }"#),
+            Ok(Command::Query(QueryRequest { component: None }))
+        ));
+        assert!(matches!(
+            route(Some("stop"), r#"{ "type": "pause" }"#),
+            Ok(Command::Pause(_))
+        ));
+        assert!(matches!(
+            route(None, r#"{ "type": "explode" }"#),
+            Err(RouteError::UnknownType(_))
+        ));
+        assert!(matches!(
+            route(None, r#"{ "count": 5 }"#),
+            Err(RouteError::MissingType)
+        ));
+    }
+
+    #[test]
+    fn command_ids_are_read_from_the_payload() {
+        assert_eq!(
+            command_id(r#"{ "id": "a1", "count": 5 }"#),
+            Some("a1".to_string())
+        );
+        assert_eq!(command_id(r#"{ "id": 7 }"#), Some("7".to_string()));
+        assert_eq!(command_id(r#"{ "count": 5 }"#), None);
+        assert_eq!(command_id(""), None);
+    }
+
+    #[test]
+    fn idempotency_keys_fall_back_to_the_id() {
+        assert_eq!(
+            idempotency_key(r#"{ "id": "a1", "idempotency_key": "shift-1" }"#),
+            Some("shift-1".to_string())
+        );
+        assert_eq!(idempotency_key(r#"{ "id": 7 }"#), Some("7".to_string()));
+        assert_eq!(idempotency_key(""), None);
+    }
+
+    #[test]
+    fn envelopes_are_opened() {
+        assert_eq!(
+            open(r#"{ "count": 5 }"#).unwrap(),
+            (1, r#"{ "count": 5 }"#.to_string())
+        );
+        assert_eq!(open("").unwrap(), (1, String::new()));
+
+        let (version, payload) = open(
+            r#"{ "schema_version": 2, "device_id": "pi", "payload": { "type": "start", "count": 5 } }"#,
+        )
+        .unwrap();
+        assert_eq!(version, 2);
+        assert!(matches!(
+            route(None, &payload),
+            Ok(Command::Start(StartRequest { count: 5, .. }))
+        ));
+
+        let (_, payload) = open(r#"{ "schema_version": 2, "device_id": "pi" }"#).unwrap();
+        assert!(matches!(
+            route(Some("stop"), &payload),
+            Ok(Command::Stop(_))
+        ));
+
+        assert!(matches!(
+            open(r#"{ "schema_version": 3, "device_id": "pi", "payload": {} }"#),
+            Err(EnvelopeError::UnsupportedVersion(3))
+        ));
+        assert!(matches!(
+            open(r#"{ "schema_version": 2, "payload": {} }"#),
+            Err(EnvelopeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn messages_are_downgraded_for_legacy_consumers() {
+        let ack = serde_json::json!({ "id": "a1", "status": "accepted" });
+
+        let legacy = seal_as(1, "pi", &ack).unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&legacy).unwrap(), ack);
+
+        let sealed = seal_as(2, "pi", &ack).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&sealed).unwrap(),
+            serde_json::json!({ "schema_version": 2, "device_id": "pi", "payload": ack })
+        );
+    }
+
+    #[test]
+    fn config_payloads_are_validated() {
+        assert!(matches!(
+            parse_config(r#"{ "count": 5 }"#),
+            Ok(StartRequest { count: 5, .. })
+        ));
+        assert!(matches!(
+            parse_config(r#"{ "version": 1, "count": 5 }"#),
+            Ok(StartRequest { count: 5, .. })
+        ));
+        assert!(matches!(
+            parse_config(r#"{ "version": 2, "count": 5 }"#),
+            Err(ConfigPayloadError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            parse_config("not json"),
+            Err(ConfigPayloadError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn commands_know_their_type() {
+        for command_type in ["clear-estop", "home-stepper", "query-state", "redrive"] {
+            let command = route(Some(command_type), "").unwrap();
+            assert_eq!(command.kind(), command_type);
+        }
+        let refill = route(
+            None,
+            r#"{ "type": "refill", "feeder": "Material feeder", "count": 3 }"#,
+        );
+        assert_eq!(refill.unwrap().kind(), "refill-feeder");
+    }
 }
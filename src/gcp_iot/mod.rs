@@ -1,150 +1,251 @@
-use async_trait::async_trait;
+use crate::backend::{required, CloudError};
+use crate::config::Settings;
+use crate::tls::{Tls, TlsConfig, TlsError};
+use crate::transport::{self, Client, ConnectOptions, Message, MessageStream};
 use color_eyre::Result;
-use google_cloud_iot_jwt::create_google_jwt_es256;
-use log::info;
-pub use paho_mqtt::AsyncClient;
-use paho_mqtt::{
-    ConnectOptions, ConnectOptionsBuilder, CreateOptionsBuilder, Properties, ReasonCode,
-    SslOptions, SslOptionsBuilder, SslVersion, MQTT_VERSION_3_1_1,
-};
-use std::env;
+use endpoint::{Endpoint, Endpoints};
+use jwt::JwtAlgorithm;
+use key_source::KeySource;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::fs;
+use tracing::warn;
 
+pub mod endpoint;
+pub mod gateway;
+pub mod http_bridge;
+pub mod jwt;
+pub mod key_source;
 pub mod message;
+pub mod schema;
+pub mod storage;
+pub mod topic;
 
-async fn new_password_jwt() -> String {
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+/// Why the twin couldn't connect to Google IoT, telling apart what a retry can fix from what needs
+/// an operator
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    /// a setting or credential of the device isn't set
+    #[error(transparent)]
+    MissingCredential(#[from] CloudError),
+    /// the private key isn't set or can't be read from where it's said to be
+    #[error("Error: Unable to read the private key, {0:#}")]
+    KeyUnavailable(color_eyre::Report),
+    /// the private key was read but can't sign the connection JWT, e.g. it isn't PEM encoded or
+    /// isn't an RSA or EC key
+    #[error("Error: The private key can't sign the connection JWT, {0:#}")]
+    BadKey(color_eyre::Report),
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+    /// Google IoT refused the JWT or the device, e.g. the key isn't registered for the device or
+    /// the device is blocked
+    #[error("Error: Google IoT rejected the credentials of the device, {0}")]
+    AuthRejected(color_eyre::Report),
+    #[error("Error: Unable to reach Google IoT, {0}")]
+    Unreachable(color_eyre::Report),
+}
+
+impl ConnectError {
+    /// Classifies a failed connection attempt, the clients only describe refused connections in
+    /// the error message
+    pub fn refused(e: color_eyre::Report) -> Self {
+        let message = e.to_string().to_lowercase();
+        if message.contains("not authorized") || message.contains("bad user name or password") {
+            Self::AuthRejected(e)
+        } else if message.contains("tcp/tls connect failure") {
+            // paho reports the host not being reachable this way, whether TLS is used or not
+            Self::Unreachable(e)
+        } else if message.contains("ssl") || message.contains("tls") {
+            Self::Tls(TlsError::Handshake(e))
+        } else {
+            Self::Unreachable(e)
+        }
+    }
+
+    /// Whether trying again later, or on another endpoint, may succeed without an operator fixing
+    /// the settings or the credentials
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Unreachable(_))
+    }
+}
 
-    let private_key = fs::read_to_string("ec_private.pem")
+/// A JWT for the device to authenticate with, Google IoT requires its audience to be the project
+pub(crate) async fn new_password_jwt(
+    settings: &Settings,
+    project_id: &str,
+) -> Result<String, ConnectError> {
+    let private_key = KeySource::from_settings(settings)
+        .map_err(ConnectError::KeyUnavailable)?
+        .read()
         .await
-        .expect("No private key found");
-
-    create_google_jwt_es256(
-        "digital-twin-experiment",
-        &private_key,
-        now.as_secs().try_into().unwrap(),
-    )
-    .unwrap()
-    .to_string()
+        .map_err(ConnectError::KeyUnavailable)?;
+
+    sign_jwt(settings, &private_key, project_id).map_err(ConnectError::BadKey)
 }
 
-fn get_ssl_ops() -> SslOptions {
-    let pub_key =
-        env::var("CA_CERTIFICATE").expect("Missing CA_CERTIFICATE in environment variables");
-    let pri_key = env::var("PRIVATE_KEY").expect("Missing PRIVATE_KEY in environment variable");
-
-    SslOptionsBuilder::new()
-        .trust_store(pub_key)
-        .unwrap()
-        .ssl_version(SslVersion::Tls_1_2)
-        .private_key(pri_key)
-        .unwrap()
-        .finalize()
+fn sign_jwt(settings: &Settings, private_key: &str, project_id: &str) -> Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let algorithm = JwtAlgorithm::detect(settings, private_key)?;
+
+    jwt::create_jwt(algorithm, project_id, private_key, now.as_secs())
 }
 
-fn get_connect_ops(ssl_ops: SslOptions, jwt: impl Into<String>) -> ConnectOptions {
-    ConnectOptionsBuilder::new()
-        .mqtt_version(MQTT_VERSION_3_1_1)
-        .keep_alive_interval(Duration::from_secs(60 * 20))
-        .user_name("ignore")
-        .clean_session(true)
-        .password(jwt)
-        .ssl_options(ssl_ops)
-        .finalize()
+/// Checks a PEM encoded key can sign a connection JWT for the project
+pub fn validate_private_key(
+    settings: &Settings,
+    private_key: &str,
+    project_id: &str,
+) -> Result<()> {
+    sign_jwt(settings, private_key, project_id)?;
+    Ok(())
 }
-#[async_trait]
-pub trait GoogleIotConnect {
-    async fn gcp_connect() -> Result<AsyncClient>;
+
+/// The root CAs of the endpoint are used when configured, otherwise the system trust store
+fn get_ssl_ops(settings: &Settings, endpoint: &Endpoint) -> Result<Tls, ConnectError> {
+    let pri_key = required(settings, "PRIVATE_KEY")?;
+
+    let mut tls = TlsConfig::from_settings(settings)?.builder();
+    if let Some(pub_key) = &endpoint.ca_certificate {
+        tls = tls.trust_store(pub_key.as_str());
+    }
+    Ok(tls.private_key(pri_key))
 }
 
-#[async_trait]
-impl GoogleIotConnect for AsyncClient {
-    async fn gcp_connect() -> Result<AsyncClient> {
-        // create the MqttJWT object
-        let jwt = new_password_jwt().await;
-
-        let project_id =
-            env::var("PROJECT_ID").expect("Missing PROJECT_ID in environment variables");
-        let device_id = env::var("DEVICE_ID").expect("Missing DEVICE_ID in environment variables");
-        let registry_id =
-            env::var("REGISTRY_ID").expect("Missing REGISTRY_ID in environment variables");
-        let region = env::var("REGION").expect("Missing REGION in environment variables");
-        let mqtt_client_id = format!(
-            "projects/{project_id}/locations/{region}/registries/{registry_id}/devices/{device_id}"
-        );
+fn get_connect_ops(
+    settings: &Settings,
+    endpoint: &Endpoint,
+    tls: Tls,
+    jwt: String,
+    will: Option<Message>,
+) -> Result<ConnectOptions, ConnectError> {
+    Ok(ConnectOptions {
+        keep_alive: Duration::from_secs(60 * 20),
+        user_name: Some("ignore".to_string()),
+        password: Some(jwt),
+        tls: Some(tls),
+        will,
+        ..ConnectOptions::new(&endpoint.uri, client_id(settings)?)
+    })
+}
 
-        let ssl_ops = get_ssl_ops();
-        let connect_ops = get_connect_ops(ssl_ops, jwt);
-
-        let create_options = CreateOptionsBuilder::new()
-            .server_uri("ssl://mqtt.googleapis.com:8883")
-            .client_id(mqtt_client_id)
-            .finalize();
-
-        let mut client = AsyncClient::new(create_options).unwrap();
-
-        // Google IoT will automatically discount after 20 minutes of inactivity, unfortunately, the we
-        // need to update the password to reconnect
-        client.set_disconnected_callback(
-            |client: &AsyncClient, _properties: Properties, reason_code: ReasonCode| {
-                match reason_code {
-                    ReasonCode::KeepAliveTimeout => {
-                        // the callback function cannot be async, so we have to block
-                        let handle = tokio::runtime::Handle::current();
-                        let jwt = handle.block_on(new_password_jwt());
-
-                        let ssl_options = get_ssl_ops();
-                        let connect_options = get_connect_ops(ssl_options, jwt);
-                        client.connect(connect_options);
-                    }
-                    _ => {
-                        info!("Disconnected for reasons other than time out, unable to resolve")
-                    }
-                }
-            },
-        );
+/// The client id Google IoT identifies the device with
+fn client_id(settings: &Settings) -> Result<String, CloudError> {
+    let project_id = required(settings, "PROJECT_ID")?;
+    let device_id = required(settings, "DEVICE_ID")?;
+    let registry_id = required(settings, "REGISTRY_ID")?;
+    let region = required(settings, "REGION")?;
+    Ok(format!(
+        "projects/{project_id}/locations/{region}/registries/{registry_id}/devices/{device_id}"
+    ))
+}
 
-        client.connect(connect_ops).await?;
-        Ok(client)
+/// Connect options for the endpoint with a freshly minted JWT, Google IoT will disconnect once the
+/// JWT expires so every reconnection needs new options
+pub async fn gcp_connect_options(
+    settings: &Settings,
+    endpoint: &Endpoint,
+    will: Option<Message>,
+) -> Result<ConnectOptions, ConnectError> {
+    let jwt = new_password_jwt(settings, &required(settings, "PROJECT_ID")?).await?;
+    let tls = get_ssl_ops(settings, endpoint)?;
+    get_connect_ops(settings, endpoint, tls, jwt, will)
+}
+
+/// Connect to the current endpoint, failing over to the next ones if it can't be reached. A
+/// rejected credential fails right away, the other endpoints would reject it as well
+pub async fn connect(
+    settings: &Settings,
+    endpoints: &Endpoints,
+    will: Option<Message>,
+) -> Result<(Client, MessageStream), ConnectError> {
+    let mut attempts = 1;
+    loop {
+        let connect_options =
+            gcp_connect_options(settings, endpoints.current(), will.clone()).await?;
+        match transport::connect(&connect_options)
+            .await
+            .map_err(ConnectError::refused)
+        {
+            Ok(connection) => {
+                endpoints.record_success();
+                return Ok(connection);
+            }
+            Err(e) if e.is_transient() && attempts < endpoints.len() => {
+                warn!("Unable to connect to {}: {e}", endpoints.current().uri);
+                endpoints.failover();
+                attempts += 1;
+            }
+            Err(e) => return Err(e),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use color_eyre::eyre::eyre;
     use color_eyre::Result;
     use dotenv::dotenv;
-    use paho_mqtt::{Message, QOS_1};
+    use topic::Topic;
+    use transport::QOS_1;
+
+    #[test]
+    fn jwts_are_for_the_project() {
+        let private_key = include_str!("../../ec_private.pem");
+        let jwt = sign_jwt(&Settings::default(), private_key, "plant-twin").unwrap();
+
+        let claims = jwt.split('.').nth(1).unwrap();
+        let claims = base64::decode_config(claims, base64::URL_SAFE_NO_PAD).unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(&claims).unwrap();
+        assert_eq!(claims["aud"], "plant-twin");
+    }
+
+    #[test]
+    fn only_unreachable_brokers_are_retried() {
+        let rejected = ConnectError::refused(eyre!("Not authorized"));
+        assert!(matches!(rejected, ConnectError::AuthRejected(_)));
+        assert!(!rejected.is_transient());
+
+        let handshake = ConnectError::refused(eyre!("SSL connect error"));
+        assert!(matches!(
+            handshake,
+            ConnectError::Tls(TlsError::Handshake(_))
+        ));
+        assert!(!handshake.is_transient());
+
+        let unreachable = ConnectError::refused(eyre!("TCP/TLS connect failure"));
+        assert!(unreachable.is_transient());
+    }
 
     #[tokio::test]
+    #[ignore = "connects to Google IoT with the device of the environment"]
     async fn push_to_custom_topics() -> Result<()> {
         dotenv().ok();
         color_eyre::install()?;
-        let client = AsyncClient::gcp_connect().await?;
+        let settings = Settings::from_env(&[]);
+        let endpoints = Endpoints::from_settings(&settings)?;
+        let (client, _stream) = connect(&settings, &endpoints, None).await?;
 
-        let device_id = env::var("DEVICE_ID").expect("Missing DEVICE_ID in environment variables");
+        let device_id = required(&settings, "DEVICE_ID")?;
 
         client
-            .subscribe(format!("/devices/{device_id}/config"), QOS_1)
+            .subscribe(&Topic::device(&device_id).config(), QOS_1)
             .await?;
 
         let msg = Message::new(
-            format!("/devices/{device_id}/events/piston"),
+            Topic::device(&device_id).event("piston")?,
             "piston debug data",
             QOS_1,
         );
         client.publish(msg).await?;
 
         let msg = Message::new(
-            format!("/devices/{device_id}/events/robot"),
+            Topic::device(&device_id).event("robot")?,
             "robot debug data",
             QOS_1,
         );
         client.publish(msg).await?;
 
         let msg = Message::new(
-            format!("/devices/{device_id}/events/feeder"),
+            Topic::device(&device_id).event("feeder")?,
             "feeder debug data",
             QOS_1,
         );
@@ -0,0 +1,121 @@
+use crate::gcp_iot::message::COMMAND_TYPES;
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+
+/// JSON Schema of the payload of the command type, embedded from schemas/commands
+fn schema(command_type: &str) -> Option<&'static str> {
+    match command_type {
+        "start" => Some(include_str!("../../schemas/commands/start.json")),
+        "stop" => Some(include_str!("../../schemas/commands/stop.json")),
+        "emergency-stop" => Some(include_str!("../../schemas/commands/emergency-stop.json")),
+        "clear-estop" => Some(include_str!("../../schemas/commands/clear-estop.json")),
+        "pause" => Some(include_str!("../../schemas/commands/pause.json")),
+        "resume" => Some(include_str!("../../schemas/commands/resume.json")),
+        "refill-feeder" | "refill" => {
+            Some(include_str!("../../schemas/commands/refill-feeder.json"))
+        }
+        "move-robot" => Some(include_str!("../../schemas/commands/move-robot.json")),
+        "move-stepper" => Some(include_str!("../../schemas/commands/move-stepper.json")),
+        "home-stepper" => Some(include_str!("../../schemas/commands/home-stepper.json")),
+        "dry-run" => Some(include_str!("../../schemas/commands/dry-run.json")),
+        "query" => Some(include_str!("../../schemas/commands/query.json")),
+        "query-state" => Some(include_str!("../../schemas/commands/query-state.json")),
+        "rotate-key" => Some(include_str!("../../schemas/commands/rotate-key.json")),
+        "redrive" => Some(include_str!("../../schemas/commands/redrive.json")),
+        "reload-config" => Some(include_str!("../../schemas/commands/reload-config.json")),
+        _ => None,
+    }
+}
+
+/// The schemas of every command type, compiled the first time a command is validated
+fn compiled(command_type: &str) -> Option<&'static JSONSchema> {
+    static COMPILED: OnceLock<HashMap<&str, JSONSchema>> = OnceLock::new();
+
+    let compiled = COMPILED.get_or_init(|| {
+        COMMAND_TYPES
+            .iter()
+            .filter_map(|command_type| Some((*command_type, schema(command_type)?)))
+            .map(|(command_type, schema)| {
+                // the schemas are embedded, they can only be broken by a change to the repository
+                let schema: Value =
+                    serde_json::from_str(schema).expect("Malformed embedded command schema");
+                let schema = JSONSchema::compile(&schema).expect("Invalid embedded command schema");
+                (command_type, schema)
+            })
+            .collect()
+    });
+    compiled.get(command_type)
+}
+
+/// A command payload that doesn't match the schema of its type
+#[derive(Debug)]
+pub struct Invalid {
+    /// JSON pointer to the offending field, empty for the payload itself
+    pub path: String,
+    pub error: String,
+}
+
+impl Display for Invalid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "Error: Invalid command payload, {}", self.error)
+        } else {
+            write!(
+                f,
+                "Error: Invalid command payload at {}, {}",
+                self.path, self.error
+            )
+        }
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+/// Validate the payload of a command against the schema of its type, reporting the first error.
+/// Types without a schema are left to the deserialization
+pub fn validate(command_type: &str, payload: &Value) -> Result<(), Invalid> {
+    let schema = match compiled(command_type) {
+        Some(schema) => schema,
+        None => return Ok(()),
+    };
+
+    let result = schema.validate(payload);
+    match result {
+        Ok(()) => Ok(()),
+        Err(mut errors) => match errors.next() {
+            Some(error) => Err(Invalid {
+                path: error.instance_path.to_string(),
+                error: error.to_string(),
+            }),
+            None => Ok(()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn every_command_type_has_a_valid_schema() {
+        for command_type in crate::gcp_iot::message::COMMAND_TYPES {
+            let schema: Value = serde_json::from_str(schema(command_type).unwrap()).unwrap();
+            assert!(JSONSchema::compile(&schema).is_ok(), "{command_type}");
+        }
+    }
+
+    #[test]
+    fn offending_fields_are_reported_with_their_path() {
+        let invalid = validate("start", &json!({ "count": "five" })).unwrap_err();
+        assert_eq!(invalid.path, "/count");
+
+        let invalid = validate("refill-feeder", &json!({ "count": 3 })).unwrap_err();
+        assert_eq!(invalid.path, "");
+
+        assert!(validate("start", &json!({ "id": "a1", "count": 5 })).is_ok());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::backend::required;
+use crate::config::Settings;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use jwt_simple::prelude::{Claims, Duration, RS256KeyPair, RSAKeyPairLike};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const UPLOAD_URL: &str = "https://storage.googleapis.com/upload/storage/v1/b";
+const OBJECT_URL: &str = "https://storage.googleapis.com";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+/// Google rejects assertions valid for more than an hour
+const ASSERTION_LIFETIME_MINUTES: u64 = 60;
+/// Tokens are renewed this long before they expire, so a request never goes out with a stale one
+const TOKEN_MARGIN: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Where objects are put, see CloudStorage
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Put data in the object of the given name, returning its URL
+    async fn upload(&self, name: &str, data: Vec<u8>, content_type: &str) -> Result<String>;
+}
+
+/// The fields of a service account key file the twin uses
+#[derive(Deserialize)]
+struct ServiceAccount {
+    client_email: String,
+    private_key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Scope {
+    scope: String,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    /// in seconds
+    expires_in: u64,
+}
+
+/// A Cloud Storage bucket, written to as a service account rather than as the device, since the
+/// device's JWT is only accepted by IoT Core. Access tokens are exchanged for a JWT signed with the
+/// service account's key and kept until they're about to expire
+pub struct CloudStorage {
+    client: reqwest::Client,
+    bucket: String,
+    account: ServiceAccount,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl CloudStorage {
+    /// The bucket, written to as the service account of the JSON key file at credentials
+    pub fn new(bucket: &str, credentials: &str) -> Result<Self> {
+        let key = std::fs::read_to_string(credentials)
+            .map_err(|e| eyre!("Failed to read the service account key {credentials}: {e}"))?;
+        let account = serde_json::from_str(&key)
+            .map_err(|e| eyre!("Failed to parse the service account key {credentials}: {e}"))?;
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            bucket: bucket.to_string(),
+            account,
+            token: Mutex::new(None),
+        })
+    }
+
+    /// The bucket STORAGE_BUCKET, written to with the key file at STORAGE_CREDENTIALS
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let bucket = required(settings, "STORAGE_BUCKET")?;
+        let credentials = required(settings, "STORAGE_CREDENTIALS")?;
+        Self::new(&bucket, &credentials)
+    }
+
+    /// An access token for the bucket, the cached one unless it's about to expire
+    async fn access_token(&self) -> Result<String> {
+        let mut cached = self.token.lock().await;
+        if let Some((token, expires)) = &*cached {
+            if Instant::now() + TOKEN_MARGIN < *expires {
+                return Ok(token.clone());
+            }
+        }
+
+        let key_pair = RS256KeyPair::from_pem(&self.account.private_key)
+            .map_err(|e| eyre!("Unable to read the service account's private key: {e}"))?;
+        let scope = Scope {
+            scope: SCOPE.to_string(),
+        };
+        let claims =
+            Claims::with_custom_claims(scope, Duration::from_mins(ASSERTION_LIFETIME_MINUTES))
+                .with_issuer(&self.account.client_email)
+                .with_audience(TOKEN_URL);
+        let assertion = key_pair
+            .sign(claims)
+            .map_err(|e| eyre!("Unable to sign the token request: {e}"))?;
+
+        let response = self
+            .client
+            .post(TOKEN_URL)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = response.text().await.unwrap_or_default();
+            return Err(eyre!("No access token for the bucket, {status}: {message}"));
+        }
+
+        let token: Token = response.json().await?;
+        let expires = Instant::now() + std::time::Duration::from_secs(token.expires_in);
+        *cached = Some((token.access_token.clone(), expires));
+        Ok(token.access_token)
+    }
+}
+
+#[async_trait]
+impl Storage for CloudStorage {
+    async fn upload(&self, name: &str, data: Vec<u8>, content_type: &str) -> Result<String> {
+        let token = self.access_token().await?;
+        let response = self
+            .client
+            .post(format!("{UPLOAD_URL}/{}/o", self.bucket))
+            .query(&[("uploadType", "media"), ("name", name)])
+            .bearer_auth(token)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(data)
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(format!("{OBJECT_URL}/{}/{name}", self.bucket))
+        } else {
+            let status = response.status();
+            let message = response.text().await.unwrap_or_default();
+            Err(eyre!(
+                "Cloud Storage rejected {name} with {status}: {message}"
+            ))
+        }
+    }
+}
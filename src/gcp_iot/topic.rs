@@ -0,0 +1,136 @@
+/// Builds the MQTT topics of a device, so every topic the twin publishes or subscribes to is
+/// spelled in one place. Google IoT silently drops publishes on topics it doesn't know about, a
+/// typo would otherwise go unnoticed.
+///
+/// Google IoT topics are below /devices/{device_id}, AWS IoT and plain brokers use the same layout
+/// below a prefix of their own
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topic {
+    /// every topic of the device is below it
+    root: String,
+}
+
+/// A subfolder that isn't a single topic level, e.g. with a '/' or a wildcard
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid subfolder {0:?}, expected a single topic level without wildcards")]
+pub struct InvalidLevel(pub String);
+
+/// Subfolders are a single topic level, without wildcards
+fn level(subfolder: &str) -> Result<&str, InvalidLevel> {
+    if subfolder.is_empty() || subfolder.contains(['/', '+', '#']) {
+        return Err(InvalidLevel(subfolder.to_string()));
+    }
+    Ok(subfolder)
+}
+
+impl Topic {
+    /// The topics of a Google IoT device
+    pub fn device(device_id: &str) -> Self {
+        Self {
+            root: format!("/devices/{device_id}"),
+        }
+    }
+
+    /// The topics below prefix, for backends which don't impose a layout
+    pub fn prefixed(prefix: &str) -> Self {
+        Self {
+            root: prefix.to_string(),
+        }
+    }
+
+    /// Default telemetry topic, {root}/events
+    pub fn events(&self) -> String {
+        format!("{}/events", self.root)
+    }
+
+    /// Telemetry subfolder, {root}/events/{subfolder}
+    pub fn event(&self, subfolder: &str) -> Result<String, InvalidLevel> {
+        Ok(format!("{}/{}", self.events(), level(subfolder)?))
+    }
+
+    /// Liveness of the twin, for backends retaining it. Google IoT doesn't, it's an event there
+    pub fn status(&self) -> String {
+        format!("{}/status", self.root)
+    }
+
+    pub fn state(&self) -> String {
+        format!("{}/state", self.root)
+    }
+
+    pub fn config(&self) -> String {
+        format!("{}/config", self.root)
+    }
+
+    pub fn commands(&self) -> String {
+        format!("{}/commands", self.root)
+    }
+
+    /// Filter matching the commands sent to any subfolder
+    pub fn commands_filter(&self) -> String {
+        format!("{}/#", self.commands())
+    }
+
+    pub fn command(&self, subfolder: &str) -> Result<String, InvalidLevel> {
+        Ok(format!("{}/{}", self.commands(), level(subfolder)?))
+    }
+
+    /// Returns the commands subfolder the topic belongs to, e.g. start for
+    /// /devices/{device_id}/commands/start
+    pub fn command_subfolder<'t>(&self, topic: &'t str) -> Option<&'t str> {
+        topic
+            .strip_prefix(self.commands().as_str())?
+            .strip_prefix('/')
+    }
+
+    pub fn attach(&self) -> String {
+        format!("{}/attach", self.root)
+    }
+
+    pub fn detach(&self) -> String {
+        format!("{}/detach", self.root)
+    }
+
+    pub fn errors(&self) -> String {
+        format!("{}/errors", self.root)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn topics_are_derived_from_the_device_id() {
+        let topic = Topic::device("pi");
+
+        assert_eq!(topic.event("feeder").unwrap(), "/devices/pi/events/feeder");
+        assert_eq!(topic.state(), "/devices/pi/state");
+        assert_eq!(topic.config(), "/devices/pi/config");
+        assert_eq!(topic.commands_filter(), "/devices/pi/commands/#");
+        assert_eq!(
+            topic.command_subfolder(&topic.command("start").unwrap()),
+            Some("start")
+        );
+        assert_eq!(topic.command_subfolder("/devices/pi/config"), None);
+        assert_eq!(topic.attach(), "/devices/pi/attach");
+    }
+
+    #[test]
+    fn prefixed_topics_share_the_layout() {
+        let topic = Topic::prefixed("tvilling/pi");
+
+        assert_eq!(topic.event("feeder").unwrap(), "tvilling/pi/events/feeder");
+        assert_eq!(topic.status(), "tvilling/pi/status");
+        assert_eq!(topic.commands_filter(), "tvilling/pi/commands/#");
+    }
+
+    #[test]
+    fn subfolders_are_a_single_level() {
+        let topic = Topic::device("pi");
+
+        assert!(topic.event("feeder/json").is_err());
+        assert!(topic.event("+").is_err());
+        assert!(topic.command("#").is_err());
+        assert!(topic.command("").is_err());
+    }
+}
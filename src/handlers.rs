@@ -0,0 +1,261 @@
+use crate::ack::{AckStatus, Acknowledger};
+use crate::backend::{self, Backend};
+use crate::cancellation::CancellationToken;
+use crate::config::Config;
+use crate::dispatcher::{EmergencyTrigger, Pending, Queued};
+use crate::envelope::EventSender;
+use crate::gcp_iot::message::{
+    EmergencyStopRequest, MoveRobot, MoveStepper, RefillFeeder, ReloadConfigRequest,
+};
+use crate::manufacturing_components::estop::{self, EmergencyStopButton};
+use crate::manufacturing_components::gpio::GpioProvider;
+use crate::manufacturing_components::registry::{ComponentRegistry, RUNTIME_SETTINGS};
+use crate::transport::MqttTransport;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde_json::{json, Value};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// Let the cloud know a message was rejected, failing to do so is only logged since the listener
+/// has to keep running
+pub async fn report_error(
+    backend: &Backend,
+    client: &dyn MqttTransport,
+    topic: &str,
+    error: &str,
+    path: Option<&str>,
+) {
+    if let Err(e) = backend.report_error(client, topic, error, path).await {
+        error!("Failed to report the rejected message on {topic}: {e}");
+    }
+}
+
+/// Add the restocked materials to the feeder named in the request, publishing the refilled event
+/// as confirmation
+pub fn refill_feeder(
+    cell: &mut ComponentRegistry,
+    request: RefillFeeder,
+    tx: &EventSender,
+    state_tx: &watch::Sender<Value>,
+) -> Result<Option<Value>> {
+    let feeder = cell.feeder(&request.feeder)?;
+    let event = feeder.add_new_material(request.count);
+    tx.send(event);
+    state_tx.send(cell.state()).ok();
+    Ok(None)
+}
+
+/// Reload the settings, from the content of the request or else the settings file, and apply the
+/// changed ones. Thresholds, rates and timeouts are applied to the running components, a change to
+/// the wiring of the cell re-creates it as a whole, its components releasing their lines before
+/// they're requested again. Other settings the cell isn't built from, e.g. the credentials, apply
+/// after a restart and are listed as such in the outcome.
+///
+/// Changed topics are rejected as a whole, the previous settings staying in place, since every
+/// client of the backend subscribes and publishes with the topics it started with.
+///
+/// When the changed settings can't be applied, the previous ones are applied again. Only when the
+/// cell can't be re-created on those either is an error returned, without the cell
+pub fn reload_config(
+    config: &mut Config,
+    mut cell: ComponentRegistry,
+    gpio: &mut dyn GpioProvider,
+    request: ReloadConfigRequest,
+    tx: &EventSender,
+    state_tx: &watch::Sender<Value>,
+) -> Result<(ComponentRegistry, Result<Option<Value>>)> {
+    let reloaded = match config.reload(request.content.as_deref()) {
+        Ok(reloaded) => reloaded,
+        Err(e) => return Ok((cell, Err(e.into()))),
+    };
+    let previous = config.clone();
+    let changes = config.apply(reloaded);
+    if changes.is_empty() {
+        return Ok((cell, Ok(Some(json!({ "changed": changes })))));
+    }
+    info!("Settings changed: {}", changes.join(", "));
+
+    let topics: Vec<_> = changes
+        .iter()
+        .filter(|variable| backend::is_topic_setting(variable))
+        .cloned()
+        .collect();
+    if !topics.is_empty() {
+        config.apply(previous);
+        let e = eyre!(
+            "The topics can't be changed while the twin is running, restart it to change {}",
+            topics.join(", ")
+        );
+        return Ok((cell, Err(e)));
+    }
+
+    // the executor reads the progress interval from the settings with each start
+    let (applied, others): (Vec<_>, Vec<_>) = changes.iter().cloned().partition(|variable| {
+        RUNTIME_SETTINGS.contains(&variable.as_str()) || variable == "PROGRESS_EVERY"
+    });
+    let (wiring, restart): (Vec<_>, Vec<_>) = others
+        .into_iter()
+        .partition(|variable| ComponentRegistry::depends_on(&config.settings, variable));
+    if !restart.is_empty() {
+        warn!("Settings applied after a restart: {}", restart.join(", "));
+    }
+
+    let result = if wiring.is_empty() {
+        let settings: Vec<_> = applied
+            .iter()
+            .filter(|variable| RUNTIME_SETTINGS.contains(&variable.as_str()))
+            .cloned()
+            .collect();
+        cell.apply_settings(config, &settings, tx).inspect_err(|_| {
+            config.apply(previous);
+            if let Err(e) = cell.apply_settings(config, &settings, tx) {
+                error!("Failed to apply the previous settings again: {e}");
+            }
+        })
+    } else {
+        let estop = cell.estop.take();
+        let camera = cell.camera.take();
+        let carryover = cell.carryover();
+        let result = match cell.rebuild(config, gpio, &wiring) {
+            Ok(rebuilt) => {
+                cell = rebuilt;
+                Ok(())
+            }
+            // the components were dropped along the way, releasing their lines
+            Err(e) => {
+                config.apply(previous);
+                cell = ComponentRegistry::from_config(config, gpio)?;
+                cell.restore(&carryover);
+                Err(e)
+            }
+        };
+        cell.estop = estop;
+        cell.camera = camera;
+        result
+    };
+    state_tx.send(cell.state()).ok();
+
+    let outcome = json!({
+        "changed": changes,
+        "applied": applied,
+        "recreated": wiring,
+        "restart": restart,
+    });
+    Ok((cell, result.map(|_| Some(outcome))))
+}
+
+/// Move the robot arm to the position in the request, publishing the positions it reaches, until
+/// it's there or the move is cancelled. The position it ends up at is reported either way
+pub async fn move_robot(
+    cell: &mut ComponentRegistry,
+    request: MoveRobot,
+    tx: &EventSender,
+    state_tx: &watch::Sender<Value>,
+    cancel: &CancellationToken,
+) -> Result<Option<Value>> {
+    cell.check_move(request.position)?;
+    let moved = cell.robot.move_to(request.position, tx, cancel).await;
+    state_tx.send(cell.state()).ok();
+
+    let event = moved?;
+    tx.send(event);
+    Ok(Some(json!({ "position": request.position })))
+}
+
+/// Move the stepper motor to the position in the request, or by its steps, until it's there or the
+/// move is cancelled. The position it ends up at is reported either way
+pub async fn move_stepper(
+    cell: &mut ComponentRegistry,
+    request: MoveStepper,
+    tx: &EventSender,
+    state_tx: &watch::Sender<Value>,
+    cancel: &CancellationToken,
+) -> Result<Option<Value>> {
+    let stepper = cell.stepper()?;
+    let moved = match (request.position, request.steps) {
+        (Some(position), _) => stepper.move_to(position, cancel).await,
+        (None, Some(steps)) => stepper.move_by(steps, cancel).await,
+        (None, None) => Err(eyre!(
+            "Missing the position or steps to move the stepper by"
+        )),
+    };
+    let position = stepper.position();
+    state_tx.send(cell.state()).ok();
+
+    let event = moved?;
+    tx.send(event);
+    Ok(Some(json!({ "position": position })))
+}
+
+/// Home the stepper motor against its limit switch, publishing the homed event as confirmation
+pub async fn home_stepper(
+    cell: &mut ComponentRegistry,
+    tx: &EventSender,
+    state_tx: &watch::Sender<Value>,
+    cancel: &CancellationToken,
+) -> Result<Option<Value>> {
+    let homed = cell.stepper()?.home(cancel).await;
+    state_tx.send(cell.state()).ok();
+
+    let event = homed?;
+    tx.send(event);
+    Ok(None)
+}
+
+/// Publish the presses and releases of the e-stop button, a press stopping the cell like an
+/// emergency stop from the cloud. Watched until the line of the button stops sending events, or
+/// until shutdown is cancelled, so nothing is sent once the events are no longer published
+pub async fn watch_estop(
+    mut button: EmergencyStopButton,
+    trigger: EmergencyTrigger,
+    tx: EventSender,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let event = tokio::select! {
+            event = button.async_next_event() => match event {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("No longer watching the e-stop button: {e}");
+                    break;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        if let estop::Event::Asserted = event {
+            trigger.trigger(EmergencyStopRequest {
+                reason: Some("E-stop button pressed".to_string()),
+            });
+        }
+        tx.send(event);
+    }
+}
+
+/// Fail every queued command with the reason, e.g. nothing queued before an emergency stop should
+/// run after it
+pub async fn discard_queued(
+    command_rx: &mut UnboundedReceiver<Queued>,
+    pending: &Pending,
+    acks: &Acknowledger,
+    reason: &str,
+) {
+    while let Ok((id, _)) = command_rx.try_recv() {
+        let error = format!("Discarded {reason}");
+        acks.send(id.as_deref(), AckStatus::Failed, Some(error))
+            .await;
+        pending.done();
+    }
+}
+
+/// Wait for the twin to be asked to shut down, by systemd with a SIGTERM or from the terminal with
+/// a SIGINT. Returns the name of the signal
+pub async fn shutdown_signal() -> Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        interrupted = tokio::signal::ctrl_c() => interrupted.map(|_| "SIGINT").map_err(Into::into),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
@@ -0,0 +1,220 @@
+use crate::config::Settings;
+use crate::vitals::Vitals;
+use color_eyre::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Map, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// A condition the twin has to meet to take commands, e.g. being connected to the broker
+type Check = Box<dyn Fn() -> Result<()> + Send>;
+
+#[derive(Default)]
+struct Probes {
+    /// the conditions of /readyz, by name
+    checks: Vec<(String, Check)>,
+    started: bool,
+}
+
+/// What the twin reports on /healthz and /readyz, shared by the startup and the server, cheap to
+/// clone.
+///
+/// The twin is healthy for as long as every task of its vitals runs, e.g. the event publisher, an
+/// orchestrator restarts it otherwise. It's ready once it's started and every check passes, e.g.
+/// it's connected to the broker and the GPIO chip can be opened
+#[derive(Clone)]
+pub struct Health {
+    probes: Arc<Mutex<Probes>>,
+    vitals: Vitals,
+}
+
+impl Health {
+    pub fn new(vitals: Vitals) -> Self {
+        Self {
+            probes: Arc::default(),
+            vitals,
+        }
+    }
+
+    /// Only report the twin ready while the named check passes, it's run on every request
+    pub fn require(&self, name: &str, check: impl Fn() -> Result<()> + Send + 'static) {
+        self.probes
+            .lock()
+            .unwrap()
+            .checks
+            .push((name.to_string(), Box::new(check)));
+    }
+
+    /// The twin is done starting, it's ready whenever its checks pass from now on
+    pub fn started(&self) {
+        self.probes.lock().unwrap().started = true;
+    }
+
+    /// Whether every watched task is running, with the state of each of them
+    pub fn liveness(&self) -> (bool, Value) {
+        let (alive, tasks) = self.tasks();
+        (alive, json!({ "status": status(alive), "tasks": tasks }))
+    }
+
+    /// Whether the twin is started, alive and passes every check, with the outcome of each of them
+    pub fn readiness(&self) -> (bool, Value) {
+        let (alive, tasks) = self.tasks();
+        let probes = self.probes.lock().unwrap();
+        let mut passed = true;
+        let checks: Map<String, Value> = probes
+            .checks
+            .iter()
+            .map(|(name, check)| {
+                let outcome = match check() {
+                    Ok(()) => "ok".to_string(),
+                    Err(e) => {
+                        passed = false;
+                        e.to_string()
+                    }
+                };
+                (name.clone(), Value::from(outcome))
+            })
+            .collect();
+
+        let ready = probes.started && alive && passed;
+        let report = json!({
+            "status": status(ready),
+            "started": probes.started,
+            "tasks": tasks,
+            "checks": checks,
+        });
+        (ready, report)
+    }
+
+    /// Whether every watched task is running, with the state of each of them
+    fn tasks(&self) -> (bool, Map<String, Value>) {
+        let tasks: Map<String, Value> = self
+            .vitals
+            .tasks()
+            .into_iter()
+            .map(|(name, running)| {
+                let state = if running { "running" } else { "stopped" };
+                (name, Value::from(state))
+            })
+            .collect();
+        let alive = tasks.values().all(|state| state == "running");
+        (alive, tasks)
+    }
+}
+
+fn status(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "failing"
+    }
+}
+
+/// The response to a probe, 503 when the twin isn't healthy or ready
+fn respond(health: &Health, request: &Request<Body>) -> Response<Body> {
+    let (ok, report) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") => health.liveness(),
+        (&Method::GET, "/readyz") => health.readiness(),
+        _ => {
+            // the status alone is a valid response, unwrap is safe
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap();
+        }
+    };
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    // the status and content type are valid, unwrap is safe
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(report.to_string()))
+        .unwrap()
+}
+
+/// Serve /healthz and /readyz on HEALTH_ADDR, e.g. 0.0.0.0:8080, for container orchestrators and
+/// the plant monitoring system. None when it isn't set
+pub fn spawn(settings: &Settings, health: Health) -> Result<Option<JoinHandle<()>>> {
+    let address: SocketAddr = match settings.parse("HEALTH_ADDR", "an address")? {
+        Some(address) => address,
+        None => return Ok(None),
+    };
+
+    let server = Server::try_bind(&address)?.serve(make_service_fn(move |_| {
+        let health = health.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&health, &request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    }));
+    info!("Serving the health probes on {address}");
+
+    Ok(Some(tokio::task::spawn(async move {
+        if let Err(e) = server.await {
+            error!("The health probes are no longer served: {e}");
+        }
+    })))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use color_eyre::eyre::eyre;
+
+    fn get(health: &Health, path: &str) -> StatusCode {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        respond(health, &request).status()
+    }
+
+    #[test]
+    fn unhealthy_once_a_watched_task_is_gone() {
+        let vitals = Vitals::new();
+        let health = Health::new(vitals.clone());
+        let vital = vitals.watch("event publisher");
+        assert_eq!(get(&health, "/healthz"), StatusCode::OK);
+
+        drop(vital);
+        assert_eq!(get(&health, "/healthz"), StatusCode::SERVICE_UNAVAILABLE);
+        let (_, report) = health.liveness();
+        assert_eq!(report["tasks"]["event publisher"], "stopped");
+        assert_eq!(get(&health, "/metrics"), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn ready_once_started_with_every_check_passing() {
+        let health = Health::new(Vitals::new());
+        let connected = Arc::new(Mutex::new(true));
+        health.require("mqtt", {
+            let connected = connected.clone();
+            move || {
+                if *connected.lock().unwrap() {
+                    Ok(())
+                } else {
+                    Err(eyre!("Disconnected from the broker"))
+                }
+            }
+        });
+        assert_eq!(get(&health, "/readyz"), StatusCode::SERVICE_UNAVAILABLE);
+
+        health.started();
+        assert_eq!(get(&health, "/readyz"), StatusCode::OK);
+
+        *connected.lock().unwrap() = false;
+        let (ready, report) = health.readiness();
+        assert!(!ready);
+        assert_eq!(report["checks"]["mqtt"], "Disconnected from the broker");
+        // a disconnected twin is still alive, restarting it wouldn't help
+        assert_eq!(get(&health, "/healthz"), StatusCode::OK);
+    }
+}
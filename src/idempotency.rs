@@ -0,0 +1,174 @@
+use crate::config::Settings;
+use crate::utils;
+use color_eyre::Result;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::task;
+
+const DEFAULT_CAPACITY: usize = 1000;
+
+/// Idempotency keys of the commands already completed or still running, so a duplicate delivery,
+/// e.g. a config sent again on resubscribe, is acknowledged without running it again. A command
+/// that failed is forgotten, its next delivery is run.
+///
+/// Keys are appended as lines to IDEMPOTENCY_PATH so they survive a restart of the twin. Only the
+/// latest IDEMPOTENCY_CAPACITY keys are remembered, the file is compacted once it holds twice that
+pub struct IdempotencyStore {
+    path: PathBuf,
+    capacity: usize,
+    keys: VecDeque<String>,
+    /// number of lines in the file, including the keys already forgotten
+    lines: usize,
+    /// keys of the commands accepted and not concluded yet, by the id of the command
+    accepted: HashMap<String, String>,
+}
+
+impl IdempotencyStore {
+    pub async fn from_settings(settings: &Settings) -> Result<Self> {
+        let path = settings
+            .get("IDEMPOTENCY_PATH")
+            .unwrap_or("idempotency_keys.txt");
+        let capacity = settings
+            .parse("IDEMPOTENCY_CAPACITY", "unsigned integer")?
+            .unwrap_or(DEFAULT_CAPACITY);
+
+        Self::load(path, capacity).await
+    }
+
+    /// Reads the keys recorded before a restart, a missing file has none
+    pub async fn load(path: impl Into<PathBuf>, capacity: usize) -> Result<Self> {
+        let path = path.into();
+        let content = match fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let lines: Vec<_> = content.lines().filter(|line| !line.is_empty()).collect();
+        let keys = lines
+            .iter()
+            .skip(lines.len().saturating_sub(capacity))
+            .map(|key| key.to_string())
+            .collect();
+
+        Ok(Self {
+            path,
+            capacity,
+            keys,
+            lines: lines.len(),
+            accepted: HashMap::new(),
+        })
+    }
+
+    /// Whether the command with the key completed or is still running
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.iter().any(|recorded| recorded == key)
+            || self.accepted.values().any(|accepted| accepted == key)
+    }
+
+    /// Hold the key of an accepted command until it's concluded. A command without an id is never
+    /// concluded, the cloud can't be told how it went, so its key is recorded right away
+    pub async fn accept(&mut self, id: Option<&str>, key: &str) -> Result<()> {
+        match id {
+            Some(id) => {
+                self.accepted.insert(id.to_string(), key.to_string());
+                Ok(())
+            }
+            None => self.record(key).await,
+        }
+    }
+
+    /// Record the key of the command once it completed, the key of a failed one is forgotten so
+    /// the command is run again when it's delivered again
+    pub async fn conclude(&mut self, id: &str, completed: bool) -> Result<()> {
+        match self.accepted.remove(id) {
+            Some(key) if completed => self.record(&key).await,
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn record(&mut self, key: &str) -> Result<()> {
+        // keys end at the line break they're stored with
+        let key = key.replace('\n', " ");
+        self.keys.push_back(key.clone());
+        if self.keys.len() > self.capacity {
+            self.keys.pop_front();
+        }
+
+        if self.lines + 1 > self.capacity * 2 {
+            return self.compact().await;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(format!("{key}\n").as_bytes()).await?;
+        file.flush().await?;
+        self.lines += 1;
+        Ok(())
+    }
+
+    /// Rewrite the file with only the keys still remembered
+    async fn compact(&mut self) -> Result<()> {
+        let mut content = String::new();
+        for key in &self.keys {
+            content.push_str(key);
+            content.push('\n');
+        }
+
+        let path = self.path.clone();
+        task::spawn_blocking(move || utils::write_atomic(&path, content)).await??;
+        self.lines = self.keys.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn keys_survive_a_restart() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("idempotency.txt");
+
+        let mut store = IdempotencyStore::load(&path, 2).await?;
+        for key in ["a", "b", "c", "d", "e"] {
+            store.record(key).await?;
+        }
+        assert!(!store.contains("c"));
+        assert!(store.contains("e"));
+
+        let store = IdempotencyStore::load(&path, 2).await?;
+        assert!(!store.contains("a"));
+        assert!(store.contains("d"));
+        assert!(store.contains("e"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_completed_commands_are_recorded() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("idempotency.txt");
+
+        let mut store = IdempotencyStore::load(&path, 10).await?;
+        store.accept(Some("1"), "start-1").await?;
+        store.accept(Some("2"), "start-2").await?;
+        // running commands are duplicates too
+        assert!(store.contains("start-1"));
+
+        store.conclude("1", false).await?;
+        store.conclude("2", true).await?;
+        assert!(!store.contains("start-1"));
+        assert!(store.contains("start-2"));
+
+        let store = IdempotencyStore::load(&path, 10).await?;
+        assert!(!store.contains("start-1"));
+        assert!(store.contains("start-2"));
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+//! The digital twin of the manufacturing cell: the components of the cell and the programs driving
+//! them, the clients of the cloud backends, and the pipeline publishing the events of the cell.
+//!
+//! The twin module wires them together from the settings, which is all the tvilling binary runs.
+//! Other tools such as simulators and test harnesses can use them on their own
+
+pub mod ack;
+pub mod aws_iot;
+pub mod backend;
+pub mod batcher;
+pub mod cancellation;
+pub mod compression;
+pub mod config;
+pub mod diagnostics;
+pub mod dispatcher;
+pub mod encoding;
+pub mod envelope;
+pub mod gcp_iot;
+pub mod handlers;
+pub mod health;
+pub mod idempotency;
+pub mod manufacturing_components;
+pub mod metrics;
+pub mod mirror;
+pub mod mqtt_broker;
+pub mod offline_buffer;
+pub mod opcua_server;
+pub mod pipeline;
+pub mod publisher;
+pub mod rate_limiter;
+pub mod reconnect;
+pub mod rotation;
+pub mod scheduler;
+pub mod session;
+pub mod signature;
+pub mod sparkplug;
+pub mod state_reporter;
+pub mod tls;
+pub mod transport;
+pub mod twin;
+pub mod utils;
+pub mod vitals;
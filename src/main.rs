@@ -1,119 +1,131 @@
-mod gcp_iot;
-mod manufacturing_components;
-mod utils;
-
-use crate::gcp_iot::message::StartRequest;
-use crate::gcp_iot::GoogleIotConnect;
-use crate::manufacturing_components::feeder::{Event as FeederEvent, Feeder};
-use crate::manufacturing_components::program::{ManufacturingProgram, SimplifiedScenario2};
-use base64::{decode, URL_SAFE};
+use clap::Parser;
+use cli::{Cli, GpioCommand};
 use color_eyre::Result;
 use dotenv::dotenv;
-use futures::stream::StreamExt;
-use gpio_cdev::Chip;
-use log::{info, log};
-use paho_mqtt::{AsyncClient, QOS_1};
-use pretty_env_logger;
-use std::env;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use gpio_cdev::{Chip, LineDirection};
+use serde_json::json;
+use std::path::Path;
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+use tvilling::backend::Backend;
+use tvilling::config::{Config, Settings};
+use tvilling::transport::{Message, QOS_1};
+use tvilling::twin;
+use tvilling::utils::{Iso8601Utc, SystemTime};
+
+mod cli;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
-    pretty_env_logger::init();
+    // RUST_LOG picks what's logged, e.g. RUST_LOG=tvilling=debug, every level from info by default
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .init();
     color_eyre::install()?;
 
-    // any events we wish to sent to the google cloud is sent across the channel to be processed by a
-    // dedicated task
-    let (mut tx, mut rx) = unbounded_channel();
-
-    // a dedicated task just to process events to be sent to google cloud
-    let event_processor = tokio::task::spawn(async move {
-        while let Some(event) = rx.recv().await {
-            println!("{event:?}");
-        }
-    });
-
-    let mut client = AsyncClient::gcp_connect().await?;
-    let mut msg_stream = client.get_stream(100);
-
-    let device_id = env::var("DEVICE_ID").expect("Missing DEVICE_ID in environment variables");
-
-    // config used to ease development, feel free to change to any more appropriate topic names
-    let config_topic = format!("/devices/{device_id}/config");
-    client.subscribe(&config_topic, QOS_1).await?;
-
-    let mut gpio_chip = Chip::new("/dev/gpiochip0")
-        .expect("Unable to gain access to /dev/gpiochip0, make sure you have read and write permission to it");
-
-    let material_line: u32 = env::var("MATERIAL_LINE")
-        .expect("Missing MATERIAL_LINE in environment variables")
-        .parse()
-        .expect("MATERIAL_LINE cannot be parsed as unsigned integer");
-
-    let program_controller: u32 = env::var("PROGRAM_CONTROL")
-        .expect("Missing PROGRAM_CONTROL in environment variables")
-        .parse()
-        .expect("PROGRAM_CONTROL cannot be parsed as unsigned integer");
-
-    let mut program_controller = SimplifiedScenario2::new(&mut gpio_chip, program_controller)?;
-
-    let mut material_feeder = Feeder::new("Material feeder", 10, &mut gpio_chip, material_line)?;
-
-    let gcp_listener = tokio::task::spawn(async move {
-        while let Some(msg) = msg_stream.next().await {
-            let msg = msg.unwrap();
-
-            if msg.topic() == &config_topic {
-                // this is inefficient, only there to easy development
-                let payload_str = msg.payload_str();
-                println!("{payload_str:?}");
-
-                let request: StartRequest = serde_json::from_str(payload_str.as_ref()).unwrap();
+    let cli = Cli::parse();
+    let overrides = cli.variables();
+    match cli.command.unwrap_or(cli::Command::Run) {
+        cli::Command::Run => twin::run(Config::load(&overrides)?).await,
+        cli::Command::CheckConfig => check_config(&overrides),
+        cli::Command::Gpio {
+            command: GpioCommand::List { chip },
+        } => gpio_list(&overrides, &chip),
+        cli::Command::PublishTest { component } => publish_test(&overrides, &component).await,
+    }
+}
 
-                // unwrap for ease of development
-                simplified_scenario2_cycle(
-                    request.count,
-                    &mut material_feeder,
-                    &mut program_controller,
-                    &mut tx,
-                )
-                .await
-                .unwrap();
-            }
+/// Report every problem with the settings, or else the lines they wire the cell to
+fn check_config(overrides: &[(String, String)]) -> Result<()> {
+    let config = Config::load(overrides)?;
+    let lines = &config.lines;
+
+    println!(
+        "The settings are valid, the twin reports to {}",
+        config.device.backend
+    );
+    for (setting, line) in [
+        ("material", Some(lines.material)),
+        ("feeder_b", lines.feeder_b),
+        ("robot", Some(lines.robot)),
+        ("piston", Some(lines.piston)),
+        ("piston_output", Some(lines.piston_output)),
+        ("conveyor", Some(lines.conveyor)),
+        ("conveyor_output", Some(lines.conveyor_output)),
+        ("program_control", Some(lines.program_control)),
+    ] {
+        if let Some(line) = line {
+            println!("  lines.{setting} = {line}");
         }
-    });
-
-    gcp_listener.await?;
-    event_processor.await?;
+    }
     Ok(())
 }
 
-/// Start running the simplified scenario 2 program until there are no materials left, returning the
-/// the number of materials picked up
-async fn simplified_scenario2_cycle(
-    count: u32,
-    feeder: &mut Feeder,
-    program: &mut SimplifiedScenario2,
-    tx: &mut UnboundedSender<FeederEvent>,
-) -> Result<u32> {
-    program.start()?;
-
-    for _i in 0..count {
-        assert!(!feeder.is_empty());
-        // wait for some material to be picked up and sent the event across the channel
-        let event = feeder.async_next_event().await?;
-
-        // tx should be alive, unwrap is safe
-        tx.send(event).unwrap();
-
-        // wait for the materials to be pushed
-        feeder.async_next_event().await?;
+/// List the lines of the chip with their use, and the settings wiring components to them
+fn gpio_list(overrides: &[(String, String)], chip: &Path) -> Result<()> {
+    // the listing helps fixing the settings, it doesn't need them valid
+    let settings = match Config::load(overrides) {
+        Ok(config) => config.settings,
+        Err(e) => {
+            warn!("Listing the settings of the environment only, {e}");
+            Settings::from_env(overrides)
+        }
+    };
+    let wired: Vec<(String, u32)> = settings
+        .iter()
+        .filter(|(variable, _)| variable.ends_with("_LINE") || *variable == "PROGRAM_CONTROL")
+        .filter_map(|(variable, line)| Some((variable.to_string(), line.parse().ok()?)))
+        .collect();
+
+    let chip = Chip::new(chip)?;
+    println!(
+        "{:<6} {:<24} {:<10} {:<24} SETTINGS",
+        "LINE", "NAME", "DIRECTION", "CONSUMER"
+    );
+    for line in chip.lines() {
+        let info = line.info()?;
+        let direction = match info.direction() {
+            LineDirection::In => "input",
+            LineDirection::Out => "output",
+        };
+        let settings: Vec<&str> = wired
+            .iter()
+            .filter(|(_, wired)| *wired == line.offset())
+            .map(|(variable, _)| variable.as_str())
+            .collect();
+        println!(
+            "{:<6} {:<24} {:<10} {:<24} {}",
+            line.offset(),
+            info.name().unwrap_or("-"),
+            direction,
+            info.consumer().unwrap_or("-"),
+            settings.join(", ")
+        );
     }
+    Ok(())
+}
 
-    program.stop()?;
+/// Publish a test event for the component on its events topic, done once the broker acknowledges
+/// it
+async fn publish_test(overrides: &[(String, String)], component: &str) -> Result<()> {
+    let config = Config::load(overrides)?;
+    let backend = Backend::from_settings(&config.settings)?;
+    let (client, _) = backend.connect().await?;
+
+    let topic = backend.event_topic(component)?;
+    let payload = json!({
+        "test": true,
+        "deviceId": backend.device_id(),
+        "timestamp": SystemTime::iso8601_now(),
+    });
+    client
+        .publish(Message::new(&topic, payload.to_string(), QOS_1))
+        .await?;
+    println!("Published a test event on {topic}");
 
-    Ok(count)
+    backend.disconnect(&client).await?;
+    Ok(())
 }
 
 #[cfg(test)]
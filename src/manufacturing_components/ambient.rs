@@ -0,0 +1,193 @@
+use crate::manufacturing_components::{Component, ComponentEvent};
+use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use embedded_hal::blocking::i2c::{Read, Write};
+use linux_embedded_hal::I2cdev;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime};
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tracing::error;
+
+/// Address of an SHT31 with its ADDR pin low, 0x45 when it's high
+pub const DEFAULT_ADDRESS: u8 = 0x44;
+/// Time between two samples of a sensor that wasn't given one
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Single shot measurement with high repeatability, without clock stretching
+const MEASURE: [u8; 2] = [0x24, 0x00];
+/// Longest a high repeatability measurement takes
+const MEASUREMENT_TIME: Duration = Duration::from_millis(16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Reading {
+    /// in degrees Celsius
+    pub temperature: f32,
+    /// relative humidity, in percent
+    pub humidity: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    Reading(Reading),
+}
+
+/// An SHT31 temperature and humidity sensor on an I2C bus, sampling the ambient conditions at the
+/// cell on an interval. A sample that fails is logged and taken again at the next interval
+pub struct AmbientSensor {
+    name: String,
+    i2c: I2cdev,
+    address: u8,
+    interval: Interval,
+    /// the last sample taken, None until one succeeds
+    reading: Option<Reading>,
+}
+
+impl Serialize for AmbientSensor {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("ambient", 4)?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("temperature", &self.reading.map(|r| r.temperature))?;
+        s.serialize_field("humidity", &self.reading.map(|r| r.humidity))?;
+
+        let now = SystemTime::iso8601_now();
+        s.serialize_field("updateTimestamp", &now)?;
+
+        s.end()
+    }
+}
+
+impl AmbientSensor {
+    /// The sensor at address on the bus, e.g. /dev/i2c-1, sampled every interval starting now
+    pub fn new(name: &str, bus: &str, address: u8, interval: Duration) -> Result<Self> {
+        let i2c = I2cdev::new(bus).map_err(|e| eyre!("Failed to open the I2C bus {bus}: {e}"))?;
+        let mut interval = time::interval(interval);
+        // a cycle polls the sensor along with the rest of the cell, samples aren't caught up on
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Ok(Self {
+            name: name.to_string(),
+            i2c,
+            address,
+            interval,
+            reading: None,
+        })
+    }
+
+    /// Sample every interval from now on
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = time::interval(interval);
+        self.interval
+            .set_missed_tick_behavior(MissedTickBehavior::Delay);
+    }
+
+    /// Take a single measurement
+    async fn sample(&mut self) -> Result<Reading> {
+        self.i2c
+            .write(self.address, &MEASURE)
+            .map_err(|e| eyre!("Failed to start a measurement of {}: {e}", self.name))?;
+        time::sleep(MEASUREMENT_TIME).await;
+
+        let mut data = [0; 6];
+        self.i2c
+            .read(self.address, &mut data)
+            .map_err(|e| eyre!("Failed to read the measurement of {}: {e}", self.name))?;
+        decode(&data)
+    }
+
+    /// Wait for the next sample that succeeds
+    pub async fn async_next_event(&mut self) -> Event {
+        loop {
+            self.interval.tick().await;
+            match self.sample().await {
+                Ok(reading) => {
+                    self.reading = Some(reading);
+                    return Event::Reading(reading);
+                }
+                Err(e) => error!("{e}"),
+            }
+        }
+    }
+}
+
+/// Convert the raw temperature and humidity words of a measurement, each followed by its CRC
+fn decode(data: &[u8; 6]) -> Result<Reading> {
+    if crc8(&data[0..2]) != data[2] || crc8(&data[3..5]) != data[5] {
+        return Err(eyre!("The measurement failed its checksum"));
+    }
+    let temperature = u16::from_be_bytes([data[0], data[1]]) as f32;
+    let humidity = u16::from_be_bytes([data[3], data[4]]) as f32;
+
+    Ok(Reading {
+        temperature: -45.0 + 175.0 * temperature / 65535.0,
+        humidity: 100.0 * humidity / 65535.0,
+    })
+}
+
+/// CRC-8 of the SHT3x, polynomial 0x31 starting from 0xFF
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[async_trait]
+impl Component for AmbientSensor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "ambient"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksums_match_the_datasheet() {
+        assert_eq!(crc8(&[0xBE, 0xEF]), 0x92);
+    }
+
+    #[test]
+    fn measurements_are_converted_to_degrees_and_percent() {
+        let reading = decode(&[
+            0x66,
+            0x66,
+            crc8(&[0x66, 0x66]),
+            0x80,
+            0x00,
+            crc8(&[0x80, 0x00]),
+        ])
+        .unwrap();
+        assert!((reading.temperature - 25.0).abs() < 0.01);
+        assert!((reading.humidity - 50.0).abs() < 0.01);
+
+        assert!(decode(&[0x66, 0x66, 0, 0x80, 0x00, 0]).is_err());
+    }
+}
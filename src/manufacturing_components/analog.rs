@@ -0,0 +1,374 @@
+use crate::config::{ConfigError, Settings};
+use crate::manufacturing_components::spi::SpiDevice;
+use crate::manufacturing_components::{Component, ComponentEvent};
+use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime};
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tracing::error;
+
+/// Time between two samples of an input that wasn't given one
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An analog to digital converter, sampling the voltage on one of its channels
+#[async_trait]
+pub trait Adc: Send {
+    /// Convert the channel, returning the raw value
+    async fn read(&mut self, channel: u8) -> Result<u16>;
+
+    /// Raw value of a full scale input
+    fn full_scale(&self) -> u16;
+}
+
+/// An MCP3008, 8 channels of 10 bits on an SPI bus
+pub struct Mcp3008 {
+    spi: Box<dyn SpiDevice>,
+}
+
+impl Mcp3008 {
+    pub fn new(spi: Box<dyn SpiDevice>) -> Self {
+        Self { spi }
+    }
+}
+
+#[async_trait]
+impl Adc for Mcp3008 {
+    async fn read(&mut self, channel: u8) -> Result<u16> {
+        if channel > 7 {
+            return Err(eyre!(
+                "The MCP3008 has no channel {channel}, expected 0 to 7"
+            ));
+        }
+        // start bit, then single ended conversion of the channel, the result is clocked out in
+        // the last 10 bits
+        let read = self
+            .spi
+            .transfer(&[0x01, (0x08 | channel) << 4, 0x00])
+            .await?;
+        Ok(((read[1] as u16 & 0x03) << 8) | read[2] as u16)
+    }
+
+    fn full_scale(&self) -> u16 {
+        1023
+    }
+}
+
+/// Linear mapping of raw values to the quantity measured, from two points, e.g. the raw values
+/// read at 0 and 10 bar
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub raw: (u16, u16),
+    pub scaled: (f32, f32),
+}
+
+impl Calibration {
+    /// Raw values as they are, 0 to full scale
+    pub fn identity(full_scale: u16) -> Self {
+        Self {
+            raw: (0, full_scale),
+            scaled: (0.0, full_scale as f32),
+        }
+    }
+
+    /// Parse the two points as raw:scaled pairs, e.g. 102:0,921:10
+    fn parse(value: &str) -> Option<Self> {
+        let point = |point: &str| -> Option<(u16, f32)> {
+            let (raw, scaled) = point.split_once(':')?;
+            Some((raw.trim().parse().ok()?, scaled.trim().parse().ok()?))
+        };
+        let (low, high) = value.split_once(',')?;
+        let (low, high) = (point(low)?, point(high)?);
+        if low.0 == high.0 {
+            return None;
+        }
+        Some(Self {
+            raw: (low.0, high.0),
+            scaled: (low.1, high.1),
+        })
+    }
+
+    pub fn scale(&self, raw: u16) -> f32 {
+        let (raw_low, raw_high) = (self.raw.0 as f32, self.raw.1 as f32);
+        let (low, high) = self.scaled;
+        low + (raw as f32 - raw_low) * (high - low) / (raw_high - raw_low)
+    }
+}
+
+/// Which side of the range a value left it by
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bound {
+    Below,
+    Above,
+}
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    Sample {
+        input: String,
+        value: f32,
+        unit: String,
+    },
+    /// the value left the range of the alarm thresholds, raised once until it's back in range
+    OutOfRange {
+        input: String,
+        value: f32,
+        threshold: f32,
+        bound: Bound,
+    },
+}
+
+/// An analog signal of the cell, e.g. the air pressure of the piston or the tension of the feeder
+/// spring, sampled from a channel of an ADC on an interval and scaled to the quantity measured.
+/// A sample that fails is logged and taken again at the next interval
+pub struct AnalogInput {
+    name: String,
+    adc: Box<dyn Adc>,
+    channel: u8,
+    calibration: Calibration,
+    unit: String,
+    /// alarm thresholds, the value is out of range below the first or above the second
+    low: Option<f32>,
+    high: Option<f32>,
+    /// whether the alarm was raised since the value last left the range
+    out_of_range: bool,
+    interval: Interval,
+    /// the last value sampled, None until a sample succeeds
+    value: Option<f32>,
+    /// the out of range alarm of the last sample, published after it
+    pending_alarm: Option<Event>,
+}
+
+impl Serialize for AnalogInput {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("analog", 4)?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("value", &self.value)?;
+        s.serialize_field("unit", &self.unit)?;
+
+        let now = SystemTime::iso8601_now();
+        s.serialize_field("updateTimestamp", &now)?;
+
+        s.end()
+    }
+}
+
+impl AnalogInput {
+    /// The input on channel of the ADC, in raw values sampled every second until configured
+    /// otherwise
+    pub fn new(name: &str, adc: Box<dyn Adc>, channel: u8) -> Self {
+        let calibration = Calibration::identity(adc.full_scale());
+        Self {
+            name: name.to_string(),
+            adc,
+            channel,
+            calibration,
+            unit: String::new(),
+            low: None,
+            high: None,
+            out_of_range: false,
+            interval: sampling(DEFAULT_INTERVAL),
+            value: None,
+            pending_alarm: None,
+        }
+    }
+
+    /// The input configured from <NAME>_CHANNEL, <NAME>_CALIBRATION as two raw:scaled points,
+    /// <NAME>_UNIT, <NAME>_ALARM_BELOW, <NAME>_ALARM_ABOVE and <NAME>_INTERVAL in milliseconds,
+    /// where the name is upper cased, e.g. PISTON_PRESSURE_CHANNEL
+    pub fn from_settings(
+        settings: &Settings,
+        name: &str,
+        adc: Box<dyn Adc>,
+    ) -> Result<Self, ConfigError> {
+        let prefix = name.to_uppercase();
+        let number = |key: &str| -> Result<Option<f32>, ConfigError> {
+            settings.parse(&format!("{prefix}_{key}"), "a number")
+        };
+
+        let key = format!("{prefix}_CHANNEL");
+        let channel = settings
+            .parse(&key, "unsigned integer")?
+            .ok_or_else(|| ConfigError::missing(&key, "the input is sampled on it"))?;
+        let mut input = Self::new(name, adc, channel)
+            .with_thresholds(number("ALARM_BELOW")?, number("ALARM_ABOVE")?);
+
+        let key = format!("{prefix}_CALIBRATION");
+        if let Some(calibration) = settings.get(&key) {
+            let calibration = Calibration::parse(calibration)
+                .ok_or_else(|| ConfigError::invalid(&key, "raw:scaled,raw:scaled", calibration))?;
+            let unit = settings.get(&format!("{prefix}_UNIT")).map(str::to_string);
+            input = input.with_calibration(calibration, unit);
+        }
+        if let Some(millis) = number("INTERVAL")? {
+            input = input.with_interval(Duration::from_millis(millis as u64));
+        }
+        Ok(input)
+    }
+
+    /// Scale the raw values to the quantity measured, in unit when it has one
+    pub fn with_calibration(mut self, calibration: Calibration, unit: Option<String>) -> Self {
+        self.calibration = calibration;
+        self.unit = unit.unwrap_or_default();
+        self
+    }
+
+    /// Raise an alarm when the value drops below low or rises above high
+    pub fn with_thresholds(mut self, low: Option<f32>, high: Option<f32>) -> Self {
+        self.low = low;
+        self.high = high;
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = sampling(interval);
+        self
+    }
+
+    /// The last value sampled, None until a sample succeeds
+    pub fn value(&self) -> Option<f32> {
+        self.value
+    }
+
+    /// Wait for the next sample that succeeds, the alarm of a sample out of range is returned
+    /// right after it
+    pub async fn async_next_event(&mut self) -> Event {
+        if let Some(alarm) = self.pending_alarm.take() {
+            return alarm;
+        }
+
+        loop {
+            self.interval.tick().await;
+            match self.adc.read(self.channel).await {
+                Ok(raw) => {
+                    let value = self.calibration.scale(raw);
+                    self.value = Some(value);
+                    self.pending_alarm = self.alarm(value);
+                    return Event::Sample {
+                        input: self.name.clone(),
+                        value,
+                        unit: self.unit.clone(),
+                    };
+                }
+                Err(e) => error!("Failed to sample {}: {e}", self.name),
+            }
+        }
+    }
+
+    /// The alarm of a value out of range, once until it's back in range
+    fn alarm(&mut self, value: f32) -> Option<Event> {
+        let crossed = match (self.low, self.high) {
+            (Some(low), _) if value < low => Some((low, Bound::Below)),
+            (_, Some(high)) if value > high => Some((high, Bound::Above)),
+            _ => None,
+        };
+        let (threshold, bound) = match crossed {
+            Some(crossed) => crossed,
+            None => {
+                self.out_of_range = false;
+                return None;
+            }
+        };
+        if self.out_of_range {
+            return None;
+        }
+
+        self.out_of_range = true;
+        Some(Event::OutOfRange {
+            input: self.name.clone(),
+            value,
+            threshold,
+            bound,
+        })
+    }
+}
+
+fn sampling(interval: Duration) -> Interval {
+    let mut interval = time::interval(interval);
+    // a cycle polls the input along with the rest of the cell, samples aren't caught up on
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+#[async_trait]
+impl Component for AnalogInput {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "analog"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manufacturing_components::spi::mock::MockSpi;
+
+    #[test]
+    fn raw_values_are_scaled_between_the_calibration_points() {
+        let calibration = Calibration::parse("102:0,921:10").unwrap();
+        assert_eq!(calibration.scale(102), 0.0);
+        assert_eq!(calibration.scale(921), 10.0);
+        assert!((calibration.scale(511) - 4.994).abs() < 0.01);
+
+        assert_eq!(Calibration::parse("102:0,102:10"), None);
+        assert_eq!(Calibration::parse("102"), None);
+    }
+
+    #[tokio::test]
+    async fn mcp3008_reads_the_last_10_bits() {
+        let spi = MockSpi::default();
+        spi.responses
+            .lock()
+            .unwrap()
+            .push_back(vec![0xFF, 0xFE, 0x34]);
+        let mut adc = Mcp3008::new(Box::new(spi.clone()));
+
+        assert_eq!(adc.read(2).await.unwrap(), 0x234);
+        assert_eq!(spi.written.lock().unwrap()[0], [0x01, 0xA0, 0x00]);
+        assert!(adc.read(8).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn alarms_are_raised_once_until_back_in_range() {
+        let adc = Mcp3008::new(Box::new(MockSpi::default()));
+        let mut input =
+            AnalogInput::new("pressure", Box::new(adc), 0).with_thresholds(Some(2.0), Some(8.0));
+
+        assert!(input.alarm(5.0).is_none());
+        assert!(matches!(
+            input.alarm(1.0),
+            Some(Event::OutOfRange {
+                bound: Bound::Below,
+                ..
+            })
+        ));
+        assert!(input.alarm(0.5).is_none());
+        assert!(input.alarm(5.0).is_none());
+        assert!(matches!(
+            input.alarm(9.0),
+            Some(Event::OutOfRange {
+                bound: Bound::Above,
+                ..
+            })
+        ));
+    }
+}
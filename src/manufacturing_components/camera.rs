@@ -0,0 +1,272 @@
+use crate::config::{ConfigError, Settings};
+use crate::envelope::EventSender;
+use crate::gcp_iot::storage::{CloudStorage, Storage};
+use async_trait::async_trait;
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Serialize;
+use tokio::process::Command;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::error;
+
+/// Folder of the bucket snapshots are put in when the camera wasn't given one
+pub const DEFAULT_PREFIX: &str = "snapshots";
+
+/// The step of a cycle after which a snapshot is taken
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// a material was picked up from the feeder
+    Pickup,
+    /// the piston pressed a material
+    Press,
+    /// the arm dropped a pressed material off onto the conveyor at position 15
+    Dropoff,
+}
+
+impl Trigger {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pickup" => Some(Trigger::Pickup),
+            "press" => Some(Trigger::Press),
+            "dropoff" => Some(Trigger::Dropoff),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Trigger::Pickup => "pickup",
+            Trigger::Press => "press",
+            Trigger::Dropoff => "dropoff",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    /// a snapshot was uploaded to url, taken after the trigger in the cycle with the id
+    Snapshot {
+        trigger: Trigger,
+        cycle_id: String,
+        url: String,
+    },
+}
+
+/// Takes still pictures, JPEG encoded
+#[async_trait]
+pub trait Capture: Send {
+    async fn capture(&mut self) -> Result<Vec<u8>>;
+}
+
+/// A camera captured through libcamera-still, e.g. the Raspberry Pi camera module
+pub struct LibcameraStill {
+    width: u32,
+    height: u32,
+}
+
+#[async_trait]
+impl Capture for LibcameraStill {
+    async fn capture(&mut self) -> Result<Vec<u8>> {
+        // no preview, the picture is taken right away and written to stdout
+        let output = Command::new("libcamera-still")
+            .args([
+                "--nopreview",
+                "--immediate",
+                "--encoding",
+                "jpg",
+                "--output",
+                "-",
+            ])
+            .args(["--width", &self.width.to_string()])
+            .args(["--height", &self.height.to_string()])
+            .output()
+            .await
+            .map_err(|e| eyre!("Failed to run libcamera-still: {e}"))?;
+
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            let message = String::from_utf8_lossy(&output.stderr);
+            Err(eyre!(
+                "libcamera-still failed with {}: {message}",
+                output.status
+            ))
+        }
+    }
+}
+
+/// A snapshot to take
+#[derive(Debug)]
+struct Request {
+    trigger: Trigger,
+    cycle_id: String,
+}
+
+/// The camera of the cell, taking a snapshot after the steps of a cycle it's triggered by. The
+/// snapshots are queued for the CameraWorker, so the cycle doesn't wait on the capture and upload
+pub struct Camera {
+    triggers: Vec<Trigger>,
+    requests: UnboundedSender<Request>,
+}
+
+impl Camera {
+    /// The camera triggered by triggers, along with the worker taking its snapshots with capture
+    /// and uploading them to storage under prefix
+    pub fn new(
+        triggers: Vec<Trigger>,
+        capture: Box<dyn Capture>,
+        storage: Box<dyn Storage>,
+        prefix: &str,
+    ) -> (Self, CameraWorker) {
+        let (requests, rx) = unbounded_channel();
+        let worker = CameraWorker {
+            capture,
+            storage,
+            prefix: prefix.to_string(),
+            requests: rx,
+        };
+        (Self { triggers, requests }, worker)
+    }
+
+    /// The camera triggered by the comma separated CAMERA_TRIGGERS, pickup, press or dropoff,
+    /// taking CAMERA_WIDTH by CAMERA_HEIGHT pictures put under CAMERA_PREFIX in the Cloud Storage
+    /// bucket, None when the cell has no camera
+    pub fn from_settings(settings: &Settings) -> Result<Option<(Self, CameraWorker)>> {
+        let triggers = match settings.get("CAMERA_TRIGGERS") {
+            Some(triggers) => triggers
+                .split(',')
+                .map(str::trim)
+                .map(|trigger| {
+                    Trigger::parse(trigger).ok_or_else(|| {
+                        ConfigError::invalid("CAMERA_TRIGGERS", "pickup, press or dropoff", trigger)
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            None => return Ok(None),
+        };
+        let dimension = |key: &str, default: u32| -> Result<u32, ConfigError> {
+            Ok(settings.parse(key, "unsigned integer")?.unwrap_or(default))
+        };
+        let capture = LibcameraStill {
+            width: dimension("CAMERA_WIDTH", 1920)?,
+            height: dimension("CAMERA_HEIGHT", 1080)?,
+        };
+        let prefix = settings.get("CAMERA_PREFIX").unwrap_or(DEFAULT_PREFIX);
+
+        Ok(Some(Self::new(
+            triggers,
+            Box::new(capture),
+            Box::new(CloudStorage::from_settings(settings)?),
+            prefix,
+        )))
+    }
+
+    /// Take a snapshot for the cycle with the id, when the camera is triggered by trigger
+    pub fn snapshot(&self, trigger: Trigger, cycle_id: &str) {
+        if !self.triggers.contains(&trigger) {
+            return;
+        }
+        let request = Request {
+            trigger,
+            cycle_id: cycle_id.to_string(),
+        };
+        if self.requests.send(request).is_err() {
+            error!("The camera worker is gone, no snapshot is taken");
+        }
+    }
+}
+
+/// Takes the snapshots requested from the Camera one after the other, publishing the URL of each
+/// once it's uploaded. A snapshot that fails is logged and skipped
+pub struct CameraWorker {
+    capture: Box<dyn Capture>,
+    storage: Box<dyn Storage>,
+    prefix: String,
+    requests: UnboundedReceiver<Request>,
+}
+
+impl CameraWorker {
+    /// Take snapshots until the camera is dropped
+    pub async fn run(mut self, tx: EventSender) {
+        while let Some(request) = self.requests.recv().await {
+            match self.take(&request).await {
+                Ok(url) => tx.send(Event::Snapshot {
+                    trigger: request.trigger,
+                    cycle_id: request.cycle_id,
+                    url,
+                }),
+                Err(e) => error!(
+                    "Failed to take a snapshot after the {:?}: {e}",
+                    request.trigger
+                ),
+            }
+        }
+    }
+
+    /// Capture and upload a snapshot, named after the cycle, the trigger and the time it's taken
+    async fn take(&mut self, request: &Request) -> Result<String> {
+        let picture = self.capture.capture().await?;
+        let name = format!(
+            "{}/{}/{}-{}.jpg",
+            self.prefix,
+            request.cycle_id,
+            request.trigger.as_str(),
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+        );
+        self.storage.upload(&name, picture, "image/jpeg").await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::envelope::channel;
+    use crate::manufacturing_components::ComponentEvent;
+
+    struct Still;
+
+    #[async_trait]
+    impl Capture for Still {
+        async fn capture(&mut self) -> Result<Vec<u8>> {
+            Ok(vec![0xFF, 0xD8])
+        }
+    }
+
+    struct Bucket;
+
+    #[async_trait]
+    impl Storage for Bucket {
+        async fn upload(&self, name: &str, _: Vec<u8>, _: &str) -> Result<String> {
+            Ok(format!("https://storage.googleapis.com/bucket/{name}"))
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_taken_after_their_triggers() {
+        let (camera, worker) = Camera::new(
+            vec![Trigger::Press],
+            Box::new(Still),
+            Box::new(Bucket),
+            "snapshots",
+        );
+        let (tx, mut rx) = channel();
+
+        camera.snapshot(Trigger::Pickup, "cycle-1");
+        camera.snapshot(Trigger::Press, "cycle-1");
+        drop(camera);
+        worker.run(tx).await;
+
+        let envelope = rx.recv().await.unwrap();
+        match envelope.event {
+            ComponentEvent::Camera(Event::Snapshot { trigger, url, .. }) => {
+                assert_eq!(trigger, Trigger::Press);
+                assert!(url
+                    .starts_with("https://storage.googleapis.com/bucket/snapshots/cycle-1/press-"));
+            }
+            other => panic!("Unexpected event {other:?}"),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+}
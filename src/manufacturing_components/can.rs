@@ -0,0 +1,378 @@
+use crate::config::Settings;
+use crate::manufacturing_components::{Component, ComponentEvent};
+use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures::StreamExt;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Map, Value};
+use std::time::{Duration, SystemTime};
+use tokio::time;
+use tokio_socketcan::{CANFrame, CANSocket};
+use tracing::error;
+
+/// Interface of a device that wasn't given one
+pub const DEFAULT_INTERFACE: &str = "can0";
+/// Time waited before receiving again after the interface failed, e.g. while it's down
+const RECEIVE_RETRY: Duration = Duration::from_secs(1);
+
+/// How a signal is encoded in the data of its frames, always little endian
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    U8,
+    U16,
+    U32,
+    I8,
+    I16,
+    I32,
+}
+
+impl Encoding {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "u8" => Some(Encoding::U8),
+            "u16" => Some(Encoding::U16),
+            "u32" => Some(Encoding::U32),
+            "i8" => Some(Encoding::I8),
+            "i16" => Some(Encoding::I16),
+            "i32" => Some(Encoding::I32),
+            _ => None,
+        }
+    }
+
+    /// Bytes the signal takes in the data
+    fn len(self) -> usize {
+        match self {
+            Encoding::U8 | Encoding::I8 => 1,
+            Encoding::U16 | Encoding::I16 => 2,
+            Encoding::U32 | Encoding::I32 => 4,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> i64 {
+        match self {
+            Encoding::U8 => bytes[0] as i64,
+            Encoding::I8 => bytes[0] as i8 as i64,
+            Encoding::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as i64,
+            Encoding::I16 => i16::from_le_bytes([bytes[0], bytes[1]]) as i64,
+            Encoding::U32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64,
+            Encoding::I32 => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64,
+        }
+    }
+}
+
+/// A value carried by the frames of a CAN id, e.g. the position of the robot track
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signal {
+    pub name: String,
+    pub id: u32,
+    /// first byte of the value in the data
+    pub offset: usize,
+    pub encoding: Encoding,
+}
+
+impl Signal {
+    /// Signals in the comma separated list of name:id:offset:encoding, where the id is decimal or
+    /// hexadecimal prefixed with 0x and the encoding is u8, u16, u32, i8, i16 or i32, e.g.
+    /// position:0x181:0:i32,speed:0x181:4:u16
+    pub fn parse_list(value: &str) -> Result<Vec<Self>> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|signal| !signal.is_empty())
+            .map(|signal| match signal.split(':').collect::<Vec<_>>()[..] {
+                [name, id, offset, encoding] => {
+                    let id = match id.strip_prefix("0x") {
+                        Some(hex) => u32::from_str_radix(hex, 16),
+                        None => id.parse(),
+                    }
+                    .map_err(|_| eyre!("The id of {name} isn't a CAN id"))?;
+                    let encoding = Encoding::parse(encoding).ok_or_else(|| {
+                        eyre!("Unknown encoding {encoding} of {name}, expected u8, u16, u32, i8, i16 or i32")
+                    })?;
+                    let offset: usize = offset
+                        .parse()
+                        .map_err(|_| eyre!("The offset of {name} isn't a byte offset"))?;
+                    if offset + encoding.len() > 8 {
+                        return Err(eyre!("{name} doesn't fit in the 8 bytes of a frame"));
+                    }
+                    Ok(Signal {
+                        name: name.to_string(),
+                        id,
+                        offset,
+                        encoding,
+                    })
+                }
+                _ => Err(eyre!("{signal} isn't a signal, expected name:id:offset:encoding")),
+            })
+            .collect()
+    }
+
+    /// The value of the signal in the data of a frame of its id, None when the frame is too short
+    fn decode(&self, data: &[u8]) -> Option<i64> {
+        let bytes = data.get(self.offset..self.offset + self.encoding.len())?;
+        Some(self.encoding.decode(bytes))
+    }
+}
+
+/// Receives and sends the frames of a CAN bus, see SocketCan
+#[async_trait]
+pub trait CanBus: Send {
+    /// The id and data of the next frame on the bus
+    async fn receive(&mut self) -> Result<(u32, Vec<u8>)>;
+    async fn send(&mut self, id: u32, data: &[u8]) -> Result<()>;
+}
+
+/// A CAN interface of the board through SocketCAN, e.g. can0
+pub struct SocketCan {
+    interface: String,
+    socket: CANSocket,
+}
+
+impl SocketCan {
+    pub fn open(interface: &str) -> Result<Self> {
+        let socket = CANSocket::open(interface)
+            .map_err(|e| eyre!("Failed to open the CAN interface {interface}: {e}"))?;
+        Ok(Self {
+            interface: interface.to_string(),
+            socket,
+        })
+    }
+}
+
+#[async_trait]
+impl CanBus for SocketCan {
+    async fn receive(&mut self) -> Result<(u32, Vec<u8>)> {
+        loop {
+            let frame = self
+                .socket
+                .next()
+                .await
+                .ok_or_else(|| eyre!("The CAN interface {} was closed", self.interface))??;
+            // error frames report the state of the bus, they carry no signal
+            if !frame.is_error() {
+                return Ok((frame.id(), frame.data().to_vec()));
+            }
+        }
+    }
+
+    async fn send(&mut self, id: u32, data: &[u8]) -> Result<()> {
+        let frame = CANFrame::new(id, data, false, false)
+            .map_err(|e| eyre!("Frame {id:#x} can't be sent: {e}"))?;
+        self.socket.write_frame(frame)?.await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    /// a signal changed, or was received for the first time
+    Changed {
+        device: String,
+        signal: String,
+        value: i64,
+    },
+}
+
+/// A device of the cell reporting over CAN rather than through lines, e.g. the newer robot track
+/// reporting its position. The frames of the ids of its signals are decoded as they're received,
+/// a signal is published when it changes. A failure to receive is logged and received again.
+/// Programs send it command frames, see send
+pub struct CanDevice {
+    name: String,
+    bus: Box<dyn CanBus>,
+    signals: Vec<Signal>,
+    /// last value received of every signal, in the order of the signals
+    values: Vec<Option<i64>>,
+    /// the changes of the last frame not returned yet
+    changes: Vec<Event>,
+}
+
+impl Serialize for CanDevice {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let values: Map<String, Value> = self
+            .signals
+            .iter()
+            .zip(&self.values)
+            .filter_map(|(signal, value)| value.map(|value| (signal.name.clone(), json!(value))))
+            .collect();
+
+        let mut s = serializer.serialize_struct("can", 3)?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("values", &values)?;
+
+        let now = SystemTime::iso8601_now();
+        s.serialize_field("updateTimestamp", &now)?;
+
+        s.end()
+    }
+}
+
+impl CanDevice {
+    pub fn new(name: &str, bus: Box<dyn CanBus>, signals: Vec<Signal>) -> Self {
+        Self {
+            name: name.to_string(),
+            bus,
+            values: vec![None; signals.len()],
+            signals,
+            changes: Vec::new(),
+        }
+    }
+
+    /// The device named name, whose signals <NAME>_SIGNALS are received on the interface
+    /// <NAME>_INTERFACE, can0 by default, where the name is upper cased
+    pub fn from_settings(settings: &Settings, name: &str) -> Result<Self> {
+        let prefix = name.to_uppercase();
+
+        let key = format!("{prefix}_SIGNALS");
+        let signals = settings.require(&key, "they're decoded from the frames of the device")?;
+        let signals = Signal::parse_list(signals).map_err(|e| eyre!("{key}: {e}"))?;
+        let interface = settings
+            .get(&format!("{prefix}_INTERFACE"))
+            .unwrap_or(DEFAULT_INTERFACE);
+
+        let bus = SocketCan::open(interface)?;
+        Ok(Self::new(name, Box::new(bus), signals))
+    }
+
+    /// Send a command frame with the id and data, of up to 8 bytes, to the device
+    pub async fn send(&mut self, id: u32, data: &[u8]) -> Result<()> {
+        self.bus.send(id, data).await
+    }
+
+    /// Wait for the next change of a signal, decoding the frames of their ids as they're received
+    pub async fn async_next_event(&mut self) -> Event {
+        loop {
+            if !self.changes.is_empty() {
+                return self.changes.remove(0);
+            }
+            match self.bus.receive().await {
+                Ok((id, data)) => self.decode(id, &data),
+                Err(e) => {
+                    error!("Failed to receive the frames of {}: {e}", self.name);
+                    time::sleep(RECEIVE_RETRY).await;
+                }
+            }
+        }
+    }
+
+    /// Decode the signals of the frame, queueing those that changed
+    fn decode(&mut self, id: u32, data: &[u8]) {
+        for (signal, last) in self.signals.iter().zip(&mut self.values) {
+            if signal.id != id {
+                continue;
+            }
+            let value = match signal.decode(data) {
+                Some(value) => value,
+                None => continue,
+            };
+            if *last != Some(value) {
+                *last = Some(value);
+                self.changes.push(Event::Changed {
+                    device: self.name.clone(),
+                    signal: signal.name.clone(),
+                    value,
+                });
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Component for CanDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "can"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    /// Frames received from the incoming channel, those sent go to the outgoing one
+    struct MockBus {
+        incoming: UnboundedReceiver<(u32, Vec<u8>)>,
+        outgoing: UnboundedSender<(u32, Vec<u8>)>,
+    }
+
+    #[async_trait]
+    impl CanBus for MockBus {
+        async fn receive(&mut self) -> Result<(u32, Vec<u8>)> {
+            self.incoming
+                .recv()
+                .await
+                .ok_or_else(|| eyre!("The bus was closed"))
+        }
+
+        async fn send(&mut self, id: u32, data: &[u8]) -> Result<()> {
+            self.outgoing.send((id, data.to_vec())).ok();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn signals_are_parsed_from_a_list() {
+        let signals = Signal::parse_list("position:0x181:0:i32, speed:385:4:u16").unwrap();
+        assert_eq!(
+            signals[1],
+            Signal {
+                name: "speed".to_string(),
+                id: 0x181,
+                offset: 4,
+                encoding: Encoding::U16,
+            }
+        );
+        assert!(Signal::parse_list("position:0x181:0:f32").is_err());
+        assert!(Signal::parse_list("position:0x181:6:i32").is_err());
+        assert!(Signal::parse_list("position:0x181").is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_are_decoded_into_changes() {
+        let (frames, incoming) = unbounded_channel();
+        let (outgoing, mut sent) = unbounded_channel();
+        let signals = Signal::parse_list("position:0x181:0:i32,speed:0x181:4:u16").unwrap();
+        let mut track = CanDevice::new("Track", Box::new(MockBus { incoming, outgoing }), signals);
+
+        frames
+            .send((0x181, vec![0x9C, 0xFF, 0xFF, 0xFF, 10, 0]))
+            .unwrap();
+        // other ids and frames repeating the values change nothing
+        frames.send((0x281, vec![0; 8])).unwrap();
+        frames
+            .send((0x181, vec![0x9C, 0xFF, 0xFF, 0xFF, 12, 0]))
+            .unwrap();
+
+        assert!(matches!(
+            track.async_next_event().await,
+            Event::Changed { signal, value: -100, .. } if signal == "position"
+        ));
+        track.async_next_event().await;
+        assert!(matches!(
+            track.async_next_event().await,
+            Event::Changed { signal, value: 12, .. } if signal == "speed"
+        ));
+        assert_eq!(track.serialize_state()["values"]["position"], -100);
+
+        track.send(0x201, &[1]).await.unwrap();
+        assert_eq!(sent.recv().await.unwrap(), (0x201, vec![1]));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::config::Settings;
+use crate::manufacturing_components::gpio::{GpioProvider, OutputLine};
+use crate::manufacturing_components::input::{DebouncedLine, Trigger};
+use crate::manufacturing_components::pwm::PwmOutput;
+use crate::manufacturing_components::{input_line, output_line, Component, ComponentEvent};
+use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use gpio_cdev::EventRequestFlags;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::time::SystemTime;
+
+/// Speed of a conveyor that wasn't given one, in percent of the rated speed of its drive
+pub const DEFAULT_SPEED: u8 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Default)]
+pub enum ConveyorStates {
+    /// Belt is moving, serialized to running
+    #[serde(rename = "running")]
+    Running,
+    /// Belt is at rest, serialized to stopped
+    #[serde(rename = "stopped")]
+    #[default]
+    Stopped,
+}
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    Running {
+        speed: u8,
+    },
+    Stopped,
+    SpeedChanged {
+        speed: u8,
+    },
+    /// an item passed the sensor at the end of the belt
+    ItemDetected,
+}
+
+/// How the belt of a conveyor is driven
+enum Drive {
+    /// runs the belt while high, at the speed its drive is set to
+    Line(Box<dyn OutputLine>),
+    /// runs the belt at the speed of the duty cycle
+    Pwm(PwmOutput),
+}
+
+/// The conveyor carrying materials from the feeder to the robot. The belt is run while its output
+/// line is high, at the speed its drive is set to, or at the speed of the duty cycle when the
+/// output is PWM, see PwmOutput. Items are detected on its input line
+pub struct Conveyor {
+    name: String,
+    state: ConveyorStates,
+    /// in percent of the rated speed of the drive
+    speed: u8,
+    output: Drive,
+    pub event_handle: DebouncedLine,
+}
+
+impl Serialize for Conveyor {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("conveyor", 4)?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("state", &self.state)?;
+        s.serialize_field("speed", &self.speed)?;
+
+        let now = SystemTime::iso8601_now();
+        s.serialize_field("updateTimestamp", &now)?;
+
+        s.end()
+    }
+}
+
+impl Conveyor {
+    /// Items are detected on line and the belt is run on drive_line, which starts low so the belt
+    /// is stopped. drive_line is left alone when the output is a pwmchip
+    pub fn new<S>(
+        settings: &Settings,
+        name: S,
+        speed: u8,
+        gpio: &mut dyn GpioProvider,
+        line: u32,
+        drive_line: u32,
+    ) -> Result<Self>
+    where
+        S: Into<String> + Display,
+    {
+        let name = name.to_string();
+        let trigger = Trigger::from_settings(settings, "conveyor", EventRequestFlags::RISING_EDGE)?;
+        let event_handle = input_line(settings, gpio, line, trigger, &name)?;
+        let output = match PwmOutput::from_settings(settings, "conveyor", gpio, drive_line, &name)?
+        {
+            Some(pwm) => Drive::Pwm(pwm),
+            None => Drive::Line(output_line(gpio, drive_line, &name)?),
+        };
+
+        Ok(Self {
+            name,
+            state: ConveyorStates::default(),
+            speed: valid_speed(speed)?,
+            output,
+            event_handle,
+        })
+    }
+
+    pub fn run(&mut self) -> Result<Event> {
+        match &mut self.output {
+            Drive::Line(output) => output.set_value(1)?,
+            Drive::Pwm(output) => output.set_duty(self.speed)?,
+        }
+        self.state = ConveyorStates::Running;
+        Ok(Event::Running { speed: self.speed })
+    }
+
+    pub fn stop(&mut self) -> Result<Event> {
+        match &mut self.output {
+            Drive::Line(output) => output.set_value(0)?,
+            Drive::Pwm(output) => output.set_duty(0)?,
+        }
+        self.state = ConveyorStates::Stopped;
+        Ok(Event::Stopped)
+    }
+
+    /// Set the speed the belt runs at, in percent of the rated speed of the drive. A running belt
+    /// driven with PWM changes speed right away
+    pub fn set_speed(&mut self, speed: u8) -> Result<Event> {
+        self.speed = valid_speed(speed)?;
+        if let (ConveyorStates::Running, Drive::Pwm(output)) = (self.state, &mut self.output) {
+            output.set_duty(self.speed)?;
+        }
+        Ok(Event::SpeedChanged { speed })
+    }
+
+    /// Wait for the next item to pass the sensor
+    pub async fn async_next_event(&mut self) -> Result<Event> {
+        match self.event_handle.next().await {
+            Some(event) => {
+                event?;
+                Ok(Event::ItemDetected)
+            }
+            None => Err(eyre!("The line of {} stopped sending events", self.name)),
+        }
+    }
+}
+
+#[async_trait]
+impl Component for Conveyor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "conveyor"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await?.into())
+    }
+
+    /// The belt is stopped
+    fn make_safe(&mut self) -> Result<Option<ComponentEvent>> {
+        Ok(Some(self.stop()?.into()))
+    }
+}
+
+fn valid_speed(speed: u8) -> Result<u8> {
+    if speed > 100 {
+        return Err(eyre!(
+            "Conveyor speed {speed} is out of range, expected 0 to 100"
+        ));
+    }
+    Ok(speed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+    use gpio_cdev::EventType;
+
+    #[test]
+    fn conveyor_to_json() {
+        let mut gpio = MockGpio::default();
+        let conveyor = Conveyor::new(
+            &Settings::default(),
+            "conveyor 1",
+            DEFAULT_SPEED,
+            &mut gpio,
+            0,
+            1,
+        )
+        .unwrap();
+        let json = serde_json::to_value(&conveyor).unwrap();
+        assert_eq!(json["name"], "conveyor 1");
+        assert_eq!(json["state"], "stopped");
+        assert_eq!(json["speed"], DEFAULT_SPEED);
+        assert!(json["updateTimestamp"].is_string());
+    }
+
+    #[tokio::test]
+    async fn conveyor_runs_its_belt_and_detects_items() {
+        let mut gpio = MockGpio::default();
+        let mut conveyor = Conveyor::new(
+            &Settings::default(),
+            "conveyor 1",
+            DEFAULT_SPEED,
+            &mut gpio,
+            0,
+            1,
+        )
+        .unwrap();
+
+        conveyor.run().unwrap();
+        assert_eq!(gpio.value(1), 1);
+        conveyor.stop().unwrap();
+        assert_eq!(gpio.value(1), 0);
+
+        gpio.edge(0, EventType::RisingEdge);
+        assert!(matches!(
+            conveyor.async_next_event().await.unwrap(),
+            Event::ItemDetected
+        ));
+    }
+
+    #[test]
+    fn speeds_are_percentages() {
+        assert!(valid_speed(100).is_ok());
+        assert!(valid_speed(101).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::config::Settings;
+use crate::utils;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+/// Totals of the cell since it was commissioned, rather than since the twin started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProductionCounters {
+    /// materials that went through a whole cycle
+    pub produced: u64,
+    /// materials the quality-check station diverted
+    pub rejected: u64,
+}
+
+/// Production counters kept in COUNTERS_PATH so they survive a restart of the twin. The file is
+/// rewritten with every update, which is atomic so a crash mid write can't lose the totals
+#[derive(Debug)]
+pub struct CounterStore {
+    path: PathBuf,
+    counters: ProductionCounters,
+}
+
+impl CounterStore {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let path = settings
+            .get("COUNTERS_PATH")
+            .unwrap_or("production_counters.json");
+
+        Self::load(path)
+    }
+
+    /// Reads the totals recorded before a restart, a missing file starts from zero
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let counters = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ProductionCounters::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self { path, counters })
+    }
+
+    pub fn counters(&self) -> ProductionCounters {
+        self.counters
+    }
+
+    /// A material went through a whole cycle
+    pub fn record_produced(&mut self) -> Result<()> {
+        self.counters.produced += 1;
+        self.save()
+    }
+
+    /// A material was rejected by the quality-check station
+    pub fn record_rejected(&mut self) -> Result<()> {
+        self.counters.rejected += 1;
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let content = serde_json::to_string(&self.counters)?;
+        utils::write_atomic(&self.path, content)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters_survive_a_restart() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("counters.json");
+
+        let mut store = CounterStore::load(&path)?;
+        assert_eq!(store.counters(), ProductionCounters::default());
+        store.record_produced()?;
+        store.record_produced()?;
+        store.record_rejected()?;
+
+        let store = CounterStore::load(&path)?;
+        assert_eq!(
+            store.counters(),
+            ProductionCounters {
+                produced: 2,
+                rejected: 1
+            }
+        );
+        Ok(())
+    }
+}
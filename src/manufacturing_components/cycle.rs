@@ -0,0 +1,428 @@
+use crate::ack::{AckStatus, Acknowledger};
+use crate::cancellation::CancellationToken;
+use crate::dispatcher::Queued;
+use crate::envelope::EventSender;
+use crate::gcp_iot::message::{Command, StopRequest};
+use crate::manufacturing_components::feeder;
+use crate::manufacturing_components::program::{self, DynProgram, ProgramState, ProgramStatus};
+use crate::manufacturing_components::registry::{ComponentRegistry, CycleEvent};
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures::FutureExt;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::watch;
+use tracing::{error, info, instrument};
+
+/// Why a cycle ended before every material was processed
+#[derive(Debug, PartialEq)]
+pub enum Interrupted {
+    Stop,
+    EmergencyStop,
+    /// a sensor the cycle waited on didn't fire in time
+    Stalled,
+    /// the twin is shutting down
+    Shutdown,
+}
+
+/// Everything a running cycle works on, reports to and is controlled by
+pub struct CycleContext<'a> {
+    /// number of materials to process, as requested by the start
+    pub count: u32,
+    /// correlates what's published about the cycle, the id of the command starting it
+    pub cycle_id: String,
+    pub cell: &'a mut ComponentRegistry,
+    pub tx: &'a EventSender,
+    pub state_tx: &'a watch::Sender<Value>,
+    pub status_tx: &'a watch::Sender<ProgramStatus>,
+    pub control_rx: &'a mut UnboundedReceiver<Queued>,
+    pub emergency_rx: &'a mut UnboundedReceiver<Queued>,
+    pub acks: &'a Acknowledger,
+    /// cancelled on shutdown, stops and emergency stops come with their own requests
+    pub cancel: &'a CancellationToken,
+    /// materials processed between progress events
+    pub progress_every: u32,
+}
+
+/// How far a cycle went before it ended
+#[derive(Debug)]
+pub struct Report {
+    /// number of materials picked up
+    pub processed: u32,
+    pub interrupted: Option<Interrupted>,
+}
+
+/// Progress of a running cycle, reported every few materials so the cloud can follow long runs.
+/// The completion is estimated from the pace of the cycle so far, pauses included
+pub struct Progress {
+    started: Instant,
+    count: u32,
+    every: u32,
+}
+
+impl Progress {
+    /// Progress of a cycle processing count materials, reported every `every` of them and once
+    /// the last one is done
+    pub fn new(count: u32, every: u32) -> Self {
+        Self {
+            started: Instant::now(),
+            count,
+            every: every.max(1),
+        }
+    }
+
+    /// The progress event once processed materials were picked up, None in between
+    pub fn event(&self, processed: u32) -> Option<program::Event> {
+        if !processed.is_multiple_of(self.every) && processed != self.count {
+            return None;
+        }
+
+        let remaining = self.count.saturating_sub(processed);
+        let estimated_completion = time_left(self.started.elapsed(), processed, remaining)
+            .and_then(|left| chrono::Duration::from_std(left).ok())
+            .map(|left| Utc::now() + left);
+        Some(program::Event::Progress {
+            processed,
+            remaining,
+            estimated_completion,
+        })
+    }
+}
+
+/// Time the remaining materials take at the average pace so far, unknown until one is processed
+fn time_left(elapsed: Duration, processed: u32, remaining: u32) -> Option<Duration> {
+    if processed == 0 {
+        return None;
+    }
+    Some(elapsed / processed * remaining)
+}
+
+/// Publish the lifecycle event of a program, when it changed state
+pub fn publish_transition(tx: &EventSender, transition: Option<program::Event>) {
+    if let Some(event) = transition {
+        tx.send(event);
+    }
+}
+
+/// What a wait for the feeder ended with
+pub enum Picked {
+    Material(feeder::Event),
+    Interrupted(Interrupted),
+}
+
+/// Wait for a material to be picked from the feeder at index, with remaining materials left in
+/// the cycle. Pause and resume requests hold and continue the wait, emergency stops are handled
+/// before anything else, dropping the wait for the feeder. Magazines loaded and the events of the
+/// other components, e.g. the robot's moves along the track, are published as they happen. A
+/// feeder that doesn't signal within the sensor timeout stalls the cycle, which is stopped and
+/// reported rather than waited on forever
+pub async fn wait_for_pickup(
+    program: &mut DynProgram,
+    cx: &mut CycleContext<'_>,
+    index: usize,
+    remaining: u32,
+) -> Result<Picked> {
+    loop {
+        let interrupted = tokio::select! {
+            biased;
+            Some(estop) = cx.emergency_rx.recv() => {
+                emergency_stop(Some(&mut *program), cx.cell, estop, cx.tx, cx.acks).await;
+                Some(Interrupted::EmergencyStop)
+            }
+            control = cx.control_rx.recv() => {
+                control_cycle(&mut *program, cx, control, remaining).await?
+            }
+            // stops and emergency stops are queued before they cancel, so only a shutdown gets here
+            _ = cx.cancel.cancelled() => {
+                safe_state(Some(&mut *program), cx.cell, cx.tx)?;
+                Some(Interrupted::Shutdown)
+            }
+            event = cx.cell.next_cycle_event(index) => match event {
+                CycleEvent::Feeder(Ok(event @ feeder::Event::MaterialPickedUp { .. })) => {
+                    return Ok(Picked::Material(event));
+                }
+                CycleEvent::Feeder(Ok(refilled)) => {
+                    cx.tx.send(refilled);
+                    cx.state_tx.send(cx.cell.state()).ok();
+                    None
+                }
+                // the cycle would wait forever on a sensor that doesn't fire
+                CycleEvent::Feeder(Err(feeder::Error::Timeout(timeout))) => {
+                    error!("The cycle stalled: {timeout}");
+                    publish_transition(cx.tx, program.stop()?);
+                    cx.tx.send(program::Event::Stalled {
+                        component: timeout.component,
+                        waited_ms: timeout.after.as_millis() as u64,
+                    });
+                    Some(Interrupted::Stalled)
+                }
+                CycleEvent::Feeder(Err(e)) => return Err(e.into()),
+                CycleEvent::Sensor(event) => {
+                    cx.tx.send(event?);
+                    cx.state_tx.send(cx.cell.state()).ok();
+                    None
+                }
+            },
+        };
+
+        if let Some(interrupted) = interrupted {
+            return Ok(Picked::Interrupted(interrupted));
+        }
+    }
+}
+
+/// Handle the requests received while the program drove the cell through a step, which is only
+/// ever cancelled, never dropped halfway, so the cell is at rest when they're handled. Returns why
+/// the cycle was interrupted, None when it goes on
+pub async fn between_steps(
+    program: &mut DynProgram,
+    cx: &mut CycleContext<'_>,
+    remaining: u32,
+) -> Result<Option<Interrupted>> {
+    if let Ok(estop) = cx.emergency_rx.try_recv() {
+        emergency_stop(Some(program), cx.cell, estop, cx.tx, cx.acks).await;
+        return Ok(Some(Interrupted::EmergencyStop));
+    }
+    if let Ok(control) = cx.control_rx.try_recv() {
+        return control_cycle(program, cx, Some(control), remaining).await;
+    }
+    // stops and emergency stops are queued before they cancel, so only a shutdown gets here
+    if cx.cancel.is_cancelled() {
+        safe_state(Some(program), cx.cell, cx.tx)?;
+        return Ok(Some(Interrupted::Shutdown));
+    }
+    Ok(None)
+}
+
+/// Stop the program, leaving the cell in a safe state
+pub fn stop_program(
+    program: &mut DynProgram,
+    request: &StopRequest,
+    tx: &EventSender,
+) -> Result<()> {
+    match &request.reason {
+        Some(reason) => info!("Stopping the program: {reason}"),
+        None => info!("Stopping the program"),
+    }
+    publish_transition(tx, program.stop()?);
+    Ok(())
+}
+
+/// Handle a control request received while a cycle is running. A pause holds the program and waits
+/// for a resume or stop, without reading the feeder, so the cycle continues at the same step. The
+/// program's own state tells whether it's paused. Returns why the cycle was interrupted, None when
+/// it goes on
+pub async fn control_cycle(
+    program: &mut DynProgram,
+    cx: &mut CycleContext<'_>,
+    control: Option<Queued>,
+    remaining: u32,
+) -> Result<Option<Interrupted>> {
+    let mut next = control;
+
+    loop {
+        // the listener is gone when the channel is closed, stop all the same
+        let (id, control) = match next.take() {
+            Some(control) => control,
+            None => (None, Command::Stop(StopRequest::default())),
+        };
+        let id = id.as_deref();
+        cx.acks.send(id, AckStatus::Started, None).await;
+
+        let outcome: Result<Option<Value>> = match control {
+            Command::Stop(request) => {
+                // a failed stop fails the cycle too
+                return match stop_program(program, &request, cx.tx) {
+                    Ok(()) => {
+                        cx.acks
+                            .complete(id, json!({ "remaining": remaining }))
+                            .await;
+                        Ok(Some(Interrupted::Stop))
+                    }
+                    Err(e) => {
+                        cx.acks
+                            .send(id, AckStatus::Failed, Some(e.to_string()))
+                            .await;
+                        Err(e)
+                    }
+                };
+            }
+            Command::Pause(request) if program.state() == ProgramState::Running => {
+                match &request.reason {
+                    Some(reason) => info!("Pausing the program: {reason}"),
+                    None => info!("Pausing the program"),
+                }
+                match program.pause() {
+                    Ok(event) => {
+                        publish_transition(cx.tx, event);
+                        cx.status_tx.send(ProgramStatus::Paused).ok();
+                        Ok(Some(json!({ "remaining": remaining })))
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Command::Resume(_) if program.state() == ProgramState::Paused => {
+                info!("Resuming the program");
+                match program.resume() {
+                    Ok(event) => {
+                        publish_transition(cx.tx, event);
+                        cx.status_tx.send(ProgramStatus::Running).ok();
+                        Ok(None)
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Command::Pause(_) => Err(eyre!("The cycle is already paused")),
+            Command::Resume(_) => Err(eyre!("The cycle isn't paused")),
+            _ => unreachable!("only controls are sent on the control channel"),
+        };
+        cx.acks.conclude(id, outcome).await;
+
+        if program.state() != ProgramState::Paused {
+            return Ok(None);
+        }
+        next = tokio::select! {
+            biased;
+            Some(estop) = cx.emergency_rx.recv() => {
+                emergency_stop(Some(&mut *program), cx.cell, estop, cx.tx, cx.acks).await;
+                return Ok(Some(Interrupted::EmergencyStop));
+            }
+            control = cx.control_rx.recv() => control,
+        };
+    }
+}
+
+/// Run the cycle of the program, making sure the program and the cell are left in their safe state
+/// when it fails or panics, whatever step it was at. A panic fails the cycle like an error does
+#[instrument(name = "cycle", skip_all, fields(cycle_id = %cx.cycle_id, count = cx.count))]
+pub async fn supervise(program: &mut DynProgram, cx: &mut CycleContext<'_>) -> Result<Report> {
+    let report = match AssertUnwindSafe(program.run(cx)).catch_unwind().await {
+        Ok(report) => report,
+        Err(panic) => Err(eyre!(
+            "The cycle panicked: {}",
+            panic_message(panic.as_ref())
+        )),
+    };
+    // whatever material the cycle ended at, the cell isn't working on it anymore
+    cx.tx.end_material();
+
+    if let Err(e) = &report {
+        error!("The cycle failed, driving the cell to its safe state: {e}");
+        if let Err(e) = safe_state(Some(program), cx.cell, cx.tx) {
+            error!("Failed to drive the cell to its safe state: {e}");
+        }
+    }
+    report
+}
+
+/// The message a panic was raised with
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message,
+            None => "no message",
+        },
+    }
+}
+
+/// Drive every output of the program and the cell to its safe value, e.g. on a shutdown. Without a
+/// program only the cell is driven
+pub fn safe_state(
+    program: Option<&mut DynProgram>,
+    cell: &mut ComponentRegistry,
+    tx: &EventSender,
+) -> Result<()> {
+    let program: Result<()> = match program {
+        Some(program) => program
+            .safe_state()
+            .map(|event| publish_transition(tx, event))
+            .map_err(Into::into),
+        None => Ok(()),
+    };
+    // the cell is made safe even when the program couldn't be
+    program.and(cell.drive_safe(tx))
+}
+
+/// Drive every output of the program and the cell to its safe value, then raise the e-stop alarm.
+/// Without a program only the cell is driven
+pub async fn emergency_stop(
+    program: Option<&mut DynProgram>,
+    cell: &mut ComponentRegistry,
+    (id, command): Queued,
+    tx: &EventSender,
+    acks: &Acknowledger,
+) {
+    let request = match command {
+        Command::EmergencyStop(request) => request,
+        _ => unreachable!("only emergency stops are sent on the emergency channel"),
+    };
+
+    let cutoff: Result<()> = match program {
+        Some(program) => program
+            .emergency_stop()
+            .map(|event| publish_transition(tx, event))
+            .map_err(Into::into),
+        None => Ok(()),
+    };
+    // the cell is made safe even when the program couldn't be stopped
+    let cutoff = cutoff.and(cell.drive_safe(tx));
+
+    let id = id.as_deref();
+    acks.send(id, AckStatus::Started, None).await;
+    match cutoff {
+        Ok(()) => {
+            let reason = request.reason.as_deref().unwrap_or("no reason given");
+            error!("Emergency stop: {reason}");
+            tx.send(program::Event::EmergencyStop {
+                reason: request.reason,
+            });
+            acks.send(id, AckStatus::Completed, None).await;
+        }
+        Err(e) => {
+            error!("Emergency stop failed to drive the outputs to their safe values: {e}");
+            acks.send(id, AckStatus::Failed, Some(e.to_string())).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn completion_is_estimated_from_the_pace_so_far() {
+        let elapsed = Duration::from_secs(30);
+        assert_eq!(time_left(elapsed, 0, 10), None);
+        assert_eq!(time_left(elapsed, 3, 6), Some(Duration::from_secs(60)));
+        assert_eq!(time_left(elapsed, 3, 0), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn progress_is_reported_every_few_materials_and_at_the_end() {
+        let progress = Progress::new(5, 2);
+        assert!(progress.event(1).is_none());
+        assert!(matches!(
+            progress.event(2),
+            Some(program::Event::Progress {
+                processed: 2,
+                remaining: 3,
+                ..
+            })
+        ));
+        assert!(progress.event(5).is_some());
+    }
+
+    #[test]
+    fn panics_fail_with_their_message() {
+        let panic = std::panic::catch_unwind(|| panic!("feeder empty")).unwrap_err();
+        assert_eq!(panic_message(panic.as_ref()), "feeder empty");
+
+        let panic = std::panic::catch_unwind(|| panic!("feeder {} empty", 1)).unwrap_err();
+        assert_eq!(panic_message(panic.as_ref()), "feeder 1 empty");
+    }
+}
@@ -0,0 +1,118 @@
+use serde::Serialize;
+use std::time::Duration;
+
+/// A step a cycle goes through for every material, as walked by a dry run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedStep {
+    /// what the step does, e.g. set line 17 high
+    pub action: String,
+    /// the event the step is expected to publish, None when it publishes none
+    pub expected: Option<String>,
+    /// time the step takes for sure, e.g. a sleep, in milliseconds
+    pub duration_ms: u64,
+    /// whether the step waits on a sensor of the cell
+    pub waits: bool,
+    /// longest the step waits before the cycle stalls, None when it waits as long as it takes
+    pub timeout_ms: Option<u64>,
+}
+
+impl PlannedStep {
+    /// A step driving the cell that takes duration
+    pub fn act(action: impl Into<String>, expected: Option<&str>, duration: Duration) -> Self {
+        Self {
+            action: action.into(),
+            expected: expected.map(str::to_string),
+            duration_ms: duration.as_millis() as u64,
+            waits: false,
+            timeout_ms: None,
+        }
+    }
+
+    /// A step waiting on a sensor until the timeout, when there is one
+    pub fn wait(
+        action: impl Into<String>,
+        expected: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            action: action.into(),
+            expected: expected.map(str::to_string),
+            duration_ms: 0,
+            waits: true,
+            timeout_ms: timeout.map(|timeout| timeout.as_millis() as u64),
+        }
+    }
+}
+
+/// The steps run for every material of a program, a sequence has one stage for each of its steps
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stage {
+    /// scenario of the step of a sequence, None for a program of its own
+    pub scenario: Option<String>,
+    /// materials the stage processes
+    pub count: u32,
+    pub steps: Vec<PlannedStep>,
+}
+
+/// What a program would do for a start, walked without driving a line of the cell, so a scenario
+/// can be checked from the cloud before it's run
+#[derive(Debug, Serialize)]
+pub struct DryRun {
+    pub stages: Vec<Stage>,
+    /// time the cycle takes at least, every wait completing right away, in milliseconds
+    pub min_ms: u64,
+    /// time the cycle takes at most before it stalls, None when a wait has no timeout
+    pub max_ms: Option<u64>,
+}
+
+impl DryRun {
+    pub fn new(stages: Vec<Stage>) -> Self {
+        let mut min_ms = 0;
+        let mut max_ms = Some(0);
+        for stage in &stages {
+            let count = stage.count as u64;
+            for step in &stage.steps {
+                min_ms += step.duration_ms * count;
+                let longest = match (step.waits, step.timeout_ms) {
+                    (false, _) => Some(step.duration_ms),
+                    (true, timeout) => timeout,
+                };
+                max_ms = max_ms
+                    .zip(longest)
+                    .map(|(max, longest)| max + longest * count);
+            }
+        }
+
+        Self {
+            stages,
+            min_ms,
+            max_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timing_is_estimated_for_every_material() {
+        let steps = vec![
+            PlannedStep::act("press", None, Duration::from_millis(500)),
+            PlannedStep::wait("wait for the feeder", None, Some(Duration::from_secs(2))),
+        ];
+        let stage = |steps| Stage {
+            scenario: None,
+            count: 3,
+            steps,
+        };
+
+        let dry_run = DryRun::new(vec![stage(steps.clone())]);
+        assert_eq!(dry_run.min_ms, 1500);
+        assert_eq!(dry_run.max_ms, Some(7500));
+
+        let mut unbounded = steps;
+        unbounded.push(PlannedStep::wait("wait for an edge", None, None));
+        assert_eq!(DryRun::new(vec![stage(unbounded)]).max_ms, None);
+    }
+}
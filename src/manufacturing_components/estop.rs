@@ -0,0 +1,185 @@
+use crate::config::Settings;
+use crate::manufacturing_components::gpio::GpioProvider;
+use crate::manufacturing_components::input::{DebouncedLine, Trigger};
+use crate::manufacturing_components::input_line;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use gpio_cdev::{EventRequestFlags, EventType};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    /// the button was pressed, the cell is stopped and the e-stop latched
+    Asserted,
+    /// the button was released, the e-stop stays latched until it's cleared
+    Released,
+    /// the latch was cleared, the cell can be started again
+    Cleared,
+}
+
+/// The e-stop is latched, nothing drives the cell until it's cleared
+#[derive(Debug)]
+pub struct Latched;
+
+impl Display for Latched {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Error: The e-stop is latched, release the button and clear it first"
+        )
+    }
+}
+
+impl std::error::Error for Latched {}
+
+#[derive(Debug, Default)]
+struct Inner {
+    latched: AtomicBool,
+    /// whether the button is pressed
+    asserted: AtomicBool,
+}
+
+/// Latched when the e-stop button is pressed and held until it's cleared once the button is
+/// released, so the cell isn't started again just because the button was. Clones share the latch
+#[derive(Debug, Clone, Default)]
+pub struct Latch(Arc<Inner>);
+
+impl Latch {
+    pub fn is_latched(&self) -> bool {
+        self.0.latched.load(Ordering::SeqCst)
+    }
+
+    /// Fails with Latched while the e-stop is latched
+    pub fn check(&self) -> Result<(), Latched> {
+        if self.is_latched() {
+            return Err(Latched);
+        }
+        Ok(())
+    }
+
+    /// Clear the latch, which fails while the button is still pressed
+    pub fn clear(&self) -> Result<Event> {
+        if self.0.asserted.load(Ordering::SeqCst) {
+            return Err(eyre!(
+                "The e-stop button is still pressed, release it first"
+            ));
+        }
+        self.0.latched.store(false, Ordering::SeqCst);
+        Ok(Event::Cleared)
+    }
+
+    fn set_asserted(&self, asserted: bool) {
+        self.0.asserted.store(asserted, Ordering::SeqCst);
+        if asserted {
+            self.0.latched.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// State of the e-stop as reported in the twin state
+    pub fn state(&self) -> Value {
+        json!({
+            "latched": self.is_latched(),
+            "asserted": self.0.asserted.load(Ordering::SeqCst),
+        })
+    }
+}
+
+/// The physical e-stop button of the cell, read on both edges of its line. It's watched on its own
+/// rather than with the other components, so it's heard whatever the twin is doing
+pub struct EmergencyStopButton {
+    line: DebouncedLine,
+    latch: Latch,
+}
+
+impl EmergencyStopButton {
+    /// The button read on line, active as configured for the estop component. A button pressed
+    /// already latches the e-stop right away
+    pub fn new(settings: &Settings, gpio: &mut dyn GpioProvider, line: u32) -> Result<Self> {
+        // the edges are read either way, only the polarity is configurable
+        let trigger = Trigger {
+            edge: EventRequestFlags::BOTH_EDGES,
+            ..Trigger::from_settings(settings, "estop", EventRequestFlags::BOTH_EDGES)?
+        };
+        let line = input_line(settings, gpio, line, trigger, "E-stop")?;
+        let latch = Latch::default();
+        latch.set_asserted(line.value()? == 1);
+
+        Ok(Self { line, latch })
+    }
+
+    /// The button on ESTOP_LINE, None when the cell has none. Buttons are usually wired normally
+    /// closed, ESTOP_ACTIVE=low
+    pub fn from_settings(settings: &Settings, gpio: &mut dyn GpioProvider) -> Result<Option<Self>> {
+        let line = match settings.parse("ESTOP_LINE", "unsigned integer")? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        Ok(Some(Self::new(settings, gpio, line)?))
+    }
+
+    /// The latch of the button, checked by whatever drives the cell
+    pub fn latch(&self) -> Latch {
+        self.latch.clone()
+    }
+
+    /// Wait for the button to be pressed or released, latching the e-stop when it's pressed
+    pub async fn async_next_event(&mut self) -> Result<Event> {
+        match self.line.next().await {
+            Some(edge) => {
+                let asserted = edge?.event_type == EventType::RisingEdge;
+                self.latch.set_asserted(asserted);
+                Ok(if asserted {
+                    Event::Asserted
+                } else {
+                    Event::Released
+                })
+            }
+            None => Err(eyre!(
+                "The line of the e-stop button stopped sending events"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+
+    #[tokio::test]
+    async fn the_latch_is_held_until_cleared_after_release() {
+        let mut gpio = MockGpio::default();
+        let mut button = EmergencyStopButton::new(&Settings::default(), &mut gpio, 0).unwrap();
+        let latch = button.latch();
+        assert!(latch.check().is_ok());
+
+        gpio.edge(0, EventType::RisingEdge);
+        assert!(matches!(
+            button.async_next_event().await.unwrap(),
+            Event::Asserted
+        ));
+        assert!(latch.check().is_err());
+        assert!(latch.clear().is_err());
+
+        gpio.edge(0, EventType::FallingEdge);
+        assert!(matches!(
+            button.async_next_event().await.unwrap(),
+            Event::Released
+        ));
+        assert!(latch.is_latched());
+        assert!(matches!(latch.clear(), Ok(Event::Cleared)));
+        assert!(latch.check().is_ok());
+    }
+
+    #[test]
+    fn a_button_pressed_at_start_latches() {
+        let mut gpio = MockGpio::default();
+        gpio.edge(0, EventType::RisingEdge);
+        let button = EmergencyStopButton::new(&Settings::default(), &mut gpio, 0).unwrap();
+        assert!(button.latch().is_latched());
+    }
+}
@@ -1,33 +1,125 @@
+use crate::config::{ConfigError, Settings};
+use crate::manufacturing_components::gpio::{GpioError, GpioProvider};
+use crate::manufacturing_components::input::{DebouncedLine, Trigger};
+use crate::manufacturing_components::{input_line, Component, ComponentEvent, Timeout};
 use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
 use color_eyre::Result;
-use futures::StreamExt;
-use gpio_cdev::{AsyncLineEventHandle, Chip, EventRequestFlags, Line, LineRequestFlags};
+use futures::future;
+use gpio_cdev::EventRequestFlags;
 use serde::ser::SerializeStruct;
 use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
 use std::fmt::{Debug, Display, Formatter};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+use tokio::time;
 
 pub struct Feeder {
     name: String,
     count: u32,
-    gpio_line: Line,
-    pub event_handle: AsyncLineEventHandle,
+    /// the low supply alarm is raised once the count drops below it
+    low_supply_threshold: Option<u32>,
+    /// whether the alarm was raised since the count last dropped below the threshold
+    low_supply_raised: bool,
+    refill: Option<RefillLine>,
+    pub event_handle: DebouncedLine,
+}
+
+/// Line signalling an operator loaded a magazine into the feeder
+struct RefillLine {
+    event_handle: DebouncedLine,
+    magazine_size: u32,
 }
 
 #[derive(Debug)]
 pub enum Error {
     NoMoreSupply,
+    /// nothing was picked up in time
+    Timeout(Timeout),
+    /// the sensor of the feeder can't be read
+    Gpio(GpioError),
+    /// the sensor reports the feeder empty while materials are left by its count
+    CountMismatch {
+        feeder: String,
+        count: u32,
+    },
 }
 
+/// Events name the feeder they come from, the cell can have one at each pickup position of the robot
 #[derive(Debug, Serialize)]
 pub enum Event {
-    MaterialPickedUp,
+    MaterialPickedUp {
+        feeder: String,
+        /// the code scanned at the feeder before the pickup, when the cell has a scanner
+        material: Option<String>,
+    },
+    /// an operator restocked the feeder, confirming a refill command
+    Refilled {
+        feeder: String,
+        added: u32,
+        count: u32,
+    },
+    /// the count dropped below the low supply threshold, the feeder should be refilled before the
+    /// line stops
+    LowSupply {
+        feeder: String,
+        count: u32,
+        threshold: u32,
+    },
+}
+
+/// Which feeder the robot picks the next material from when the cell has more than one, selected
+/// with FEEDER_POLICY ("alternate" or "balance")
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeederPolicy {
+    /// take turns, skipping the empty feeders
+    Alternate,
+    /// the feeder with the most materials left, so they run out together
+    Balance,
+}
+
+impl FeederPolicy {
+    pub fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        match settings.get("FEEDER_POLICY") {
+            Some("alternate") | None => Ok(FeederPolicy::Alternate),
+            Some("balance") => Ok(FeederPolicy::Balance),
+            Some(other) => Err(ConfigError::invalid(
+                "FEEDER_POLICY",
+                "alternate or balance",
+                other,
+            )),
+        }
+    }
+
+    /// Index of the feeder to pick the material of the given turn from, given the count of every
+    /// feeder. None when they're all empty
+    pub fn select(&self, counts: &[u32], turn: usize) -> Option<usize> {
+        match self {
+            FeederPolicy::Alternate => (0..counts.len())
+                .map(|offset| (turn + offset) % counts.len())
+                .find(|&index| counts[index] > 0),
+            // the first of the fullest feeders
+            FeederPolicy::Balance => counts
+                .iter()
+                .enumerate()
+                .filter(|(_, &count)| count > 0)
+                .rev()
+                .max_by_key(|(_, &count)| count)
+                .map(|(index, _)| index),
+        }
+    }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::NoMoreSupply => write!(f, "Error: There are no more supply in the feeder"),
+            Error::Timeout(timeout) => write!(f, "{timeout}"),
+            Error::Gpio(e) => write!(f, "{e}"),
+            Error::CountMismatch { feeder, count } => write!(
+                f,
+                "Error: The sensor of {feeder} reports it empty while {count} materials are left"
+            ),
         }
     }
 }
@@ -50,35 +142,112 @@ impl Serialize for Feeder {
 }
 
 impl Feeder {
-    pub fn new<S>(name: S, count: u32, chip: &mut Chip, line: u32) -> Result<Self>
+    pub fn new<S>(
+        settings: &Settings,
+        name: S,
+        count: u32,
+        gpio: &mut dyn GpioProvider,
+        line: u32,
+    ) -> Result<Self>
     where
         S: Into<String> + Display,
     {
-        let line = chip.get_line(line)?;
-        let event_handle = line.async_events(
-            LineRequestFlags::INPUT,
-            EventRequestFlags::BOTH_EDGES,
-            &format!("{name} consumer"),
-        )?;
+        let trigger = Trigger::from_settings(settings, "feeder", EventRequestFlags::BOTH_EDGES)?;
+        let event_handle = input_line(settings, gpio, line, trigger, &name.to_string())?;
 
         Ok(Self {
             name: name.into(),
             count,
-            gpio_line: line,
+            low_supply_threshold: None,
+            low_supply_raised: false,
+            refill: None,
             event_handle,
         })
     }
 
-    pub async fn async_next_event(self: &mut Self) -> Result<Event, Error> {
+    /// Count the magazines loaded into the feeder, signalled by a rising edge on line unless the
+    /// refill trigger is configured otherwise, as refills of magazine_size materials
+    pub fn with_refill_line(
+        mut self,
+        settings: &Settings,
+        gpio: &mut dyn GpioProvider,
+        line: u32,
+        magazine_size: u32,
+    ) -> Result<Self> {
+        let name = format!("{} refill", self.name);
+        let trigger = Trigger::from_settings(settings, "refill", EventRequestFlags::RISING_EDGE)?;
+        let event_handle = input_line(settings, gpio, line, trigger, &name)?;
+
+        self.refill = Some(RefillLine {
+            event_handle,
+            magazine_size,
+        });
+        Ok(self)
+    }
+
+    /// Raise a low supply alarm when the count drops below the threshold
+    pub fn with_low_supply_threshold(mut self, threshold: u32) -> Self {
+        self.set_low_supply_threshold(Some(threshold));
+        self
+    }
+
+    /// Change the threshold of the low supply alarm, or stop raising it with None
+    pub fn set_low_supply_threshold(&mut self, threshold: Option<u32>) {
+        self.low_supply_threshold = threshold;
+    }
+
+    pub async fn async_next_event(&mut self) -> Result<Event, Error> {
         if self.count == 0 {
             return Err(Error::NoMoreSupply);
         }
 
-        if let Some(_event) = self.event_handle.next().await {
-            self.count -= 1;
+        // a magazine can be loaded while the feeder is waited on
+        let edge = tokio::select! {
+            edge = self.event_handle.next() => edge,
+            added = magazine_loaded(&mut self.refill) => return Ok(self.add_new_material(added)),
+        };
+        // only an edge read from the sensor is a pickup
+        match edge {
+            Some(Ok(_)) => self.count -= 1,
+            Some(Err(source)) => {
+                return Err(Error::Gpio(GpioError::Read {
+                    consumer: self.name.clone(),
+                    source,
+                }))
+            }
+            None => {
+                return Err(Error::Gpio(GpioError::Stopped {
+                    consumer: self.name.clone(),
+                }))
+            }
+        }
+
+        Ok(Event::MaterialPickedUp {
+            feeder: self.name.clone(),
+            material: None,
+        })
+    }
+
+    /// Wait for the next event like async_next_event, failing when nothing happens within after
+    pub async fn async_next_event_timeout(&mut self, after: Duration) -> Result<Event, Error> {
+        match time::timeout(after, self.async_next_event()).await {
+            Ok(event) => event,
+            Err(_) => Err(Error::Timeout(Timeout {
+                component: self.name.clone(),
+                after,
+            })),
         }
+    }
+
+    /// Wait for an operator to load a magazine, returning the refilled event. Never completes for a
+    /// feeder without a refill line
+    pub async fn async_next_refill(&mut self) -> Event {
+        let added = magazine_loaded(&mut self.refill).await;
+        self.add_new_material(added)
+    }
 
-        Ok(Event::MaterialPickedUp)
+    pub fn has_refill_line(&self) -> bool {
+        self.refill.is_some()
     }
 
     /// Returns true if the material has no materials left at the current moment
@@ -87,32 +256,156 @@ impl Feeder {
     /// This relies on the current event stream having nothing, meaning if you await now,
     /// you should block. Currently I don't know ensure this since the stream doesn't provide
     /// a non blocking way to see if it will block to read the next one
-    pub fn is_empty(&self) -> bool {
-        // if unwrap fails, then that means we have some how lost connection to the line, we can't
-        // recover
-        let request = self.event_handle.as_ref();
+    pub fn is_empty(&self) -> Result<bool, GpioError> {
+        let value = self
+            .event_handle
+            .value()
+            .map_err(|source| GpioError::Read {
+                consumer: self.name.clone(),
+                source,
+            })?;
+        Ok(value == 1)
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Set the materials left, e.g. those counted before the feeder was re-created on another line
+    pub fn set_count(&mut self, count: u32) {
+        self.count = count;
+    }
+
+    /// The low supply alarm, once each time the count drops below the threshold
+    pub fn low_supply_alarm(&mut self) -> Option<Event> {
+        let threshold = self.low_supply_threshold?;
+        if self.count >= threshold || self.low_supply_raised {
+            return None;
+        }
 
-        // similar rationale for unwrap above
-        request.get_value().unwrap() == 1
+        self.low_supply_raised = true;
+        Some(Event::LowSupply {
+            feeder: self.name.clone(),
+            count: self.count,
+            threshold,
+        })
     }
 
-    pub fn add_new_material(&mut self, new_material_count: u32) {
+    /// Add restocked materials, returning the event confirming the refill
+    pub fn add_new_material(&mut self, new_material_count: u32) -> Event {
         self.count += new_material_count;
+        // the alarm is raised again the next time the count drops below the threshold
+        if self
+            .low_supply_threshold
+            .is_some_and(|threshold| self.count >= threshold)
+        {
+            self.low_supply_raised = false;
+        }
+        Event::Refilled {
+            feeder: self.name.clone(),
+            added: new_material_count,
+            count: self.count,
+        }
+    }
+}
+
+#[async_trait]
+impl Component for Feeder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "feeder"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await?.into())
+    }
+}
+
+/// Size of the magazine loaded on the refill line, pending forever without one
+async fn magazine_loaded(refill: &mut Option<RefillLine>) -> u32 {
+    if let Some(refill) = refill {
+        if let Some(_event) = refill.event_handle.next().await {
+            return refill.magazine_size;
+        }
     }
+    future::pending().await
 }
 
 #[cfg(test)]
 mod test {
-    use crate::manufacturing_components::feeder::Feeder;
-    use gpio_cdev::Chip;
+    use crate::config::Settings;
+    use crate::manufacturing_components::feeder::{Error, Feeder, FeederPolicy};
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+    use crate::manufacturing_components::gpio::GpioError;
+    use gpio_cdev::EventType;
 
     #[test]
     fn feeder_to_json() {
-        let mut chip = Chip::new("/dev/gpiochip0")
-            .expect("Sorry the current hack requires access to /dev/gpiochip0");
-        let feeder = Feeder::new("material feeder", 5, &mut chip, 0).unwrap();
+        let mut gpio = MockGpio::default();
+        let feeder = Feeder::new(&Settings::default(), "material feeder", 5, &mut gpio, 0).unwrap();
+
+        let json = serde_json::to_value(&feeder).unwrap();
+        assert_eq!(json["name"], "material feeder");
+        assert_eq!(json["count"], 5);
+        assert!(json["updateTimestamp"].is_string());
+    }
+
+    #[tokio::test]
+    async fn pickups_are_counted() {
+        let mut gpio = MockGpio::default();
+        let mut feeder =
+            Feeder::new(&Settings::default(), "material feeder", 2, &mut gpio, 0).unwrap();
+
+        gpio.edge(0, EventType::RisingEdge);
+        feeder.async_next_event().await.unwrap();
+        assert_eq!(feeder.count(), 1);
+        assert!(feeder.is_empty().unwrap());
+
+        gpio.edge(0, EventType::FallingEdge);
+        feeder.async_next_event().await.unwrap();
+        assert_eq!(feeder.count(), 0);
+        assert!(!feeder.is_empty().unwrap());
+        assert!(matches!(
+            feeder.async_next_event().await,
+            Err(Error::NoMoreSupply)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stopped_lines_are_not_pickups() {
+        let mut gpio = MockGpio::default();
+        let mut feeder =
+            Feeder::new(&Settings::default(), "material feeder", 2, &mut gpio, 0).unwrap();
+
+        gpio.close(0);
+        assert!(matches!(
+            feeder.async_next_event().await,
+            Err(Error::Gpio(GpioError::Stopped { .. }))
+        ));
+        assert_eq!(feeder.count(), 2);
+    }
 
-        let json = serde_json::to_string(&feeder).unwrap();
-        println!("{json}")
+    #[test]
+    fn feeders_take_turns_skipping_empty_ones() {
+        let policy = FeederPolicy::Alternate;
+        assert_eq!(policy.select(&[3, 3], 0), Some(0));
+        assert_eq!(policy.select(&[3, 3], 1), Some(1));
+        assert_eq!(policy.select(&[3, 0], 1), Some(0));
+        assert_eq!(policy.select(&[0, 0], 0), None);
+    }
+
+    #[test]
+    fn balanced_feeders_run_out_together() {
+        let policy = FeederPolicy::Balance;
+        assert_eq!(policy.select(&[2, 5], 0), Some(1));
+        assert_eq!(policy.select(&[4, 4], 1), Some(0));
+        assert_eq!(policy.select(&[0, 0], 0), None);
     }
 }
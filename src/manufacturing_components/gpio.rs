@@ -0,0 +1,373 @@
+use crate::manufacturing_components::input::Trigger;
+use async_trait::async_trait;
+use color_eyre::Result;
+use futures::StreamExt;
+use gpio_cdev::{AsyncLineEventHandle, Chip, EventType, LineHandle, LineRequestFlags};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, thiserror::Error)]
+pub enum GpioError {
+    #[error("Error: Unable to open the GPIO chip {path:?}, make sure you have read and write permission to it: {source}")]
+    Chip {
+        path: PathBuf,
+        source: gpio_cdev::Error,
+    },
+    /// the line is missing from the chip or already requested by another consumer
+    #[error("Error: Unable to request line {offset} for {consumer}: {source}")]
+    Request {
+        offset: u32,
+        consumer: String,
+        source: gpio_cdev::Error,
+    },
+    /// a requested line can't be read anymore, e.g. the chip went away
+    #[error("Error: Unable to read the line of {consumer}: {source}")]
+    Read {
+        consumer: String,
+        source: gpio_cdev::Error,
+    },
+    /// the events of a requested line stopped, e.g. its file descriptor was closed
+    #[error("Error: The line of {consumer} stopped sending events")]
+    Stopped { consumer: String },
+    /// a setting wiring a line can't be used
+    #[error("Error: {variable} {problem}")]
+    Setting {
+        variable: String,
+        problem: &'static str,
+    },
+}
+
+/// An edge read on an input line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    pub event_type: EventType,
+    /// when the edge happened, in nanoseconds of a monotonic clock
+    pub timestamp: u64,
+}
+
+/// The edges of an input line, as triggered when it was requested
+#[async_trait]
+pub trait InputLine: Send {
+    /// The next edge, None once the line stopped sending events
+    async fn next_edge(&mut self) -> Option<Result<Edge, gpio_cdev::Error>>;
+
+    /// Current value of the line
+    fn value(&self) -> Result<u8, gpio_cdev::Error>;
+}
+
+/// A line driven high or low
+pub trait OutputLine: Send {
+    fn set_value(&self, value: u8) -> Result<(), gpio_cdev::Error>;
+}
+
+/// Where the components and programs request their lines from, the GPIO character device of the
+/// board, see CdevGpio, or an in-memory chip in the tests, see mock
+pub trait GpioProvider: Send {
+    /// Request the edges of an input line for the consumer, as the trigger selects them
+    fn input(
+        &mut self,
+        offset: u32,
+        trigger: Trigger,
+        consumer: &str,
+    ) -> Result<Box<dyn InputLine>>;
+
+    /// Request an output line for the consumer, starting low
+    fn output(&mut self, offset: u32, consumer: &str) -> Result<Box<dyn OutputLine>>;
+}
+
+/// The lines of a chip of the GPIO character device, e.g. /dev/gpiochip0
+pub struct CdevGpio {
+    chip: Chip,
+}
+
+impl CdevGpio {
+    pub fn new(path: &str) -> Result<Self, GpioError> {
+        let chip = Chip::new(path).map_err(|source| GpioError::Chip {
+            path: path.into(),
+            source,
+        })?;
+        Ok(Self { chip })
+    }
+
+    /// Whether the chip can still be opened, e.g. the device node is there and the twin is still
+    /// allowed to use it
+    pub fn probe(path: &str) -> Result<(), GpioError> {
+        Self::new(path).map(|_| ())
+    }
+}
+
+impl GpioProvider for CdevGpio {
+    fn input(
+        &mut self,
+        offset: u32,
+        trigger: Trigger,
+        consumer: &str,
+    ) -> Result<Box<dyn InputLine>> {
+        let handle = self
+            .chip
+            .get_line(offset)
+            .and_then(|line| line.async_events(trigger.line_flags(), trigger.edge, consumer))
+            .map_err(|source| request_error(offset, consumer, source))?;
+        Ok(Box::new(CdevInput(handle)))
+    }
+
+    fn output(&mut self, offset: u32, consumer: &str) -> Result<Box<dyn OutputLine>> {
+        let handle = self
+            .chip
+            .get_line(offset)
+            .and_then(|line| line.request(LineRequestFlags::OUTPUT, 0, consumer))
+            .map_err(|source| request_error(offset, consumer, source))?;
+        Ok(Box::new(handle))
+    }
+}
+
+fn request_error(offset: u32, consumer: &str, source: gpio_cdev::Error) -> GpioError {
+    GpioError::Request {
+        offset,
+        consumer: consumer.to_string(),
+        source,
+    }
+}
+
+struct CdevInput(AsyncLineEventHandle);
+
+#[async_trait]
+impl InputLine for CdevInput {
+    async fn next_edge(&mut self) -> Option<Result<Edge, gpio_cdev::Error>> {
+        let event = self.0.next().await?;
+        Some(event.map(|event| Edge {
+            event_type: event.event_type(),
+            timestamp: event.timestamp(),
+        }))
+    }
+
+    fn value(&self) -> Result<u8, gpio_cdev::Error> {
+        self.0.as_ref().get_value()
+    }
+}
+
+impl OutputLine for LineHandle {
+    fn set_value(&self, value: u8) -> Result<(), gpio_cdev::Error> {
+        LineHandle::set_value(self, value)
+    }
+}
+
+/// A provider shared by whoever requests lines from it, e.g. the steps of a sequence, which are
+/// only built once they run
+#[derive(Clone)]
+pub struct SharedGpio(Arc<Mutex<Box<dyn GpioProvider>>>);
+
+impl SharedGpio {
+    pub fn new(gpio: Box<dyn GpioProvider>) -> Self {
+        Self(Arc::new(Mutex::new(gpio)))
+    }
+}
+
+impl GpioProvider for SharedGpio {
+    fn input(
+        &mut self,
+        offset: u32,
+        trigger: Trigger,
+        consumer: &str,
+    ) -> Result<Box<dyn InputLine>> {
+        let mut gpio = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        gpio.input(offset, trigger, consumer)
+    }
+
+    fn output(&mut self, offset: u32, consumer: &str) -> Result<Box<dyn OutputLine>> {
+        let mut gpio = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        gpio.output(offset, consumer)
+    }
+}
+
+/// Lines that aren't wired to anything, so a program can be built to walk its plan without
+/// requesting its lines from the chip. Outputs ignore what they're driven to, inputs never signal
+pub struct Unwired;
+
+impl GpioProvider for Unwired {
+    fn input(&mut self, _: u32, _: Trigger, _: &str) -> Result<Box<dyn InputLine>> {
+        Ok(Box::new(UnwiredLine))
+    }
+
+    fn output(&mut self, _: u32, _: &str) -> Result<Box<dyn OutputLine>> {
+        Ok(Box::new(UnwiredLine))
+    }
+}
+
+struct UnwiredLine;
+
+#[async_trait]
+impl InputLine for UnwiredLine {
+    async fn next_edge(&mut self) -> Option<Result<Edge, gpio_cdev::Error>> {
+        None
+    }
+
+    fn value(&self) -> Result<u8, gpio_cdev::Error> {
+        Ok(0)
+    }
+}
+
+impl OutputLine for UnwiredLine {
+    fn set_value(&self, _: u8) -> Result<(), gpio_cdev::Error> {
+        Ok(())
+    }
+}
+
+/// A chip without hardware behind it, so the components and programs can be tested anywhere. The
+/// test keeps a clone of it to raise edges on the input lines and read what was driven on the
+/// outputs
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::io::ErrorKind;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    #[derive(Default)]
+    struct Lines {
+        /// every value a line was driven to or raised to, in order
+        history: HashMap<u32, Vec<u8>>,
+        inputs: HashMap<u32, UnboundedSender<Edge>>,
+        /// lines requested and not released yet
+        held: HashSet<u32>,
+        /// timestamp of the last edge raised, in nanoseconds
+        clock: u64,
+    }
+
+    impl Lines {
+        fn value(&self, offset: u32) -> u8 {
+            self.history
+                .get(&offset)
+                .and_then(|history| history.last())
+                .copied()
+                .unwrap_or(0)
+        }
+
+        /// Hold the line until its handle is dropped, a line already held is busy like it is on
+        /// a chip
+        fn request(&mut self, offset: u32, consumer: &str) -> Result<(), GpioError> {
+            if !self.held.insert(offset) {
+                let busy = std::io::Error::from(ErrorKind::ResourceBusy);
+                return Err(request_error(offset, consumer, busy.into()));
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    pub struct MockGpio {
+        lines: Arc<Mutex<Lines>>,
+    }
+
+    impl MockGpio {
+        /// Current value of the line, low until it's driven
+        pub fn value(&self, offset: u32) -> u8 {
+            self.lines.lock().unwrap().value(offset)
+        }
+
+        /// Every value the line was driven to, starting with the low it's requested at
+        pub fn history(&self, offset: u32) -> Vec<u8> {
+            let lines = self.lines.lock().unwrap();
+            lines.history.get(&offset).cloned().unwrap_or_default()
+        }
+
+        /// Stop the events of the input line, like a chip going away
+        pub fn close(&self, offset: u32) {
+            self.lines.lock().unwrap().inputs.remove(&offset);
+        }
+
+        /// Raise an edge on the input line, 1 ms after the previous one
+        pub fn edge(&self, offset: u32, event_type: EventType) {
+            let timestamp = self.lines.lock().unwrap().clock + 1_000_000;
+            self.edge_at(offset, event_type, timestamp);
+        }
+
+        /// Raise an edge on the input line at the timestamp, in nanoseconds, e.g. to bounce a
+        /// contact. The value of the line follows the edge
+        pub fn edge_at(&self, offset: u32, event_type: EventType, timestamp: u64) {
+            let mut lines = self.lines.lock().unwrap();
+            lines.clock = timestamp;
+            let value = match event_type {
+                EventType::RisingEdge => 1,
+                EventType::FallingEdge => 0,
+            };
+            lines.history.entry(offset).or_default().push(value);
+            if let Some(input) = lines.inputs.get(&offset) {
+                input
+                    .send(Edge {
+                        event_type,
+                        timestamp,
+                    })
+                    .ok();
+            }
+        }
+    }
+
+    impl GpioProvider for MockGpio {
+        fn input(&mut self, offset: u32, _: Trigger, consumer: &str) -> Result<Box<dyn InputLine>> {
+            let mut lines = self.lines.lock().unwrap();
+            lines.request(offset, consumer)?;
+            let (edges_tx, edges) = unbounded_channel();
+            lines.inputs.insert(offset, edges_tx);
+            Ok(Box::new(MockInput {
+                offset,
+                lines: self.lines.clone(),
+                edges,
+            }))
+        }
+
+        fn output(&mut self, offset: u32, consumer: &str) -> Result<Box<dyn OutputLine>> {
+            let mut lines = self.lines.lock().unwrap();
+            lines.request(offset, consumer)?;
+            lines.history.insert(offset, vec![0]);
+            Ok(Box::new(MockOutput {
+                offset,
+                lines: self.lines.clone(),
+            }))
+        }
+    }
+
+    struct MockInput {
+        offset: u32,
+        lines: Arc<Mutex<Lines>>,
+        edges: UnboundedReceiver<Edge>,
+    }
+
+    #[async_trait]
+    impl InputLine for MockInput {
+        async fn next_edge(&mut self) -> Option<Result<Edge, gpio_cdev::Error>> {
+            self.edges.recv().await.map(Ok)
+        }
+
+        fn value(&self) -> Result<u8, gpio_cdev::Error> {
+            Ok(self.lines.lock().unwrap().value(self.offset))
+        }
+    }
+
+    impl Drop for MockInput {
+        fn drop(&mut self) {
+            let mut lines = self.lines.lock().unwrap();
+            lines.inputs.remove(&self.offset);
+            lines.held.remove(&self.offset);
+        }
+    }
+
+    struct MockOutput {
+        offset: u32,
+        lines: Arc<Mutex<Lines>>,
+    }
+
+    impl OutputLine for MockOutput {
+        fn set_value(&self, value: u8) -> Result<(), gpio_cdev::Error> {
+            let mut lines = self.lines.lock().unwrap();
+            lines.history.entry(self.offset).or_default().push(value);
+            Ok(())
+        }
+    }
+
+    impl Drop for MockOutput {
+        fn drop(&mut self) {
+            self.lines.lock().unwrap().held.remove(&self.offset);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use crate::config::Settings;
+use crate::manufacturing_components::gpio::{GpioProvider, OutputLine};
+use crate::manufacturing_components::output_line;
+use crate::vitals::Vitals;
+use color_eyre::Result;
+use std::time::Duration;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::error;
+
+/// Time between two toggles of the heartbeat line that wasn't given one
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Proof for the PLC that the twin is alive: an output line toggled at a fixed rate for as long as
+/// every task of the vitals runs, e.g. the event publisher. Once one of them is gone the line is
+/// left low and never toggled again, so the PLC interlocks the cell rather than run it blind
+pub struct Heartbeat {
+    line: Box<dyn OutputLine>,
+    interval: Duration,
+    vitals: Vitals,
+}
+
+impl Heartbeat {
+    pub fn new(
+        gpio: &mut dyn GpioProvider,
+        line: u32,
+        interval: Duration,
+        vitals: Vitals,
+    ) -> Result<Self> {
+        Ok(Self {
+            line: output_line(gpio, line, "Heartbeat")?,
+            interval,
+            vitals,
+        })
+    }
+
+    /// The heartbeat on HEARTBEAT_LINE, toggled every HEARTBEAT_INTERVAL milliseconds, None when
+    /// it isn't configured
+    pub fn from_settings(
+        settings: &Settings,
+        gpio: &mut dyn GpioProvider,
+        vitals: Vitals,
+    ) -> Result<Option<Self>> {
+        let line = match settings.parse("HEARTBEAT_LINE", "unsigned integer")? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let interval = settings
+            .positive("HEARTBEAT_INTERVAL", "milliseconds")?
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_INTERVAL);
+
+        Ok(Some(Self::new(gpio, line, interval, vitals)?))
+    }
+
+    /// Toggle the line until a watched task is gone or the line can't be driven, then leave it low
+    pub async fn run(self) {
+        let mut interval = time::interval(self.interval);
+        // a late toggle is still a toggle, missed ones aren't made up for in a burst
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut value = 0;
+
+        loop {
+            interval.tick().await;
+            if let Some(name) = self.vitals.dead() {
+                error!("The heartbeat stopped, the {name} is gone");
+                break;
+            }
+            value ^= 1;
+            if let Err(e) = self.line.set_value(value) {
+                error!("The heartbeat stopped, its line can't be driven: {e}");
+                break;
+            }
+        }
+        self.line.set_value(0).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+
+    #[tokio::test]
+    async fn toggling_stops_once_a_watched_task_is_gone() {
+        let mut gpio = MockGpio::default();
+        let vitals = Vitals::new();
+        let heartbeat = Heartbeat::new(&mut gpio, 0, Duration::from_millis(1), vitals.clone());
+        let vital = vitals.watch("event publisher");
+        let beating = tokio::spawn(heartbeat.unwrap().run());
+
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(gpio.history(0).len() > 3);
+
+        drop(vital);
+        beating.await.unwrap();
+        let toggled = gpio.history(0).len();
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(gpio.history(0).len(), toggled);
+        assert_eq!(gpio.value(0), 0);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::config::{ConfigError, Settings};
+use crate::manufacturing_components::gpio::{Edge, InputLine};
+use gpio_cdev::{EventRequestFlags, LineRequestFlags};
+use std::time::Duration;
+
+/// Which edges of the input line of a component are its events, and the level the line is active
+/// at, so the same binary works with sensors wired in either polarity.
+///
+/// Read from <COMPONENT>_EDGE (rising, falling or both) and <COMPONENT>_ACTIVE (high or low), the
+/// edge defaults to the one the component was built for and the line is active high by default.
+/// The edges and the value of an active low line are those of the inverted line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trigger {
+    pub edge: EventRequestFlags,
+    pub active_low: bool,
+}
+
+impl Trigger {
+    pub fn from_settings(
+        settings: &Settings,
+        component: &str,
+        edge: EventRequestFlags,
+    ) -> Result<Self, ConfigError> {
+        let prefix = component.to_uppercase();
+
+        let key = format!("{prefix}_EDGE");
+        let edge = match settings.get(&key) {
+            None => edge,
+            Some("rising") => EventRequestFlags::RISING_EDGE,
+            Some("falling") => EventRequestFlags::FALLING_EDGE,
+            Some("both") => EventRequestFlags::BOTH_EDGES,
+            Some(other) => {
+                return Err(ConfigError::invalid(&key, "rising, falling or both", other))
+            }
+        };
+        let key = format!("{prefix}_ACTIVE");
+        let active_low = match settings.get(&key) {
+            Some("high") | None => false,
+            Some("low") => true,
+            Some(other) => return Err(ConfigError::invalid(&key, "high or low", other)),
+        };
+
+        Ok(Self { edge, active_low })
+    }
+
+    /// Flags the line is requested with
+    pub fn line_flags(&self) -> LineRequestFlags {
+        if self.active_low {
+            LineRequestFlags::INPUT | LineRequestFlags::ACTIVE_LOW
+        } else {
+            LineRequestFlags::INPUT
+        }
+    }
+}
+
+/// The events of an input line, with the edges of a bouncing contact coalesced into one.
+///
+/// Edges within the debounce window of the last one let through are dropped. The window of a line
+/// is read from DEBOUNCE_LINE_<offset>, or DEBOUNCE for every line, in milliseconds, and is off by
+/// default
+pub struct DebouncedLine {
+    line: Box<dyn InputLine>,
+    window: Duration,
+    /// kernel timestamp of the last edge let through, in nanoseconds
+    last_edge: Option<u64>,
+}
+
+impl DebouncedLine {
+    pub fn new(
+        settings: &Settings,
+        line: Box<dyn InputLine>,
+        offset: u32,
+    ) -> Result<Self, ConfigError> {
+        let key = format!("DEBOUNCE_LINE_{offset}");
+        let window = match settings.parse(&key, "milliseconds")? {
+            Some(millis) => Some(millis),
+            None => settings.parse("DEBOUNCE", "milliseconds")?,
+        }
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::ZERO);
+
+        Ok(Self {
+            line,
+            window,
+            last_edge: None,
+        })
+    }
+
+    /// The next edge outside the debounce window, None once the line stopped sending events
+    pub async fn next(&mut self) -> Option<Result<Edge, gpio_cdev::Error>> {
+        loop {
+            let event = self.line.next_edge().await?;
+            match &event {
+                Ok(edge) if !self.accept(edge.timestamp) => continue,
+                _ => return Some(event),
+            }
+        }
+    }
+
+    /// Current value of the line
+    pub fn value(&self) -> Result<u8, gpio_cdev::Error> {
+        self.line.value()
+    }
+
+    fn accept(&mut self, timestamp: u64) -> bool {
+        if bouncing(self.last_edge, timestamp, self.window) {
+            return false;
+        }
+        self.last_edge = Some(timestamp);
+        true
+    }
+}
+
+/// Whether the edge at timestamp is within the window of the last edge let through
+fn bouncing(last_edge: Option<u64>, timestamp: u64, window: Duration) -> bool {
+    last_edge.is_some_and(|last| timestamp.saturating_sub(last) < window.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+    use crate::manufacturing_components::gpio::GpioProvider;
+    use gpio_cdev::EventType;
+
+    #[test]
+    fn active_low_lines_are_inverted() {
+        let trigger = Trigger {
+            edge: EventRequestFlags::RISING_EDGE,
+            active_low: true,
+        };
+        assert!(trigger.line_flags().contains(LineRequestFlags::ACTIVE_LOW));
+
+        let trigger = Trigger {
+            active_low: false,
+            ..trigger
+        };
+        assert_eq!(trigger.line_flags(), LineRequestFlags::INPUT);
+    }
+
+    #[test]
+    fn edges_within_the_window_are_coalesced() {
+        let window = Duration::from_millis(20);
+        let millis = |ms: u64| ms * 1_000_000;
+
+        assert!(!bouncing(None, millis(0), window));
+        assert!(bouncing(Some(millis(0)), millis(5), window));
+        assert!(!bouncing(Some(millis(0)), millis(20), window));
+        assert!(!bouncing(Some(millis(0)), millis(5), Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn bouncing_contacts_signal_once() {
+        let mut gpio = MockGpio::default();
+        let trigger = Trigger {
+            edge: EventRequestFlags::RISING_EDGE,
+            active_low: false,
+        };
+        let mut line = DebouncedLine {
+            line: gpio.input(0, trigger, "test").unwrap(),
+            window: Duration::from_millis(20),
+            last_edge: None,
+        };
+
+        let millis = |ms: u64| ms * 1_000_000;
+        gpio.edge_at(0, EventType::RisingEdge, millis(0));
+        gpio.edge_at(0, EventType::RisingEdge, millis(3));
+        gpio.edge_at(0, EventType::RisingEdge, millis(25));
+
+        assert_eq!(line.next().await.unwrap().unwrap().timestamp, millis(0));
+        assert_eq!(line.next().await.unwrap().unwrap().timestamp, millis(25));
+    }
+}
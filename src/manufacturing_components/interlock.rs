@@ -0,0 +1,100 @@
+use crate::config::{ConfigError, Settings};
+use crate::manufacturing_components::robot::RobotPosition;
+use serde_json::{json, Map, Value};
+
+/// Arbitrates the positions robots of an extended cell share, e.g. the piston at position 15. A
+/// robot holds a shared position from the move there until it moves on, another one may only move
+/// there once it's released
+#[derive(Debug, Default)]
+pub struct Interlock {
+    /// the shared positions, with the robot holding each, if any
+    positions: Vec<(RobotPosition, Option<String>)>,
+}
+
+impl Interlock {
+    pub fn new(shared: impl IntoIterator<Item = RobotPosition>) -> Self {
+        Self {
+            positions: shared
+                .into_iter()
+                .map(|position| (position, None))
+                .collect(),
+        }
+    }
+
+    /// The positions listed in INTERLOCK_POSITIONS, e.g. "position 15", the piston by default
+    pub fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        let positions = settings.get("INTERLOCK_POSITIONS").unwrap_or("position 15");
+        let shared = positions
+            .split(',')
+            .map(|position| {
+                serde_json::from_value(json!(position.trim())).map_err(|_| {
+                    ConfigError::invalid("INTERLOCK_POSITIONS", "position 1, 15 or 66", position)
+                })
+            })
+            .collect::<Result<Vec<RobotPosition>, _>>()?;
+        Ok(Self::new(shared))
+    }
+
+    /// Whether robot may move to position, taking it when it's shared and free. A robot already
+    /// holding it may move there again
+    pub fn acquire(&mut self, robot: &str, position: RobotPosition) -> bool {
+        match self
+            .positions
+            .iter_mut()
+            .find(|(shared, _)| *shared == position)
+        {
+            Some((_, holder @ None)) => {
+                *holder = Some(robot.to_string());
+                true
+            }
+            Some((_, Some(holder))) => holder == robot,
+            None => true,
+        }
+    }
+
+    /// Release the shared positions robot holds, other than the one it's at
+    pub fn release(&mut self, robot: &str, at: RobotPosition) {
+        for (position, holder) in &mut self.positions {
+            if *position != at && holder.as_deref() == Some(robot) {
+                *holder = None;
+            }
+        }
+    }
+
+    /// The robot holding each shared position, by position
+    pub fn state(&self) -> Value {
+        let holders: Map<String, Value> = self
+            .positions
+            .iter()
+            .map(|(position, holder)| {
+                // positions serialize to their name, unwrap is safe
+                (json!(position).as_str().unwrap().to_string(), json!(holder))
+            })
+            .collect();
+        Value::Object(holders)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use RobotPosition::{Position1, Position15, Position66};
+
+    #[test]
+    fn shared_positions_are_held_until_moved_on() {
+        let mut interlock = Interlock::new([Position15]);
+
+        assert!(interlock.acquire("Robot", Position1));
+        assert!(interlock.acquire("Robot", Position15));
+        assert!(!interlock.acquire("Robot B", Position15));
+        assert!(interlock.acquire("Robot", Position15));
+
+        // still at the piston
+        interlock.release("Robot", Position15);
+        assert!(!interlock.acquire("Robot B", Position15));
+
+        interlock.release("Robot", Position66);
+        assert!(interlock.acquire("Robot B", Position15));
+        assert_eq!(interlock.state(), json!({ "position 15": "Robot B" }));
+    }
+}
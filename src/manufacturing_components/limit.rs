@@ -0,0 +1,301 @@
+use crate::config::{ConfigError, Settings};
+use crate::manufacturing_components::gpio::GpioProvider;
+use crate::manufacturing_components::input::{DebouncedLine, Trigger};
+use crate::manufacturing_components::{input_line, Component, ComponentEvent};
+use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures::future;
+use gpio_cdev::{EventRequestFlags, EventType};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use std::time::SystemTime;
+
+/// How the contact of a switch is wired
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Contact {
+    /// the circuit closes when the switch is engaged, the line is high
+    NormallyOpen,
+    /// the circuit opens when the switch is engaged, the line is low, so a broken wire reads as
+    /// engaged rather than never engaging
+    NormallyClosed,
+}
+
+impl Contact {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "no" | "normally-open" => Some(Contact::NormallyOpen),
+            "nc" | "normally-closed" => Some(Contact::NormallyClosed),
+            _ => None,
+        }
+    }
+
+    /// Whether the switch is engaged when its line is at value
+    fn engaged(self, value: u8) -> bool {
+        match self {
+            Contact::NormallyOpen => value == 1,
+            Contact::NormallyClosed => value == 0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    Engaged { switch: String },
+    Released { switch: String },
+}
+
+/// A switch at the end of a travel, e.g. the piston at its top, read on both edges of its line so
+/// its state is always known. Programs check it before moving another part of the cell into the
+/// way, see Precondition
+pub struct LimitSwitch {
+    name: String,
+    line: DebouncedLine,
+    contact: Contact,
+    engaged: bool,
+    /// whether the line stopped sending events, which is only reported once
+    ended: bool,
+}
+
+impl Serialize for LimitSwitch {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("limit", 3)?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("engaged", &self.engaged)?;
+
+        let now = SystemTime::iso8601_now();
+        s.serialize_field("updateTimestamp", &now)?;
+
+        s.end()
+    }
+}
+
+impl LimitSwitch {
+    /// The switch read on line, its state read from the line right away
+    pub fn new(
+        settings: &Settings,
+        name: &str,
+        gpio: &mut dyn GpioProvider,
+        line: u32,
+        contact: Contact,
+    ) -> Result<Self> {
+        // the polarity is the contact's, every edge is read so the state is always known
+        let trigger = Trigger {
+            edge: EventRequestFlags::BOTH_EDGES,
+            active_low: false,
+        };
+        let line = input_line(settings, gpio, line, trigger, name)?;
+        let engaged = contact.engaged(line.value()?);
+
+        Ok(Self {
+            name: name.to_string(),
+            line,
+            contact,
+            engaged,
+            ended: false,
+        })
+    }
+
+    /// The switch read on <NAME>_LINE and wired as <NAME>_CONTACT, no or nc, normally open by
+    /// default, where the name is upper cased, e.g. PISTON_TOP_LINE
+    pub fn from_settings(
+        settings: &Settings,
+        name: &str,
+        gpio: &mut dyn GpioProvider,
+    ) -> Result<Self> {
+        let prefix = name.to_uppercase();
+
+        let key = format!("{prefix}_LINE");
+        let line = settings
+            .parse(&key, "unsigned integer")?
+            .ok_or_else(|| ConfigError::missing(&key, "the switch is read on it"))?;
+        let key = format!("{prefix}_CONTACT");
+        let contact = match settings.get(&key) {
+            None => Contact::NormallyOpen,
+            Some(value) => Contact::parse(value)
+                .ok_or_else(|| ConfigError::invalid(&key, "no or nc", value))?,
+        };
+
+        Self::new(settings, name, gpio, line, contact)
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged
+    }
+
+    /// Wait for the switch to be engaged or released. Edges leaving it as it was, e.g. after the
+    /// other edge of a bounce was dropped, aren't events
+    pub async fn async_next_event(&mut self) -> Result<Event> {
+        if self.ended {
+            return future::pending().await;
+        }
+        loop {
+            let edge = match self.line.next().await {
+                Some(edge) => edge?,
+                None => {
+                    self.ended = true;
+                    return Err(eyre!("The line of {} stopped sending events", self.name));
+                }
+            };
+            let value = match edge.event_type {
+                EventType::RisingEdge => 1,
+                EventType::FallingEdge => 0,
+            };
+            let engaged = self.contact.engaged(value);
+            if engaged == self.engaged {
+                continue;
+            }
+
+            self.engaged = engaged;
+            let switch = self.name.clone();
+            return Ok(if engaged {
+                Event::Engaged { switch }
+            } else {
+                Event::Released { switch }
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Component for LimitSwitch {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "limit"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await?.into())
+    }
+}
+
+/// Limit switches that have to be engaged before a move, e.g. the piston at its top before the arm
+/// moves to position 15
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Precondition {
+    pub switches: Vec<String>,
+}
+
+impl Precondition {
+    /// The switches named in the comma separated list
+    pub fn parse(value: &str) -> Self {
+        Self {
+            switches: value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+
+    /// Fails with the first switch that isn't engaged, or isn't a switch of the cell, before what
+    pub fn check(&self, switches: &[LimitSwitch], what: &str) -> Result<()> {
+        for name in &self.switches {
+            let engaged = switches
+                .iter()
+                .find(|switch| switch.name == *name)
+                .ok_or_else(|| eyre!("Unknown limit switch {name}, required before {what}"))?
+                .is_engaged();
+            if !engaged {
+                return Err(eyre!("{name} has to be engaged before {what}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+
+    #[tokio::test]
+    async fn changes_are_published_once() {
+        let mut gpio = MockGpio::default();
+        let mut switch = LimitSwitch::new(
+            &Settings::default(),
+            "piston_top",
+            &mut gpio,
+            0,
+            Contact::NormallyOpen,
+        )
+        .unwrap();
+        assert!(!switch.is_engaged());
+
+        gpio.edge(0, EventType::RisingEdge);
+        gpio.edge(0, EventType::RisingEdge);
+        gpio.edge(0, EventType::FallingEdge);
+        assert!(matches!(
+            switch.async_next_event().await.unwrap(),
+            Event::Engaged { .. }
+        ));
+        assert!(matches!(
+            switch.async_next_event().await.unwrap(),
+            Event::Released { .. }
+        ));
+    }
+
+    #[test]
+    fn normally_closed_switches_are_engaged_when_their_line_is_low() {
+        let mut gpio = MockGpio::default();
+        let switch = LimitSwitch::new(
+            &Settings::default(),
+            "piston_top",
+            &mut gpio,
+            0,
+            Contact::NormallyClosed,
+        )
+        .unwrap();
+        assert!(switch.is_engaged());
+
+        assert_eq!(Contact::parse("nc"), Some(Contact::NormallyClosed));
+        assert_eq!(Contact::parse("closed"), None);
+    }
+
+    #[test]
+    fn preconditions_need_every_switch_engaged() {
+        let mut gpio = MockGpio::default();
+        let switches = [
+            LimitSwitch::new(
+                &Settings::default(),
+                "piston_top",
+                &mut gpio,
+                0,
+                Contact::NormallyClosed,
+            )
+            .unwrap(),
+            LimitSwitch::new(
+                &Settings::default(),
+                "gripper_open",
+                &mut gpio,
+                1,
+                Contact::NormallyOpen,
+            )
+            .unwrap(),
+        ];
+
+        assert!(Precondition::parse("piston_top")
+            .check(&switches, "moving")
+            .is_ok());
+        assert!(Precondition::parse("piston_top, gripper_open")
+            .check(&switches, "moving")
+            .is_err());
+        assert!(Precondition::parse("door")
+            .check(&switches, "moving")
+            .is_err());
+        assert!(Precondition::default().check(&switches, "moving").is_ok());
+    }
+}
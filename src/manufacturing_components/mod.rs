@@ -1,4 +1,307 @@
+pub mod ambient;
+pub mod analog;
+pub mod camera;
+pub mod can;
+pub mod conveyor;
+pub mod counters;
+pub mod cycle;
+pub mod dry_run;
+pub mod estop;
 pub mod feeder;
+pub mod gpio;
+pub mod heartbeat;
+pub mod input;
+pub mod interlock;
+pub mod limit;
+pub mod modbus;
 pub mod piston;
+pub mod plc;
 pub mod program;
+pub mod pwm;
+pub mod quality;
+pub mod registry;
 pub mod robot;
+pub mod scanner;
+pub mod script;
+pub mod sequence;
+pub mod spi;
+pub mod stepper;
+pub mod vibration;
+
+use crate::config::Settings;
+use async_trait::async_trait;
+use color_eyre::Result;
+use gpio::{GpioProvider, OutputLine};
+use input::{DebouncedLine, Trigger};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// A part of the cell the twin follows through its GPIO lines
+#[async_trait]
+pub trait Component: Send {
+    fn name(&self) -> &str;
+
+    /// Type of the component, e.g. feeder, the key of its state in the twin state and the topic of
+    /// its events
+    fn component(&self) -> &'static str;
+
+    /// State of the component as reported in the twin state
+    fn serialize_state(&self) -> Value;
+
+    /// Wait for the next event read from the input line of the component
+    async fn next_event(&mut self) -> Result<ComponentEvent>;
+
+    /// Drive the outputs of the component to their safe values, e.g. when what it was doing is
+    /// cancelled. Returns the event announcing the new state, None when nothing changed
+    fn make_safe(&mut self) -> Result<Option<ComponentEvent>> {
+        Ok(None)
+    }
+}
+
+/// The sensor of a component didn't fire within the time it was given, e.g. because it's
+/// disconnected
+#[derive(Debug)]
+pub struct Timeout {
+    pub component: String,
+    pub after: Duration,
+}
+
+impl Display for Timeout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Error: {} didn't signal within {} ms",
+            self.component,
+            self.after.as_millis()
+        )
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// Request the events of an input line for the named component, triggered and debounced as
+/// configured for the line
+pub fn input_line(
+    settings: &Settings,
+    gpio: &mut dyn GpioProvider,
+    offset: u32,
+    trigger: Trigger,
+    name: &str,
+) -> Result<DebouncedLine> {
+    let line = gpio.input(offset, trigger, &format!("{name} consumer"))?;
+    Ok(DebouncedLine::new(settings, line, offset)?)
+}
+
+/// Request an output line driven by the named component, starting low
+pub fn output_line(
+    gpio: &mut dyn GpioProvider,
+    line: u32,
+    name: &str,
+) -> Result<Box<dyn OutputLine>> {
+    gpio.output(line, &format!("{name} driver"))
+}
+
+/// An event from any of the components, tagged with the component it came from so it can be routed
+/// to a per component topic
+#[derive(Debug, Serialize)]
+#[serde(tag = "component", content = "event", rename_all = "lowercase")]
+pub enum ComponentEvent {
+    Feeder(feeder::Event),
+    Robot(robot::Event),
+    Piston(piston::Event),
+    Program(program::Event),
+    Conveyor(conveyor::Event),
+    Ambient(ambient::Event),
+    Analog(analog::Event),
+    Stepper(stepper::Event),
+    Limit(limit::Event),
+    EStop(estop::Event),
+    Vibration(vibration::Event),
+    Scanner(scanner::Event),
+    Camera(camera::Event),
+    Modbus(modbus::Event),
+    Can(can::Event),
+    Quality(quality::Event),
+}
+
+/// Broad classification of events, used to decide how they are delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// regular production telemetry, e.g. materials picked up
+    Telemetry,
+    /// high rate position updates which are superseded by the next one
+    Position,
+    /// faults and conditions an operator has to act on
+    Alarm,
+    /// replies to commands from the cloud
+    Ack,
+}
+
+impl ComponentEvent {
+    /// Name of the component that emitted the event, used as the topic subfolder
+    pub fn component(&self) -> &'static str {
+        match self {
+            ComponentEvent::Feeder(_) => "feeder",
+            ComponentEvent::Robot(_) => "robot",
+            ComponentEvent::Piston(_) => "piston",
+            ComponentEvent::Program(_) => "program",
+            ComponentEvent::Conveyor(_) => "conveyor",
+            ComponentEvent::Ambient(_) => "ambient",
+            ComponentEvent::Analog(_) => "analog",
+            ComponentEvent::Stepper(_) => "stepper",
+            ComponentEvent::Limit(_) => "limit",
+            ComponentEvent::EStop(_) => "estop",
+            ComponentEvent::Vibration(_) => "vibration",
+            ComponentEvent::Scanner(_) => "scanner",
+            ComponentEvent::Camera(_) => "camera",
+            ComponentEvent::Modbus(_) => "modbus",
+            ComponentEvent::Can(_) => "can",
+            ComponentEvent::Quality(_) => "quality",
+        }
+    }
+
+    /// Subfolder of the topic the event is published on, the component's except for alarms
+    /// operators have to act on before the line stops, which get a topic of their own
+    pub fn topic(&self) -> &'static str {
+        match self {
+            ComponentEvent::Feeder(feeder::Event::LowSupply { .. })
+            | ComponentEvent::Robot(robot::Event::IllegalTransition { .. })
+            | ComponentEvent::Piston(piston::Event::OverPressure { .. })
+            | ComponentEvent::Analog(analog::Event::OutOfRange { .. })
+            | ComponentEvent::EStop(estop::Event::Asserted | estop::Event::Cleared)
+            | ComponentEvent::Vibration(vibration::Event::Anomaly { .. })
+            | ComponentEvent::Quality(quality::Event::RejectRateHigh { .. }) => "alarms",
+            _ => self.component(),
+        }
+    }
+
+    pub fn kind(&self) -> EventKind {
+        match self {
+            ComponentEvent::Feeder(feeder::Event::LowSupply { .. }) => EventKind::Alarm,
+            ComponentEvent::Feeder(_) => EventKind::Telemetry,
+            ComponentEvent::Robot(robot::Event::PositionReached { .. }) => EventKind::Position,
+            ComponentEvent::Robot(robot::Event::IllegalTransition { .. }) => EventKind::Alarm,
+            ComponentEvent::Piston(piston::Event::OverPressure { .. }) => EventKind::Alarm,
+            ComponentEvent::Piston(_) => EventKind::Telemetry,
+            ComponentEvent::Program(
+                program::Event::Transition { .. } | program::Event::Progress { .. },
+            ) => EventKind::Telemetry,
+            ComponentEvent::Program(_) => EventKind::Alarm,
+            ComponentEvent::Conveyor(_) => EventKind::Telemetry,
+            ComponentEvent::Ambient(_) => EventKind::Telemetry,
+            ComponentEvent::Analog(analog::Event::Sample { .. }) => EventKind::Telemetry,
+            ComponentEvent::Analog(analog::Event::OutOfRange { .. }) => EventKind::Alarm,
+            ComponentEvent::Stepper(stepper::Event::Moved { .. }) => EventKind::Position,
+            ComponentEvent::Stepper(stepper::Event::Homed) => EventKind::Telemetry,
+            ComponentEvent::Limit(_) => EventKind::Telemetry,
+            ComponentEvent::EStop(estop::Event::Released) => EventKind::Telemetry,
+            ComponentEvent::EStop(_) => EventKind::Alarm,
+            ComponentEvent::Vibration(vibration::Event::Measured { .. }) => EventKind::Telemetry,
+            ComponentEvent::Vibration(vibration::Event::Anomaly { .. }) => EventKind::Alarm,
+            ComponentEvent::Scanner(_) => EventKind::Telemetry,
+            ComponentEvent::Camera(_) => EventKind::Telemetry,
+            ComponentEvent::Modbus(_) => EventKind::Telemetry,
+            ComponentEvent::Can(_) => EventKind::Telemetry,
+            ComponentEvent::Quality(quality::Event::Inspected { .. }) => EventKind::Telemetry,
+            ComponentEvent::Quality(quality::Event::RejectRateHigh { .. }) => EventKind::Alarm,
+        }
+    }
+}
+
+impl From<feeder::Event> for ComponentEvent {
+    fn from(event: feeder::Event) -> Self {
+        Self::Feeder(event)
+    }
+}
+
+impl From<robot::Event> for ComponentEvent {
+    fn from(event: robot::Event) -> Self {
+        Self::Robot(event)
+    }
+}
+
+impl From<piston::Event> for ComponentEvent {
+    fn from(event: piston::Event) -> Self {
+        Self::Piston(event)
+    }
+}
+
+impl From<program::Event> for ComponentEvent {
+    fn from(event: program::Event) -> Self {
+        Self::Program(event)
+    }
+}
+
+impl From<conveyor::Event> for ComponentEvent {
+    fn from(event: conveyor::Event) -> Self {
+        Self::Conveyor(event)
+    }
+}
+
+impl From<ambient::Event> for ComponentEvent {
+    fn from(event: ambient::Event) -> Self {
+        Self::Ambient(event)
+    }
+}
+
+impl From<analog::Event> for ComponentEvent {
+    fn from(event: analog::Event) -> Self {
+        Self::Analog(event)
+    }
+}
+
+impl From<stepper::Event> for ComponentEvent {
+    fn from(event: stepper::Event) -> Self {
+        Self::Stepper(event)
+    }
+}
+
+impl From<limit::Event> for ComponentEvent {
+    fn from(event: limit::Event) -> Self {
+        Self::Limit(event)
+    }
+}
+
+impl From<estop::Event> for ComponentEvent {
+    fn from(event: estop::Event) -> Self {
+        Self::EStop(event)
+    }
+}
+
+impl From<vibration::Event> for ComponentEvent {
+    fn from(event: vibration::Event) -> Self {
+        Self::Vibration(event)
+    }
+}
+
+impl From<scanner::Event> for ComponentEvent {
+    fn from(event: scanner::Event) -> Self {
+        Self::Scanner(event)
+    }
+}
+
+impl From<camera::Event> for ComponentEvent {
+    fn from(event: camera::Event) -> Self {
+        Self::Camera(event)
+    }
+}
+
+impl From<modbus::Event> for ComponentEvent {
+    fn from(event: modbus::Event) -> Self {
+        Self::Modbus(event)
+    }
+}
+
+impl From<can::Event> for ComponentEvent {
+    fn from(event: can::Event) -> Self {
+        Self::Can(event)
+    }
+}
+
+impl From<quality::Event> for ComponentEvent {
+    fn from(event: quality::Event) -> Self {
+        Self::Quality(event)
+    }
+}
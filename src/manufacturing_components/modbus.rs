@@ -0,0 +1,559 @@
+use crate::config::Settings;
+use crate::manufacturing_components::{Component, ComponentEvent};
+use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures::future::{BoxFuture, FutureExt};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Map, Value};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::lookup_host;
+use tokio::sync::Mutex;
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tokio_modbus::client::{rtu, tcp, Context, Reader, Writer};
+use tokio_modbus::slave::{Slave, SlaveContext};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use tracing::error;
+
+/// Time a device is given to answer a request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// Baud rate of a bus that wasn't given one
+pub const DEFAULT_BAUD_RATE: u32 = 9600;
+/// Time between two polls of a device that wasn't given one
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The table of a device a point is in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Table {
+    /// single bits, read and written
+    Coil,
+    /// single bits, read only
+    DiscreteInput,
+    /// 16 bit words, read and written
+    HoldingRegister,
+    /// 16 bit words, read only
+    InputRegister,
+}
+
+impl Table {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "coil" => Some(Table::Coil),
+            "discrete" => Some(Table::DiscreteInput),
+            "holding" => Some(Table::HoldingRegister),
+            "input" => Some(Table::InputRegister),
+            _ => None,
+        }
+    }
+}
+
+/// A named coil or register of a device, e.g. the valve of a gripper
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub name: String,
+    pub table: Table,
+    pub address: u16,
+}
+
+impl Point {
+    /// Points in the comma separated list of name:table:address, where the table is coil,
+    /// discrete, holding or input, e.g. gripper:coil:0,pressure:input:3
+    pub fn parse_list(value: &str) -> Result<Vec<Self>> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|point| !point.is_empty())
+            .map(|point| match point.split(':').collect::<Vec<_>>()[..] {
+                [name, table, address] => Ok(Point {
+                    name: name.to_string(),
+                    table: Table::parse(table).ok_or_else(|| {
+                        eyre!("Unknown table {table} of {name}, expected coil, discrete, holding or input")
+                    })?,
+                    address: address
+                        .parse()
+                        .map_err(|_| eyre!("The address of {name} isn't a register address"))?,
+                }),
+                _ => Err(eyre!("{point} isn't a point, expected name:table:address")),
+            })
+            .collect()
+    }
+}
+
+/// Reads and writes the points of a single device, coils are read and written as 0 or 1. Devices
+/// are reached either way, see RtuClient and TcpClient
+#[async_trait]
+pub trait ModbusClient: Send {
+    async fn read(&mut self, table: Table, address: u16) -> Result<u16>;
+    async fn write(&mut self, table: Table, address: u16, value: u16) -> Result<()>;
+}
+
+/// An RS-485 bus on a serial port, shared by the devices on it. The port is opened right away and
+/// framed by the first request of one of its devices
+pub struct RtuBus {
+    port: Option<SerialStream>,
+    context: Option<Context>,
+}
+
+impl RtuBus {
+    /// The context framing the port, framing it when it isn't yet
+    async fn connect(&mut self) -> Result<&mut Context> {
+        if let Some(port) = self.port.take() {
+            self.context = Some(rtu::connect(port).await?);
+        }
+        self.context
+            .as_mut()
+            .ok_or_else(|| eyre!("The Modbus bus failed to be framed"))
+    }
+}
+
+/// A device on an RS-485 bus, which it shares with the other devices on the same serial port
+pub struct RtuClient {
+    bus: Arc<Mutex<RtuBus>>,
+    slave: Slave,
+}
+
+impl RtuClient {
+    /// Open the bus on the serial port at path, e.g. /dev/ttyUSB0, to be shared by its devices
+    pub fn open_bus(path: &str, baud_rate: u32) -> Result<Arc<Mutex<RtuBus>>> {
+        let port = tokio_serial::new(path, baud_rate)
+            .open_native_async()
+            .map_err(|e| eyre!("Failed to open the Modbus bus {path}: {e}"))?;
+        Ok(Arc::new(Mutex::new(RtuBus {
+            port: Some(port),
+            context: None,
+        })))
+    }
+
+    /// The device with the slave id on the bus
+    pub fn new(bus: Arc<Mutex<RtuBus>>, slave: u8) -> Self {
+        Self {
+            bus,
+            slave: Slave(slave),
+        }
+    }
+}
+
+#[async_trait]
+impl ModbusClient for RtuClient {
+    async fn read(&mut self, table: Table, address: u16) -> Result<u16> {
+        let mut bus = self.bus.lock().await;
+        let context = bus.connect().await?;
+        context.set_slave(self.slave);
+        time::timeout(REQUEST_TIMEOUT, read_point(context, table, address))
+            .await
+            .map_err(|_| {
+                eyre!(
+                    "Slave {} didn't answer within {REQUEST_TIMEOUT:?}",
+                    self.slave.0
+                )
+            })?
+    }
+
+    async fn write(&mut self, table: Table, address: u16, value: u16) -> Result<()> {
+        let mut bus = self.bus.lock().await;
+        let context = bus.connect().await?;
+        context.set_slave(self.slave);
+        time::timeout(REQUEST_TIMEOUT, write_point(context, table, address, value))
+            .await
+            .map_err(|_| {
+                eyre!(
+                    "Slave {} didn't answer within {REQUEST_TIMEOUT:?}",
+                    self.slave.0
+                )
+            })?
+    }
+}
+
+/// A device reached over the network, e.g. the pneumatic controller of the piston. It's connected
+/// to on the first request, and again on the next one after a request fails or times out
+pub struct TcpClient {
+    /// host and port, e.g. 192.168.1.20:502
+    address: String,
+    slave: Slave,
+    context: Option<Context>,
+}
+
+impl TcpClient {
+    pub fn new(address: &str, slave: u8) -> Self {
+        Self {
+            address: address.to_string(),
+            slave: Slave(slave),
+            context: None,
+        }
+    }
+
+    /// The connection to the device, connecting when there's none
+    async fn connect(&mut self) -> Result<&mut Context> {
+        if self.context.is_none() {
+            let address = lookup_host(&self.address)
+                .await?
+                .next()
+                .ok_or_else(|| eyre!("{} doesn't resolve to an address", self.address))?;
+            let context = time::timeout(REQUEST_TIMEOUT, tcp::connect_slave(address, self.slave))
+                .await
+                .map_err(|_| eyre!("Timed out connecting to {}", self.address))??;
+            self.context = Some(context);
+        }
+        Ok(self.context.as_mut().expect("Connected above"))
+    }
+
+    /// Run the request, dropping the connection when it fails so the next request reconnects
+    async fn request<T>(
+        &mut self,
+        request: impl FnOnce(&mut Context) -> BoxFuture<'_, Result<T>>,
+    ) -> Result<T> {
+        let address = self.address.clone();
+        let context = self.connect().await?;
+        let result = match time::timeout(REQUEST_TIMEOUT, request(context)).await {
+            Ok(result) => result,
+            Err(_) => Err(eyre!("{address} didn't answer within {REQUEST_TIMEOUT:?}")),
+        };
+        if result.is_err() {
+            self.context = None;
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl ModbusClient for TcpClient {
+    async fn read(&mut self, table: Table, address: u16) -> Result<u16> {
+        self.request(|context| read_point(context, table, address).boxed())
+            .await
+    }
+
+    async fn write(&mut self, table: Table, address: u16, value: u16) -> Result<()> {
+        self.request(|context| write_point(context, table, address, value).boxed())
+            .await
+    }
+}
+
+async fn read_point(context: &mut Context, table: Table, address: u16) -> Result<u16> {
+    let value = match table {
+        Table::Coil => context
+            .read_coils(address, 1)
+            .await?
+            .first()
+            .map(|&bit| bit as u16),
+        Table::DiscreteInput => context
+            .read_discrete_inputs(address, 1)
+            .await?
+            .first()
+            .map(|&bit| bit as u16),
+        Table::HoldingRegister => context
+            .read_holding_registers(address, 1)
+            .await?
+            .first()
+            .copied(),
+        Table::InputRegister => context
+            .read_input_registers(address, 1)
+            .await?
+            .first()
+            .copied(),
+    };
+    value.ok_or_else(|| eyre!("The device sent nothing for {table:?} {address}"))
+}
+
+async fn write_point(context: &mut Context, table: Table, address: u16, value: u16) -> Result<()> {
+    match table {
+        Table::Coil => context.write_single_coil(address, value != 0).await?,
+        Table::HoldingRegister => context.write_single_register(address, value).await?,
+        Table::DiscreteInput | Table::InputRegister => {
+            return Err(eyre!("{table:?} {address} is read only"))
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    /// a point polled changed, or was read for the first time
+    Changed {
+        device: String,
+        point: String,
+        value: u16,
+    },
+}
+
+/// A device of the cell speaking Modbus rather than driven through discrete lines, e.g. a valve
+/// terminal. Its points are polled at a fixed interval and published when they change, and read and
+/// written by the programs by name. A point that can't be read is logged and polled again
+pub struct ModbusDevice {
+    name: String,
+    client: Box<dyn ModbusClient>,
+    points: Vec<Point>,
+    /// last value polled of every point, in the order of the points
+    values: Vec<Option<u16>>,
+    interval: Interval,
+    /// the changes of the last poll not returned yet
+    changes: VecDeque<Event>,
+}
+
+impl Serialize for ModbusDevice {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let values: Map<String, Value> = self
+            .points
+            .iter()
+            .zip(&self.values)
+            .filter_map(|(point, value)| value.map(|value| (point.name.clone(), json!(value))))
+            .collect();
+
+        let mut s = serializer.serialize_struct("modbus", 3)?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("values", &values)?;
+
+        let now = SystemTime::iso8601_now();
+        s.serialize_field("updateTimestamp", &now)?;
+
+        s.end()
+    }
+}
+
+impl ModbusDevice {
+    pub fn new(
+        name: &str,
+        client: Box<dyn ModbusClient>,
+        points: Vec<Point>,
+        poll_interval: Duration,
+    ) -> Self {
+        let mut interval = time::interval(poll_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            name: name.to_string(),
+            client,
+            values: vec![None; points.len()],
+            points,
+            interval,
+            changes: VecDeque::new(),
+        }
+    }
+
+    /// The device named name, with slave id <NAME>_SLAVE, whose points <NAME>_POINTS are polled
+    /// every <NAME>_POLL_INTERVAL milliseconds, where the name is upper cased. It's reached over
+    /// TCP at <NAME>_ADDRESS, host:port, or else on the RS-485 bus at <NAME>_PORT. Devices on the
+    /// same port share buses, opened at the baud rate <NAME>_BAUD_RATE of the first of them
+    pub fn from_settings(
+        settings: &Settings,
+        name: &str,
+        buses: &mut Vec<(String, Arc<Mutex<RtuBus>>)>,
+    ) -> Result<Self> {
+        let prefix = name.to_uppercase();
+
+        let slave = settings
+            .parse(&format!("{prefix}_SLAVE"), "a slave id")?
+            .unwrap_or(1);
+        let key = format!("{prefix}_POINTS");
+        let points = settings.require(&key, "they're polled on the device")?;
+        let points = Point::parse_list(points).map_err(|e| eyre!("{key}: {e}"))?;
+        let poll_interval = settings
+            .positive(&format!("{prefix}_POLL_INTERVAL"), "milliseconds")?
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_POLL_INTERVAL);
+
+        if let Some(address) = settings.get(&format!("{prefix}_ADDRESS")) {
+            let client = TcpClient::new(address, slave);
+            return Ok(Self::new(name, Box::new(client), points, poll_interval));
+        }
+
+        let key = format!("{prefix}_PORT");
+        let reason = format!("the device is reached on it without {prefix}_ADDRESS");
+        let path = settings.require(&key, &reason)?;
+        let bus = match buses.iter().find(|(bus, _)| bus == path) {
+            Some((_, bus)) => bus.clone(),
+            None => {
+                let baud_rate = settings
+                    .parse(&format!("{prefix}_BAUD_RATE"), "unsigned integer")?
+                    .unwrap_or(DEFAULT_BAUD_RATE);
+                let bus = RtuClient::open_bus(path, baud_rate)?;
+                buses.push((path.to_string(), bus.clone()));
+                bus
+            }
+        };
+
+        let client = RtuClient::new(bus, slave);
+        Ok(Self::new(name, Box::new(client), points, poll_interval))
+    }
+
+    fn point(&self, name: &str) -> Result<&Point> {
+        self.points
+            .iter()
+            .find(|point| point.name == name)
+            .ok_or_else(|| eyre!("{} has no point {name}", self.name))
+    }
+
+    /// Read the point from the device
+    pub async fn read(&mut self, point: &str) -> Result<u16> {
+        let Point { table, address, .. } = *self.point(point)?;
+        self.client.read(table, address).await
+    }
+
+    /// Write the value to the point of the device
+    pub async fn write(&mut self, point: &str, value: u16) -> Result<()> {
+        let Point { table, address, .. } = *self.point(point)?;
+        self.client.write(table, address, value).await
+    }
+
+    /// Wait for the next change of a point, polling every point of the device at each interval
+    pub async fn async_next_event(&mut self) -> Event {
+        loop {
+            if let Some(change) = self.changes.pop_front() {
+                return change;
+            }
+            self.interval.tick().await;
+            self.poll().await;
+        }
+    }
+
+    /// Read every point, queueing those that changed since the last poll
+    async fn poll(&mut self) {
+        for index in 0..self.points.len() {
+            let Point {
+                ref name,
+                table,
+                address,
+            } = self.points[index];
+            let value = match self.client.read(table, address).await {
+                Ok(value) => value,
+                Err(e) => {
+                    error!("Failed to poll {name} of {}: {e}", self.name);
+                    continue;
+                }
+            };
+            if self.values[index] != Some(value) {
+                self.values[index] = Some(value);
+                self.changes.push_back(Event::Changed {
+                    device: self.name.clone(),
+                    point: name.clone(),
+                    value,
+                });
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Component for ModbusDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "modbus"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await.into())
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// The tables of a device in memory, clones share them so tests can look at what was written
+    #[derive(Debug, Clone, Default)]
+    pub struct MockModbus {
+        tables: Arc<Mutex<HashMap<(u8, u16), u16>>>,
+    }
+
+    impl MockModbus {
+        fn key(table: Table, address: u16) -> (u8, u16) {
+            (table as u8, address)
+        }
+
+        pub fn set(&self, table: Table, address: u16, value: u16) {
+            let key = Self::key(table, address);
+            self.tables.lock().unwrap().insert(key, value);
+        }
+
+        pub fn get(&self, table: Table, address: u16) -> u16 {
+            let key = Self::key(table, address);
+            self.tables.lock().unwrap().get(&key).copied().unwrap_or(0)
+        }
+    }
+
+    #[async_trait]
+    impl ModbusClient for MockModbus {
+        async fn read(&mut self, table: Table, address: u16) -> Result<u16> {
+            Ok(self.get(table, address))
+        }
+
+        async fn write(&mut self, table: Table, address: u16, value: u16) -> Result<()> {
+            self.set(table, address, value);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::mock::MockModbus;
+    use super::*;
+
+    #[test]
+    fn points_are_parsed_from_a_list() {
+        let points = Point::parse_list("gripper:coil:0, pressure:input:3").unwrap();
+        assert_eq!(
+            points[1],
+            Point {
+                name: "pressure".to_string(),
+                table: Table::InputRegister,
+                address: 3,
+            }
+        );
+        assert!(Point::parse_list("gripper:relay:0").is_err());
+        assert!(Point::parse_list("gripper:coil").is_err());
+    }
+
+    #[tokio::test]
+    async fn changes_are_published_once() {
+        let modbus = MockModbus::default();
+        modbus.set(Table::InputRegister, 3, 420);
+        let points = Point::parse_list("gripper:coil:0,pressure:input:3").unwrap();
+        let mut device = ModbusDevice::new(
+            "Valves",
+            Box::new(modbus.clone()),
+            points,
+            Duration::from_millis(1),
+        );
+
+        // every point is read the first time
+        for _ in 0..2 {
+            device.async_next_event().await;
+        }
+        device.write("gripper", 1).await.unwrap();
+        assert_eq!(modbus.get(Table::Coil, 0), 1);
+        assert!(matches!(
+            device.async_next_event().await,
+            Event::Changed { point, value: 1, .. } if point == "gripper"
+        ));
+        assert_eq!(device.read("pressure").await.unwrap(), 420);
+        assert_eq!(device.serialize_state()["values"]["pressure"], 420);
+    }
+    #[tokio::test]
+    async fn devices_that_dont_answer_are_reconnected_to() {
+        // the device accepts the connection but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut client = TcpClient::new(&address, 1);
+
+        let accepted = tokio::spawn(async move { listener.accept().await });
+        assert!(client.read(Table::HoldingRegister, 0).await.is_err());
+        assert!(client.context.is_none());
+        drop(accepted);
+    }
+}
@@ -1,32 +1,89 @@
+use crate::config::Settings;
+use crate::envelope::EventSender;
+use crate::manufacturing_components::analog::{self, AnalogInput};
+use crate::manufacturing_components::gpio::{GpioProvider, OutputLine};
+use crate::manufacturing_components::input::{DebouncedLine, Trigger};
+use crate::manufacturing_components::{
+    input_line, output_line, Component, ComponentEvent, Timeout,
+};
 use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
-use gpio_cdev::{AsyncLineEventHandle, Chip, EventRequestFlags, Line, LineRequestFlags};
+use futures::future;
+use gpio_cdev::EventRequestFlags;
 use serde::ser::SerializeStruct;
 use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
 use std::fmt::Display;
+use std::future::Future;
 use std::time::{Duration, SystemTime};
+use tokio::time;
 
-#[derive(Serialize)]
-enum PistonStates {
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Default)]
+pub enum PistonStates {
     /// Piston is raised and await for commands, serialized to steady
     #[serde(rename = "steady")]
+    #[default]
     Steady,
     /// Piston is at its bottom, serialized to depressed
     #[serde(rename = "depressed")]
     Depressed,
 }
 
-impl Default for PistonStates {
-    fn default() -> Self {
-        Self::Steady
+#[derive(Debug, Serialize)]
+pub enum Event {
+    Depressed,
+    Steady,
+    /// the air pressure rose above the limit, the piston was returned to steady
+    OverPressure {
+        piston: String,
+        pressure: f32,
+        limit: f32,
+    },
+}
+
+/// The air pressure of the piston, sampled from an analog input and checked against a limit
+struct Pressure {
+    input: AnalogInput,
+    limit: f32,
+    /// whether the alarm was raised since the pressure last rose above the limit
+    alarmed: bool,
+}
+
+impl Pressure {
+    /// Sample the pressure until it rises above the limit, once until it's back under it. Returns
+    /// the pressure sampled
+    async fn above_limit(&mut self) -> f32 {
+        loop {
+            if let analog::Event::Sample { value, .. } = self.input.async_next_event().await {
+                if value <= self.limit {
+                    self.alarmed = false;
+                } else if !self.alarmed {
+                    self.alarmed = true;
+                    return value;
+                }
+            }
+        }
+    }
+}
+
+/// Wait for the pressure to rise above the limit, never when the piston has no pressure input
+async fn over_pressure(pressure: &mut Option<Pressure>) -> f32 {
+    match pressure {
+        Some(pressure) => pressure.above_limit().await,
+        None => future::pending().await,
     }
 }
 
 pub struct Piston {
     name: String,
     state: PistonStates,
-    gpio_line: Line,
-    pub event_handle: AsyncLineEventHandle,
+    /// drives the piston, high to depress it
+    output: Box<dyn OutputLine>,
+    pub event_handle: DebouncedLine,
+    /// the air pressure, when the piston has a sensor
+    pressure: Option<Pressure>,
 }
 
 impl Serialize for Piston {
@@ -34,9 +91,14 @@ impl Serialize for Piston {
     where
         S: Serializer,
     {
-        let mut s = serializer.serialize_struct("piston", 3)?;
+        let mut s = serializer.serialize_struct("piston", 4)?;
         s.serialize_field("name", &self.name)?;
         s.serialize_field("state", &self.state)?;
+        let pressure = self
+            .pressure
+            .as_ref()
+            .and_then(|pressure| pressure.input.value());
+        s.serialize_field("pressure", &pressure)?;
 
         let now = SystemTime::iso8601_now();
         s.serialize_field("updateTimestamp", &now)?;
@@ -45,44 +107,241 @@ impl Serialize for Piston {
     }
 }
 
-trait PistonActions {
-    fn depress(self: &mut Self);
-    fn steady(self: &mut Self);
-    fn depress_for(duration: Duration);
+/// Commands driving the piston, each returning the event announcing the new state
+#[async_trait]
+pub trait PistonActions {
+    fn depress(&mut self) -> Result<Event>;
+    fn steady(&mut self) -> Result<Event>;
+
+    /// Depress the piston for the given time, then return it to steady, publishing both
+    /// transitions. The piston is returned to steady early when cancel completes first, e.g. when
+    /// an emergency stop is received, in which case its output is returned so the caller can
+    /// handle it. It's returned early too when its pressure rises above the limit, publishing the
+    /// alarm
+    async fn depress_for<C>(
+        &mut self,
+        duration: Duration,
+        tx: &EventSender,
+        cancel: C,
+    ) -> Result<Option<C::Output>>
+    where
+        C: Future + Send,
+        C::Output: Send;
 }
 
 impl Piston {
-    pub fn new<S>(name: S, chip: &mut Chip, line: u32) -> Result<Self>
+    /// The piston is read on line and driven on drive_line, which starts low so the piston is
+    /// steady
+    pub fn new<S>(
+        settings: &Settings,
+        name: S,
+        gpio: &mut dyn GpioProvider,
+        line: u32,
+        drive_line: u32,
+    ) -> Result<Self>
     where
         S: Into<String> + Display,
     {
-        let line = chip.get_line(line)?;
-        let event_handle = line.async_events(
-            LineRequestFlags::INPUT,
-            EventRequestFlags::RISING_EDGE,
-            &format!("{name} consumer"),
-        )?;
+        let name = name.to_string();
+        let trigger = Trigger::from_settings(settings, "piston", EventRequestFlags::RISING_EDGE)?;
+        let event_handle = input_line(settings, gpio, line, trigger, &name)?;
+        let output = output_line(gpio, drive_line, &name)?;
 
         Ok(Self {
-            name: name.into(),
+            name,
             state: PistonStates::default(),
-            gpio_line: line,
+            output,
             event_handle,
+            pressure: None,
         })
     }
+
+    /// Sample the air pressure of the piston from input, returning it to steady with an alarm
+    /// whenever it rises above limit, in the unit of the input
+    pub fn with_pressure(mut self, input: AnalogInput, limit: f32) -> Self {
+        self.pressure = Some(Pressure {
+            input,
+            limit,
+            alarmed: false,
+        });
+        self
+    }
+
+    /// Change the limit of the air pressure, when the piston has a pressure sensor
+    pub fn set_pressure_limit(&mut self, limit: f32) {
+        if let Some(pressure) = &mut self.pressure {
+            pressure.limit = limit;
+        }
+    }
+
+    /// Wait for the piston to reach its bottom, signalled by a rising edge on its line, or for its
+    /// pressure to rise above the limit, which returns it to steady
+    pub async fn async_next_event(&mut self) -> Result<Event> {
+        let edge = tokio::select! {
+            edge = self.event_handle.next() => edge,
+            pressure = over_pressure(&mut self.pressure) => {
+                self.steady()?;
+                return Ok(self.over_pressure_alarm(pressure));
+            }
+        };
+
+        match edge {
+            Some(event) => {
+                event?;
+                self.state = PistonStates::Depressed;
+                Ok(Event::Depressed)
+            }
+            None => Err(eyre!("The line of {} stopped sending events", self.name)),
+        }
+    }
+
+    fn over_pressure_alarm(&self, pressure: f32) -> Event {
+        Event::OverPressure {
+            piston: self.name.clone(),
+            pressure,
+            // there's only an alarm when there's a limit, unwrap is safe
+            limit: self.pressure.as_ref().unwrap().limit,
+        }
+    }
+
+    /// Wait for the next event like async_next_event, failing with a Timeout when the piston
+    /// doesn't reach its bottom within after
+    pub async fn async_next_event_timeout(&mut self, after: Duration) -> Result<Event> {
+        match time::timeout(after, self.async_next_event()).await {
+            Ok(event) => event,
+            Err(_) => Err(Timeout {
+                component: self.name.clone(),
+                after,
+            }
+            .into()),
+        }
+    }
+}
+
+#[async_trait]
+impl Component for Piston {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "piston"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await?.into())
+    }
+
+    /// The piston is retracted
+    fn make_safe(&mut self) -> Result<Option<ComponentEvent>> {
+        Ok(Some(self.steady()?.into()))
+    }
+}
+
+#[async_trait]
+impl PistonActions for Piston {
+    fn depress(&mut self) -> Result<Event> {
+        self.output.set_value(1)?;
+        self.state = PistonStates::Depressed;
+        Ok(Event::Depressed)
+    }
+
+    fn steady(&mut self) -> Result<Event> {
+        self.output.set_value(0)?;
+        self.state = PistonStates::Steady;
+        Ok(Event::Steady)
+    }
+
+    async fn depress_for<C>(
+        &mut self,
+        duration: Duration,
+        tx: &EventSender,
+        cancel: C,
+    ) -> Result<Option<C::Output>>
+    where
+        C: Future + Send,
+        C::Output: Send,
+    {
+        tx.send(self.depress()?);
+
+        let cancelled = tokio::select! {
+            biased;
+            output = cancel => Some(output),
+            pressure = over_pressure(&mut self.pressure) => {
+                tx.send(self.over_pressure_alarm(pressure));
+                None
+            }
+            _ = time::sleep(duration) => None,
+        };
+
+        tx.send(self.steady()?);
+        Ok(cancelled)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::manufacturing_components::piston::Piston;
-    use gpio_cdev::Chip;
+    use super::*;
+    use crate::manufacturing_components::analog::Mcp3008;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+    use crate::manufacturing_components::spi::mock::MockSpi;
+    use gpio_cdev::EventType;
 
     #[test]
     fn piston_to_json() {
-        let mut chip = Chip::new("/dev/gpiochip0")
-            .expect("Sorry the current hack requires access to /dev/gpiochip0");
-        let piston = Piston::new("piston 1", &mut chip, 0).unwrap();
-        let json = serde_json::to_string(&piston).unwrap();
-        println!("{json}");
+        let mut gpio = MockGpio::default();
+        let piston = Piston::new(&Settings::default(), "piston 1", &mut gpio, 0, 1).unwrap();
+        let json = serde_json::to_value(&piston).unwrap();
+        assert_eq!(json["name"], "piston 1");
+        assert_eq!(json["state"], "steady");
+        // without a pressure sensor
+        assert!(json["pressure"].is_null());
+        assert!(json["updateTimestamp"].is_string());
+    }
+
+    #[tokio::test]
+    async fn piston_drives_its_line_and_reads_its_bottom() {
+        let mut gpio = MockGpio::default();
+        let mut piston = Piston::new(&Settings::default(), "piston 1", &mut gpio, 0, 1).unwrap();
+
+        piston.depress().unwrap();
+        piston.steady().unwrap();
+        assert_eq!(gpio.history(1), [0, 1, 0]);
+
+        gpio.edge(0, EventType::RisingEdge);
+        assert!(matches!(
+            piston.async_next_event().await.unwrap(),
+            Event::Depressed
+        ));
+        assert_eq!(piston.state, PistonStates::Depressed);
+    }
+
+    #[tokio::test]
+    async fn over_pressure_returns_the_piston_to_steady() {
+        let mut gpio = MockGpio::default();
+        let spi = MockSpi::default();
+        // raw 600, then back under the limit
+        spi.responses
+            .lock()
+            .unwrap()
+            .extend([vec![0, 0x02, 0x58], vec![0, 0x01, 0x00]]);
+        let input = AnalogInput::new("piston_pressure", Box::new(Mcp3008::new(Box::new(spi))), 0)
+            .with_interval(Duration::from_millis(1));
+        let mut piston = Piston::new(&Settings::default(), "piston 1", &mut gpio, 0, 1)
+            .unwrap()
+            .with_pressure(input, 500.0);
+
+        piston.depress().unwrap();
+        assert!(matches!(
+            piston.async_next_event().await.unwrap(),
+            Event::OverPressure { pressure, limit, .. } if pressure == 600.0 && limit == 500.0
+        ));
+        assert_eq!(gpio.history(1), [0, 1, 0]);
+        assert_eq!(piston.state, PistonStates::Steady);
+        assert_eq!(piston.serialize_state()["pressure"], 600.0);
     }
 }
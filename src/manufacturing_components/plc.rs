@@ -0,0 +1,321 @@
+use crate::config::Settings;
+use crate::manufacturing_components::gpio::{Edge, GpioError, GpioProvider, InputLine, OutputLine};
+use crate::manufacturing_components::input::Trigger;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use gpio_cdev::{EventRequestFlags, EventType};
+use opcua::client::prelude::{
+    AttributeId, AttributeService, ClientBuilder, DataChangeCallback, DataValue,
+    EndpointDescription, IdentityToken, MessageSecurityMode, MonitoredItem,
+    MonitoredItemCreateRequest, MonitoredItemService, NodeId, SecurityPolicy, Session,
+    SubscriptionService, TimestampsToReturn, UAString, UserTokenPolicy, Variant, WriteValue,
+};
+use opcua::sync::RwLock;
+use std::collections::HashMap;
+use std::io;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{error, warn};
+
+/// Time between two notifications of the subscription, in milliseconds
+const PUBLISHING_INTERVAL: f64 = 50.0;
+
+/// An input line requested from the PLC
+struct Input {
+    trigger: Trigger,
+    edges: UnboundedSender<Edge>,
+}
+
+/// The lines of the PLC as they're known to the twin, shared with the subscription
+struct Lines {
+    /// last value of every line, as the PLC reported it
+    values: HashMap<u32, bool>,
+    inputs: HashMap<u32, Input>,
+    /// edges are stamped with the time since the lines were created, like a monotonic clock
+    started: Instant,
+}
+
+impl Lines {
+    fn new() -> Self {
+        Self {
+            values: HashMap::new(),
+            inputs: HashMap::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// The PLC reported the value of the line, which is an edge of the input on it when it changed
+    /// and the input is triggered by it
+    fn changed(&mut self, offset: u32, value: bool) {
+        let previous = self.values.insert(offset, value);
+        if previous.is_none() || previous == Some(value) {
+            return;
+        }
+        let input = match self.inputs.get(&offset) {
+            Some(input) => input,
+            None => return,
+        };
+
+        // the edges of an active low line are those of the inverted line
+        let rising = value != input.trigger.active_low;
+        let (event_type, flag) = if rising {
+            (EventType::RisingEdge, EventRequestFlags::RISING_EDGE)
+        } else {
+            (EventType::FallingEdge, EventRequestFlags::FALLING_EDGE)
+        };
+        if input.trigger.edge.contains(flag) {
+            let timestamp = self.started.elapsed().as_nanos() as u64;
+            input
+                .edges
+                .send(Edge {
+                    event_type,
+                    timestamp,
+                })
+                .ok();
+        }
+    }
+
+    fn value(&self, offset: u32, active_low: bool) -> u8 {
+        let value = self.values.get(&offset).copied().unwrap_or(false);
+        (value != active_low) as u8
+    }
+}
+
+/// Lines backed by the boolean nodes of a PLC over OPC UA rather than by the GPIO of the board, so
+/// a cell wired to a Siemens or Beckhoff PLC runs the same components and programs. Every line is
+/// mapped to a node with OPCUA_LINE_<offset>, e.g. OPCUA_LINE_4=ns=3;s="DB1"."MaterialSensor".
+///
+/// The nodes are subscribed to, their changes are the edges of the input lines. Outputs write
+/// their node. The OPC UA client is blocking, it's run on threads of its own rather than on the
+/// runtime
+pub struct PlcGpio {
+    nodes: HashMap<u32, NodeId>,
+    lines: Arc<Mutex<Lines>>,
+    writes: mpsc::Sender<(NodeId, bool)>,
+}
+
+impl PlcGpio {
+    /// Connect anonymously to the server at the endpoint URL, e.g. opc.tcp://192.168.0.1:4840,
+    /// and subscribe to the nodes of the lines
+    pub fn connect(endpoint: &str, nodes: HashMap<u32, NodeId>) -> Result<Self> {
+        let mut client = ClientBuilder::new()
+            .application_name("tvilling")
+            .application_uri("urn:tvilling")
+            .trust_server_certs(true)
+            .session_retry_limit(-1)
+            .client()
+            .ok_or_else(|| eyre!("Invalid OPC UA client configuration"))?;
+        let endpoint: EndpointDescription = (
+            endpoint,
+            SecurityPolicy::None.to_str(),
+            MessageSecurityMode::None,
+            UserTokenPolicy::anonymous(),
+        )
+            .into();
+        let session = client
+            .connect_to_endpoint(endpoint, IdentityToken::Anonymous)
+            .map_err(|status| eyre!("Failed to connect to the PLC: {status}"))?;
+
+        let lines = Arc::new(Mutex::new(Lines::new()));
+        subscribe(&session, &nodes, lines.clone())?;
+        let _ = Session::run_async(session.clone());
+
+        let (writes, requests) = mpsc::channel();
+        thread::Builder::new()
+            .name("opcua-writes".to_string())
+            .spawn(move || write_nodes(session, requests))?;
+
+        Ok(Self {
+            nodes,
+            lines,
+            writes,
+        })
+    }
+
+    /// The PLC at OPCUA_ENDPOINT, None when the cell's lines are the board's
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let endpoint = match settings.get("OPCUA_ENDPOINT") {
+            Some(endpoint) => endpoint,
+            None => return Ok(None),
+        };
+        let nodes = settings
+            .iter()
+            .filter_map(|(key, node)| {
+                let offset = key.strip_prefix("OPCUA_LINE_")?.parse();
+                Some((key, offset, node))
+            })
+            .map(|(key, offset, node)| {
+                let setting = |problem| GpioError::Setting {
+                    variable: key.to_string(),
+                    problem,
+                };
+                let offset = offset.map_err(|_| setting("doesn't name a line offset"))?;
+                let node =
+                    NodeId::from_str(node).map_err(|_| setting("cannot be parsed as a node id"))?;
+                Ok((offset, node))
+            })
+            .collect::<Result<_, GpioError>>()?;
+
+        Ok(Some(Self::connect(endpoint, nodes)?))
+    }
+
+    fn node(&self, offset: u32) -> Result<NodeId> {
+        self.nodes
+            .get(&offset)
+            .cloned()
+            .ok_or_else(|| eyre!("Line {offset} isn't mapped to a node, set OPCUA_LINE_{offset}"))
+    }
+}
+
+/// Subscribe to the value of every node, whose changes are reported to the lines
+fn subscribe(
+    session: &Arc<RwLock<Session>>,
+    nodes: &HashMap<u32, NodeId>,
+    lines: Arc<Mutex<Lines>>,
+) -> Result<()> {
+    let offsets: HashMap<NodeId, u32> = nodes
+        .iter()
+        .map(|(offset, node)| (node.clone(), *offset))
+        .collect();
+    let callback = DataChangeCallback::new(move |items: &[&MonitoredItem]| {
+        let mut lines = lines.lock().unwrap();
+        for item in items {
+            let node = &item.item_to_monitor().node_id;
+            match (offsets.get(node), &item.last_value().value) {
+                (Some(offset), Some(Variant::Boolean(value))) => lines.changed(*offset, *value),
+                (_, value) => warn!("Ignored {value:?} of {node}, which isn't a boolean line"),
+            }
+        }
+    });
+
+    let session = session.read();
+    let subscription = session
+        .create_subscription(PUBLISHING_INTERVAL, 10, 30, 0, 0, true, callback)
+        .map_err(|status| eyre!("Failed to subscribe to the PLC: {status}"))?;
+    let items: Vec<MonitoredItemCreateRequest> =
+        nodes.values().map(|node| node.clone().into()).collect();
+    session
+        .create_monitored_items(subscription, TimestampsToReturn::Both, &items)
+        .map_err(|status| eyre!("Failed to monitor the nodes of the PLC: {status}"))?;
+    Ok(())
+}
+
+/// Write the values requested by the outputs to their nodes, until every output is gone
+fn write_nodes(session: Arc<RwLock<Session>>, requests: mpsc::Receiver<(NodeId, bool)>) {
+    for (node, value) in requests {
+        let write = WriteValue {
+            node_id: node.clone(),
+            attribute_id: AttributeId::Value as u32,
+            index_range: UAString::null(),
+            value: DataValue::new_now(Variant::Boolean(value)),
+        };
+        match session.read().write(&[write]) {
+            Ok(results) if results.iter().all(|status| status.is_good()) => {}
+            Ok(results) => error!("The PLC refused to write {node}: {results:?}"),
+            Err(status) => error!("Failed to write {node}: {status}"),
+        }
+    }
+}
+
+impl GpioProvider for PlcGpio {
+    fn input(&mut self, offset: u32, trigger: Trigger, _: &str) -> Result<Box<dyn InputLine>> {
+        self.node(offset)?;
+        let (edges_tx, edges) = unbounded_channel();
+        let input = Input {
+            trigger,
+            edges: edges_tx,
+        };
+        self.lines.lock().unwrap().inputs.insert(offset, input);
+
+        Ok(Box::new(PlcInput {
+            offset,
+            active_low: trigger.active_low,
+            lines: self.lines.clone(),
+            edges,
+        }))
+    }
+
+    fn output(&mut self, offset: u32, _: &str) -> Result<Box<dyn OutputLine>> {
+        let output = PlcOutput {
+            node: self.node(offset)?,
+            writes: self.writes.clone(),
+        };
+        output.set_value(0)?;
+        Ok(Box::new(output))
+    }
+}
+
+struct PlcInput {
+    offset: u32,
+    active_low: bool,
+    lines: Arc<Mutex<Lines>>,
+    edges: UnboundedReceiver<Edge>,
+}
+
+#[async_trait]
+impl InputLine for PlcInput {
+    async fn next_edge(&mut self) -> Option<Result<Edge, gpio_cdev::Error>> {
+        self.edges.recv().await.map(Ok)
+    }
+
+    fn value(&self) -> Result<u8, gpio_cdev::Error> {
+        Ok(self
+            .lines
+            .lock()
+            .unwrap()
+            .value(self.offset, self.active_low))
+    }
+}
+
+struct PlcOutput {
+    node: NodeId,
+    writes: mpsc::Sender<(NodeId, bool)>,
+}
+
+impl OutputLine for PlcOutput {
+    /// The write is queued, a node the PLC refuses to write is only logged
+    fn set_value(&self, value: u8) -> Result<(), gpio_cdev::Error> {
+        self.writes
+            .send((self.node.clone(), value == 1))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The PLC writer is gone").into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn changes_are_the_edges_of_the_inputs() {
+        let lines = Arc::new(Mutex::new(Lines::new()));
+        let (edges_tx, mut edges) = unbounded_channel();
+        let trigger = Trigger {
+            edge: EventRequestFlags::RISING_EDGE,
+            active_low: true,
+        };
+        lines.lock().unwrap().inputs.insert(
+            4,
+            Input {
+                trigger,
+                edges: edges_tx,
+            },
+        );
+
+        let mut lines = lines.lock().unwrap();
+        // the first value reported is the state the PLC is in, not a change
+        lines.changed(4, true);
+        lines.changed(4, true);
+        lines.changed(4, false);
+        lines.changed(4, true);
+
+        assert_eq!(lines.value(4, true), 0);
+        let edge = edges.try_recv().unwrap();
+        assert_eq!(edge.event_type, EventType::RisingEdge);
+        assert!(edges.try_recv().is_err());
+    }
+}
@@ -1,38 +1,1020 @@
-use gpio_cdev::LineRequestFlags;
+use crate::cancellation::Cancelled;
+use crate::config::Settings;
+use crate::manufacturing_components::camera::Trigger;
+use crate::manufacturing_components::cycle::{
+    between_steps, publish_transition, wait_for_pickup, CycleContext, Picked, Progress, Report,
+};
+use crate::manufacturing_components::dry_run::{PlannedStep, Stage};
+use crate::manufacturing_components::gpio::{GpioProvider, OutputLine, SharedGpio, Unwired};
+use crate::manufacturing_components::piston::PistonActions;
+use crate::manufacturing_components::registry::ComponentRegistry;
+use crate::manufacturing_components::robot::RobotPosition;
+use crate::manufacturing_components::script::{ScriptDefinition, ScriptProgram};
+use crate::manufacturing_components::sequence::{Sequence, SequenceParameters, SEQUENCE_SCENARIO};
+use crate::manufacturing_components::Component;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
 
-/// A manufacturing program that can be started and stopped, the semantics of whether calling start
-/// and stop multiple times and potentially interleaving is left undefined  
+/// Scenario run when a start request doesn't name one
+pub const DEFAULT_SCENARIO: &str = "simplified-scenario-2";
+
+/// A manufacturing program that can be started, paused, resumed and stopped. Programs follow the
+/// lifecycle of ProgramState: starting is only legal when idle or stopped, pausing when running
+/// and resuming when paused, any other call fails without driving a line. Stopping is legal at any
+/// time and leaves the program stopped, a program that isn't running is only driven to rest again.
+/// Every change of state comes with the lifecycle event for it. Each program runs its own cycle on
+/// the cell, so scenarios are self-contained
+#[async_trait]
 pub trait ManufacturingProgram {
     type Error;
     type Success;
+    /// Where the program is in its lifecycle
+    fn state(&self) -> ProgramState;
     fn start(&mut self) -> Result<Self::Success, Self::Error>;
     fn stop(&mut self) -> Result<Self::Success, Self::Error>;
+    /// Hold the program where it is, e.g. for a tool change, until it's resumed
+    fn pause(&mut self) -> Result<Self::Success, Self::Error>;
+    /// Continue a paused program from where it was held
+    fn resume(&mut self) -> Result<Self::Success, Self::Error>;
+    /// Drive every output line of the program to its safe value and leave it stopped, whatever
+    /// state it's in. Called by the supervisor of the cycles whenever one fails, so it can't be
+    /// left out
+    fn safe_state(&mut self) -> Result<Self::Success, Self::Error>;
+    /// Drive every output line to its safe value right away, the safe state for most programs
+    fn emergency_stop(&mut self) -> Result<Self::Success, Self::Error> {
+        self.safe_state()
+    }
+
+    /// Run a cycle on the cell to completion, or until it's interrupted, reporting how far it got
+    async fn run(&mut self, cx: &mut CycleContext<'_>) -> Result<Report>;
+
+    /// The steps a cycle goes through for every material, walked by a dry run without driving
+    /// anything
+    fn plan(&self, cell: &ComponentRegistry) -> Vec<PlannedStep>;
+
+    /// The stages a cycle of count materials goes through, the program's own plan unless it runs
+    /// other programs
+    fn stages(&self, count: u32, cell: &ComponentRegistry) -> Vec<Stage> {
+        vec![Stage {
+            scenario: None,
+            count,
+            steps: self.plan(cell),
+        }]
+    }
+}
+
+/// What the program is doing, reported in the twin snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProgramStatus {
+    Idle,
+    Running,
+    Paused,
+    EmergencyStopped,
+}
+
+/// Where a program is in its lifecycle, a program is idle until it's first started
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgramState {
+    #[default]
+    Idle,
+    Running,
+    Paused,
+    Stopped,
+}
+
+/// The changes of state a program goes through. A stopped program can be started again, stopping
+/// one that isn't running or paused is no transition
+const TRANSITIONS: [(ProgramState, ProgramState); 6] = [
+    (ProgramState::Idle, ProgramState::Running),
+    (ProgramState::Stopped, ProgramState::Running),
+    (ProgramState::Running, ProgramState::Paused),
+    (ProgramState::Paused, ProgramState::Running),
+    (ProgramState::Running, ProgramState::Stopped),
+    (ProgramState::Paused, ProgramState::Stopped),
+];
+
+impl ProgramState {
+    /// Whether a program can go from this state straight to the other one
+    pub fn can_become(self, to: Self) -> bool {
+        TRANSITIONS.contains(&(self, to))
+    }
+}
+
+/// The state of a program, only ever changed along the transitions of the lifecycle
+#[derive(Debug, Default)]
+pub struct Lifecycle(ProgramState);
+
+impl Lifecycle {
+    pub fn state(&self) -> ProgramState {
+        self.0
+    }
+
+    /// Drive the lines of the program with drive and move to the state, returning the lifecycle
+    /// event for it. An illegal transition fails before anything is driven, and the state is left
+    /// as it was when driving fails
+    pub fn transition<F>(&mut self, to: ProgramState, drive: F) -> Result<Event, ProgramError>
+    where
+        F: FnOnce() -> Result<(), gpio_cdev::Error>,
+    {
+        let from = self.0;
+        if !from.can_become(to) {
+            return Err(ProgramError::IllegalTransition { from, to });
+        }
+        drive()?;
+        self.0 = to;
+        Ok(Event::Transition { from, to })
+    }
+
+    /// Drive the lines of the program to rest and leave it stopped, the lifecycle event is None
+    /// when it was neither running nor paused
+    pub fn stop<F>(&mut self, drive: F) -> Result<Option<Event>, ProgramError>
+    where
+        F: FnOnce() -> Result<(), gpio_cdev::Error>,
+    {
+        match self.0 {
+            ProgramState::Running | ProgramState::Paused => {
+                self.transition(ProgramState::Stopped, drive).map(Some)
+            }
+            ProgramState::Idle | ProgramState::Stopped => {
+                drive()?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProgramError {
+    #[error("Error: Failed to drive the program: {0}")]
+    Gpio(#[from] gpio_cdev::Error),
+    /// the call isn't legal in the state the program is in
+    #[error("Error: The program can't go from {from:?} to {to:?}")]
+    IllegalTransition {
+        from: ProgramState,
+        to: ProgramState,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    /// every output line was driven to its safe value
+    EmergencyStop { reason: Option<String> },
+    /// the cycle was stopped since the component didn't signal for waited_ms milliseconds
+    Stalled { component: String, waited_ms: u64 },
+    /// the program went from one state of its lifecycle to another
+    Transition {
+        from: ProgramState,
+        to: ProgramState,
+    },
+    /// a running cycle processed some materials, the completion is estimated once the first is
+    /// done
+    Progress {
+        processed: u32,
+        remaining: u32,
+        estimated_completion: Option<DateTime<Utc>>,
+    },
 }
 
 pub struct SimplifiedScenario2 {
-    line: gpio_cdev::Line,
-    line_handle: gpio_cdev::LineHandle,
+    line_handle: Box<dyn OutputLine>,
+    lifecycle: Lifecycle,
 }
 
 impl SimplifiedScenario2 {
-    pub fn new(chip: &mut gpio_cdev::Chip, line_num: u32) -> Result<Self, gpio_cdev::Error> {
-        let line = chip.get_line(line_num)?;
-        let line_handle =
-            line.request(LineRequestFlags::OUTPUT, 0, "Simplified Scenario 2 program")?;
+    pub fn new(gpio: &mut dyn GpioProvider, line_num: u32) -> Result<Self> {
+        let line_handle = gpio.output(line_num, "Simplified Scenario 2 program")?;
+
+        Ok(Self {
+            line_handle,
+            lifecycle: Lifecycle::default(),
+        })
+    }
+
+    /// Run the program until the requested count of materials have been picked up, or a stop
+    /// request is received. Each material is picked from the feeder the feeder policy selects,
+    /// the requests received meanwhile are handled while the feeder is waited on
+    async fn cycle(&mut self, cx: &mut CycleContext<'_>) -> Result<Report> {
+        publish_transition(cx.tx, self.start()?);
+        cx.status_tx.send(ProgramStatus::Running).ok();
+        let progress = Progress::new(cx.count, cx.progress_every);
+
+        for processed in 0..cx.count {
+            let index = cx.cell.select_feeder(processed as usize)?;
+
+            // wait for some material to be picked up and sent the event across the channel, twice
+            // since the materials are pushed afterwards
+            for step in 0..2 {
+                let remaining = cx.count.saturating_sub(processed);
+                let event = match wait_for_pickup(self, cx, index, remaining).await? {
+                    Picked::Material(event) => event,
+                    Picked::Interrupted(interrupted) => {
+                        return Ok(Report {
+                            processed,
+                            interrupted: Some(interrupted),
+                        })
+                    }
+                };
+
+                if step == 0 {
+                    cx.tx.begin_material();
+                    cx.tx.send(event);
+                    if let Some(alarm) = cx.cell.feeders[index].low_supply_alarm() {
+                        cx.tx.send(alarm);
+                    }
+                    cx.cell.snapshot(Trigger::Pickup, &cx.cycle_id);
+                }
+            }
+            cx.tx.end_material();
+            cx.cell.complete_material();
+
+            // the receiver lives as long as the state reporter, which outlives the cycles
+            cx.state_tx.send(cx.cell.state()).ok();
+            if let Some(event) = progress.event(processed + 1) {
+                cx.tx.send(event);
+            }
+        }
+
+        publish_transition(cx.tx, self.stop()?);
 
-        Ok(Self { line, line_handle })
+        Ok(Report {
+            processed: cx.count,
+            interrupted: None,
+        })
     }
 }
 
+#[async_trait]
 impl ManufacturingProgram for SimplifiedScenario2 {
-    type Error = gpio_cdev::Error;
-    type Success = ();
+    type Error = ProgramError;
+    type Success = Option<Event>;
+
+    fn state(&self) -> ProgramState {
+        self.lifecycle.state()
+    }
+
+    fn start(&mut self) -> Result<Self::Success, Self::Error> {
+        let line_handle = &self.line_handle;
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Running, || line_handle.set_value(1))?;
+        Ok(Some(event))
+    }
+
+    fn stop(&mut self) -> Result<Self::Success, Self::Error> {
+        let line_handle = &self.line_handle;
+        self.lifecycle.stop(|| line_handle.set_value(0))
+    }
+
+    /// The control line is driven low, stopping the PLC
+    fn safe_state(&mut self) -> Result<Self::Success, Self::Error> {
+        self.stop()
+    }
+
+    /// The PLC holds its step while the control line is low, so pausing is the same as stopping,
+    /// the twin is what keeps track of the progress of the cycle
+    fn pause(&mut self) -> Result<Self::Success, Self::Error> {
+        let line_handle = &self.line_handle;
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Paused, || line_handle.set_value(0))?;
+        Ok(Some(event))
+    }
+
+    fn resume(&mut self) -> Result<Self::Success, Self::Error> {
+        let line_handle = &self.line_handle;
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Running, || line_handle.set_value(1))?;
+        Ok(Some(event))
+    }
+
+    /// Run the program until the requested count of materials have been picked up, or a stop
+    /// request is received, see cycle
+    async fn run(&mut self, cx: &mut CycleContext<'_>) -> Result<Report> {
+        self.cycle(cx).await
+    }
+
+    fn plan(&self, cell: &ComponentRegistry) -> Vec<PlannedStep> {
+        let timeout = Some(cell.sensor_timeout);
+        vec![
+            PlannedStep::wait(
+                "wait for a material to be picked up",
+                Some("feeder MaterialPickedUp"),
+                timeout,
+            ),
+            PlannedStep::wait("wait for the material to be pushed", None, timeout),
+        ]
+    }
+}
+
+/// Time the piston presses each material for in scenario 1, unless the start says otherwise
+const DEFAULT_PRESS_TIME: Duration = Duration::from_millis(500);
+
+/// The complete scenario 1, where the twin drives the cell itself rather than a PLC. Each material
+/// is picked up from the feeder the feeder policy selects, by the arm at position 1 for the
+/// material feeder or at position 66 for feeder B, carried along the track to the piston at
+/// position 15, pressed, and dropped off there onto the conveyor. The arm only moves around the
+/// track, from the piston to feeder A it passes feeder B
+pub struct Scenario1 {
+    lifecycle: Lifecycle,
+    /// how long the piston presses each material for
+    press_time: Duration,
+}
+
+impl Scenario1 {
+    pub fn new(press_time: Duration) -> Self {
+        Self {
+            lifecycle: Lifecycle::default(),
+            press_time,
+        }
+    }
+
+    /// Run the program until the requested count of materials have been processed, or a stop
+    /// request is received. The feeder is waited on like in the simplified scenario 2. The moves
+    /// and presses are never dropped halfway, stops and emergency stops cancel them and pauses are
+    /// handled once they're done, so the arm and the piston are at rest in between
+    async fn cycle(&mut self, cx: &mut CycleContext<'_>) -> Result<Report> {
+        publish_transition(cx.tx, self.start()?);
+        cx.status_tx.send(ProgramStatus::Running).ok();
+        let progress = Progress::new(cx.count, cx.progress_every);
+
+        for processed in 0..cx.count {
+            let remaining = cx.count.saturating_sub(processed);
+            let index = cx.cell.select_feeder(processed as usize)?;
+
+            self.move_robot(cx, ComponentRegistry::pickup_position(index))
+                .await?;
+            if let Some(interrupted) = between_steps(self, cx, remaining).await? {
+                return Ok(Report {
+                    processed,
+                    interrupted: Some(interrupted),
+                });
+            }
+
+            match wait_for_pickup(self, cx, index, remaining).await? {
+                Picked::Material(event) => {
+                    cx.tx.begin_material();
+                    cx.tx.send(event);
+                    if let Some(alarm) = cx.cell.feeders[index].low_supply_alarm() {
+                        cx.tx.send(alarm);
+                    }
+                    cx.cell.snapshot(Trigger::Pickup, &cx.cycle_id);
+                }
+                Picked::Interrupted(interrupted) => {
+                    return Ok(Report {
+                        processed,
+                        interrupted: Some(interrupted),
+                    })
+                }
+            }
+
+            self.move_robot(cx, RobotPosition::Position15).await?;
+            if let Some(interrupted) = between_steps(self, cx, remaining).await? {
+                return Ok(Report {
+                    processed,
+                    interrupted: Some(interrupted),
+                });
+            }
+
+            cx.cell
+                .piston
+                .depress_for(self.press_time, cx.tx, cx.cancel.cancelled())
+                .await?;
+            cx.cell.snapshot(Trigger::Press, &cx.cycle_id);
+            cx.cell.inspect(cx.tx)?;
+            cx.cell.snapshot(Trigger::Dropoff, &cx.cycle_id);
+            cx.tx.end_material();
+            cx.cell.complete_material();
+            if let Some(interrupted) = between_steps(self, cx, remaining).await? {
+                return Ok(Report {
+                    processed,
+                    interrupted: Some(interrupted),
+                });
+            }
+
+            // the receiver lives as long as the state reporter, which outlives the cycles
+            cx.state_tx.send(cx.cell.state()).ok();
+            if let Some(event) = progress.event(processed + 1) {
+                cx.tx.send(event);
+            }
+        }
+
+        publish_transition(cx.tx, self.stop()?);
+
+        Ok(Report {
+            processed: cx.count,
+            interrupted: None,
+        })
+    }
+
+    /// Move the arm to position, publishing the position it reaches. A cancelled move isn't a
+    /// failure, whatever cancelled it is handled once the arm stopped
+    async fn move_robot(
+        &mut self,
+        cx: &mut CycleContext<'_>,
+        position: RobotPosition,
+    ) -> Result<()> {
+        cx.cell.check_move(position)?;
+        let moved = cx.cell.robot.move_to(position, cx.tx, cx.cancel).await;
+        cx.state_tx.send(cx.cell.state()).ok();
+        match moved {
+            Ok(event) => {
+                cx.tx.send(event);
+                Ok(())
+            }
+            Err(e) if e.is::<Cancelled>() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl ManufacturingProgram for Scenario1 {
+    type Error = ProgramError;
+    type Success = Option<Event>;
+
+    fn state(&self) -> ProgramState {
+        self.lifecycle.state()
+    }
+
+    // the cell is driven step by step by the cycle, there's no line of the program itself
+
+    fn start(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Running, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    fn stop(&mut self) -> Result<Self::Success, Self::Error> {
+        self.lifecycle.stop(|| Ok(()))
+    }
+
+    /// The arm and the piston are made safe with the rest of the cell
+    fn safe_state(&mut self) -> Result<Self::Success, Self::Error> {
+        self.stop()
+    }
+
+    fn pause(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self.lifecycle.transition(ProgramState::Paused, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    fn resume(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Running, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    /// Run the program until the requested count of materials have been processed, or a stop
+    /// request is received, see cycle
+    async fn run(&mut self, cx: &mut CycleContext<'_>) -> Result<Report> {
+        self.cycle(cx).await
+    }
+
+    fn plan(&self, cell: &ComponentRegistry) -> Vec<PlannedStep> {
+        let move_timeout = Some(cell.robot.move_timeout());
+        vec![
+            PlannedStep::wait(
+                "move the arm to the selected feeder, position 1 or 66 for feeder B",
+                Some("robot PositionReached"),
+                move_timeout,
+            ),
+            PlannedStep::wait(
+                "wait for a material to be picked up",
+                Some("feeder MaterialPickedUp"),
+                Some(cell.sensor_timeout),
+            ),
+            PlannedStep::wait(
+                "move the arm to position 15",
+                Some("robot PositionReached(position 15)"),
+                move_timeout,
+            ),
+            PlannedStep::act(
+                "press the material and drop it off onto the conveyor",
+                Some("piston Depressed, then Steady"),
+                self.press_time,
+            ),
+        ]
+    }
+}
+
+/// Where an arm is in its cycle in the interleaved scenario 1, the step it takes next
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArmStep {
+    ToPickup,
+    /// at the pickup of the feeder at the index
+    Pickup(usize),
+    ToPress,
+    /// the material is dropped off once it's pressed
+    Press,
+}
+
+/// Scenario 1 run by every robot of an extended cell, their cycles interleaved. The arms take
+/// their steps in turns, one step each, and the positions they share are arbitrated by the
+/// interlock of the cell: an arm whose next move is to a position another one holds passes its turn
+/// until it's released, so only one arm is ever at the piston.
+///
+/// The materials of the arms are in the cell together, so their events aren't correlated to one
+pub struct InterleavedScenario1 {
+    lifecycle: Lifecycle,
+    /// how long the piston presses each material for
+    press_time: Duration,
+}
+
+impl InterleavedScenario1 {
+    pub fn new(press_time: Duration) -> Self {
+        Self {
+            lifecycle: Lifecycle::default(),
+            press_time,
+        }
+    }
+
+    /// Run the program until the requested count of materials have been processed by the arms
+    /// together, or a stop request is received. Moves, presses and requests are handled like in
+    /// scenario 1, in between the steps of any arm. Fails when no arm can take its step, which only
+    /// happens when the arms hold positions the others are moving to
+    async fn cycle(&mut self, cx: &mut CycleContext<'_>) -> Result<Report> {
+        publish_transition(cx.tx, self.start()?);
+        cx.status_tx.send(ProgramStatus::Running).ok();
+        let progress = Progress::new(cx.count, cx.progress_every);
+
+        let mut arms = vec![ArmStep::ToPickup; 1 + cx.cell.robots.len()];
+        let mut started = 0;
+        let mut processed = 0;
+        // turns passed in a row, the arms are deadlocked once each of them passed its turn
+        let mut passed = 0;
+        let mut turn = 0;
+
+        while processed < cx.count {
+            let arm = turn % arms.len();
+            turn += 1;
+            if passed >= arms.len() {
+                return Err(eyre!(
+                    "No robot can move, each waits on a position another holds"
+                ));
+            }
+            let remaining = cx.count.saturating_sub(processed);
+
+            let next = match arms[arm] {
+                // every material left is already on its way
+                ArmStep::ToPickup if started == cx.count => None,
+                ArmStep::ToPickup => {
+                    let index = cx.cell.select_feeder(started as usize)?;
+                    let pickup = ComponentRegistry::pickup_position(index);
+                    if self.move_robot(cx, arm, pickup).await? {
+                        started += 1;
+                        Some(ArmStep::Pickup(index))
+                    } else {
+                        None
+                    }
+                }
+                ArmStep::Pickup(index) => {
+                    match wait_for_pickup(self, cx, index, remaining).await? {
+                        Picked::Material(event) => {
+                            cx.tx.send(event);
+                            if let Some(alarm) = cx.cell.feeders[index].low_supply_alarm() {
+                                cx.tx.send(alarm);
+                            }
+                            cx.cell.snapshot(Trigger::Pickup, &cx.cycle_id);
+                        }
+                        Picked::Interrupted(interrupted) => {
+                            return Ok(Report {
+                                processed,
+                                interrupted: Some(interrupted),
+                            })
+                        }
+                    }
+                    Some(ArmStep::ToPress)
+                }
+                ArmStep::ToPress => {
+                    if self.move_robot(cx, arm, RobotPosition::Position15).await? {
+                        Some(ArmStep::Press)
+                    } else {
+                        None
+                    }
+                }
+                ArmStep::Press => {
+                    cx.cell
+                        .piston
+                        .depress_for(self.press_time, cx.tx, cx.cancel.cancelled())
+                        .await?;
+                    cx.cell.snapshot(Trigger::Press, &cx.cycle_id);
+                    cx.cell.inspect(cx.tx)?;
+                    cx.cell.snapshot(Trigger::Dropoff, &cx.cycle_id);
+                    cx.cell.complete_material();
+                    processed += 1;
+
+                    // the receiver lives as long as the state reporter, which outlives the cycles
+                    cx.state_tx.send(cx.cell.state()).ok();
+                    if let Some(event) = progress.event(processed) {
+                        cx.tx.send(event);
+                    }
+                    Some(ArmStep::ToPickup)
+                }
+            };
+
+            match next {
+                Some(step) => {
+                    arms[arm] = step;
+                    passed = 0;
+                }
+                None => {
+                    passed += 1;
+                    continue;
+                }
+            }
+            if let Some(interrupted) =
+                between_steps(self, cx, cx.count.saturating_sub(processed)).await?
+            {
+                return Ok(Report {
+                    processed,
+                    interrupted: Some(interrupted),
+                });
+            }
+        }
+
+        publish_transition(cx.tx, self.stop()?);
+
+        Ok(Report {
+            processed: cx.count,
+            interrupted: None,
+        })
+    }
+
+    /// Move the arm at index to position like scenario 1 does, once the interlock lets it. Returns
+    /// whether it moved, the shared positions it left are released once it's there. A move the
+    /// limit switches don't allow fails before the position is held, so no other arm waits on it
+    async fn move_robot(
+        &mut self,
+        cx: &mut CycleContext<'_>,
+        arm: usize,
+        position: RobotPosition,
+    ) -> Result<bool> {
+        cx.cell.check_move(position)?;
+        let robot = cx.cell.robot_at(arm).name().to_string();
+        if !cx.cell.interlock.acquire(&robot, position) {
+            return Ok(false);
+        }
+
+        let moved = cx
+            .cell
+            .robot_at(arm)
+            .move_to(position, cx.tx, cx.cancel)
+            .await;
+        cx.state_tx.send(cx.cell.state()).ok();
+        match moved {
+            Ok(event) => {
+                cx.cell.interlock.release(&robot, position);
+                cx.tx.send(event);
+                Ok(true)
+            }
+            Err(e) if e.is::<Cancelled>() => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl ManufacturingProgram for InterleavedScenario1 {
+    type Error = ProgramError;
+    type Success = Option<Event>;
+
+    fn state(&self) -> ProgramState {
+        self.lifecycle.state()
+    }
+
+    // the cell is driven step by step by the cycle, there's no line of the program itself
 
     fn start(&mut self) -> Result<Self::Success, Self::Error> {
-        self.line_handle.set_value(1)
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Running, || Ok(()))?;
+        Ok(Some(event))
     }
 
     fn stop(&mut self) -> Result<Self::Success, Self::Error> {
-        self.line_handle.set_value(0)
+        self.lifecycle.stop(|| Ok(()))
+    }
+
+    /// The arms and the piston are made safe with the rest of the cell
+    fn safe_state(&mut self) -> Result<Self::Success, Self::Error> {
+        self.stop()
+    }
+
+    fn pause(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self.lifecycle.transition(ProgramState::Paused, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    fn resume(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Running, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    /// Run the program until the requested count of materials have been processed, or a stop
+    /// request is received, see cycle
+    async fn run(&mut self, cx: &mut CycleContext<'_>) -> Result<Report> {
+        self.cycle(cx).await
+    }
+
+    /// The steps of every arm, which the cycle interleaves
+    fn plan(&self, cell: &ComponentRegistry) -> Vec<PlannedStep> {
+        let move_timeout = Some(cell.robot.move_timeout());
+        vec![
+            PlannedStep::wait(
+                "move an arm to the selected feeder, releasing position 15",
+                Some("robot PositionReached"),
+                move_timeout,
+            ),
+            PlannedStep::wait(
+                "wait for a material to be picked up",
+                Some("feeder MaterialPickedUp"),
+                Some(cell.sensor_timeout),
+            ),
+            PlannedStep::wait(
+                "move the arm to position 15 once no other arm holds it",
+                Some("robot PositionReached(position 15)"),
+                move_timeout,
+            ),
+            PlannedStep::act(
+                "press the material and drop it off onto the conveyor",
+                Some("piston Depressed, then Steady"),
+                self.press_time,
+            ),
+        ]
+    }
+}
+
+/// Parameters of scenario 1, the press time is in milliseconds
+#[derive(Debug, Default, Deserialize)]
+struct Scenario1Parameters {
+    press_ms: Option<u64>,
+}
+
+fn scenario1(_: &mut dyn GpioProvider, _: u32, parameters: &Value) -> Result<Program> {
+    let parameters: Scenario1Parameters = if parameters.is_null() {
+        Default::default()
+    } else {
+        serde_json::from_value(parameters.clone())?
+    };
+    let press_time = parameters
+        .press_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_PRESS_TIME);
+
+    Ok(Box::new(Scenario1::new(press_time)))
+}
+
+fn interleaved_scenario1(_: &mut dyn GpioProvider, _: u32, parameters: &Value) -> Result<Program> {
+    let parameters: Scenario1Parameters = if parameters.is_null() {
+        Default::default()
+    } else {
+        serde_json::from_value(parameters.clone())?
+    };
+    let press_time = parameters
+        .press_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_PRESS_TIME);
+
+    Ok(Box::new(InterleavedScenario1::new(press_time)))
+}
+
+/// Parameters of the simplified scenario 2, the control line defaults to PROGRAM_CONTROL
+#[derive(Debug, Default, Deserialize)]
+struct SimplifiedScenario2Parameters {
+    line: Option<u32>,
+}
+
+fn simplified_scenario2(
+    gpio: &mut dyn GpioProvider,
+    control_line: u32,
+    parameters: &Value,
+) -> Result<Program> {
+    let parameters: SimplifiedScenario2Parameters = if parameters.is_null() {
+        Default::default()
+    } else {
+        serde_json::from_value(parameters.clone())?
+    };
+    let line = parameters.line.unwrap_or(control_line);
+
+    Ok(Box::new(SimplifiedScenario2::new(gpio, line)?))
+}
+
+/// A program selected at runtime, all of them drive GPIO lines
+pub type DynProgram =
+    dyn ManufacturingProgram<Error = ProgramError, Success = Option<Event>> + Send;
+pub type Program = Box<DynProgram>;
+
+/// Builds a program from the lines of the chip, the default control line and the parameters of the
+/// start request
+pub type Constructor = fn(&mut dyn GpioProvider, u32, &Value) -> Result<Program>;
+
+/// How the programs are built by scenario name, from a program of their own, a scenario written in
+/// TOML or the steps of a sequence
+#[derive(Clone)]
+pub struct Scenarios {
+    /// the settings the scenarios written in TOML request their lines with
+    settings: Settings,
+    control_line: u32,
+    constructors: HashMap<&'static str, Constructor>,
+    /// where the scenarios written in TOML are read from, SCENARIO_DIR
+    scenario_dir: PathBuf,
+}
+
+impl Scenarios {
+    /// The scenarios with a program of their own, the others are read from SCENARIO_DIR
+    pub fn new(settings: &Settings, control_line: u32) -> Self {
+        let mut scenarios = Self {
+            settings: settings.clone(),
+            control_line,
+            constructors: HashMap::new(),
+            scenario_dir: settings.get("SCENARIO_DIR").unwrap_or("scenarios").into(),
+        };
+        scenarios.register(DEFAULT_SCENARIO, simplified_scenario2);
+        scenarios.register("scenario-1", scenario1);
+        scenarios.register("scenario-1-interleaved", interleaved_scenario1);
+        scenarios
+    }
+
+    pub fn register(&mut self, scenario: &'static str, constructor: Constructor) {
+        self.constructors.insert(scenario, constructor);
+    }
+
+    fn constructor(&self, scenario: &str) -> Result<Constructor> {
+        self.constructors.get(scenario).copied().ok_or_else(|| {
+            let mut scenarios: Vec<_> = self.constructors.keys().collect();
+            scenarios.push(&SEQUENCE_SCENARIO);
+            scenarios.sort();
+            eyre!("Unknown scenario {scenario}, expected one of {scenarios:?}")
+        })
+    }
+
+    /// The file of a scenario written in TOML, None when the scenario has a program of its own or
+    /// there is no such file. Names that aren't a plain file name are never read
+    fn script(&self, scenario: &str) -> Option<PathBuf> {
+        if self.constructors.contains_key(scenario)
+            || scenario == SEQUENCE_SCENARIO
+            || scenario.contains(['/', '\\'])
+            || scenario.starts_with('.')
+        {
+            return None;
+        }
+        let path = self.scenario_dir.join(format!("{scenario}.toml"));
+        if path.is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// Build the program for the scenario on the lines of gpio, a sequence builds the programs
+    /// of its steps as they run
+    pub fn build(&self, gpio: &SharedGpio, scenario: &str, parameters: &Value) -> Result<Program> {
+        if let Some(path) = self.script(scenario) {
+            let definition = ScriptDefinition::load(&path)?;
+            return Ok(Box::new(ScriptProgram::new(
+                &self.settings,
+                scenario,
+                &definition,
+                &mut gpio.clone(),
+            )?));
+        }
+        if scenario != SEQUENCE_SCENARIO {
+            let constructor = self.constructor(scenario)?;
+            return constructor(&mut gpio.clone(), self.control_line, parameters);
+        }
+
+        let parameters: SequenceParameters = serde_json::from_value(parameters.clone())?;
+        Ok(Box::new(Sequence::new(
+            self.clone(),
+            gpio.clone(),
+            parameters,
+        )?))
+    }
+}
+
+/// The programs the cloud can choose from by scenario name when starting a cycle
+pub struct ProgramRegistry {
+    scenarios: Scenarios,
+    gpio: SharedGpio,
+    /// the program last selected, with the scenario and parameters it was built with
+    current: Option<(String, Value, Program)>,
+}
+
+impl ProgramRegistry {
+    pub fn new(settings: &Settings, gpio: Box<dyn GpioProvider>, control_line: u32) -> Self {
+        Self {
+            scenarios: Scenarios::new(settings, control_line),
+            gpio: SharedGpio::new(gpio),
+            current: None,
+        }
+    }
+
+    pub fn register(&mut self, scenario: &'static str, constructor: Constructor) {
+        self.scenarios.register(scenario, constructor);
+    }
+
+    /// The program for the scenario, reusing the current one when neither the scenario nor the
+    /// parameters changed. The current program is released first since programs share lines.
+    /// Scenarios without a program of their own are read from <SCENARIO_DIR>/<scenario>.toml, so
+    /// they can be changed without a rebuild, and are read again whenever they're selected anew
+    pub fn select(&mut self, scenario: &str, parameters: &Value) -> Result<&mut DynProgram> {
+        let reusable = matches!(
+            &self.current,
+            Some((current, current_parameters, _))
+                if current == scenario && current_parameters == parameters
+        );
+
+        if !reusable {
+            if scenario != SEQUENCE_SCENARIO && self.scenarios.script(scenario).is_none() {
+                self.scenarios.constructor(scenario)?;
+            }
+
+            self.current = None;
+            let program = self.scenarios.build(&self.gpio, scenario, parameters)?;
+            self.current = Some((scenario.to_string(), parameters.clone(), program));
+        }
+
+        Ok(self.current().expect("A program was just selected"))
+    }
+
+    /// The stages a cycle of count materials of the scenario goes through. The program is built
+    /// on unwired lines to walk its plan, so no line is requested and the current program is left
+    /// as it is
+    pub fn stages(
+        &self,
+        scenario: &str,
+        parameters: &Value,
+        count: u32,
+        cell: &ComponentRegistry,
+    ) -> Result<Vec<Stage>> {
+        let unwired = SharedGpio::new(Box::new(Unwired));
+        let program = self.scenarios.build(&unwired, scenario, parameters)?;
+        Ok(program.stages(count, cell))
+    }
+
+    /// Drive the outputs of the current program to their safe values, there's nothing to drive
+    /// without one
+    pub fn emergency_stop(&mut self) -> Result<()> {
+        if let Some(program) = self.current() {
+            program.emergency_stop()?;
+        }
+        Ok(())
+    }
+
+    /// The lines the programs are driven through, which the cell shares
+    pub fn gpio(&mut self) -> &mut dyn GpioProvider {
+        &mut self.gpio
+    }
+
+    /// The program last selected, None when building it failed
+    pub fn current(&mut self) -> Option<&mut DynProgram> {
+        self.current
+            .as_mut()
+            .map(|(_, _, program)| program.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn programs_only_change_state_along_the_lifecycle() {
+        let mut lifecycle = Lifecycle::default();
+        assert!(matches!(
+            lifecycle.transition(ProgramState::Paused, || Ok(())),
+            Err(ProgramError::IllegalTransition { .. })
+        ));
+        assert_eq!(lifecycle.state(), ProgramState::Idle);
+
+        lifecycle
+            .transition(ProgramState::Running, || Ok(()))
+            .unwrap();
+        lifecycle
+            .transition(ProgramState::Paused, || Ok(()))
+            .unwrap();
+        assert!(lifecycle
+            .transition(ProgramState::Paused, || Ok(()))
+            .is_err());
+
+        assert!(lifecycle.stop(|| Ok(())).unwrap().is_some());
+        assert_eq!(lifecycle.state(), ProgramState::Stopped);
+        // stopping again only drives the lines to rest
+        assert!(lifecycle.stop(|| Ok(())).unwrap().is_none());
+        assert!(ProgramState::Stopped.can_become(ProgramState::Running));
     }
 }
@@ -0,0 +1,230 @@
+use crate::config::{ConfigError, Settings};
+use crate::manufacturing_components::gpio::{GpioProvider, OutputLine};
+use crate::manufacturing_components::output_line;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time;
+use tracing::error;
+
+/// Period of a PWM output that wasn't given one, 50 Hz
+pub const DEFAULT_PERIOD: Duration = Duration::from_millis(20);
+
+/// Where the pulses of a PWM output come from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// a task toggling a GPIO line, only as precise as the timers of the runtime
+    Software,
+    /// a channel of a pwmchip of the sysfs, generated by the hardware
+    Sysfs { chip: u32, channel: u32 },
+}
+
+impl Backend {
+    /// Parse software, or the pwmchip and channel the way the sysfs names them, e.g. pwmchip0/pwm1
+    fn parse(value: &str) -> Option<Self> {
+        if value == "software" {
+            return Some(Backend::Software);
+        }
+        let (chip, channel) = value.split_once('/')?;
+        Some(Backend::Sysfs {
+            chip: chip.strip_prefix("pwmchip")?.parse().ok()?,
+            channel: channel.strip_prefix("pwm")?.parse().ok()?,
+        })
+    }
+}
+
+/// An output driven with a duty cycle rather than high or low, e.g. for the speed of a motor or the
+/// brightness of an indicator.
+///
+/// A component's output is PWM when <COMPONENT>_PWM is set, to software for pulses toggled on its
+/// GPIO line or to a channel of a pwmchip, e.g. pwmchip0/pwm1. The period is read from
+/// <COMPONENT>_PWM_PERIOD, in microseconds. Outputs start with a duty cycle of 0 and are driven low
+/// once dropped
+pub enum PwmOutput {
+    Software(SoftwarePwm),
+    Sysfs(SysfsPwm),
+}
+
+impl PwmOutput {
+    /// The PWM output of the named component as configured, None when it isn't configured, in which
+    /// case line is left to be driven high or low
+    pub fn from_settings(
+        settings: &Settings,
+        component: &str,
+        gpio: &mut dyn GpioProvider,
+        line: u32,
+        name: &str,
+    ) -> Result<Option<Self>> {
+        let prefix = component.to_uppercase();
+
+        let key = format!("{prefix}_PWM");
+        let backend = match settings.get(&key) {
+            None => return Ok(None),
+            Some(value) => Backend::parse(value).ok_or_else(|| {
+                ConfigError::invalid(&key, "software or pwmchip<N>/pwm<N>", value)
+            })?,
+        };
+        let period = settings
+            .parse(&format!("{prefix}_PWM_PERIOD"), "microseconds")?
+            .map(Duration::from_micros)
+            .unwrap_or(DEFAULT_PERIOD);
+
+        let output = match backend {
+            Backend::Software => {
+                PwmOutput::Software(SoftwarePwm::new(output_line(gpio, line, name)?, period))
+            }
+            Backend::Sysfs { chip, channel } => {
+                PwmOutput::Sysfs(SysfsPwm::new(chip, channel, period)?)
+            }
+        };
+        Ok(Some(output))
+    }
+
+    /// Set the duty cycle, in percent of the period the output is high
+    pub fn set_duty(&mut self, duty: u8) -> Result<()> {
+        if duty > 100 {
+            return Err(eyre!(
+                "Duty cycle {duty} is out of range, expected 0 to 100"
+            ));
+        }
+        match self {
+            PwmOutput::Software(pwm) => pwm.set_duty(duty),
+            PwmOutput::Sysfs(pwm) => pwm.set_duty(duty),
+        }
+    }
+}
+
+/// Pulses toggled on a GPIO line by a task of the runtime, for boards without a free pwmchip
+pub struct SoftwarePwm {
+    duty_tx: watch::Sender<u8>,
+}
+
+impl SoftwarePwm {
+    pub fn new(handle: Box<dyn OutputLine>, period: Duration) -> Self {
+        let (duty_tx, duty_rx) = watch::channel(0);
+        tokio::spawn(toggle(handle, period, duty_rx));
+        Self { duty_tx }
+    }
+
+    fn set_duty(&mut self, duty: u8) -> Result<()> {
+        self.duty_tx
+            .send(duty)
+            .map_err(|_| eyre!("The software PWM stopped toggling its line"))
+    }
+}
+
+/// Toggle the line at the duty cycle received until the output is dropped, leaving it low
+async fn toggle(
+    mut handle: Box<dyn OutputLine>,
+    period: Duration,
+    mut duty_rx: watch::Receiver<u8>,
+) {
+    loop {
+        let duty = *duty_rx.borrow();
+        let toggled = match duty {
+            // a constant level, held until the duty cycle changes
+            0 | 100 => match handle.set_value(duty / 100) {
+                Ok(()) => duty_rx.changed().await.is_ok(),
+                Err(e) => {
+                    error!("Failed to drive the software PWM: {e}");
+                    false
+                }
+            },
+            _ => {
+                let high = period * duty as u32 / 100;
+                match pulse(handle.as_mut(), high).await {
+                    Ok(()) => tokio::select! {
+                        _ = time::sleep(period - high) => true,
+                        changed = duty_rx.changed() => changed.is_ok(),
+                    },
+                    Err(e) => {
+                        error!("Failed to drive the software PWM: {e}");
+                        false
+                    }
+                }
+            }
+        };
+        if !toggled {
+            break;
+        }
+    }
+    handle.set_value(0).ok();
+}
+
+/// Drive the line high for the given time, then low
+async fn pulse(handle: &mut dyn OutputLine, high: Duration) -> Result<(), gpio_cdev::Error> {
+    handle.set_value(1)?;
+    time::sleep(high).await;
+    handle.set_value(0)
+}
+
+/// A channel of a pwmchip of the sysfs, exported when it isn't already
+pub struct SysfsPwm {
+    /// directory of the channel, e.g. /sys/class/pwm/pwmchip0/pwm1
+    dir: PathBuf,
+    period_ns: u64,
+}
+
+impl SysfsPwm {
+    pub fn new(chip: u32, channel: u32, period: Duration) -> Result<Self> {
+        let chip = PathBuf::from(format!("/sys/class/pwm/pwmchip{chip}"));
+        let dir = chip.join(format!("pwm{channel}"));
+        if !dir.exists() {
+            fs::write(chip.join("export"), channel.to_string()).map_err(|e| {
+                eyre!(
+                    "Failed to export channel {channel} of {}: {e}",
+                    chip.display()
+                )
+            })?;
+        }
+
+        let pwm = Self {
+            dir,
+            period_ns: period.as_nanos() as u64,
+        };
+        // the duty cycle can't be longer than the period, it's cleared first
+        pwm.write("duty_cycle", 0)?;
+        pwm.write("period", pwm.period_ns)?;
+        pwm.write("enable", 1)?;
+        Ok(pwm)
+    }
+
+    fn set_duty(&mut self, duty: u8) -> Result<()> {
+        self.write("duty_cycle", self.period_ns * duty as u64 / 100)
+    }
+
+    fn write(&self, attribute: &str, value: u64) -> Result<()> {
+        let path = self.dir.join(attribute);
+        fs::write(&path, value.to_string())
+            .map_err(|e| eyre!("Failed to write {value} to {}: {e}", path.display()))
+    }
+}
+
+impl Drop for SysfsPwm {
+    fn drop(&mut self) {
+        self.write("duty_cycle", 0).ok();
+        self.write("enable", 0).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backends_are_named_like_the_sysfs() {
+        assert_eq!(Backend::parse("software"), Some(Backend::Software));
+        assert_eq!(
+            Backend::parse("pwmchip0/pwm1"),
+            Some(Backend::Sysfs {
+                chip: 0,
+                channel: 1
+            })
+        );
+        assert_eq!(Backend::parse("pwmchip0"), None);
+        assert_eq!(Backend::parse("hardware"), None);
+    }
+}
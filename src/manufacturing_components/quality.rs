@@ -0,0 +1,248 @@
+use crate::config::{ConfigError, Settings};
+use crate::manufacturing_components::gpio::{GpioProvider, OutputLine};
+use crate::manufacturing_components::input::{DebouncedLine, Trigger};
+use crate::manufacturing_components::{input_line, output_line, Component, ComponentEvent};
+use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::Result;
+use futures::future;
+use gpio_cdev::EventRequestFlags;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+/// Inspections the reject rate is taken over when the station wasn't given a window
+pub const DEFAULT_WINDOW: usize = 20;
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    /// a material was inspected, it was diverted unless it passed
+    Inspected { station: String, passed: bool },
+    /// the share of materials rejected over the last inspections rose above the threshold
+    RejectRateHigh {
+        station: String,
+        rate: f32,
+        threshold: f32,
+    },
+}
+
+/// A quality-check station the materials go through after they're pressed. The inspection system
+/// holds its result on the pass/fail line while the material is at the station, the line is active
+/// when it passed. Rejects are diverted by driving the divert line high, which holds the gate until
+/// a material passes.
+///
+/// Rejects are counted, and the reject rate is taken over the last inspections of the window. It
+/// raises an alarm once above the threshold, when the station has one, which is latched until the
+/// rate is back under it
+pub struct QualityStation {
+    name: String,
+    /// read when a material is inspected, its edges aren't events
+    line: DebouncedLine,
+    divert: Box<dyn OutputLine>,
+    inspected: u64,
+    rejected: u64,
+    /// whether each of the last inspections rejected the material, the oldest first
+    window: VecDeque<bool>,
+    window_len: usize,
+    threshold: Option<f32>,
+    alarmed: bool,
+}
+
+impl Serialize for QualityStation {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("quality", 5)?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("inspected", &self.inspected)?;
+        s.serialize_field("rejected", &self.rejected)?;
+        s.serialize_field("rejectRate", &self.reject_rate())?;
+
+        let now = SystemTime::iso8601_now();
+        s.serialize_field("updateTimestamp", &now)?;
+
+        s.end()
+    }
+}
+
+impl QualityStation {
+    /// The station reading the result of its inspections on line and diverting rejects on
+    /// divert_line, whose reject rate is taken over the last window inspections
+    pub fn new(
+        settings: &Settings,
+        name: &str,
+        gpio: &mut dyn GpioProvider,
+        line: u32,
+        divert_line: u32,
+        window: usize,
+    ) -> Result<Self> {
+        let trigger = Trigger::from_settings(settings, "quality", EventRequestFlags::BOTH_EDGES)?;
+        let window_len = window.max(1);
+
+        Ok(Self {
+            name: name.to_string(),
+            line: input_line(settings, gpio, line, trigger, name)?,
+            divert: output_line(gpio, divert_line, name)?,
+            inspected: 0,
+            rejected: 0,
+            window: VecDeque::with_capacity(window_len),
+            window_len,
+            threshold: None,
+            alarmed: false,
+        })
+    }
+
+    /// Raise an alarm when the reject rate, between 0 and 1, rises above threshold
+    pub fn with_threshold(mut self, threshold: f32) -> Self {
+        self.set_threshold(Some(threshold));
+        self
+    }
+
+    /// Change the threshold of the reject rate alarm, or stop raising it with None
+    pub fn set_threshold(&mut self, threshold: Option<f32>) {
+        self.threshold = threshold;
+    }
+
+    /// The station reading QUALITY_LINE and diverting on QUALITY_DIVERT_LINE, alarming above the
+    /// reject rate QUALITY_REJECT_RATE over the last QUALITY_WINDOW inspections. None when the cell
+    /// has no station
+    pub fn from_settings(settings: &Settings, gpio: &mut dyn GpioProvider) -> Result<Option<Self>> {
+        let line = match settings.parse("QUALITY_LINE", "unsigned integer")? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let divert_line = settings
+            .parse("QUALITY_DIVERT_LINE", "unsigned integer")?
+            .ok_or_else(|| {
+                ConfigError::missing("QUALITY_DIVERT_LINE", "the rejects are diverted on it")
+            })?;
+        let window = settings
+            .parse("QUALITY_WINDOW", "unsigned integer")?
+            .unwrap_or(DEFAULT_WINDOW);
+
+        let mut station = Self::new(settings, "Quality", gpio, line, divert_line, window)?;
+        station.set_threshold(settings.parse("QUALITY_REJECT_RATE", "a rate between 0 and 1")?);
+        Ok(Some(station))
+    }
+
+    /// Share of the materials rejected over the last inspections, 0 until one was inspected
+    pub fn reject_rate(&self) -> f32 {
+        if self.window.is_empty() {
+            return 0.0;
+        }
+        let rejects = self.window.iter().filter(|&&rejected| rejected).count();
+        rejects as f32 / self.window.len() as f32
+    }
+
+    /// Inspect the material at the station, diverting it when it's rejected. Returns the
+    /// inspection, followed by the reject rate alarm when it's raised
+    pub fn inspect(&mut self) -> Result<Vec<Event>> {
+        let passed = self.line.value()? == 1;
+        self.divert.set_value(if passed { 0 } else { 1 })?;
+
+        self.inspected += 1;
+        if !passed {
+            self.rejected += 1;
+        }
+        if self.window.len() == self.window_len {
+            self.window.pop_front();
+        }
+        self.window.push_back(!passed);
+
+        let mut events = vec![Event::Inspected {
+            station: self.name.clone(),
+            passed,
+        }];
+        if let Some(alarm) = self.reject_rate_alarm() {
+            events.push(alarm);
+        }
+        Ok(events)
+    }
+
+    /// The alarm once the rate rises above the threshold, over a full window so a reject among the
+    /// first inspections doesn't raise it
+    fn reject_rate_alarm(&mut self) -> Option<Event> {
+        let threshold = self.threshold?;
+        let rate = self.reject_rate();
+        if rate <= threshold {
+            self.alarmed = false;
+            return None;
+        }
+        if self.alarmed || self.window.len() < self.window_len {
+            return None;
+        }
+
+        self.alarmed = true;
+        Some(Event::RejectRateHigh {
+            station: self.name.clone(),
+            rate,
+            threshold,
+        })
+    }
+}
+
+#[async_trait]
+impl Component for QualityStation {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "quality"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    /// The station is inspected by the cycle, it has no events of its own
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        future::pending().await
+    }
+
+    /// The gate is returned to let the materials through
+    fn make_safe(&mut self) -> Result<Option<ComponentEvent>> {
+        self.divert.set_value(0)?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+    use gpio_cdev::EventType;
+
+    #[test]
+    fn rejects_are_diverted_and_counted() {
+        let mut gpio = MockGpio::default();
+        let mut station = QualityStation::new(&Settings::default(), "Quality", &mut gpio, 0, 1, 4)
+            .unwrap()
+            .with_threshold(0.5);
+
+        gpio.edge(0, EventType::RisingEdge);
+        station.inspect().unwrap();
+        station.inspect().unwrap();
+        gpio.edge(0, EventType::FallingEdge);
+        station.inspect().unwrap();
+        assert_eq!(gpio.history(1), [0, 0, 0, 1]);
+
+        // the window isn't full yet, then the rate is at the threshold
+        assert_eq!(station.inspect().unwrap().len(), 1);
+        assert_eq!(station.reject_rate(), 0.5);
+        let events = station.inspect().unwrap();
+        assert!(matches!(
+            events[1],
+            Event::RejectRateHigh { rate, .. } if rate == 0.75
+        ));
+        // latched until the rate is back under the threshold
+        assert_eq!(station.inspect().unwrap().len(), 1);
+
+        let state = station.serialize_state();
+        assert_eq!(state["inspected"], 6);
+        assert_eq!(state["rejected"], 4);
+    }
+}
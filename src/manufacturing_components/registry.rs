@@ -0,0 +1,1048 @@
+use crate::config::{Config, ConfigError, Settings};
+use crate::envelope::EventSender;
+use crate::manufacturing_components::ambient::{self, AmbientSensor};
+use crate::manufacturing_components::analog::{AnalogInput, Mcp3008};
+use crate::manufacturing_components::camera::{Camera, Trigger};
+use crate::manufacturing_components::can::CanDevice;
+use crate::manufacturing_components::conveyor::Conveyor;
+use crate::manufacturing_components::counters::CounterStore;
+use crate::manufacturing_components::estop::{self, Latch, Latched};
+use crate::manufacturing_components::feeder::{self, Feeder, FeederPolicy};
+use crate::manufacturing_components::gpio::GpioProvider;
+use crate::manufacturing_components::interlock::Interlock;
+use crate::manufacturing_components::limit::{LimitSwitch, Precondition};
+use crate::manufacturing_components::modbus::ModbusDevice;
+use crate::manufacturing_components::piston::Piston;
+use crate::manufacturing_components::quality::{self, QualityStation};
+use crate::manufacturing_components::robot::{Robot, RobotPosition};
+use crate::manufacturing_components::scanner::BarcodeScanner;
+use crate::manufacturing_components::spi::{LinuxSpi, SpiConfig};
+use crate::manufacturing_components::stepper::{self, Profile, StepperMotor};
+use crate::manufacturing_components::vibration::{self, Adxl345, Thresholds, VibrationSensor};
+use crate::manufacturing_components::{Component, ComponentEvent};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures::future::{self, FutureExt};
+use serde_json::{json, Map, Value};
+use std::iter;
+use std::time::Duration;
+use tracing::error;
+
+/// Settings applied to the running components by apply_settings, by variable
+pub const RUNTIME_SETTINGS: [&str; 12] = [
+    "SENSOR_TIMEOUT",
+    "CONVEYOR_SPEED",
+    "FEEDER_LOW_SUPPLY",
+    "FEEDER_POLICY",
+    "PISTON_PRESSURE_LIMIT",
+    "VIBRATION_RMS_THRESHOLD",
+    "VIBRATION_PEAK_THRESHOLD",
+    "QUALITY_REJECT_RATE",
+    "AMBIENT_INTERVAL",
+    "ROBOT_POSITION_1_REQUIRES",
+    "ROBOT_POSITION_15_REQUIRES",
+    "ROBOT_POSITION_66_REQUIRES",
+];
+
+/// The parts of the cell re-created on their own when the settings they're built from change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Feeders,
+    Robots,
+    Interlock,
+    Piston,
+    Conveyor,
+    Ambient,
+    Analog,
+    Stepper,
+    Vibration,
+    Modbus,
+    Can,
+    LimitSwitches,
+    Scanner,
+    Quality,
+    Counters,
+}
+
+/// Prefixes of the settings each part of the cell is built from, besides those of its named
+/// components
+const PARTS: [(&str, Part); 18] = [
+    ("MATERIAL_", Part::Feeders),
+    ("FEEDER_", Part::Feeders),
+    ("ROBOT", Part::Robots),
+    ("INTERLOCK_", Part::Interlock),
+    ("PISTON_", Part::Piston),
+    ("CONVEYOR_", Part::Conveyor),
+    ("AMBIENT_", Part::Ambient),
+    ("ANALOG_", Part::Analog),
+    // the air pressure of the piston is read from the ADC as well
+    ("ADC_", Part::Analog),
+    ("ADC_", Part::Piston),
+    ("STEPPER_", Part::Stepper),
+    ("VIBRATION_", Part::Vibration),
+    ("MODBUS_", Part::Modbus),
+    ("CAN_", Part::Can),
+    ("LIMIT_SWITCHES", Part::LimitSwitches),
+    ("SCANNER_", Part::Scanner),
+    ("QUALITY_", Part::Quality),
+    ("COUNTERS_", Part::Counters),
+];
+
+/// The parts of the cell built from the setting, by variable. Those of its named components, e.g.
+/// the further robots, are known by the prefix of their name in settings
+fn parts(settings: &Settings, variable: &str) -> Vec<Part> {
+    let named = [
+        ("ROBOTS", Part::Robots),
+        ("ANALOG_INPUTS", Part::Analog),
+        ("MODBUS_DEVICES", Part::Modbus),
+        ("CAN_DEVICES", Part::Can),
+        ("LIMIT_SWITCHES", Part::LimitSwitches),
+    ]
+    .into_iter()
+    .filter_map(|(key, part)| Some((settings.get(key)?, part)))
+    .flat_map(|(names, part)| {
+        names
+            .split(',')
+            .map(|name| (format!("{}_", name.trim().to_uppercase()), part))
+            .collect::<Vec<_>>()
+    });
+    PARTS
+        .iter()
+        .map(|(prefix, part)| (prefix.to_string(), *part))
+        .chain(named)
+        .filter(|(prefix, _)| variable.starts_with(prefix.as_str()))
+        .map(|(_, part)| part)
+        .collect()
+}
+
+/// The I2C address of a sensor, in hexadecimal when prefixed by 0x
+fn i2c_address(settings: &Settings, variable: &str) -> Result<Option<u8>, ConfigError> {
+    settings
+        .get(variable)
+        .map(|address| {
+            match address.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => address.parse(),
+            }
+            .map_err(|_| ConfigError::invalid(variable, "unsigned integer", address))
+        })
+        .transpose()
+}
+
+/// The limit switches a move of the arm to each position requires engaged, listed in
+/// ROBOT_POSITION_*_REQUIRES
+fn preconditions(settings: &Settings) -> Vec<(RobotPosition, Precondition)> {
+    [
+        (RobotPosition::Position1, "ROBOT_POSITION_1_REQUIRES"),
+        (RobotPosition::Position15, "ROBOT_POSITION_15_REQUIRES"),
+        (RobotPosition::Position66, "ROBOT_POSITION_66_REQUIRES"),
+    ]
+    .into_iter()
+    .filter_map(|(position, key)| {
+        let switches = settings.get(key)?;
+        Some((position, Precondition::parse(switches)))
+    })
+    .collect()
+}
+
+/// The material feeder, then feeder B when the cell has one, both starting full
+fn new_feeders(config: &Config, gpio: &mut dyn GpioProvider) -> Result<Vec<Feeder>> {
+    let settings = &config.settings;
+    let mut feeders = vec![Feeder::new(
+        settings,
+        "Material feeder",
+        10,
+        gpio,
+        config.lines.material,
+    )?];
+    // a second feeder, picked from at position 66, is optional
+    if let Some(line) = config.lines.feeder_b {
+        feeders.push(Feeder::new(settings, "Feeder B", 10, gpio, line)?);
+    }
+
+    // operators are alerted to refill a feeder before the line stops
+    if let Some(threshold) = settings.parse("FEEDER_LOW_SUPPLY", "unsigned integer")? {
+        feeders = feeders
+            .into_iter()
+            .map(|feeder| feeder.with_low_supply_threshold(threshold))
+            .collect();
+    }
+
+    // magazines loaded by an operator are counted on the refill line of a feeder, when it has one
+    let magazine_size: u32 = settings
+        .parse("FEEDER_MAGAZINE_SIZE", "unsigned integer")?
+        .unwrap_or(10);
+    feeders
+        .into_iter()
+        .zip(["MATERIAL_REFILL_LINE", "FEEDER_B_REFILL_LINE"])
+        .map(
+            |(feeder, key)| match settings.parse(key, "unsigned integer")? {
+                Some(line) => feeder.with_refill_line(settings, gpio, line, magazine_size),
+                None => Ok(feeder),
+            },
+        )
+        .collect()
+}
+
+/// The robot, then the further robots of an extended cell named in ROBOTS, sharing positions with
+/// the first
+fn new_robots(settings: &Settings, gpio: &mut dyn GpioProvider) -> Result<(Robot, Vec<Robot>)> {
+    let robot = Robot::from_settings(settings, "Robot", gpio)?;
+    let robots = match settings.get("ROBOTS") {
+        Some(names) => names
+            .split(',')
+            .map(|name| Robot::from_settings(settings, name.trim(), gpio))
+            .collect::<Result<_>>()?,
+        None => vec![],
+    };
+    Ok((robot, robots))
+}
+
+/// The ambient conditions are sampled when the cell has a sensor on an I2C bus
+fn new_ambient(settings: &Settings) -> Result<Option<AmbientSensor>> {
+    let Some(bus) = settings.get("AMBIENT_I2C_BUS") else {
+        return Ok(None);
+    };
+    let address = i2c_address(settings, "AMBIENT_I2C_ADDRESS")?.unwrap_or(ambient::DEFAULT_ADDRESS);
+    let interval = settings
+        .parse("AMBIENT_INTERVAL", "milliseconds")?
+        .map(Duration::from_millis)
+        .unwrap_or(ambient::DEFAULT_INTERVAL);
+    Ok(Some(AmbientSensor::new("Ambient", bus, address, interval)?))
+}
+
+/// The analog inputs named in ANALOG_INPUTS are channels of an MCP3008 on ADC_SPI_BUS
+fn new_analog(settings: &Settings) -> Result<Vec<AnalogInput>> {
+    let Some(names) = settings.get("ANALOG_INPUTS") else {
+        return Ok(vec![]);
+    };
+    let adc = SpiConfig::from_settings(settings, "adc")?
+        .ok_or_else(|| ConfigError::missing("ADC_SPI_BUS", "ANALOG_INPUTS are read from it"))?;
+    names
+        .split(',')
+        .map(|name| {
+            let adc = Mcp3008::new(Box::new(LinuxSpi::open(&adc)?));
+            Ok(AnalogInput::from_settings(
+                settings,
+                name.trim(),
+                Box::new(adc),
+            )?)
+        })
+        .collect()
+}
+
+/// The piston, its air pressure watched when it has a sensor on a channel of the ADC
+fn new_piston(config: &Config, gpio: &mut dyn GpioProvider) -> Result<Piston> {
+    let settings = &config.settings;
+    let lines = &config.lines;
+    let piston = Piston::new(settings, "Piston", gpio, lines.piston, lines.piston_output)?;
+    if settings.get("PISTON_PRESSURE_CHANNEL").is_none() {
+        return Ok(piston);
+    }
+
+    let adc = SpiConfig::from_settings(settings, "adc")?.ok_or_else(|| {
+        ConfigError::missing("ADC_SPI_BUS", "PISTON_PRESSURE_CHANNEL is read from it")
+    })?;
+    let adc = Mcp3008::new(Box::new(LinuxSpi::open(&adc)?));
+    let limit = settings
+        .parse("PISTON_PRESSURE_LIMIT", "a number")?
+        .ok_or_else(|| {
+            ConfigError::missing(
+                "PISTON_PRESSURE_LIMIT",
+                "the pressure is watched against it",
+            )
+        })?;
+    let input = AnalogInput::from_settings(settings, "piston_pressure", Box::new(adc))?;
+    Ok(piston.with_pressure(input, limit))
+}
+
+fn new_conveyor(config: &Config, gpio: &mut dyn GpioProvider) -> Result<Conveyor> {
+    Conveyor::new(
+        &config.settings,
+        "Conveyor",
+        config.cycle.conveyor_speed,
+        gpio,
+        config.lines.conveyor,
+        config.lines.conveyor_output,
+    )
+}
+
+/// The stepper motor is driven when the cell has its step and direction lines
+fn new_stepper(settings: &Settings, gpio: &mut dyn GpioProvider) -> Result<Option<StepperMotor>> {
+    let Some(step_line) = settings.parse::<u32>("STEPPER_STEP_LINE", "unsigned integer")? else {
+        return Ok(None);
+    };
+    let speed = |key: &str, default: f64| -> Result<f64, ConfigError> {
+        Ok(settings.parse(key, "a number")?.unwrap_or(default))
+    };
+    let direction_line = settings
+        .parse("STEPPER_DIRECTION_LINE", "unsigned integer")?
+        .ok_or_else(|| {
+            ConfigError::missing("STEPPER_DIRECTION_LINE", "the stepper is driven through it")
+        })?;
+
+    let mut motor = StepperMotor::new("Stepper", gpio, step_line, direction_line)?
+        .with_profile(Profile {
+            max_speed: speed("STEPPER_MAX_SPEED", stepper::DEFAULT_MAX_SPEED)?,
+            acceleration: speed("STEPPER_ACCELERATION", stepper::DEFAULT_ACCELERATION)?,
+        })
+        .with_homing(
+            speed("STEPPER_HOMING_SPEED", stepper::DEFAULT_HOMING_SPEED)?,
+            stepper::DEFAULT_HOMING_RANGE,
+        );
+    if let Some(limit_line) = settings.parse("STEPPER_LIMIT_LINE", "unsigned integer")? {
+        motor = motor.with_limit_line(settings, gpio, limit_line)?;
+    }
+    Ok(Some(motor))
+}
+
+/// The robot track is watched for wear when the cell has an accelerometer on an I2C bus
+fn new_vibration(settings: &Settings) -> Result<Option<VibrationSensor>> {
+    let Some(bus) = settings.get("VIBRATION_I2C_BUS") else {
+        return Ok(None);
+    };
+    let address =
+        i2c_address(settings, "VIBRATION_I2C_ADDRESS")?.unwrap_or(vibration::DEFAULT_ADDRESS);
+    let count = |key: &str, default: usize| -> Result<usize, ConfigError> {
+        Ok(settings.parse(key, "unsigned integer")?.unwrap_or(default))
+    };
+    let rate = settings
+        .positive("VIBRATION_RATE", "samples per second")?
+        .unwrap_or(vibration::DEFAULT_RATE);
+    let window = count("VIBRATION_WINDOW", vibration::DEFAULT_WINDOW)?;
+    let report_every = count("VIBRATION_REPORT_EVERY", window)?;
+    let thresholds = Thresholds {
+        rms: settings.parse("VIBRATION_RMS_THRESHOLD", "g")?,
+        peak: settings.parse("VIBRATION_PEAK_THRESHOLD", "g")?,
+    };
+
+    let accelerometer = Adxl345::new(bus, address)?;
+    let sensor = VibrationSensor::new("Track", Box::new(accelerometer), rate, window, report_every)
+        .with_thresholds(thresholds);
+    Ok(Some(sensor))
+}
+
+/// The devices named in MODBUS_DEVICES, those on the same port share its bus
+fn new_modbus(settings: &Settings) -> Result<Vec<ModbusDevice>> {
+    let Some(names) = settings.get("MODBUS_DEVICES") else {
+        return Ok(vec![]);
+    };
+    let mut buses = vec![];
+    names
+        .split(',')
+        .map(|name| ModbusDevice::from_settings(settings, name.trim(), &mut buses))
+        .collect()
+}
+
+/// The devices named in CAN_DEVICES
+fn new_can(settings: &Settings) -> Result<Vec<CanDevice>> {
+    match settings.get("CAN_DEVICES") {
+        Some(names) => names
+            .split(',')
+            .map(|name| CanDevice::from_settings(settings, name.trim()))
+            .collect(),
+        None => Ok(vec![]),
+    }
+}
+
+/// The limit switches named in LIMIT_SWITCHES, checked before the moves requiring them
+fn new_limit_switches(
+    settings: &Settings,
+    gpio: &mut dyn GpioProvider,
+) -> Result<Vec<LimitSwitch>> {
+    match settings.get("LIMIT_SWITCHES") {
+        Some(names) => names
+            .split(',')
+            .map(|name| LimitSwitch::from_settings(settings, name.trim(), gpio))
+            .collect(),
+        None => Ok(vec![]),
+    }
+}
+
+/// The materials left in each feeder and the position of each robot, by name, kept when the
+/// components are re-created, see ComponentRegistry::rebuild
+pub struct Carryover {
+    counts: Vec<(String, u32)>,
+    positions: Vec<(String, RobotPosition)>,
+}
+
+/// An event a cycle waits for, from the feeders or from the other components
+pub enum CycleEvent {
+    Feeder(Result<feeder::Event, feeder::Error>),
+    Sensor(Result<ComponentEvent>),
+}
+
+/// Every component of the manufacturing cell the twin follows, constructed once from the lines
+/// configured in the settings. Components are looked up by name for the commands naming them,
+/// and their states are put together into the twin state
+pub struct ComponentRegistry {
+    /// the material feeder, picked from at position 1, then feeder B when there is one
+    pub feeders: Vec<Feeder>,
+    pub feeder_policy: FeederPolicy,
+    pub robot: Robot,
+    /// the further robots of an extended cell, sharing positions with the first, e.g. the piston
+    pub robots: Vec<Robot>,
+    /// arbitrates the positions the robots share, see Interlock
+    pub interlock: Interlock,
+    pub piston: Piston,
+    pub conveyor: Conveyor,
+    /// temperature and humidity at the cell, when it has a sensor
+    pub ambient: Option<AmbientSensor>,
+    /// analog signals sampled from the ADC, e.g. the air pressure of the piston
+    pub analog: Vec<AnalogInput>,
+    /// moved by the cloud, when the cell has one
+    pub stepper: Option<StepperMotor>,
+    pub limit_switches: Vec<LimitSwitch>,
+    /// vibration of the robot track, when the cell has an accelerometer on it
+    pub vibration: Option<VibrationSensor>,
+    /// reads the barcodes of the materials at the feeder, when the cell has a scanner
+    pub scanner: Option<BarcodeScanner>,
+    /// takes snapshots after the steps of a cycle, when the cell has one, see Camera
+    pub camera: Option<Camera>,
+    /// devices driven over Modbus rather than through lines, e.g. a valve terminal on RS-485 or the
+    /// pneumatic controller of the piston on the network
+    pub modbus: Vec<ModbusDevice>,
+    /// devices reporting over CAN, e.g. the newer robot track
+    pub can: Vec<CanDevice>,
+    /// inspects the materials after they're pressed and diverts the rejects, when the cell has a
+    /// quality-check station
+    pub quality: Option<QualityStation>,
+    /// materials produced and rejected since the cell was commissioned, kept across restarts
+    pub counters: CounterStore,
+    /// latched by the e-stop button, when the cell has one, see EmergencyStopButton
+    pub estop: Option<Latch>,
+    /// the limit switches a move of the arm to a position requires engaged
+    preconditions: Vec<(RobotPosition, Precondition)>,
+    /// time a feeder is waited on before the cycle is stalled
+    pub sensor_timeout: Duration,
+}
+
+impl ComponentRegistry {
+    /// The components of the cell on the lines of config, the optional ones configured from its
+    /// settings
+    pub fn from_config(config: &Config, gpio: &mut dyn GpioProvider) -> Result<Self> {
+        let settings = &config.settings;
+        let (robot, robots) = new_robots(settings, gpio)?;
+
+        Ok(Self {
+            feeders: new_feeders(config, gpio)?,
+            feeder_policy: FeederPolicy::from_settings(settings)?,
+            robot,
+            robots,
+            interlock: Interlock::from_settings(settings)?,
+            piston: new_piston(config, gpio)?,
+            conveyor: new_conveyor(config, gpio)?,
+            ambient: new_ambient(settings)?,
+            analog: new_analog(settings)?,
+            stepper: new_stepper(settings, gpio)?,
+            limit_switches: new_limit_switches(settings, gpio)?,
+            vibration: new_vibration(settings)?,
+            scanner: BarcodeScanner::from_settings(settings)?,
+            preconditions: preconditions(settings),
+            estop: None,
+            camera: None,
+            modbus: new_modbus(settings)?,
+            can: new_can(settings)?,
+            quality: QualityStation::from_settings(settings, gpio)?,
+            counters: CounterStore::from_settings(settings)?,
+            sensor_timeout: config.cycle.sensor_timeout,
+        })
+    }
+
+    /// Re-create the parts of the cell built from the changed settings, by variable, on their new
+    /// lines in config, keeping its other components as they are. The materials left in the
+    /// feeders and the positions of the robots are carried over
+    pub fn rebuild(
+        self,
+        config: &Config,
+        gpio: &mut dyn GpioProvider,
+        changes: &[String],
+    ) -> Result<Self> {
+        let settings = &config.settings;
+        let carryover = self.carryover();
+        let parts: Vec<Part> = changes
+            .iter()
+            .flat_map(|variable| parts(settings, variable))
+            .collect();
+        let kept = |part| !parts.contains(&part);
+
+        let Self {
+            feeders,
+            feeder_policy,
+            robot,
+            robots,
+            interlock,
+            piston,
+            conveyor,
+            ambient,
+            analog,
+            stepper,
+            limit_switches,
+            vibration,
+            scanner,
+            camera,
+            modbus,
+            can,
+            quality,
+            counters,
+            estop,
+            preconditions,
+            sensor_timeout,
+        } = self;
+        // the components re-created release their lines before any is requested again, since
+        // lines may be swapped between them
+        let feeders = kept(Part::Feeders).then_some(feeders);
+        let robots = kept(Part::Robots).then_some((robot, robots));
+        let interlock = kept(Part::Interlock).then_some(interlock);
+        let piston = kept(Part::Piston).then_some(piston);
+        let conveyor = kept(Part::Conveyor).then_some(conveyor);
+        let ambient = kept(Part::Ambient).then_some(ambient);
+        let analog = kept(Part::Analog).then_some(analog);
+        let stepper = kept(Part::Stepper).then_some(stepper);
+        let vibration = kept(Part::Vibration).then_some(vibration);
+        let modbus = kept(Part::Modbus).then_some(modbus);
+        let can = kept(Part::Can).then_some(can);
+        let limit_switches = kept(Part::LimitSwitches).then_some(limit_switches);
+        let scanner = kept(Part::Scanner).then_some(scanner);
+        let quality = kept(Part::Quality).then_some(quality);
+        let counters = kept(Part::Counters).then_some(counters);
+
+        let (robot, robots) = robots.map_or_else(|| new_robots(settings, gpio), Ok)?;
+        let mut cell = Self {
+            feeders: feeders.map_or_else(|| new_feeders(config, gpio), Ok)?,
+            feeder_policy,
+            robot,
+            robots,
+            interlock: interlock.map_or_else(|| Interlock::from_settings(settings), Ok)?,
+            piston: piston.map_or_else(|| new_piston(config, gpio), Ok)?,
+            conveyor: conveyor.map_or_else(|| new_conveyor(config, gpio), Ok)?,
+            ambient: ambient.map_or_else(|| new_ambient(settings), Ok)?,
+            analog: analog.map_or_else(|| new_analog(settings), Ok)?,
+            stepper: stepper.map_or_else(|| new_stepper(settings, gpio), Ok)?,
+            limit_switches: limit_switches
+                .map_or_else(|| new_limit_switches(settings, gpio), Ok)?,
+            vibration: vibration.map_or_else(|| new_vibration(settings), Ok)?,
+            scanner: scanner.map_or_else(|| BarcodeScanner::from_settings(settings), Ok)?,
+            preconditions,
+            estop,
+            camera,
+            modbus: modbus.map_or_else(|| new_modbus(settings), Ok)?,
+            can: can.map_or_else(|| new_can(settings), Ok)?,
+            quality: quality.map_or_else(|| QualityStation::from_settings(settings, gpio), Ok)?,
+            counters: counters.map_or_else(|| CounterStore::from_settings(settings), Ok)?,
+            sensor_timeout,
+        };
+        cell.restore(&carryover);
+        Ok(cell)
+    }
+
+    /// What the components of the cell keep when they're re-created
+    pub fn carryover(&self) -> Carryover {
+        Carryover {
+            counts: self
+                .feeders
+                .iter()
+                .map(|feeder| (feeder.name().to_string(), feeder.count()))
+                .collect(),
+            positions: iter::once(&self.robot)
+                .chain(&self.robots)
+                .map(|robot| (robot.name().to_string(), robot.position()))
+                .collect(),
+        }
+    }
+
+    /// Carry over the counts of the feeders and the positions of the robots to those of the same
+    /// name, e.g. once they're re-created on other lines
+    pub fn restore(&mut self, carryover: &Carryover) {
+        for feeder in &mut self.feeders {
+            if let Some((_, count)) = carryover
+                .counts
+                .iter()
+                .find(|(name, _)| name == feeder.name())
+            {
+                feeder.set_count(*count);
+            }
+        }
+        for robot in iter::once(&mut self.robot).chain(&mut self.robots) {
+            if let Some((_, position)) = carryover
+                .positions
+                .iter()
+                .find(|(name, _)| name == robot.name())
+            {
+                robot.set_position(*position);
+            }
+        }
+    }
+
+    /// Whether the cell is built from the setting, by variable, see rebuild
+    pub fn depends_on(settings: &Settings, variable: &str) -> bool {
+        !parts(settings, variable).is_empty()
+    }
+
+    /// Apply the changed settings, by variable, which don't need the components re-created, to
+    /// their new values in config. The events of the components whose settings changed are sent
+    /// on tx
+    pub fn apply_settings(
+        &mut self,
+        config: &Config,
+        changes: &[String],
+        tx: &EventSender,
+    ) -> Result<()> {
+        let settings = &config.settings;
+        for variable in changes {
+            match variable.as_str() {
+                "SENSOR_TIMEOUT" => self.sensor_timeout = config.cycle.sensor_timeout,
+                "CONVEYOR_SPEED" => {
+                    let event = self.conveyor.set_speed(config.cycle.conveyor_speed)?;
+                    tx.send(event);
+                }
+                "FEEDER_LOW_SUPPLY" => {
+                    let threshold = settings.parse("FEEDER_LOW_SUPPLY", "unsigned integer")?;
+                    for feeder in &mut self.feeders {
+                        feeder.set_low_supply_threshold(threshold);
+                    }
+                }
+                "FEEDER_POLICY" => self.feeder_policy = FeederPolicy::from_settings(settings)?,
+                "PISTON_PRESSURE_LIMIT" => {
+                    if let Some(limit) = settings.parse("PISTON_PRESSURE_LIMIT", "a number")? {
+                        self.piston.set_pressure_limit(limit);
+                    }
+                }
+                "VIBRATION_RMS_THRESHOLD" | "VIBRATION_PEAK_THRESHOLD" => {
+                    if let Some(sensor) = &mut self.vibration {
+                        sensor.set_thresholds(Thresholds {
+                            rms: settings.parse("VIBRATION_RMS_THRESHOLD", "g")?,
+                            peak: settings.parse("VIBRATION_PEAK_THRESHOLD", "g")?,
+                        });
+                    }
+                }
+                "QUALITY_REJECT_RATE" => {
+                    if let Some(quality) = &mut self.quality {
+                        let rate =
+                            settings.parse("QUALITY_REJECT_RATE", "a rate between 0 and 1")?;
+                        quality.set_threshold(rate);
+                    }
+                }
+                "AMBIENT_INTERVAL" => {
+                    if let Some(sensor) = &mut self.ambient {
+                        let interval = settings
+                            .parse("AMBIENT_INTERVAL", "milliseconds")?
+                            .map(Duration::from_millis)
+                            .unwrap_or(ambient::DEFAULT_INTERVAL);
+                        sensor.set_interval(interval);
+                    }
+                }
+                "ROBOT_POSITION_1_REQUIRES"
+                | "ROBOT_POSITION_15_REQUIRES"
+                | "ROBOT_POSITION_66_REQUIRES" => self.preconditions = preconditions(settings),
+                other => {
+                    return Err(eyre!(
+                        "{other} can't be changed without re-creating the cell"
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The feeder with the given name
+    pub fn feeder(&mut self, name: &str) -> Result<&mut Feeder> {
+        self.feeders
+            .iter_mut()
+            .find(|feeder| feeder.name() == name)
+            .ok_or_else(|| eyre!("Unknown feeder {name}"))
+    }
+
+    /// The robot at index among every robot of the cell, the first one being robot
+    pub fn robot_at(&mut self, index: usize) -> &mut Robot {
+        match index {
+            0 => &mut self.robot,
+            index => &mut self.robots[index - 1],
+        }
+    }
+
+    /// The stepper motor of the cell
+    pub fn stepper(&mut self) -> Result<&mut StepperMotor> {
+        self.stepper
+            .as_mut()
+            .ok_or_else(|| eyre!("The cell has no stepper motor"))
+    }
+
+    /// Fails with Latched while the e-stop is latched, nothing may drive the cell then
+    pub fn check_estop(&self) -> Result<(), Latched> {
+        match &self.estop {
+            Some(latch) => latch.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Clear the latched e-stop once its button is released
+    pub fn clear_estop(&self) -> Result<estop::Event> {
+        self.estop
+            .as_ref()
+            .ok_or_else(|| eyre!("The cell has no e-stop button"))?
+            .clear()
+    }
+
+    /// Fails unless the limit switches the move of the arm to position requires are engaged, e.g.
+    /// the piston at its top before the arm moves over it
+    pub fn check_move(&self, position: RobotPosition) -> Result<()> {
+        match self.preconditions.iter().find(|(to, _)| *to == position) {
+            Some((_, precondition)) => precondition.check(
+                &self.limit_switches,
+                &format!("{} moves to {position:?}", self.robot.name()),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Where the arm picks up the materials of the feeder at index, position 1 for the material
+    /// feeder and position 66 for feeder B
+    pub fn pickup_position(index: usize) -> RobotPosition {
+        match index {
+            0 => RobotPosition::Position1,
+            _ => RobotPosition::Position66,
+        }
+    }
+
+    /// Index of the feeder the policy picks the material of the given turn of a cycle from. Fails
+    /// when the sensor of that feeder reports it empty although its count says otherwise
+    pub fn select_feeder(&self, turn: usize) -> Result<usize, feeder::Error> {
+        let counts: Vec<u32> = self.feeders.iter().map(Feeder::count).collect();
+        let index = self
+            .feeder_policy
+            .select(&counts, turn)
+            .ok_or(feeder::Error::NoMoreSupply)?;
+
+        let feeder = &self.feeders[index];
+        if feeder.is_empty().map_err(feeder::Error::Gpio)? {
+            return Err(feeder::Error::CountMismatch {
+                feeder: feeder.name().to_string(),
+                count: feeder.count(),
+            });
+        }
+        Ok(index)
+    }
+
+    /// Snapshot of the state of every component, reported as the device state, with the inventory
+    /// of the feeders together
+    pub fn state(&self) -> Value {
+        let mut state = Map::new();
+        // the second feeder is set apart from the first
+        for (feeder, key) in self.feeders.iter().zip(["feeder", "feeder_b"]) {
+            state.insert(key.to_string(), feeder.serialize_state());
+        }
+        for component in self.sensors() {
+            state.insert(
+                component.component().to_string(),
+                component.serialize_state(),
+            );
+        }
+        // the analog inputs are set apart by name
+        if !self.analog.is_empty() {
+            let analog: Map<String, Value> = self
+                .analog
+                .iter()
+                .map(|input| (input.name().to_string(), input.serialize_state()))
+                .collect();
+            state.insert("analog".to_string(), Value::Object(analog));
+        }
+        // and so are the Modbus devices
+        if !self.modbus.is_empty() {
+            let devices: Map<String, Value> = self
+                .modbus
+                .iter()
+                .map(|device| (device.name().to_string(), device.serialize_state()))
+                .collect();
+            state.insert("modbus".to_string(), Value::Object(devices));
+        }
+        // and the CAN devices
+        if !self.can.is_empty() {
+            let devices: Map<String, Value> = self
+                .can
+                .iter()
+                .map(|device| (device.name().to_string(), device.serialize_state()))
+                .collect();
+            state.insert("can".to_string(), Value::Object(devices));
+        }
+        // and the further robots, with the positions they share
+        if !self.robots.is_empty() {
+            let robots: Map<String, Value> = self
+                .robots
+                .iter()
+                .map(|robot| (robot.name().to_string(), robot.serialize_state()))
+                .collect();
+            state.insert("robots".to_string(), Value::Object(robots));
+            state.insert("interlock".to_string(), self.interlock.state());
+        }
+        if let Some(latch) = &self.estop {
+            state.insert("estop".to_string(), latch.state());
+        }
+        // the limit switches are set apart by name too
+        if !self.limit_switches.is_empty() {
+            let switches: Map<String, Value> = self
+                .limit_switches
+                .iter()
+                .map(|switch| (switch.name().to_string(), switch.serialize_state()))
+                .collect();
+            state.insert("limit".to_string(), Value::Object(switches));
+        }
+
+        state.insert("counters".to_string(), json!(self.counters.counters()));
+
+        let counts: Map<String, Value> = self
+            .feeders
+            .iter()
+            .map(|feeder| (feeder.name().to_string(), json!(feeder.count())))
+            .collect();
+        let total: u32 = self.feeders.iter().map(Feeder::count).sum();
+        state.insert(
+            "inventory".to_string(),
+            json!({ "total": total, "feeders": counts }),
+        );
+        Value::Object(state)
+    }
+
+    /// Every component besides the feeders, whose pickups are counted by the cycle itself
+    fn sensors(&self) -> Vec<&dyn Component> {
+        let mut sensors: Vec<&dyn Component> = vec![&self.robot, &self.piston, &self.conveyor];
+        if let Some(ambient) = &self.ambient {
+            sensors.push(ambient);
+        }
+        if let Some(stepper) = &self.stepper {
+            sensors.push(stepper);
+        }
+        if let Some(vibration) = &self.vibration {
+            sensors.push(vibration);
+        }
+        if let Some(scanner) = &self.scanner {
+            sensors.push(scanner);
+        }
+        if let Some(quality) = &self.quality {
+            sensors.push(quality);
+        }
+        sensors
+    }
+
+    /// Wait for the next event of the components reporting whether a cycle runs or not: a magazine
+    /// loaded into a feeder, a limit switch engaged or released, a barcode scanned, or a sample of
+    /// the ambient conditions, an analog input, the vibration of the track, a Modbus device or a CAN
+    /// device. None when the cell has none of them
+    pub async fn next_idle_event(&mut self) -> Option<Result<ComponentEvent>> {
+        let mut events: Vec<_> = self
+            .feeders
+            .iter_mut()
+            .filter(|feeder| feeder.has_refill_line())
+            .map(|feeder| async move { Ok(feeder.async_next_refill().await.into()) }.boxed())
+            .collect();
+        for switch in &mut self.limit_switches {
+            events.push(async move { switch.async_next_event().await.map(Into::into) }.boxed());
+        }
+        if let Some(scanner) = &mut self.scanner {
+            events.push(async move { scanner.async_next_event().await.map(Into::into) }.boxed());
+        }
+        for input in &mut self.analog {
+            events.push(async move { Ok(input.async_next_event().await.into()) }.boxed());
+        }
+        if let Some(ambient) = &mut self.ambient {
+            events.push(async move { Ok(ambient.async_next_event().await.into()) }.boxed());
+        }
+        if let Some(vibration) = &mut self.vibration {
+            events.push(async move { Ok(vibration.async_next_event().await.into()) }.boxed());
+        }
+        for device in &mut self.modbus {
+            events.push(async move { Ok(device.async_next_event().await.into()) }.boxed());
+        }
+        for device in &mut self.can {
+            events.push(async move { Ok(device.async_next_event().await.into()) }.boxed());
+        }
+        if events.is_empty() {
+            return None;
+        }
+
+        let (event, _, _) = future::select_all(events).await;
+        Some(event)
+    }
+
+    /// The Modbus device with the given name
+    pub fn modbus(&mut self, name: &str) -> Result<&mut ModbusDevice> {
+        self.modbus
+            .iter_mut()
+            .find(|device| device.name() == name)
+            .ok_or_else(|| eyre!("Unknown Modbus device {name}"))
+    }
+
+    /// The CAN device with the given name
+    pub fn can(&mut self, name: &str) -> Result<&mut CanDevice> {
+        self.can
+            .iter_mut()
+            .find(|device| device.name() == name)
+            .ok_or_else(|| eyre!("Unknown CAN device {name}"))
+    }
+
+    /// Take a snapshot after the step of the cycle with the id, when the cell has a camera
+    /// triggered by it
+    pub fn snapshot(&self, trigger: Trigger, cycle_id: &str) {
+        if let Some(camera) = &self.camera {
+            camera.snapshot(trigger, cycle_id);
+        }
+    }
+
+    /// A material went through a whole cycle. Failing to persist the count doesn't stop the cycle
+    pub fn complete_material(&mut self) {
+        if let Err(e) = self.counters.record_produced() {
+            error!("Failed to record the material produced: {e}");
+        }
+    }
+
+    /// Inspect the material at the quality-check station, diverting it when it's rejected, and
+    /// publish the inspection. Nothing to do when the cell has no station
+    pub fn inspect(&mut self, tx: &EventSender) -> Result<()> {
+        if let Some(quality) = &mut self.quality {
+            for event in quality.inspect()? {
+                if let quality::Event::Inspected { passed: false, .. } = event {
+                    if let Err(e) = self.counters.record_rejected() {
+                        error!("Failed to record the reject: {e}");
+                    }
+                }
+                tx.send(event);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for the next event of a cycle picking from the feeder at index: a material picked from
+    /// it, a magazine loaded into any feeder, or an event of any other component. Picking fails
+    /// with a timeout when the feeder doesn't signal within the sensor timeout
+    pub async fn next_cycle_event(&mut self, index: usize) -> CycleEvent {
+        let timeout = self.sensor_timeout;
+        let feeders = self.feeders.iter_mut().enumerate().map(|(i, feeder)| {
+            if i == index {
+                feeder.async_next_event_timeout(timeout).boxed()
+            } else {
+                async move { Ok(feeder.async_next_refill().await) }.boxed()
+            }
+        });
+        let mut sensors: Vec<&mut dyn Component> =
+            vec![&mut self.robot, &mut self.piston, &mut self.conveyor];
+        for robot in &mut self.robots {
+            sensors.push(robot);
+        }
+        if let Some(ambient) = &mut self.ambient {
+            sensors.push(ambient);
+        }
+        for input in &mut self.analog {
+            sensors.push(input);
+        }
+        for switch in &mut self.limit_switches {
+            sensors.push(switch);
+        }
+        if let Some(vibration) = &mut self.vibration {
+            sensors.push(vibration);
+        }
+        if let Some(scanner) = &mut self.scanner {
+            sensors.push(scanner);
+        }
+        for device in &mut self.modbus {
+            sensors.push(device);
+        }
+        for device in &mut self.can {
+            sensors.push(device);
+        }
+        let sensors = sensors.into_iter().map(|component| component.next_event());
+
+        let event = tokio::select! {
+            (event, _, _) = future::select_all(feeders) => CycleEvent::Feeder(event),
+            (event, _, _) = future::select_all(sensors) => CycleEvent::Sensor(event),
+        };
+        // the material picked up is the one scanned last
+        match event {
+            CycleEvent::Feeder(Ok(feeder::Event::MaterialPickedUp { feeder, .. })) => {
+                let material = self.scanner.as_mut().and_then(BarcodeScanner::take_code);
+                CycleEvent::Feeder(Ok(feeder::Event::MaterialPickedUp { feeder, material }))
+            }
+            event => event,
+        }
+    }
+
+    /// Leave every component in its safe state, e.g. the piston retracted and the conveyor
+    /// stopped, publishing their events. Every output is driven even when another fails, the first
+    /// error is returned
+    pub fn drive_safe(&mut self, tx: &EventSender) -> Result<()> {
+        let mut components: Vec<&mut dyn Component> =
+            vec![&mut self.robot, &mut self.piston, &mut self.conveyor];
+        for robot in &mut self.robots {
+            components.push(robot);
+        }
+        if let Some(stepper) = &mut self.stepper {
+            components.push(stepper);
+        }
+        if let Some(quality) = &mut self.quality {
+            components.push(quality);
+        }
+
+        let mut result = Ok(());
+        for component in components {
+            match component.make_safe() {
+                Ok(Some(event)) => tx.send(event),
+                Ok(None) => {}
+                Err(e) => result = result.and(Err(e)),
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+    use crate::manufacturing_components::robot::RobotPosition::Position66;
+    use std::path::Path;
+
+    fn config(lines: &str) -> Config {
+        let content = format!(
+            "[device]\nbackend = \"mqtt\"\nbroker_uri = \"tcp://localhost:1883\"\n\
+             [lines]\n{lines}\nrobot = 2\nprogram_control = 7\n"
+        );
+        Config::parse(
+            &content,
+            Path::new("tvilling.toml"),
+            Settings::new::<&str, &str>([]),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rebuilding_recreates_only_the_rewired_components() {
+        let mut gpio = MockGpio::default();
+        let before = config(
+            "material = 1\npiston = 3\npiston_output = 4\nconveyor = 5\nconveyor_output = 6",
+        );
+        let mut cell = ComponentRegistry::from_config(&before, &mut gpio).unwrap();
+        cell.feeders[0].set_count(7);
+        cell.robot.set_position(Position66);
+
+        // the piston and the conveyor swap their lines
+        let after = config(
+            "material = 9\npiston = 5\npiston_output = 6\nconveyor = 3\nconveyor_output = 4",
+        );
+        let changes: Vec<String> = [
+            "MATERIAL_LINE",
+            "PISTON_LINE",
+            "PISTON_OUTPUT_LINE",
+            "CONVEYOR_LINE",
+            "CONVEYOR_OUTPUT_LINE",
+        ]
+        .map(String::from)
+        .into();
+        let cell = cell.rebuild(&after, &mut gpio, &changes).unwrap();
+
+        assert_eq!(cell.feeders[0].count(), 7);
+        assert_eq!(cell.robot.position(), Position66);
+        // the feeder released its previous line, the robot still holds its own
+        assert!(gpio.output(1, "test").is_ok());
+        assert!(gpio.output(2, "test").is_err());
+        assert!(gpio.output(9, "test").is_err());
+    }
+}
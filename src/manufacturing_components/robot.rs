@@ -1,70 +1,344 @@
+use crate::cancellation::{CancellationToken, Cancelled};
+use crate::config::{ConfigError, Settings};
+use crate::envelope::EventSender;
+use crate::manufacturing_components::gpio::{GpioProvider, OutputLine};
+use crate::manufacturing_components::input::{DebouncedLine, Trigger};
 use crate::manufacturing_components::robot::RobotPosition::{Position1, Position15, Position66};
+use crate::manufacturing_components::{
+    input_line, output_line, Component, ComponentEvent, Timeout,
+};
 use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
-use futures::StreamExt;
-use gpio_cdev::{AsyncLineEventHandle, Chip, EventRequestFlags, Line, LineRequestFlags};
+use futures::future::{self, FutureExt};
+use gpio_cdev::EventRequestFlags;
 use serde::ser::SerializeStruct;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{json, Value};
 use std::fmt::Display;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+use tokio::time;
 
-#[derive(Serialize)]
-enum RobotPosition {
+/// Time the arm is given to reach the position it's moved to
+pub const DEFAULT_MOVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+pub enum RobotPosition {
     /// Track position when the arm is picking materials from feeder A, serializes to position1
     #[serde(rename = "position 1")]
+    #[default]
     Position1,
-    /// Track position when the arm is placing materials to the piston, serializes to position15
+    /// Track position when the arm is placing materials to the piston, serializes to position15. The
+    /// pressed materials are dropped off there too, onto the conveyor under the piston
     #[serde(rename = "position 15")]
     Position15,
 
-    /// Track position when the arm is picking materials from feeder B, serializes to position66
+    /// Track position when the arm is picking materials from feeder B, serializes to position66. Only
+    /// a cell with a second feeder has materials there
     #[serde(rename = "position 66")]
     Position66,
 }
 
-impl Default for RobotPosition {
-    fn default() -> Self {
-        Self::Position1
+/// The moves the arm makes along the track, from a position to the next one. It goes around the
+/// track in a loop, any other move means a sensor is faulty
+const TRANSITIONS: [(RobotPosition, RobotPosition); 3] = [
+    (Position1, Position15),
+    (Position15, Position66),
+    (Position66, Position1),
+];
+
+impl RobotPosition {
+    /// The position the arm moves to from this one
+    pub fn next(self) -> Self {
+        TRANSITIONS
+            .iter()
+            .find(|(from, _)| *from == self)
+            .map(|(_, to)| *to)
+            .expect("Every position has a move to the next one")
+    }
+
+    /// Whether the arm can get from this position straight to the other one
+    pub fn can_move_to(self, to: Self) -> bool {
+        TRANSITIONS.contains(&(self, to))
     }
 }
 
+#[derive(Debug, Serialize)]
+pub enum Event {
+    PositionReached {
+        robot: String,
+        position: RobotPosition,
+    },
+    /// a position sensor signalled the arm got somewhere it can't move to from where it was, it's
+    /// left where it was
+    IllegalTransition {
+        robot: String,
+        from: RobotPosition,
+        to: RobotPosition,
+    },
+}
+
 pub struct Robot {
     name: String,
     position: RobotPosition,
-    gpio_line: Line,
-    pub event_handle: AsyncLineEventHandle,
+    pub event_handle: DebouncedLine,
+    /// the sensor at each position which has its own, signalling the arm reached that position
+    sensors: Vec<(RobotPosition, DebouncedLine)>,
+    /// the output moving the arm to each position it can be moved to, held high until it's there
+    drive: Vec<(RobotPosition, Box<dyn OutputLine>)>,
+    move_timeout: Duration,
 }
 
 impl Robot {
-    pub fn new<S>(name: S, chip: &mut Chip, line: u32) -> Result<Self>
+    pub fn new<S>(
+        settings: &Settings,
+        name: S,
+        gpio: &mut dyn GpioProvider,
+        line: u32,
+    ) -> Result<Self>
     where
         S: Into<String> + Display,
     {
-        let line = chip.get_line(line)?;
-        let event_handle = line.async_events(
-            LineRequestFlags::INPUT,
-            EventRequestFlags::RISING_EDGE,
-            &format!("{name} consumer"),
-        )?;
+        let trigger = Trigger::from_settings(settings, "robot", EventRequestFlags::RISING_EDGE)?;
+        let event_handle = input_line(settings, gpio, line, trigger, &name.to_string())?;
 
         Ok(Self {
             name: name.into(),
             position: RobotPosition::default(),
-            gpio_line: line,
             event_handle,
+            sensors: vec![],
+            drive: vec![],
+            move_timeout: DEFAULT_MOVE_TIMEOUT,
         })
     }
 
-    async fn async_next_event(self: &mut Self) -> Result<()> {
-        if let Some(_event) = self.event_handle.next().await {
-            match self.position {
-                RobotPosition::Position1 => self.position = Position15,
-                RobotPosition::Position15 => self.position = Position66,
-                RobotPosition::Position66 => self.position = Position1,
+    /// The robot reading the positions reached from <NAME>_LINE, moved by <NAME>_POSITION_1_LINE and
+    /// the like, caught out of order by <NAME>_POSITION_1_SENSOR and the like, with <NAME>_MOVE_TIMEOUT
+    /// milliseconds to reach a position, where the name is upper cased, e.g. ROBOT_LINE
+    pub fn from_settings(
+        settings: &Settings,
+        name: &str,
+        gpio: &mut dyn GpioProvider,
+    ) -> Result<Self> {
+        let prefix = name.to_uppercase();
+        let line = |key: &str| -> Result<Option<u32>, ConfigError> {
+            settings.parse(&format!("{prefix}_{key}"), "unsigned integer")
+        };
+
+        let robot_line = line("LINE")?.ok_or_else(|| {
+            ConfigError::missing(&format!("{prefix}_LINE"), "the positions are read from it")
+        })?;
+        let mut robot = Robot::new(settings, name, gpio, robot_line)?;
+        // the arm can be moved by the cloud to the positions it has a drive line for
+        for (position, key) in [
+            (Position1, "POSITION_1_LINE"),
+            (Position15, "POSITION_15_LINE"),
+            (Position66, "POSITION_66_LINE"),
+        ] {
+            if let Some(line) = line(key)? {
+                robot = robot.with_drive_line(gpio, position, line)?;
+            }
+        }
+        // moves out of order are caught at the positions with a sensor of their own
+        for (position, key) in [
+            (Position1, "POSITION_1_SENSOR"),
+            (Position15, "POSITION_15_SENSOR"),
+            (Position66, "POSITION_66_SENSOR"),
+        ] {
+            if let Some(line) = line(key)? {
+                robot = robot.with_position_line(settings, gpio, position, line)?;
             }
         }
+        let move_timeout = settings
+            .parse(&format!("{prefix}_MOVE_TIMEOUT"), "milliseconds")?
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_MOVE_TIMEOUT);
+
+        Ok(robot.with_move_timeout(move_timeout))
+    }
+
+    /// Read the arm reaching position from the rising edges on line, so moves out of order are
+    /// detected rather than taken as the move to the next position
+    pub fn with_position_line(
+        mut self,
+        settings: &Settings,
+        gpio: &mut dyn GpioProvider,
+        position: RobotPosition,
+        line: u32,
+    ) -> Result<Self> {
+        let name = format!("{} {position:?}", self.name);
+        let trigger = Trigger::from_settings(settings, "robot", EventRequestFlags::RISING_EDGE)?;
+        let sensor = input_line(settings, gpio, line, trigger, &name)?;
+        self.sensors.push((position, sensor));
+        Ok(self)
+    }
+
+    /// Move the arm to position by driving line high, which starts low
+    pub fn with_drive_line(
+        mut self,
+        gpio: &mut dyn GpioProvider,
+        position: RobotPosition,
+        line: u32,
+    ) -> Result<Self> {
+        let name = format!("{} {position:?}", self.name);
+        let handle = output_line(gpio, line, &name)?;
+        self.drive.push((position, handle));
+        Ok(self)
+    }
+
+    /// Give the arm timeout to reach the position it's moved to
+    pub fn with_move_timeout(mut self, timeout: Duration) -> Self {
+        self.move_timeout = timeout;
+        self
+    }
 
-        Ok(())
+    /// The position the arm was last seen at
+    pub fn position(&self) -> RobotPosition {
+        self.position
+    }
+
+    /// Take the arm to be at position, e.g. where it was seen before the robot was re-created on
+    /// other lines
+    pub fn set_position(&mut self, position: RobotPosition) {
+        self.position = position;
+    }
+
+    /// Time a move is given before it fails
+    pub fn move_timeout(&self) -> Duration {
+        self.move_timeout
+    }
+
+    /// Move the arm to target, driving its output until the arm gets there, the move times out or
+    /// it's cancelled. The positions passed on the way are published, the one reached is returned
+    pub async fn move_to(
+        &mut self,
+        target: RobotPosition,
+        tx: &EventSender,
+        cancel: &CancellationToken,
+    ) -> Result<Event> {
+        if self.position == target {
+            return Ok(Event::PositionReached {
+                robot: self.name.clone(),
+                position: target,
+            });
+        }
+        let index = self
+            .drive
+            .iter()
+            .position(|(position, _)| *position == target)
+            .ok_or_else(|| eyre!("{} has no drive line to {target:?}", self.name))?;
+
+        self.drive[index].1.set_value(1)?;
+        let moving = time::timeout(self.move_timeout, async {
+            loop {
+                let event = self.async_next_event().await?;
+                if self.position == target {
+                    return Ok(event);
+                }
+                tx.send(event);
+            }
+        });
+        let reached = tokio::select! {
+            reached = moving => match reached {
+                Ok(reached) => reached,
+                Err(_) => Err(eyre!(
+                    "{} didn't reach {target:?} within {} ms",
+                    self.name,
+                    self.move_timeout.as_millis()
+                )),
+            },
+            _ = cancel.cancelled() => Err(Cancelled.into()),
+        };
+        // the arm is stopped whether it got there or not
+        self.drive[index].1.set_value(0)?;
+        reached
+    }
+
+    /// Wait for the arm to reach a position on the track, signalled by a rising edge on the sensor
+    /// of that position, or on its line for the next position. Returns the position reached, or the
+    /// illegal transition when the arm can't have moved there
+    pub async fn async_next_event(&mut self) -> Result<Event> {
+        let (reached, edge) = {
+            let handle = &mut self.event_handle;
+            let mut edges: Vec<_> = self
+                .sensors
+                .iter_mut()
+                .map(|(position, sensor)| {
+                    async move { (Some(*position), sensor.next().await) }.boxed()
+                })
+                .collect();
+            edges.push(async move { (None, handle.next().await) }.boxed());
+
+            let (edge, _, _) = future::select_all(edges).await;
+            edge
+        };
+
+        match edge {
+            Some(edge) => edge?,
+            None => return Err(eyre!("The line of {} stopped sending events", self.name)),
+        };
+        let to = reached.unwrap_or_else(|| self.position.next());
+        Ok(self.transition(to))
+    }
+
+    /// Wait for the next event like async_next_event, failing with a Timeout when the arm doesn't
+    /// reach a position within after
+    pub async fn async_next_event_timeout(&mut self, after: Duration) -> Result<Event> {
+        match time::timeout(after, self.async_next_event()).await {
+            Ok(event) => event,
+            Err(_) => Err(Timeout {
+                component: self.name.clone(),
+                after,
+            }
+            .into()),
+        }
+    }
+
+    /// Move the arm to position when it can get there from where it is
+    fn transition(&mut self, to: RobotPosition) -> Event {
+        if !self.position.can_move_to(to) {
+            return Event::IllegalTransition {
+                robot: self.name.clone(),
+                from: self.position,
+                to,
+            };
+        }
+        self.position = to;
+        Event::PositionReached {
+            robot: self.name.clone(),
+            position: to,
+        }
+    }
+}
+
+#[async_trait]
+impl Component for Robot {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "robot"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await?.into())
+    }
+
+    /// The arm is stopped where it is, every drive line is driven even when another fails
+    fn make_safe(&mut self) -> Result<Option<ComponentEvent>> {
+        let mut result = Ok(None);
+        for (_, drive) in &self.drive {
+            if let Err(e) = drive.set_value(0) {
+                result = result.and(Err(e.into()));
+            }
+        }
+        result
     }
 }
 
@@ -85,13 +359,68 @@ impl Serialize for Robot {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+    use gpio_cdev::EventType;
 
     #[test]
     fn robot_to_json() {
-        let mut chip = Chip::new("/dev/gpiochip0")
-            .expect("Sorry the current hack requires access to /dev/gpiochip0");
-        let robot = Robot::new("robot 1", &mut chip, 0).unwrap();
-        let json = serde_json::to_string(&robot).unwrap();
-        println!("{json}")
+        let mut gpio = MockGpio::default();
+        let robot = Robot::new(&Settings::default(), "robot 1", &mut gpio, 0).unwrap();
+        let json = serde_json::to_value(&robot).unwrap();
+        assert_eq!(json["name"], "robot 1");
+        assert_eq!(json["position"], "position 1");
+        assert!(json["updateTimestamp"].is_string());
+    }
+
+    #[tokio::test]
+    async fn moves_drive_the_arm_until_it_reaches_the_position() {
+        let mut gpio = MockGpio::default();
+        let mut robot = Robot::new(&Settings::default(), "robot 1", &mut gpio, 0)
+            .unwrap()
+            .with_position_line(&Settings::default(), &mut gpio, Position15, 1)
+            .unwrap()
+            .with_drive_line(&mut gpio, Position15, 2)
+            .unwrap();
+        let (tx, _rx) = crate::envelope::channel();
+
+        gpio.edge(1, EventType::RisingEdge);
+        let reached = robot
+            .move_to(Position15, &tx, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert!(matches!(
+            reached,
+            Event::PositionReached {
+                position: Position15,
+                ..
+            }
+        ));
+        assert_eq!(gpio.history(2), [0, 1, 0]);
+    }
+
+    #[test]
+    fn positions_are_named_as_serialized() {
+        let position: RobotPosition = serde_json::from_str(r#""position 66""#).unwrap();
+        assert_eq!(position, Position66);
+        assert_eq!(json!(position), json!("position 66"));
+    }
+
+    #[test]
+    fn only_moves_to_the_next_position_are_legal() {
+        assert!(Position1.can_move_to(Position15));
+        assert!(Position66.can_move_to(Position1));
+        assert!(!Position1.can_move_to(Position66));
+        assert!(!Position15.can_move_to(Position15));
+    }
+
+    #[test]
+    fn positions_loop_around_the_track() {
+        let mut position = RobotPosition::default();
+        let mut visited = vec![];
+        for _ in 0..3 {
+            position = position.next();
+            visited.push(position);
+        }
+        assert_eq!(visited, [Position15, Position66, Position1]);
     }
 }
@@ -0,0 +1,163 @@
+use crate::config::Settings;
+use crate::manufacturing_components::{Component, ComponentEvent};
+use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures::future;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use std::time::SystemTime;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, Lines};
+use tokio_serial::SerialPortBuilderExt;
+
+/// Baud rate of a scanner that wasn't given one, the default of most serial scanners
+pub const DEFAULT_BAUD_RATE: u32 = 9600;
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    /// a material was scanned at the feeder, its code is attached to the next pickup
+    Scanned { scanner: String, code: String },
+}
+
+/// A barcode scanner at the feeder on a serial port, so the cloud twin can trace individual parts.
+/// Every scan is a line, scanners are set up to end theirs with a line feed or CR LF. The code of
+/// the last scan is held until the next material is picked up, see take_code
+pub struct BarcodeScanner {
+    name: String,
+    scans: Lines<BufReader<Box<dyn AsyncRead + Send + Unpin>>>,
+    /// the last code scanned, kept for the state
+    last: Option<String>,
+    /// the code not attached to a pickup yet
+    pending: Option<String>,
+    /// whether the port stopped sending scans, which is only reported once
+    ended: bool,
+}
+
+impl Serialize for BarcodeScanner {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("scanner", 3)?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("lastCode", &self.last)?;
+
+        let now = SystemTime::iso8601_now();
+        s.serialize_field("updateTimestamp", &now)?;
+
+        s.end()
+    }
+}
+
+impl BarcodeScanner {
+    /// The scanner whose scans are read from port
+    pub fn new(name: &str, port: Box<dyn AsyncRead + Send + Unpin>) -> Self {
+        Self {
+            name: name.to_string(),
+            scans: BufReader::new(port).lines(),
+            last: None,
+            pending: None,
+            ended: false,
+        }
+    }
+
+    /// The scanner on the serial port at path, e.g. /dev/ttyUSB0
+    pub fn open(name: &str, path: &str, baud_rate: u32) -> Result<Self> {
+        let port = tokio_serial::new(path, baud_rate)
+            .open_native_async()
+            .map_err(|e| eyre!("Failed to open the serial port {path} of {name}: {e}"))?;
+        Ok(Self::new(name, Box::new(port)))
+    }
+
+    /// The scanner on SCANNER_PORT at SCANNER_BAUD_RATE, None when the cell has none
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let path = match settings.get("SCANNER_PORT") {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let baud_rate = settings
+            .parse("SCANNER_BAUD_RATE", "unsigned integer")?
+            .unwrap_or(DEFAULT_BAUD_RATE);
+
+        Ok(Some(Self::open("Scanner", path, baud_rate)?))
+    }
+
+    /// The code scanned since the last pickup, if any, which is attached to the pickup
+    pub fn take_code(&mut self) -> Option<String> {
+        self.pending.take()
+    }
+
+    /// Wait for the next scan. Empty lines aren't scans, a scan replaces the code of the one
+    /// before it that wasn't attached to a pickup
+    pub async fn async_next_event(&mut self) -> Result<Event> {
+        if self.ended {
+            return future::pending().await;
+        }
+        loop {
+            let line = match self.scans.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    self.ended = true;
+                    return Err(eyre!("The serial port of {} was closed", self.name));
+                }
+                Err(e) => return Err(eyre!("Failed to read a scan from {}: {e}", self.name)),
+            };
+            let code = line.trim();
+            if code.is_empty() {
+                continue;
+            }
+
+            self.last = Some(code.to_string());
+            self.pending = Some(code.to_string());
+            return Ok(Event::Scanned {
+                scanner: self.name.clone(),
+                code: code.to_string(),
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Component for BarcodeScanner {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "scanner"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await?.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn scans_are_held_until_taken() {
+        let (mut port, scanner_port) = tokio::io::duplex(64);
+        let mut scanner = BarcodeScanner::new("Scanner", Box::new(scanner_port));
+
+        port.write_all(b"\r\nMAT-0001\r\nMAT-0002\n").await.unwrap();
+        assert!(matches!(
+            scanner.async_next_event().await.unwrap(),
+            Event::Scanned { code, .. } if code == "MAT-0001"
+        ));
+        scanner.async_next_event().await.unwrap();
+        assert_eq!(scanner.take_code().as_deref(), Some("MAT-0002"));
+        assert_eq!(scanner.take_code(), None);
+
+        drop(port);
+        assert!(scanner.async_next_event().await.is_err());
+    }
+}
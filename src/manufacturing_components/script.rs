@@ -0,0 +1,656 @@
+use crate::cancellation::CancellationToken;
+use crate::config::Settings;
+use crate::manufacturing_components::cycle::{
+    between_steps, publish_transition, CycleContext, Interrupted, Progress, Report,
+};
+use crate::manufacturing_components::dry_run::PlannedStep;
+use crate::manufacturing_components::gpio::{GpioProvider, OutputLine};
+use crate::manufacturing_components::input::{DebouncedLine, Trigger};
+use crate::manufacturing_components::program::{
+    Event, Lifecycle, ManufacturingProgram, ProgramError, ProgramState, ProgramStatus,
+};
+use crate::manufacturing_components::registry::ComponentRegistry;
+use crate::manufacturing_components::{input_line, output_line};
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use gpio_cdev::{EventRequestFlags, EventType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time;
+use tracing::error;
+
+/// Time between two reads of a point a poll step waits on
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Level an output line is set to
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    High,
+    Low,
+}
+
+/// Edges a wait completes on, rising by default
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Edge {
+    #[default]
+    Rising,
+    Falling,
+    Both,
+}
+
+impl Edge {
+    fn matches(self, event: EventType) -> bool {
+        match self {
+            Edge::Rising => event == EventType::RisingEdge,
+            Edge::Falling => event == EventType::FallingEdge,
+            Edge::Both => true,
+        }
+    }
+}
+
+/// A step of a scenario as it's written in its file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Step {
+    /// drive an output line high or low
+    Set {
+        line: u32,
+        value: Level,
+    },
+    /// wait for an edge on an input line, the cycle stalls when it doesn't come within the timeout
+    Wait {
+        line: u32,
+        #[serde(default)]
+        edge: Edge,
+        timeout_ms: Option<u64>,
+    },
+    Sleep {
+        ms: u64,
+    },
+    /// write a coil or register of a Modbus device, coils are written 0 or 1
+    Write {
+        device: String,
+        point: String,
+        value: u16,
+    },
+    /// poll a coil or register of a Modbus device until it reads the value, the cycle stalls when
+    /// it doesn't within the timeout
+    Poll {
+        device: String,
+        point: String,
+        value: u16,
+        timeout_ms: Option<u64>,
+    },
+    /// send a command frame of up to 8 bytes to a CAN device
+    Send {
+        device: String,
+        id: u32,
+        data: Vec<u8>,
+    },
+    /// run the nested steps the given number of times
+    Repeat {
+        times: u32,
+        steps: Vec<Step>,
+    },
+}
+
+/// A scenario written in TOML, its steps are run once for every material of a start, e.g.
+///
+/// ```toml
+/// [[steps]]
+/// action = "set"
+/// line = 17
+/// value = "high"
+///
+/// [[steps]]
+/// action = "wait"
+/// line = 4
+/// edge = "rising"
+/// timeout_ms = 5000
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct ScriptDefinition {
+    pub steps: Vec<Step>,
+}
+
+impl ScriptDefinition {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| eyre!("Failed to read the scenario {}: {e}", path.display()))?;
+        toml::from_str(&content)
+            .map_err(|e| eyre!("The scenario {} is invalid: {e}", path.display()))
+    }
+}
+
+/// A step of a scenario with the repeats unrolled
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    Set {
+        line: u32,
+        value: u8,
+    },
+    Wait {
+        line: u32,
+        edge: Edge,
+        timeout: Option<Duration>,
+    },
+    Sleep(Duration),
+    Write {
+        device: String,
+        point: String,
+        value: u16,
+    },
+    Poll {
+        device: String,
+        point: String,
+        value: u16,
+        timeout: Option<Duration>,
+    },
+    Send {
+        device: String,
+        id: u32,
+        data: Vec<u8>,
+    },
+}
+
+/// Unroll the repeats of steps into the actions run in order
+fn flatten(steps: &[Step], actions: &mut Vec<Action>) {
+    for step in steps {
+        match step {
+            Step::Set { line, value } => actions.push(Action::Set {
+                line: *line,
+                value: match value {
+                    Level::High => 1,
+                    Level::Low => 0,
+                },
+            }),
+            Step::Wait {
+                line,
+                edge,
+                timeout_ms,
+            } => actions.push(Action::Wait {
+                line: *line,
+                edge: *edge,
+                timeout: timeout_ms.map(Duration::from_millis),
+            }),
+            Step::Sleep { ms } => actions.push(Action::Sleep(Duration::from_millis(*ms))),
+            Step::Write {
+                device,
+                point,
+                value,
+            } => actions.push(Action::Write {
+                device: device.clone(),
+                point: point.clone(),
+                value: *value,
+            }),
+            Step::Poll {
+                device,
+                point,
+                value,
+                timeout_ms,
+            } => actions.push(Action::Poll {
+                device: device.clone(),
+                point: point.clone(),
+                value: *value,
+                timeout: timeout_ms.map(Duration::from_millis),
+            }),
+            Step::Send { device, id, data } => actions.push(Action::Send {
+                device: device.clone(),
+                id: *id,
+                data: data.clone(),
+            }),
+            Step::Repeat { times, steps } => {
+                for _ in 0..*times {
+                    flatten(steps, actions);
+                }
+            }
+        }
+    }
+}
+
+/// How a wait on an input line or a poll of a Modbus point ended
+enum Waited {
+    /// the edge came, or the point read the value
+    Signalled,
+    TimedOut(Duration),
+    Cancelled,
+}
+
+/// The program of a scenario loaded from a file, driving and reading the lines its steps name.
+/// Lines that are set are requested as outputs starting low, lines waited on as inputs. Requests
+/// received while a step runs are handled once it's done, stops and emergency stops cancel waits
+/// and sleeps right away
+pub struct ScriptProgram {
+    name: String,
+    lifecycle: Lifecycle,
+    actions: Vec<Action>,
+    outputs: HashMap<u32, Box<dyn OutputLine>>,
+    inputs: HashMap<u32, DebouncedLine>,
+}
+
+impl ScriptProgram {
+    pub fn new(
+        settings: &Settings,
+        name: &str,
+        definition: &ScriptDefinition,
+        gpio: &mut dyn GpioProvider,
+    ) -> Result<Self> {
+        let mut actions = Vec::new();
+        flatten(&definition.steps, &mut actions);
+        if actions.is_empty() {
+            return Err(eyre!("The scenario {name} has no steps"));
+        }
+
+        let mut outputs = HashMap::new();
+        let mut inputs = HashMap::new();
+        // edges are filtered by the waits, each of which can expect a different one
+        let trigger = Trigger {
+            edge: EventRequestFlags::BOTH_EDGES,
+            active_low: false,
+        };
+        for action in &actions {
+            match action {
+                Action::Set { line, .. } if !outputs.contains_key(line) => {
+                    outputs.insert(*line, output_line(gpio, *line, name)?);
+                }
+                Action::Wait { line, .. } if !inputs.contains_key(line) => {
+                    inputs.insert(*line, input_line(settings, gpio, *line, trigger, name)?);
+                }
+                _ => {}
+            }
+        }
+        if let Some(line) = outputs.keys().find(|line| inputs.contains_key(line)) {
+            return Err(eyre!(
+                "The scenario {name} both sets and waits on line {line}"
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            lifecycle: Lifecycle::default(),
+            actions,
+            outputs,
+            inputs,
+        })
+    }
+
+    /// Run the steps once for every material requested, or until a stop request is received
+    async fn cycle(&mut self, cx: &mut CycleContext<'_>) -> Result<Report> {
+        publish_transition(cx.tx, self.start()?);
+        cx.status_tx.send(ProgramStatus::Running).ok();
+        let progress = Progress::new(cx.count, cx.progress_every);
+
+        for processed in 0..cx.count {
+            let remaining = cx.count.saturating_sub(processed);
+            for index in 0..self.actions.len() {
+                let interrupted = match self.actions[index].clone() {
+                    Action::Set { line, value } => {
+                        self.outputs[&line].set_value(value)?;
+                        None
+                    }
+                    Action::Wait {
+                        line,
+                        edge,
+                        timeout,
+                    } => match self.wait(line, edge, timeout, cx.cancel).await? {
+                        Waited::TimedOut(after) => {
+                            let component = format!("{} line {line}", self.name);
+                            Some(self.stall(component, after, cx)?)
+                        }
+                        Waited::Signalled | Waited::Cancelled => None,
+                    },
+                    Action::Sleep(duration) => {
+                        tokio::select! {
+                            _ = time::sleep(duration) => {}
+                            _ = cx.cancel.cancelled() => {}
+                        }
+                        None
+                    }
+                    Action::Write {
+                        device,
+                        point,
+                        value,
+                    } => {
+                        cx.cell.modbus(&device)?.write(&point, value).await?;
+                        None
+                    }
+                    Action::Poll {
+                        device,
+                        point,
+                        value,
+                        timeout,
+                    } => match poll(&device, &point, value, timeout, cx).await? {
+                        Waited::TimedOut(after) => {
+                            let component = format!("{device} {point}");
+                            Some(self.stall(component, after, cx)?)
+                        }
+                        Waited::Signalled | Waited::Cancelled => None,
+                    },
+                    Action::Send { device, id, data } => {
+                        cx.cell.can(&device)?.send(id, &data).await?;
+                        None
+                    }
+                };
+
+                let interrupted = match interrupted {
+                    Some(interrupted) => Some(interrupted),
+                    None => between_steps(self, cx, remaining).await?,
+                };
+                if let Some(interrupted) = interrupted {
+                    return Ok(Report {
+                        processed,
+                        interrupted: Some(interrupted),
+                    });
+                }
+            }
+
+            if let Some(event) = progress.event(processed + 1) {
+                cx.tx.send(event);
+            }
+        }
+
+        publish_transition(cx.tx, self.stop()?);
+
+        Ok(Report {
+            processed: cx.count,
+            interrupted: None,
+        })
+    }
+
+    /// Stop the cycle stalled on the component, which didn't signal within after
+    fn stall(
+        &mut self,
+        component: String,
+        after: Duration,
+        cx: &CycleContext<'_>,
+    ) -> Result<Interrupted> {
+        error!("The cycle stalled: {component} didn't signal");
+        publish_transition(cx.tx, self.stop()?);
+        cx.tx.send(Event::Stalled {
+            component,
+            waited_ms: after.as_millis() as u64,
+        });
+        Ok(Interrupted::Stalled)
+    }
+
+    /// Wait for the edge on the input line, until the timeout when there is one
+    async fn wait(
+        &mut self,
+        line: u32,
+        edge: Edge,
+        timeout: Option<Duration>,
+        cancel: &CancellationToken,
+    ) -> Result<Waited> {
+        let input = self
+            .inputs
+            .get_mut(&line)
+            .expect("Every line waited on is requested");
+        let edges = async {
+            loop {
+                let event = match input.next().await {
+                    Some(event) => event?,
+                    None => return Err(eyre!("Line {line} stopped sending events")),
+                };
+                if edge.matches(event.event_type) {
+                    return Ok(());
+                }
+            }
+        };
+        let edges = async {
+            match timeout {
+                Some(after) => match time::timeout(after, edges).await {
+                    Ok(edge) => edge.map(|_| Waited::Signalled),
+                    Err(_) => Ok(Waited::TimedOut(after)),
+                },
+                None => edges.await.map(|_| Waited::Signalled),
+            }
+        };
+
+        tokio::select! {
+            waited = edges => waited,
+            _ = cancel.cancelled() => Ok(Waited::Cancelled),
+        }
+    }
+}
+
+/// Poll the point of the Modbus device until it reads the value, until the timeout when there is one
+async fn poll(
+    device: &str,
+    point: &str,
+    value: u16,
+    timeout: Option<Duration>,
+    cx: &mut CycleContext<'_>,
+) -> Result<Waited> {
+    let cancel = cx.cancel;
+    let device = cx.cell.modbus(device)?;
+    let reads = async {
+        loop {
+            if device.read(point).await? == value {
+                return Ok(());
+            }
+            time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    let reads = async {
+        match timeout {
+            Some(after) => match time::timeout(after, reads).await {
+                Ok(read) => read.map(|_| Waited::Signalled),
+                Err(_) => Ok(Waited::TimedOut(after)),
+            },
+            None => reads.await.map(|_| Waited::Signalled),
+        }
+    };
+
+    tokio::select! {
+        waited = reads => waited,
+        _ = cancel.cancelled() => Ok(Waited::Cancelled),
+    }
+}
+
+#[async_trait]
+impl ManufacturingProgram for ScriptProgram {
+    type Error = ProgramError;
+    type Success = Option<Event>;
+
+    fn state(&self) -> ProgramState {
+        self.lifecycle.state()
+    }
+
+    // the steps drive the lines, a pause holds them as they are
+
+    fn start(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Running, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    fn stop(&mut self) -> Result<Self::Success, Self::Error> {
+        let outputs = &self.outputs;
+        self.lifecycle.stop(|| {
+            for output in outputs.values() {
+                output.set_value(0)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Every output line is driven low
+    fn safe_state(&mut self) -> Result<Self::Success, Self::Error> {
+        self.stop()
+    }
+
+    fn pause(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self.lifecycle.transition(ProgramState::Paused, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    fn resume(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Running, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    /// Run the steps once for every material, see cycle
+    async fn run(&mut self, cx: &mut CycleContext<'_>) -> Result<Report> {
+        self.cycle(cx).await
+    }
+
+    fn plan(&self, _: &ComponentRegistry) -> Vec<PlannedStep> {
+        self.actions
+            .iter()
+            .map(|action| match action {
+                Action::Set { line, value } => {
+                    let level = if *value == 1 { "high" } else { "low" };
+                    PlannedStep::act(format!("set line {line} {level}"), None, Duration::ZERO)
+                }
+                Action::Wait {
+                    line,
+                    edge,
+                    timeout,
+                } => PlannedStep::wait(
+                    format!("wait for a {edge:?} edge on line {line}").to_lowercase(),
+                    None,
+                    *timeout,
+                ),
+                Action::Sleep(duration) => PlannedStep::act("sleep", None, *duration),
+                Action::Write {
+                    device,
+                    point,
+                    value,
+                } => PlannedStep::act(
+                    format!("write {value} to {point} of {device}"),
+                    None,
+                    Duration::ZERO,
+                ),
+                Action::Poll {
+                    device,
+                    point,
+                    value,
+                    timeout,
+                } => PlannedStep::wait(
+                    format!("poll {point} of {device} until it reads {value}"),
+                    None,
+                    *timeout,
+                ),
+                Action::Send { device, id, .. } => PlannedStep::act(
+                    format!("send frame {id:#x} to {device}"),
+                    None,
+                    Duration::ZERO,
+                ),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn repeats_are_unrolled() {
+        let definition: ScriptDefinition = toml::from_str(
+            r#"
+            [[steps]]
+            action = "set"
+            line = 17
+            value = "high"
+
+            [[steps]]
+            action = "repeat"
+            times = 2
+
+            [[steps.steps]]
+            action = "wait"
+            line = 4
+            timeout_ms = 100
+
+            [[steps.steps]]
+            action = "sleep"
+            ms = 5
+            "#,
+        )
+        .unwrap();
+
+        let mut actions = Vec::new();
+        flatten(&definition.steps, &mut actions);
+        let wait = Action::Wait {
+            line: 4,
+            edge: Edge::Rising,
+            timeout: Some(Duration::from_millis(100)),
+        };
+        let sleep = Action::Sleep(Duration::from_millis(5));
+        assert_eq!(
+            actions,
+            [
+                Action::Set { line: 17, value: 1 },
+                wait.clone(),
+                sleep.clone(),
+                wait,
+                sleep
+            ]
+        );
+    }
+    #[test]
+    fn modbus_points_are_written_and_polled() {
+        let definition: ScriptDefinition = toml::from_str(
+            r#"
+            [[steps]]
+            action = "write"
+            device = "valves"
+            point = "gripper"
+            value = 1
+
+            [[steps]]
+            action = "poll"
+            device = "valves"
+            point = "gripper_closed"
+            value = 1
+            "#,
+        )
+        .unwrap();
+
+        let mut actions = Vec::new();
+        flatten(&definition.steps, &mut actions);
+        assert_eq!(
+            actions[1],
+            Action::Poll {
+                device: "valves".to_string(),
+                point: "gripper_closed".to_string(),
+                value: 1,
+                timeout: None,
+            }
+        );
+    }
+
+    #[test]
+    fn can_frames_are_sent() {
+        let definition: ScriptDefinition = toml::from_str(
+            r#"
+            [[steps]]
+            action = "send"
+            device = "track"
+            id = 0x201
+            data = [1, 0, 0, 0]
+            "#,
+        )
+        .unwrap();
+
+        let mut actions = Vec::new();
+        flatten(&definition.steps, &mut actions);
+        assert_eq!(
+            actions,
+            [Action::Send {
+                device: "track".to_string(),
+                id: 0x201,
+                data: vec![1, 0, 0, 0],
+            }]
+        );
+    }
+}
@@ -0,0 +1,363 @@
+use crate::manufacturing_components::cycle::{
+    publish_transition, safe_state, CycleContext, Interrupted, Report,
+};
+use crate::manufacturing_components::dry_run::{PlannedStep, Stage};
+use crate::manufacturing_components::gpio::{SharedGpio, Unwired};
+use crate::manufacturing_components::program::{
+    DynProgram, Event, Lifecycle, ManufacturingProgram, Program, ProgramError, ProgramState,
+    Scenarios,
+};
+use crate::manufacturing_components::registry::ComponentRegistry;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+use tracing::{error, info};
+
+/// Scenario of a sequence, its steps are given in the parameters of the start
+pub const SEQUENCE_SCENARIO: &str = "sequence";
+
+/// What a sequence does when one of its steps fails, i.e. its cycle fails or stalls
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    /// the sequence ends with the failure
+    #[default]
+    Abort,
+    /// the failure is logged and the next step is run
+    Continue,
+    /// the steps left are skipped up to the last one, e.g. a cleanup routine, which is run
+    Finish,
+}
+
+/// A step of a sequence as given in the parameters of the start
+#[derive(Debug, Deserialize)]
+pub struct StepParameters {
+    pub scenario: String,
+    #[serde(default)]
+    pub parameters: Value,
+    /// materials the step processes, the count of the start when left out
+    pub count: Option<u32>,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+}
+
+/// Parameters of a sequence, e.g. a warm-up routine, then a production run, then a cleanup
+#[derive(Debug, Deserialize)]
+pub struct SequenceParameters {
+    pub steps: Vec<StepParameters>,
+}
+
+/// A step of a sequence, with how it's run
+struct Step {
+    scenario: String,
+    parameters: Value,
+    count: Option<u32>,
+    on_failure: OnFailure,
+    /// the program of the step built on unwired lines, which dry runs walk
+    plan: Program,
+}
+
+/// Programs run one after the other as a single job. Only the steps running the count of the
+/// start add to the materials reported processed, a warm-up or cleanup routine runs a count of its
+/// own. Stops, emergency stops and shutdowns end the sequence whichever step they interrupt.
+///
+/// Steps often drive the same lines, e.g. the control line of the PLC, so the program of a step
+/// is only built once the step runs, after the program of the step before it was released
+pub struct Sequence {
+    lifecycle: Lifecycle,
+    scenarios: Scenarios,
+    gpio: SharedGpio,
+    steps: Vec<Step>,
+    /// the program of the step running, or of the last one that ran
+    running: Option<Program>,
+}
+
+impl Sequence {
+    /// A sequence of the steps, each of which is built on unwired lines first so an unknown
+    /// scenario or bad parameters fail the start rather than the step
+    pub fn new(
+        scenarios: Scenarios,
+        gpio: SharedGpio,
+        parameters: SequenceParameters,
+    ) -> Result<Self> {
+        if parameters.steps.is_empty() {
+            return Err(eyre!("A sequence needs at least one step"));
+        }
+        let unwired = SharedGpio::new(Box::new(Unwired));
+        let mut steps = Vec::with_capacity(parameters.steps.len());
+        for step in parameters.steps {
+            steps.push(Step {
+                plan: scenarios.build(&unwired, &step.scenario, &step.parameters)?,
+                scenario: step.scenario,
+                parameters: step.parameters,
+                count: step.count,
+                on_failure: step.on_failure,
+            });
+        }
+        Ok(Self {
+            lifecycle: Lifecycle::default(),
+            scenarios,
+            gpio,
+            steps,
+            running: None,
+        })
+    }
+
+    /// Build the program of the step at index, the program of the step before is released first
+    /// so its lines can be requested again
+    fn build_step(&mut self, index: usize) -> Result<&mut DynProgram> {
+        self.running = None;
+        let step = &self.steps[index];
+        let program = self
+            .scenarios
+            .build(&self.gpio, &step.scenario, &step.parameters)?;
+        Ok(self.running.insert(program).as_mut())
+    }
+
+    /// Run the steps in order, handling the failures of each as it's configured to
+    async fn cycle(&mut self, cx: &mut CycleContext<'_>) -> Result<Report> {
+        publish_transition(cx.tx, self.start()?);
+        let requested = cx.count;
+        let last = self.steps.len() - 1;
+        let mut processed = 0;
+        let mut index = 0;
+
+        while index <= last {
+            let step = &self.steps[index];
+            info!(
+                "Running step {} of {}, {}",
+                index + 1,
+                last + 1,
+                step.scenario
+            );
+            let counted = step.count.is_none();
+            let on_failure = step.on_failure;
+            let count = step.count.unwrap_or(requested);
+
+            let report = match self.build_step(index) {
+                Ok(program) => {
+                    cx.count = count;
+                    let report = program.run(cx).await;
+                    cx.count = requested;
+                    report
+                }
+                Err(e) => Err(e),
+            };
+
+            let failure = match report {
+                Ok(report) => {
+                    if counted {
+                        processed += report.processed;
+                    }
+                    match report.interrupted {
+                        None => None,
+                        Some(Interrupted::Stalled) => Some(Failure::Stalled),
+                        Some(interrupted) => {
+                            publish_transition(cx.tx, self.stop()?);
+                            return Ok(Report {
+                                processed: processed.min(requested),
+                                interrupted: Some(interrupted),
+                            });
+                        }
+                    }
+                }
+                Err(e) => Some(Failure::Error(e)),
+            };
+
+            // a failed step is left safe before the sequence goes on, the cell along with it
+            if failure.is_some() && on_failure != OnFailure::Abort {
+                safe_state(self.running.as_deref_mut(), cx.cell, cx.tx)?;
+            }
+
+            index = match (failure, on_failure) {
+                (None, _) => index + 1,
+                (Some(failure), OnFailure::Abort) => {
+                    publish_transition(cx.tx, self.stop()?);
+                    return match failure {
+                        Failure::Stalled => Ok(Report {
+                            processed: processed.min(requested),
+                            interrupted: Some(Interrupted::Stalled),
+                        }),
+                        Failure::Error(e) => Err(e),
+                    };
+                }
+                (Some(failure), OnFailure::Continue) => {
+                    error!(
+                        "Step {} of the sequence failed, continuing: {failure}",
+                        index + 1
+                    );
+                    index + 1
+                }
+                (Some(failure), OnFailure::Finish) => {
+                    error!(
+                        "Step {} of the sequence failed, finishing: {failure}",
+                        index + 1
+                    );
+                    // the last step is what finishes, it isn't run again when it's the one failing
+                    if index == last {
+                        index + 1
+                    } else {
+                        last
+                    }
+                }
+            };
+        }
+
+        publish_transition(cx.tx, self.stop()?);
+
+        Ok(Report {
+            processed: processed.min(requested),
+            interrupted: None,
+        })
+    }
+}
+
+/// How a step failed
+enum Failure {
+    Stalled,
+    Error(color_eyre::Report),
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Stalled => write!(f, "Error: The cycle stalled"),
+            Failure::Error(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[async_trait]
+impl ManufacturingProgram for Sequence {
+    type Error = ProgramError;
+    type Success = Option<Event>;
+
+    fn state(&self) -> ProgramState {
+        self.lifecycle.state()
+    }
+
+    // the steps drive the cell, the sequence only keeps track of where it is
+
+    fn start(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Running, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    /// The step running is stopped along with the sequence
+    fn stop(&mut self) -> Result<Self::Success, Self::Error> {
+        if let Some(program) = &mut self.running {
+            program.stop()?;
+        }
+        self.lifecycle.stop(|| Ok(()))
+    }
+
+    fn pause(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self.lifecycle.transition(ProgramState::Paused, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    fn resume(&mut self) -> Result<Self::Success, Self::Error> {
+        let event = self
+            .lifecycle
+            .transition(ProgramState::Running, || Ok(()))?;
+        Ok(Some(event))
+    }
+
+    /// The step running is driven to its safe state along with the sequence
+    fn safe_state(&mut self) -> Result<Self::Success, Self::Error> {
+        if let Some(program) = &mut self.running {
+            program.safe_state()?;
+        }
+        self.lifecycle.stop(|| Ok(()))
+    }
+
+    /// Run the steps in order, see cycle
+    async fn run(&mut self, cx: &mut CycleContext<'_>) -> Result<Report> {
+        self.cycle(cx).await
+    }
+
+    fn plan(&self, cell: &ComponentRegistry) -> Vec<PlannedStep> {
+        self.steps
+            .iter()
+            .flat_map(|step| step.plan.plan(cell))
+            .collect()
+    }
+
+    /// A stage for every step, named after its scenario
+    fn stages(&self, count: u32, cell: &ComponentRegistry) -> Vec<Stage> {
+        let mut stages = Vec::new();
+        for step in &self.steps {
+            for mut stage in step.plan.stages(step.count.unwrap_or(count), cell) {
+                stage.scenario.get_or_insert_with(|| step.scenario.clone());
+                stages.push(stage);
+            }
+        }
+        stages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Settings;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+    use crate::manufacturing_components::gpio::GpioProvider;
+    use serde_json::json;
+
+    #[test]
+    fn steps_abort_on_failure_by_default() {
+        let parameters: SequenceParameters = serde_json::from_value(json!({
+            "steps": [
+                { "scenario": "warm-up", "count": 2, "on_failure": "continue" },
+                { "scenario": "simplified-scenario-2" },
+                { "scenario": "cleanup", "count": 1, "on_failure": "finish" },
+            ]
+        }))
+        .unwrap();
+
+        let on_failure: Vec<_> = parameters
+            .steps
+            .iter()
+            .map(|step| step.on_failure)
+            .collect();
+        assert_eq!(
+            on_failure,
+            [OnFailure::Continue, OnFailure::Abort, OnFailure::Finish]
+        );
+        assert_eq!(parameters.steps[1].count, None);
+        assert!(parameters.steps[1].parameters.is_null());
+    }
+
+    #[test]
+    fn steps_sharing_a_line_request_it_in_turn() {
+        let mut gpio = MockGpio::default();
+        let parameters = serde_json::from_value(json!({
+            "steps": [
+                { "scenario": "simplified-scenario-2", "count": 1 },
+                { "scenario": "simplified-scenario-2" },
+            ]
+        }))
+        .unwrap();
+        let scenarios = Scenarios::new(&Settings::default(), 7);
+        let shared = SharedGpio::new(Box::new(gpio.clone()));
+        let mut sequence = Sequence::new(scenarios, shared, parameters).unwrap();
+
+        for index in 0..2 {
+            let program = sequence.build_step(index).unwrap();
+            program.start().unwrap();
+            assert_eq!(gpio.value(7), 1);
+            program.stop().unwrap();
+            // the line is held by the step until the next one is built
+            assert!(gpio.output(7, "test").is_err());
+        }
+        assert_eq!(gpio.history(7), [0, 1, 0]);
+
+        drop(sequence);
+        assert!(gpio.output(7, "test").is_ok());
+    }
+}
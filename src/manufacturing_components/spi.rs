@@ -0,0 +1,157 @@
+use crate::config::{ConfigError, Settings};
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use linux_embedded_hal::spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+/// Clock of an SPI device that wasn't given one, in hertz
+pub const DEFAULT_SPEED: u32 = 1_000_000;
+
+/// A device on an SPI bus, e.g. an external ADC or a display driver
+#[async_trait]
+pub trait SpiDevice: Send {
+    /// Clock out the bytes while clocking in as many, returned in order
+    async fn transfer(&mut self, write: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// How a component's device is reached, read from <COMPONENT>_SPI_BUS, e.g. /dev/spidev0.0,
+/// <COMPONENT>_SPI_SPEED in hertz and <COMPONENT>_SPI_MODE, 0 to 3
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpiConfig {
+    pub bus: String,
+    pub speed: u32,
+    pub mode: u8,
+}
+
+impl SpiConfig {
+    /// The configuration of the component's device, None when it has no bus
+    pub fn from_settings(
+        settings: &Settings,
+        component: &str,
+    ) -> Result<Option<Self>, ConfigError> {
+        let prefix = component.to_uppercase();
+
+        let bus = match settings.get(&format!("{prefix}_SPI_BUS")) {
+            Some(bus) => bus.to_string(),
+            None => return Ok(None),
+        };
+        let speed = settings
+            .parse(&format!("{prefix}_SPI_SPEED"), "hertz")?
+            .unwrap_or(DEFAULT_SPEED);
+        let key = format!("{prefix}_SPI_MODE");
+        let mode = match settings.get(&key) {
+            None => 0,
+            Some(mode) => match mode.parse() {
+                Ok(mode @ 0..=3) => mode,
+                _ => return Err(ConfigError::invalid(&key, "0, 1, 2 or 3", mode)),
+            },
+        };
+
+        Ok(Some(Self { bus, speed, mode }))
+    }
+}
+
+/// Flags of an SPI mode, its clock polarity and phase
+fn mode_flags(mode: u8) -> SpiModeFlags {
+    match mode {
+        1 => SpiModeFlags::SPI_MODE_1,
+        2 => SpiModeFlags::SPI_MODE_2,
+        3 => SpiModeFlags::SPI_MODE_3,
+        _ => SpiModeFlags::SPI_MODE_0,
+    }
+}
+
+/// A device of the spidev interface. Transfers block until the bus is done, so they're run on the
+/// blocking threads of the runtime rather than on its workers
+pub struct LinuxSpi {
+    device: Arc<Mutex<Spidev>>,
+}
+
+impl LinuxSpi {
+    pub fn open(config: &SpiConfig) -> Result<Self> {
+        let mut device = Spidev::open(&config.bus)
+            .map_err(|e| eyre!("Failed to open the SPI bus {}: {e}", config.bus))?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(config.speed)
+            .mode(mode_flags(config.mode))
+            .build();
+        device
+            .configure(&options)
+            .map_err(|e| eyre!("Failed to configure the SPI bus {}: {e}", config.bus))?;
+
+        Ok(Self {
+            device: Arc::new(Mutex::new(device)),
+        })
+    }
+}
+
+#[async_trait]
+impl SpiDevice for LinuxSpi {
+    async fn transfer(&mut self, write: &[u8]) -> Result<Vec<u8>> {
+        let device = self.device.clone();
+        let write = write.to_vec();
+        task::spawn_blocking(move || {
+            let mut read = vec![0; write.len()];
+            let mut transfer = SpidevTransfer::read_write(&write, &mut read);
+            // a transfer that panicked can't have left the device in a state worth guarding
+            let device = device.lock().unwrap_or_else(|e| e.into_inner());
+            device.transfer(&mut transfer)?;
+            Ok(read)
+        })
+        .await?
+    }
+}
+
+/// A device without a bus behind it, answering every transfer with the next of the responses it
+/// was given and recording what was written to it
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::collections::VecDeque;
+
+    #[derive(Clone, Default)]
+    pub struct MockSpi {
+        pub written: Arc<Mutex<Vec<Vec<u8>>>>,
+        pub responses: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    }
+
+    #[async_trait]
+    impl SpiDevice for MockSpi {
+        async fn transfer(&mut self, write: &[u8]) -> Result<Vec<u8>> {
+            self.written.lock().unwrap().push(write.to_vec());
+            let mut response = self
+                .responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_default();
+            response.resize(write.len(), 0);
+            Ok(response)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::mock::MockSpi;
+    use super::*;
+
+    #[test]
+    fn modes_set_polarity_and_phase() {
+        assert_eq!(mode_flags(0), SpiModeFlags::SPI_MODE_0);
+        assert_eq!(mode_flags(3), SpiModeFlags::SPI_MODE_3);
+    }
+
+    #[tokio::test]
+    async fn transfers_read_as_many_bytes_as_written() {
+        let mut spi = MockSpi::default();
+        spi.responses.lock().unwrap().push_back(vec![0x12]);
+
+        let read = spi.transfer(&[0x01, 0x80, 0x00]).await.unwrap();
+        assert_eq!(read, [0x12, 0, 0]);
+        assert_eq!(*spi.written.lock().unwrap(), [vec![0x01, 0x80, 0x00]]);
+    }
+}
@@ -0,0 +1,346 @@
+use crate::cancellation::{CancellationToken, Cancelled};
+use crate::config::Settings;
+use crate::manufacturing_components::gpio::{GpioProvider, OutputLine};
+use crate::manufacturing_components::input::{DebouncedLine, Trigger};
+use crate::manufacturing_components::{input_line, output_line, Component, ComponentEvent};
+use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures::future;
+use gpio_cdev::EventRequestFlags;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::time::{Duration, SystemTime};
+use tokio::time;
+
+/// Top speed of a motor that wasn't given one, in steps per second
+pub const DEFAULT_MAX_SPEED: f64 = 1000.0;
+/// Acceleration of a motor that wasn't given one, in steps per second squared
+pub const DEFAULT_ACCELERATION: f64 = 2000.0;
+/// Speed the motor is homed at, slow enough to stop right at the limit switch
+pub const DEFAULT_HOMING_SPEED: f64 = 200.0;
+/// Steps homing takes before it gives up on reaching the limit switch
+pub const DEFAULT_HOMING_RANGE: u32 = 20_000;
+
+/// How fast the motor gets going and stops, ramping the speed up from standstill at the start of
+/// a move and down to standstill at its end so the motor doesn't stall or overshoot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Profile {
+    /// in steps per second
+    pub max_speed: f64,
+    /// in steps per second squared
+    pub acceleration: f64,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            max_speed: DEFAULT_MAX_SPEED,
+            acceleration: DEFAULT_ACCELERATION,
+        }
+    }
+}
+
+impl Profile {
+    /// Time between a step and the next one of a move, taken steps into it with left steps to go
+    /// including this one
+    fn interval(&self, taken: u32, left: u32) -> Duration {
+        // steps since standstill, or until it, whichever is closer
+        let ramp = (taken + 1).min(left) as f64;
+        // v² = 2 a s from standstill
+        let speed = (2.0 * self.acceleration * ramp).sqrt().min(self.max_speed);
+        Duration::from_secs_f64(1.0 / speed)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    /// the motor stopped at the position, in steps from home
+    Moved { position: i64 },
+    /// the motor reached its limit switch, which is position 0 from now on
+    Homed,
+}
+
+/// A stepper motor driven through the step and direction inputs of its driver, e.g. to set the
+/// height of a gripper. The position is tracked from the steps sent, in steps from home at the
+/// limit switch, or from where the motor was at start until it's homed.
+///
+/// Steps are timed by the runtime, so the speed is only as precise as its timers, a few hundred
+/// steps per second at most
+pub struct StepperMotor {
+    name: String,
+    /// pulsed once per step
+    step: Box<dyn OutputLine>,
+    /// high to step away from home, low to step towards it
+    direction: Box<dyn OutputLine>,
+    /// active at home, the end of the travel homing moves towards
+    limit: Option<DebouncedLine>,
+    profile: Profile,
+    homing_speed: f64,
+    homing_range: u32,
+    position: i64,
+    homed: bool,
+}
+
+impl Serialize for StepperMotor {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("stepper", 4)?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("position", &self.position)?;
+        s.serialize_field("homed", &self.homed)?;
+
+        let now = SystemTime::iso8601_now();
+        s.serialize_field("updateTimestamp", &now)?;
+
+        s.end()
+    }
+}
+
+impl StepperMotor {
+    /// The motor is stepped on step_line in the direction set on direction_line, both starting low
+    pub fn new<S>(
+        name: S,
+        gpio: &mut dyn GpioProvider,
+        step_line: u32,
+        direction_line: u32,
+    ) -> Result<Self>
+    where
+        S: Into<String> + Display,
+    {
+        let name = name.to_string();
+        let step = output_line(gpio, step_line, &format!("{name} step"))?;
+        let direction = output_line(gpio, direction_line, &format!("{name} direction"))?;
+
+        Ok(Self {
+            name,
+            step,
+            direction,
+            limit: None,
+            profile: Profile::default(),
+            homing_speed: DEFAULT_HOMING_SPEED,
+            homing_range: DEFAULT_HOMING_RANGE,
+            position: 0,
+            homed: false,
+        })
+    }
+
+    /// Home the motor against the limit switch read on line, which also stops moves towards home
+    /// that would run into it
+    pub fn with_limit_line(
+        mut self,
+        settings: &Settings,
+        gpio: &mut dyn GpioProvider,
+        line: u32,
+    ) -> Result<Self> {
+        let trigger = Trigger::from_settings(settings, "stepper", EventRequestFlags::RISING_EDGE)?;
+        let name = format!("{} limit", self.name);
+        self.limit = Some(input_line(settings, gpio, line, trigger, &name)?);
+        Ok(self)
+    }
+
+    pub fn with_profile(mut self, profile: Profile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Home at speed, in steps per second, giving up after range steps
+    pub fn with_homing(mut self, speed: f64, range: u32) -> Self {
+        self.homing_speed = speed;
+        self.homing_range = range;
+        self
+    }
+
+    /// Position of the motor, in steps from home
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    pub fn is_homed(&self) -> bool {
+        self.homed
+    }
+
+    /// Move the motor to target, in steps from home, ramping its speed up and down along the
+    /// profile. A move that's cancelled stops where it is, the position it got to is kept
+    pub async fn move_to(&mut self, target: i64, cancel: &CancellationToken) -> Result<Event> {
+        let distance = target - self.position;
+        let steps = u32::try_from(distance.unsigned_abs())
+            .map_err(|_| eyre!("{} can't move {distance} steps at once", self.name))?;
+        let forward = distance > 0;
+        self.direction.set_value(forward as u8)?;
+
+        for taken in 0..steps {
+            if !forward && self.at_limit()? {
+                return Err(eyre!(
+                    "{} reached its limit switch at {} on the way to {target}",
+                    self.name,
+                    self.position
+                ));
+            }
+            self.step(forward)?;
+            self.wait(self.profile.interval(taken, steps - taken), cancel)
+                .await?;
+        }
+        Ok(Event::Moved {
+            position: self.position,
+        })
+    }
+
+    /// Move the motor by steps from where it is, away from home when positive
+    pub async fn move_by(&mut self, steps: i64, cancel: &CancellationToken) -> Result<Event> {
+        self.move_to(self.position + steps, cancel).await
+    }
+
+    /// Step towards home at the homing speed until the limit switch is active, which becomes
+    /// position 0
+    pub async fn home(&mut self, cancel: &CancellationToken) -> Result<Event> {
+        if self.limit.is_none() {
+            return Err(eyre!("{} has no limit switch to home against", self.name));
+        }
+        self.direction.set_value(0)?;
+
+        let interval = Duration::from_secs_f64(1.0 / self.homing_speed);
+        let mut taken = 0;
+        while !self.at_limit()? {
+            if taken == self.homing_range {
+                return Err(eyre!(
+                    "{} didn't reach its limit switch within {} steps",
+                    self.name,
+                    self.homing_range
+                ));
+            }
+            self.step(false)?;
+            self.wait(interval, cancel).await?;
+            taken += 1;
+        }
+
+        self.position = 0;
+        self.homed = true;
+        Ok(Event::Homed)
+    }
+
+    /// Whether the limit switch is active, never without one
+    fn at_limit(&self) -> Result<bool> {
+        match &self.limit {
+            Some(limit) => Ok(limit.value()? == 1),
+            None => Ok(false),
+        }
+    }
+
+    fn step(&mut self, forward: bool) -> Result<()> {
+        // drivers take a step on the rising edge, the pulse is as long as the line takes to drive
+        self.step.set_value(1)?;
+        self.step.set_value(0)?;
+        self.position += if forward { 1 } else { -1 };
+        Ok(())
+    }
+
+    async fn wait(&mut self, interval: Duration, cancel: &CancellationToken) -> Result<()> {
+        tokio::select! {
+            _ = time::sleep(interval) => Ok(()),
+            _ = cancel.cancelled() => Err(Cancelled.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl Component for StepperMotor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "stepper"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    /// The motor only moves when it's commanded to, it has no events of its own
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        future::pending().await
+    }
+
+    /// The step line is left low, the motor holds where it is
+    fn make_safe(&mut self) -> Result<Option<ComponentEvent>> {
+        self.step.set_value(0)?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manufacturing_components::gpio::mock::MockGpio;
+    use gpio_cdev::EventType;
+
+    fn fast(motor: StepperMotor) -> StepperMotor {
+        motor
+            .with_profile(Profile {
+                max_speed: 100_000.0,
+                acceleration: 1_000_000.0,
+            })
+            .with_homing(100_000.0, 100)
+    }
+
+    #[test]
+    fn moves_ramp_up_and_down() {
+        let profile = Profile {
+            max_speed: 1000.0,
+            acceleration: 2000.0,
+        };
+        let first = profile.interval(0, 1000);
+        let cruising = profile.interval(500, 500);
+        assert_eq!(first, profile.interval(999, 1));
+        assert!(first > profile.interval(10, 990));
+        assert_eq!(cruising, Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn positions_are_tracked_from_the_steps_sent() {
+        let mut gpio = MockGpio::default();
+        let mut motor = fast(StepperMotor::new("Stepper", &mut gpio, 0, 1).unwrap());
+        let cancel = CancellationToken::new();
+
+        motor.move_to(3, &cancel).await.unwrap();
+        assert_eq!(gpio.history(0), [0, 1, 0, 1, 0, 1, 0]);
+        assert_eq!(gpio.value(1), 1);
+
+        assert!(matches!(
+            motor.move_by(-2, &cancel).await.unwrap(),
+            Event::Moved { position: 1 }
+        ));
+        assert_eq!(gpio.value(1), 0);
+    }
+
+    #[tokio::test]
+    async fn homing_stops_at_the_limit_switch() {
+        let mut gpio = MockGpio::default();
+        let motor = StepperMotor::new("Stepper", &mut gpio, 0, 1).unwrap();
+        let mut motor = fast(
+            motor
+                .with_limit_line(&Settings::default(), &mut gpio, 2)
+                .unwrap(),
+        );
+        let cancel = CancellationToken::new();
+
+        motor.move_to(5, &cancel).await.unwrap();
+        gpio.edge(2, EventType::RisingEdge);
+        assert!(matches!(motor.home(&cancel).await, Ok(Event::Homed)));
+        assert_eq!(motor.position(), 0);
+        assert!(motor.is_homed());
+
+        // moves towards home stop at the switch rather than run into it
+        assert!(motor.move_to(-3, &cancel).await.is_err());
+        assert_eq!(motor.position(), 0);
+
+        gpio.edge(2, EventType::FallingEdge);
+        assert!(motor.home(&cancel).await.is_err());
+    }
+}
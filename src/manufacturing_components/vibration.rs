@@ -0,0 +1,373 @@
+use crate::manufacturing_components::{Component, ComponentEvent};
+use crate::utils::Iso8601Utc;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use linux_embedded_hal::I2cdev;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tracing::error;
+
+/// Address of an ADXL345 with its ALT ADDRESS pin low, 0x1D when it's high
+pub const DEFAULT_ADDRESS: u8 = 0x53;
+/// Samples taken per second by a sensor that wasn't given a rate
+pub const DEFAULT_RATE: u32 = 100;
+/// Samples in the window the vibration is computed over, a second at the default rate
+pub const DEFAULT_WINDOW: usize = 100;
+
+/// Registers of the ADXL345
+const POWER_CTL: u8 = 0x2D;
+const DATA_FORMAT: u8 = 0x31;
+const DATAX0: u8 = 0x32;
+/// Scale of a sample in full resolution, in g per least significant bit
+const SCALE: f32 = 0.0039;
+
+/// An accelerometer, read one sample at a time
+pub trait Accelerometer: Send {
+    /// Acceleration along the x, y and z axes, in g
+    fn read(&mut self) -> Result<[f32; 3]>;
+}
+
+/// An ADXL345 on an I2C bus, measuring ±16 g in full resolution
+pub struct Adxl345 {
+    i2c: I2cdev,
+    address: u8,
+}
+
+impl Adxl345 {
+    /// The sensor at address on the bus, e.g. /dev/i2c-1, put in measurement mode
+    pub fn new(bus: &str, address: u8) -> Result<Self> {
+        let mut i2c =
+            I2cdev::new(bus).map_err(|e| eyre!("Failed to open the I2C bus {bus}: {e}"))?;
+        // full resolution at ±16 g, then out of standby
+        for command in [[DATA_FORMAT, 0x0B], [POWER_CTL, 0x08]] {
+            i2c.write(address, &command)
+                .map_err(|e| eyre!("Failed to set up the accelerometer on {bus}: {e}"))?;
+        }
+        Ok(Self { i2c, address })
+    }
+}
+
+impl Accelerometer for Adxl345 {
+    fn read(&mut self) -> Result<[f32; 3]> {
+        let mut data = [0; 6];
+        self.i2c
+            .write_read(self.address, &[DATAX0], &mut data)
+            .map_err(|e| eyre!("Failed to read the accelerometer: {e}"))?;
+        let axis = |i: usize| i16::from_le_bytes([data[i], data[i + 1]]) as f32 * SCALE;
+        Ok([axis(0), axis(2), axis(4)])
+    }
+}
+
+/// The last samples of the magnitude of the acceleration. Gravity and any other constant
+/// acceleration are taken out as the mean of the window, what's left is the vibration
+#[derive(Debug)]
+struct Window {
+    samples: VecDeque<f32>,
+    size: usize,
+}
+
+impl Window {
+    fn new(size: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(size),
+            size,
+        }
+    }
+
+    fn push(&mut self, sample: f32) {
+        if self.samples.len() == self.size {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    fn is_full(&self) -> bool {
+        self.samples.len() == self.size
+    }
+
+    /// Deviation of every sample from the mean of the window
+    fn vibration(&self) -> impl Iterator<Item = f32> + '_ {
+        let mean = self.samples.iter().sum::<f32>() / self.samples.len() as f32;
+        self.samples.iter().map(move |sample| sample - mean)
+    }
+
+    /// Root mean square of the vibration, in g
+    fn rms(&self) -> f32 {
+        let squares: f32 = self.vibration().map(|v| v * v).sum();
+        (squares / self.samples.len() as f32).sqrt()
+    }
+
+    /// Largest deviation of the vibration, in g
+    fn peak(&self) -> f32 {
+        self.vibration().map(f32::abs).fold(0.0, f32::max)
+    }
+}
+
+/// What a threshold applies to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    Rms,
+    Peak,
+}
+
+/// Vibration levels above which the sensor raises an anomaly, in g
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Thresholds {
+    pub rms: Option<f32>,
+    pub peak: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+pub enum Event {
+    /// the vibration over the last window, in g
+    Measured { sensor: String, rms: f32, peak: f32 },
+    /// the vibration rose above a threshold, raised once until it's back below every threshold
+    Anomaly {
+        sensor: String,
+        metric: Metric,
+        value: f32,
+        threshold: f32,
+    },
+}
+
+/// A vibration sensor, e.g. on the robot track so the wear of its bearings is noticed before they
+/// fail. The accelerometer is sampled at a fixed rate and the vibration computed over a sliding
+/// window, reported every few samples. A sample that fails is logged and skipped
+pub struct VibrationSensor {
+    name: String,
+    accelerometer: Box<dyn Accelerometer>,
+    interval: Interval,
+    window: Window,
+    /// samples between two reports
+    report_every: usize,
+    /// samples taken since the last report
+    since_report: usize,
+    thresholds: Thresholds,
+    /// whether the anomaly was raised since the vibration last rose above a threshold
+    anomalous: bool,
+    /// the vibration of the last report
+    last: Option<(f32, f32)>,
+    /// the anomaly of the last report, published after it
+    pending_anomaly: Option<Event>,
+}
+
+impl Serialize for VibrationSensor {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("vibration", 4)?;
+        s.serialize_field("name", &self.name)?;
+        s.serialize_field("rms", &self.last.map(|(rms, _)| rms))?;
+        s.serialize_field("peak", &self.last.map(|(_, peak)| peak))?;
+
+        let now = SystemTime::iso8601_now();
+        s.serialize_field("updateTimestamp", &now)?;
+
+        s.end()
+    }
+}
+
+impl VibrationSensor {
+    /// The accelerometer sampled rate times a second, the vibration computed over window samples
+    /// and reported every report_every samples
+    pub fn new(
+        name: &str,
+        accelerometer: Box<dyn Accelerometer>,
+        rate: u32,
+        window: usize,
+        report_every: usize,
+    ) -> Self {
+        let mut interval = time::interval(Duration::from_secs(1) / rate);
+        // a cycle polls the sensor along with the rest of the cell, samples aren't caught up on
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            name: name.to_string(),
+            accelerometer,
+            interval,
+            window: Window::new(window),
+            report_every,
+            since_report: 0,
+            thresholds: Thresholds::default(),
+            anomalous: false,
+            last: None,
+            pending_anomaly: None,
+        }
+    }
+
+    pub fn with_thresholds(mut self, thresholds: Thresholds) -> Self {
+        self.set_thresholds(thresholds);
+        self
+    }
+
+    /// Change the thresholds, they apply from the next report
+    pub fn set_thresholds(&mut self, thresholds: Thresholds) {
+        self.thresholds = thresholds;
+    }
+
+    /// Wait for the next report, once the window is full, the anomaly of a report is returned
+    /// right after it
+    pub async fn async_next_event(&mut self) -> Event {
+        if let Some(anomaly) = self.pending_anomaly.take() {
+            return anomaly;
+        }
+
+        loop {
+            self.interval.tick().await;
+            if let Some(event) = self.sample() {
+                return event;
+            }
+        }
+    }
+
+    /// Take a sample, returning the report when one is due
+    fn sample(&mut self) -> Option<Event> {
+        match self.accelerometer.read() {
+            Ok([x, y, z]) => self.window.push((x * x + y * y + z * z).sqrt()),
+            Err(e) => {
+                error!("Failed to sample {}: {e}", self.name);
+                return None;
+            }
+        }
+        self.since_report += 1;
+        if !self.window.is_full() || self.since_report < self.report_every {
+            return None;
+        }
+
+        self.since_report = 0;
+        let (rms, peak) = (self.window.rms(), self.window.peak());
+        self.last = Some((rms, peak));
+        self.pending_anomaly = self.anomaly(rms, peak);
+        Some(Event::Measured {
+            sensor: self.name.clone(),
+            rms,
+            peak,
+        })
+    }
+
+    /// The anomaly of a vibration above a threshold, once until it's back below every threshold
+    fn anomaly(&mut self, rms: f32, peak: f32) -> Option<Event> {
+        let exceeded = [
+            (Metric::Rms, rms, self.thresholds.rms),
+            (Metric::Peak, peak, self.thresholds.peak),
+        ]
+        .into_iter()
+        .find_map(|(metric, value, threshold)| match threshold {
+            Some(threshold) if value > threshold => Some((metric, value, threshold)),
+            _ => None,
+        });
+
+        let (metric, value, threshold) = match exceeded {
+            Some(exceeded) => exceeded,
+            None => {
+                self.anomalous = false;
+                return None;
+            }
+        };
+        if self.anomalous {
+            return None;
+        }
+
+        self.anomalous = true;
+        Some(Event::Anomaly {
+            sensor: self.name.clone(),
+            metric,
+            value,
+            threshold,
+        })
+    }
+}
+
+#[async_trait]
+impl Component for VibrationSensor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn component(&self) -> &'static str {
+        "vibration"
+    }
+
+    fn serialize_state(&self) -> Value {
+        json!(self)
+    }
+
+    async fn next_event(&mut self) -> Result<ComponentEvent> {
+        Ok(self.async_next_event().await.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Replays the samples it was given, then keeps returning the last one
+    struct Replay(VecDeque<[f32; 3]>);
+
+    impl Accelerometer for Replay {
+        fn read(&mut self) -> Result<[f32; 3]> {
+            match self.0.len() {
+                0 => Err(eyre!("Nothing to replay")),
+                1 => Ok(self.0[0]),
+                _ => Ok(self.0.pop_front().unwrap()),
+            }
+        }
+    }
+
+    #[test]
+    fn gravity_is_taken_out_of_the_vibration() {
+        let mut window = Window::new(4);
+        for sample in [1.0, 1.0, 1.0, 1.0] {
+            window.push(sample);
+        }
+        assert_eq!(window.rms(), 0.0);
+
+        for sample in [1.5, 0.5, 1.5, 0.5] {
+            window.push(sample);
+        }
+        assert!((window.rms() - 0.5).abs() < 1e-6);
+        assert!((window.peak() - 0.5).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn anomalies_are_raised_once_until_back_below_the_thresholds() {
+        let mut samples = [[0.0, 0.0, 1.5], [0.0, 0.0, 0.5]].repeat(4);
+        samples.push([0.0, 0.0, 1.0]);
+        let accelerometer = Replay(samples.into_iter().collect());
+        let mut sensor = VibrationSensor::new("Track", Box::new(accelerometer), 100, 4, 2)
+            .with_thresholds(Thresholds {
+                rms: None,
+                peak: Some(0.3),
+            });
+
+        // nothing is reported until the window is full
+        let reports: Vec<_> = (0..4).filter_map(|_| sensor.sample()).collect();
+        assert!(matches!(reports[..], [Event::Measured { .. }]));
+        assert!(matches!(
+            sensor.pending_anomaly.take(),
+            Some(Event::Anomaly {
+                metric: Metric::Peak,
+                ..
+            })
+        ));
+
+        for _ in 0..4 {
+            sensor.sample();
+            assert!(sensor.pending_anomaly.is_none());
+        }
+        // the window is steady again
+        for _ in 0..4 {
+            sensor.sample();
+        }
+        assert!(!sensor.anomalous);
+        assert!(sensor.anomaly(0.1, 0.5).is_some());
+    }
+}
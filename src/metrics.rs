@@ -0,0 +1,225 @@
+use crate::backend::Backend;
+use crate::gcp_iot::message;
+use crate::manufacturing_components::program::{self, ProgramState};
+use crate::manufacturing_components::robot::{self, RobotPosition};
+use crate::manufacturing_components::{feeder, piston, ComponentEvent};
+use crate::transport::{Message, MqttTransport, QOS_0};
+use color_eyre::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::warn;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Aggregate of the durations measured over a report interval
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub count: u64,
+    pub min_ms: u64,
+    pub max_ms: u64,
+    pub mean_ms: u64,
+    #[serde(skip)]
+    total_ms: u64,
+}
+
+impl Stats {
+    fn record(&mut self, duration: Duration) {
+        let ms = duration.as_millis() as u64;
+        if self.count == 0 || ms < self.min_ms {
+            self.min_ms = ms;
+        }
+        self.max_ms = self.max_ms.max(ms);
+        self.count += 1;
+        self.total_ms += ms;
+        self.mean_ms = self.total_ms / self.count;
+    }
+}
+
+/// Timing of the cycles over a report interval, published for engineers to spot drifts in cycle
+/// time
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// from a material picked up to the piston pressing it
+    pub pickup_to_press: Stats,
+    /// between two materials picked up in the same run of a program, the pace of the cell
+    pub inter_cycle_gap: Stats,
+    /// from an arm reaching a position to it reaching the next one, by position
+    pub dwell: BTreeMap<String, Stats>,
+}
+
+impl Report {
+    fn is_empty(&self) -> bool {
+        self.pickup_to_press.count == 0 && self.inter_cycle_gap.count == 0 && self.dwell.is_empty()
+    }
+}
+
+/// What the durations being measured started with
+#[derive(Debug, Default)]
+struct Marks {
+    pickup: Option<Instant>,
+    /// the last pickup of the running program, cleared when it stops running
+    last_pickup: Option<Instant>,
+    /// the position each robot reached last, by name
+    positions: HashMap<String, (RobotPosition, Instant)>,
+}
+
+#[derive(Debug, Default)]
+struct Timings {
+    marks: Marks,
+    report: Report,
+}
+
+/// Handle shared by the publisher, which times the cycles from their events, and the task
+/// publishing the timings, cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct CycleMetrics {
+    timings: Arc<Mutex<Timings>>,
+}
+
+impl CycleMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time the event, which happened now
+    pub fn observe(&self, event: &ComponentEvent) {
+        self.observe_at(event, Instant::now());
+    }
+
+    fn observe_at(&self, event: &ComponentEvent, now: Instant) {
+        let mut timings = self.timings.lock().unwrap();
+        let Timings { marks, report } = &mut *timings;
+
+        match event {
+            ComponentEvent::Feeder(feeder::Event::MaterialPickedUp { .. }) => {
+                if let Some(last) = marks.last_pickup {
+                    report.inter_cycle_gap.record(now - last);
+                }
+                marks.last_pickup = Some(now);
+                marks.pickup = Some(now);
+            }
+            ComponentEvent::Piston(piston::Event::Depressed) => {
+                if let Some(pickup) = marks.pickup.take() {
+                    report.pickup_to_press.record(now - pickup);
+                }
+            }
+            ComponentEvent::Robot(robot::Event::PositionReached { robot, position }) => {
+                match marks.positions.get(robot).copied() {
+                    // the sensor signalling again at the same position isn't a move
+                    Some((last, _)) if last == *position => return,
+                    Some((last, reached)) => {
+                        let key = serde_json::to_value(last)
+                            .ok()
+                            .and_then(|key| key.as_str().map(str::to_string))
+                            .unwrap_or_else(|| format!("{last:?}"));
+                        report.dwell.entry(key).or_default().record(now - reached);
+                    }
+                    None => {}
+                }
+                marks.positions.insert(robot.clone(), (*position, now));
+            }
+            // the time a program is stopped or paused isn't the pace of the cell
+            ComponentEvent::Program(program::Event::Transition { to, .. })
+                if *to != ProgramState::Running =>
+            {
+                marks.last_pickup = None;
+                marks.pickup = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// The timings since the last report, starting over
+    pub fn take_report(&self) -> Report {
+        std::mem::take(&mut self.timings.lock().unwrap().report)
+    }
+}
+
+/// Spawn a task publishing the cycle timings on the metrics events topic every METRICS_INTERVAL
+/// milliseconds. Intervals without cycles aren't published, and neither are those ending while
+/// disconnected since the report would be stale by the time it's delivered
+pub fn spawn(
+    metrics: CycleMetrics,
+    backend: Backend,
+    client: impl MqttTransport + 'static,
+) -> Result<JoinHandle<()>> {
+    let interval = backend
+        .settings()
+        .positive("METRICS_INTERVAL", "milliseconds")?
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let topic = backend.event_topic("metrics")?;
+    let device_id = backend.device_id();
+
+    Ok(tokio::task::spawn(async move {
+        let mut interval = time::interval(interval);
+        // the first tick completes right away, there's nothing to report yet
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            let report = metrics.take_report();
+            if report.is_empty() || !client.is_connected() {
+                continue;
+            }
+
+            let payload = match message::seal(&device_id, &report) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Failed to encode the cycle metrics: {e}");
+                    continue;
+                }
+            };
+
+            if let Err(e) = client.publish(Message::new(&topic, payload, QOS_0)).await {
+                warn!("Failed to publish the cycle metrics: {e}");
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cycles_are_timed_from_their_events() {
+        let metrics = CycleMetrics::new();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let picked_up = || {
+            ComponentEvent::from(feeder::Event::MaterialPickedUp {
+                feeder: "Material feeder".to_string(),
+                material: None,
+            })
+        };
+        let reached = |position| {
+            ComponentEvent::from(robot::Event::PositionReached {
+                robot: "Robot".to_string(),
+                position,
+            })
+        };
+
+        metrics.observe_at(&reached(RobotPosition::Position1), at(0));
+        metrics.observe_at(&picked_up(), at(100));
+        metrics.observe_at(&reached(RobotPosition::Position15), at(1100));
+        metrics.observe_at(&piston::Event::Depressed.into(), at(1500));
+        metrics.observe_at(&reached(RobotPosition::Position1), at(3100));
+        metrics.observe_at(&picked_up(), at(3300));
+        metrics.observe_at(&piston::Event::Depressed.into(), at(4000));
+
+        let report = metrics.take_report();
+        assert_eq!(report.pickup_to_press.count, 2);
+        assert_eq!(report.pickup_to_press.mean_ms, 1050);
+        assert_eq!(report.inter_cycle_gap.mean_ms, 3200);
+        assert_eq!(report.dwell["position 1"].max_ms, 1100);
+        assert_eq!(report.dwell["position 15"].min_ms, 2000);
+        assert!(metrics.take_report().is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use crate::config::Settings;
+use crate::encoding::Encoding;
+use crate::envelope::Envelope;
+use crate::gcp_iot::topic::Topic;
+use crate::publisher::Outbound;
+use crate::reconnect::Backoff;
+use crate::transport::{self, Client, ConnectOptions, Message};
+use color_eyre::Result;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time;
+use tracing::{info, warn};
+
+/// Attempts to publish a message to the local broker before giving up on it
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Mirrors every outbound event to a broker on the plant network, for the plant floor systems that
+/// want the same events as the cloud.
+///
+/// Enabled with MIRROR_BROKER_URI, events are published on {MIRROR_TOPIC_PREFIX}/events/{component}
+/// with the prefix defaulting to tvilling. They're encoded as they're sent to the cloud, unless
+/// MIRROR_PAYLOAD_ENCODING selects another encoding for the plant floor. The mirror runs on its own task with its own retries, a
+/// slow or unreachable local broker never holds back the cloud publishing and the other way round
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    tx: UnboundedSender<Outbound>,
+    /// the encoding of the mirror when it differs from the one of the cloud
+    encoding: Option<Encoding>,
+}
+
+impl Mirror {
+    /// Connect to the local broker if MIRROR_BROKER_URI is set
+    pub async fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let broker_uri = match settings.get("MIRROR_BROKER_URI") {
+            Some(broker_uri) => broker_uri,
+            None => return Ok(None),
+        };
+        let topic_prefix = settings
+            .get("MIRROR_TOPIC_PREFIX")
+            .unwrap_or("tvilling")
+            .to_string();
+        let max_attempts = settings
+            .parse("MIRROR_MAX_ATTEMPTS", "unsigned integer")?
+            .unwrap_or(DEFAULT_MAX_ATTEMPTS);
+        let backoff = Backoff::from_settings(settings)?;
+        let encoding = Encoding::from_setting(settings, "MIRROR_PAYLOAD_ENCODING")?;
+        let encoding = (encoding != Encoding::from_settings(settings)?).then_some(encoding);
+
+        let mut options = ConnectOptions::new(broker_uri, mirror_client_id(settings));
+        if let Some(user_name) = settings.get("MIRROR_USERNAME") {
+            options.user_name = Some(user_name.to_string());
+            options.password = Some(
+                settings
+                    .get("MIRROR_PASSWORD")
+                    .unwrap_or_default()
+                    .to_string(),
+            );
+        }
+        // nothing is subscribed on the local broker, incoming messages are ignored
+        let (client, _stream) = transport::connect(&options).await?;
+        info!("Mirroring events to {broker_uri}");
+
+        let (tx, rx) = unbounded_channel();
+        let publisher = MirrorPublisher {
+            client,
+            options,
+            topic_prefix,
+            max_attempts,
+            backoff,
+        };
+        // the task ends once every event publisher holding the mirror is dropped
+        tokio::task::spawn(publisher.run(rx));
+        Ok(Some(Self { tx, encoding }))
+    }
+
+    /// Queue the event for the local broker, it's published with the QoS it has for the cloud and
+    /// the payload it has there, or encoded again when the mirror has an encoding of its own
+    pub fn mirror(&self, envelope: &Envelope, outbound: &Outbound) {
+        let mut outbound = outbound.clone();
+        if let Some(encoding) = self.encoding {
+            match encoding.encode_event(envelope) {
+                Ok(payload) => outbound.payload = payload,
+                Err(e) => {
+                    warn!(
+                        "Unable to encode event {} to mirror it, {e}",
+                        outbound.sequence
+                    );
+                    return;
+                }
+            }
+        }
+
+        if let Err(e) = self.tx.send(outbound) {
+            warn!(
+                "Mirror task has stopped, event {} not mirrored",
+                e.0.sequence
+            );
+        }
+    }
+}
+
+fn mirror_client_id(settings: &Settings) -> String {
+    settings
+        .get("MIRROR_CLIENT_ID")
+        .unwrap_or("tvilling-mirror")
+        .to_string()
+}
+
+/// Publishes the mirrored messages to the local broker, reconnecting whenever it's lost
+struct MirrorPublisher {
+    client: Client,
+    options: ConnectOptions,
+    topic_prefix: String,
+    max_attempts: u32,
+    backoff: Backoff,
+}
+
+impl MirrorPublisher {
+    /// Publish the mirrored messages in order, retrying each with backoff before dropping it
+    async fn run(mut self, mut rx: UnboundedReceiver<Outbound>) {
+        while let Some(outbound) = rx.recv().await {
+            let topic = match Topic::prefixed(&self.topic_prefix).event(&outbound.component) {
+                Ok(topic) => topic,
+                Err(e) => {
+                    warn!("Dropped mirrored event {}, {e}", outbound.sequence);
+                    continue;
+                }
+            };
+            self.backoff.reset();
+
+            loop {
+                match self.publish(&topic, &outbound).await {
+                    Ok(()) => break,
+                    Err(e) if self.backoff.attempts() + 1 >= self.max_attempts => {
+                        warn!(
+                            "Dropping mirrored event {} after {} attempts: {e}",
+                            outbound.sequence, self.max_attempts
+                        );
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Failed to mirror event {}: {e}", outbound.sequence);
+                        time::sleep(self.backoff.next_delay()).await;
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = self.client.disconnect(Duration::ZERO).await {
+            warn!("Failed to disconnect from the mirror broker: {e}");
+        }
+    }
+
+    /// Publish the message with the QoS it has for the cloud, reconnecting first if the connection
+    /// was lost
+    async fn publish(&self, topic: &str, outbound: &Outbound) -> Result<()> {
+        if !self.client.is_connected() {
+            self.client.reconnect(&self.options).await?;
+        }
+        let msg = Message::new(topic, outbound.payload.clone(), outbound.qos);
+        self.client.publish(msg).await
+    }
+}
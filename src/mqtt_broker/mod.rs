@@ -0,0 +1,119 @@
+use crate::backend::{required, CloudError};
+use crate::config::Settings;
+use crate::session::Session;
+use crate::tls::{self, TlsConfig};
+use crate::transport::{
+    self, Client, ConnectOptions, Message, MessageStream, MQTT_VERSION_3_1_1, MQTT_VERSION_5,
+};
+use color_eyre::Result;
+use tracing::warn;
+
+/// Prefix for all the topics on the broker, defaults to tvilling/<client id>
+pub fn topic_prefix(settings: &Settings) -> String {
+    match settings.get("MQTT_TOPIC_PREFIX") {
+        Some(prefix) => prefix.to_string(),
+        None => format!("tvilling/{}", client_id(settings)),
+    }
+}
+
+/// MQTT_VERSION=5 to use MQTT v5 if the broker supports it, 3.1.1 otherwise
+fn wants_v5(settings: &Settings) -> bool {
+    settings.get("MQTT_VERSION") == Some("5")
+}
+
+/// How long the broker keeps the session after a disconnect, only used with MQTT v5. Defaults to a
+/// day for persistent sessions, which would otherwise end with the connection
+fn session_expiry(settings: &Settings, session: &Session) -> Result<i32, CloudError> {
+    let default = if session.persistent { 24 * 60 * 60 } else { 0 };
+
+    match settings.get("MQTT_SESSION_EXPIRY") {
+        Some(secs) => secs.parse().map_err(|_| CloudError::Invalid {
+            variable: "MQTT_SESSION_EXPIRY",
+            expected: "seconds",
+        }),
+        None => Ok(default),
+    }
+}
+
+pub fn client_id(settings: &Settings) -> String {
+    settings
+        .get("MQTT_CLIENT_ID")
+        .unwrap_or("tvilling")
+        .to_string()
+}
+
+/// Builds the connect options for a self hosted broker, both authentication methods are optional
+/// since brokers on the factory network are often left open:
+///
+/// * MQTT_USERNAME and MQTT_PASSWORD for password authentication
+/// * MQTT_CA_CERTIFICATE to connect over TLS, with MQTT_CLIENT_CERTIFICATE and MQTT_CLIENT_KEY for
+///   client certificate authentication
+pub fn get_connect_ops(
+    settings: &Settings,
+    mqtt_version: u32,
+    will: Option<Message>,
+) -> Result<ConnectOptions> {
+    // e.g. tcp://localhost:1883 for mosquitto or ssl://emqx.local:8883 with TLS
+    let broker_uri = required(settings, "MQTT_BROKER_URI")?;
+    let session = Session::from_settings(settings);
+    let mut options = ConnectOptions {
+        mqtt_version,
+        clean_session: session.clean(),
+        will,
+        persistence_dir: session.persistence_dir(),
+        ..ConnectOptions::new(broker_uri, client_id(settings))
+    };
+
+    if mqtt_version == MQTT_VERSION_5 {
+        options.session_expiry = Some(session_expiry(settings, &session)?);
+    }
+
+    if let Some(user_name) = settings.get("MQTT_USERNAME") {
+        options.user_name = Some(user_name.to_string());
+        options.password = settings.get("MQTT_PASSWORD").map(str::to_string);
+    }
+
+    if let Some(ca_certificate) = settings.get("MQTT_CA_CERTIFICATE") {
+        let mut tls = TlsConfig::from_settings(settings)?
+            .builder()
+            .trust_store(ca_certificate);
+
+        if let Some(certificate) = settings.get("MQTT_CLIENT_CERTIFICATE") {
+            let key = settings
+                .get("MQTT_CLIENT_KEY")
+                .ok_or(CloudError::MissingFor {
+                    variable: "MQTT_CLIENT_KEY",
+                    required_by: "MQTT_CLIENT_CERTIFICATE",
+                })?;
+            tls = tls.key_store(certificate).private_key(key);
+        }
+
+        options.tls = Some(tls);
+    }
+
+    Ok(options)
+}
+
+async fn connect_with(
+    settings: &Settings,
+    mqtt_version: u32,
+    will: Option<Message>,
+) -> Result<(Client, MessageStream)> {
+    transport::connect(&get_connect_ops(settings, mqtt_version, will)?)
+        .await
+        .map_err(tls::connect_error)
+}
+
+pub async fn connect(
+    settings: &Settings,
+    will: Option<Message>,
+) -> Result<(Client, MessageStream)> {
+    if wants_v5(settings) {
+        match connect_with(settings, MQTT_VERSION_5, will.clone()).await {
+            Ok(connection) => return Ok(connection),
+            Err(e) => warn!("Unable to connect with MQTT v5, falling back to 3.1.1: {e}"),
+        }
+    }
+
+    connect_with(settings, MQTT_VERSION_3_1_1, will).await
+}
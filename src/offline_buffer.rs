@@ -0,0 +1,116 @@
+use crate::config::Settings;
+use crate::publisher::Outbound;
+use crate::utils;
+use color_eyre::Result;
+use std::path::PathBuf;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::task;
+
+/// Store and forward buffer for messages that couldn't be published while the connection is down.
+///
+/// Messages are appended as JSON lines to OFFLINE_BUFFER_PATH, so they survive a restart of the
+/// twin, and are replayed in the order they were buffered
+pub struct OfflineBuffer {
+    path: PathBuf,
+}
+
+impl OfflineBuffer {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self::new(
+            settings
+                .get("OFFLINE_BUFFER_PATH")
+                .unwrap_or("offline_events.jsonl"),
+        )
+    }
+
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub async fn push(&self, outbound: &Outbound) -> Result<()> {
+        let mut line = serde_json::to_string(outbound)?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        match fs::metadata(&self.path).await {
+            Ok(metadata) => metadata.len() == 0,
+            Err(_) => true,
+        }
+    }
+
+    /// Returns every buffered message, oldest first
+    pub async fn pending(&self) -> Result<Vec<Outbound>> {
+        if self.is_empty().await {
+            return Ok(Vec::new());
+        }
+
+        let content = fs::read_to_string(&self.path).await?;
+        let pending = content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(pending)
+    }
+
+    /// Replaces the buffer content with the messages that are still pending after a flush
+    pub async fn retain(&self, remaining: &[Outbound]) -> Result<()> {
+        if remaining.is_empty() {
+            if fs::metadata(&self.path).await.is_ok() {
+                fs::remove_file(&self.path).await?;
+            }
+            return Ok(());
+        }
+
+        let mut content = String::new();
+        for outbound in remaining {
+            content.push_str(&serde_json::to_string(outbound)?);
+            content.push('\n');
+        }
+
+        let path = self.path.clone();
+        task::spawn_blocking(move || utils::write_atomic(&path, content)).await??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn buffered_messages_are_replayed_in_order() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("offline_buffer.jsonl");
+        let buffer = OfflineBuffer::new(&path);
+        buffer.retain(&[]).await?;
+        assert!(buffer.is_empty().await);
+
+        for sequence in 0..3 {
+            buffer
+                .push(&Outbound::test("/devices/pi/events/feeder", sequence))
+                .await?;
+        }
+        let pending = buffer.pending().await?;
+        let sequences: Vec<_> = pending.iter().map(|outbound| outbound.sequence).collect();
+        assert_eq!(sequences, vec![0, 1, 2]);
+
+        buffer.retain(&pending[2..]).await?;
+        assert_eq!(buffer.pending().await?.len(), 1);
+
+        buffer.retain(&[]).await?;
+        assert!(buffer.is_empty().await);
+        Ok(())
+    }
+}
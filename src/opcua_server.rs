@@ -0,0 +1,216 @@
+use crate::config::Settings;
+use crate::manufacturing_components::program::ProgramStatus;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use opcua::server::prelude::{
+    AddressSpace, DataTypeId, DateTime, NodeId, Server, ServerBuilder, UAString, VariableBuilder,
+    Variant,
+};
+use opcua::sync::RwLock;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+const NAMESPACE_URI: &str = "urn:tvilling:twin";
+
+/// The variables of the twin, by component folder, with the data type of their node
+const VARIABLES: [(&str, &str, DataTypeId); 5] = [
+    ("Feeder", "Count", DataTypeId::UInt32),
+    ("FeederB", "Count", DataTypeId::UInt32),
+    ("Robot", "Position", DataTypeId::String),
+    ("Piston", "State", DataTypeId::String),
+    ("Program", "Status", DataTypeId::String),
+];
+
+/// Identifier of the node of a variable, e.g. Robot.Position
+fn node_path(component: &str, variable: &str) -> String {
+    format!("{component}.{variable}")
+}
+
+fn string(value: &str) -> Variant {
+    Variant::String(UAString::from(value))
+}
+
+/// Values of the variables, taken from the twin state and the program status. Components that
+/// aren't part of the twin are left out
+fn variable_values(state: &Value, status: ProgramStatus) -> Vec<(String, Variant)> {
+    let count = |feeder: &str| state.get(feeder)?["count"].as_u64();
+    let text = |component: &str, field: &str| state.get(component)?[field].as_str();
+
+    let mut values = Vec::new();
+    if let Some(count) = count("feeder") {
+        values.push((node_path("Feeder", "Count"), Variant::UInt32(count as u32)));
+    }
+    if let Some(count) = count("feeder_b") {
+        values.push((node_path("FeederB", "Count"), Variant::UInt32(count as u32)));
+    }
+    if let Some(position) = text("robot", "position") {
+        values.push((node_path("Robot", "Position"), string(position)));
+    }
+    if let Some(piston) = text("piston", "state") {
+        values.push((node_path("Piston", "State"), string(piston)));
+    }
+    // the status reads as it's reported in the twin snapshots, e.g. emergency-stopped
+    if let Value::String(status) = serde_json::to_value(status).unwrap_or_default() {
+        values.push((node_path("Program", "Status"), string(&status)));
+    }
+    values
+}
+
+/// The twin as an OPC UA server on the plant network, so MES systems can browse it without going
+/// through the cloud, enabled by setting OPCUA_SERVER_PORT.
+///
+/// Every component is a folder of the Twin folder, whose variables are updated live from the twin
+/// state. Clients connect anonymously, without security, the server is meant for the plant network
+/// only
+pub struct TwinServer {
+    server: Server,
+    address_space: Arc<RwLock<AddressSpace>>,
+    namespace: u16,
+}
+
+impl TwinServer {
+    /// The server listening on every interface on port, with the nodes of the twin
+    pub fn new(port: u16) -> Result<Self> {
+        let server = ServerBuilder::new_anonymous("tvilling")
+            .application_uri("urn:tvilling")
+            .product_uri("urn:tvilling")
+            .host_and_port("0.0.0.0", port)
+            .discovery_urls(vec!["/".to_string()])
+            .pki_dir("./pki-server")
+            .create_sample_keypair(true)
+            .server()
+            .ok_or_else(|| eyre!("Invalid OPC UA server configuration"))?;
+
+        let address_space = server.address_space();
+        let namespace = {
+            let mut space = address_space.write();
+            let namespace = space
+                .register_namespace(NAMESPACE_URI)
+                .map_err(|_| eyre!("Failed to register the namespace of the twin"))?;
+            let twin = space
+                .add_folder("Twin", "Twin", &NodeId::objects_folder_id())
+                .map_err(|_| eyre!("Failed to add the Twin folder"))?;
+
+            let mut folders: HashMap<&str, NodeId> = HashMap::new();
+            for (component, variable, data_type) in VARIABLES {
+                let folder = match folders.get(component).cloned() {
+                    Some(folder) => folder,
+                    None => {
+                        let folder = space
+                            .add_folder(component, component, &twin)
+                            .map_err(|_| eyre!("Failed to add the {component} folder"))?;
+                        folders.insert(component, folder.clone());
+                        folder
+                    }
+                };
+                let initial = match data_type {
+                    DataTypeId::UInt32 => Variant::UInt32(0),
+                    _ => string(""),
+                };
+                VariableBuilder::new(
+                    &NodeId::new(namespace, node_path(component, variable)),
+                    variable,
+                    variable,
+                )
+                .data_type(data_type)
+                .value(initial)
+                .organized_by(&folder)
+                .insert(&mut space);
+            }
+            namespace
+        };
+
+        Ok(Self {
+            server,
+            address_space,
+            namespace,
+        })
+    }
+
+    /// The server on OPCUA_SERVER_PORT, None when the twin isn't served locally
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        match settings.parse("OPCUA_SERVER_PORT", "a port")? {
+            Some(port) => Ok(Some(Self::new(port)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn update(
+        address_space: &RwLock<AddressSpace>,
+        namespace: u16,
+        values: Vec<(String, Variant)>,
+    ) {
+        let now = DateTime::now();
+        let mut space = address_space.write();
+        for (path, value) in values {
+            if !space.set_variable_value(NodeId::new(namespace, path.clone()), value, &now, &now) {
+                error!("The OPC UA server has no node {path}");
+            }
+        }
+    }
+
+    /// Run the server, which has a runtime of its own, on a thread, and spawn the task updating
+    /// its variables whenever the twin state or the program status changes
+    pub fn spawn(
+        self,
+        mut state_rx: watch::Receiver<Value>,
+        mut status_rx: watch::Receiver<ProgramStatus>,
+    ) -> Result<JoinHandle<()>> {
+        let Self {
+            server,
+            address_space,
+            namespace,
+        } = self;
+        thread::Builder::new()
+            .name("opcua-server".to_string())
+            .spawn(move || server.run())?;
+        info!("Serving the twin over OPC UA");
+
+        Ok(tokio::task::spawn(async move {
+            loop {
+                let values = variable_values(
+                    &state_rx.borrow_and_update(),
+                    *status_rx.borrow_and_update(),
+                );
+                Self::update(&address_space, namespace, values);
+
+                let changed = tokio::select! {
+                    changed = state_rx.changed() => changed,
+                    changed = status_rx.changed() => changed,
+                };
+                if changed.is_err() {
+                    break;
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn variables_are_taken_from_the_twin_state() {
+        let state = json!({
+            "feeder": { "name": "Material feeder", "count": 7 },
+            "robot": { "name": "robot", "position": "position 15" },
+        });
+
+        let values = variable_values(&state, ProgramStatus::EmergencyStopped);
+        assert_eq!(
+            values,
+            vec![
+                ("Feeder.Count".to_string(), Variant::UInt32(7)),
+                ("Robot.Position".to_string(), string("position 15")),
+                ("Program.Status".to_string(), string("emergency-stopped")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,9 @@
+//! The pipeline the events of the cell go through on their way to the cloud: stamped by the
+//! sender of the events channel, timed, encoded, batched, compressed and published, or buffered
+//! while the twin is offline. The state of the twin is reported alongside them
+
+pub use crate::envelope::{channel, Envelope, EventSender};
+pub use crate::metrics::CycleMetrics;
+pub use crate::mirror::Mirror;
+pub use crate::publisher::{EventPublisher, HttpFallback, Redrive};
+pub use crate::state_reporter::{self, SnapshotPublisher};
@@ -0,0 +1,523 @@
+use crate::backend::Backend;
+use crate::batcher::Batcher;
+use crate::cancellation::CancellationToken;
+use crate::compression::Compression;
+use crate::config::{ConfigError, Settings};
+use crate::diagnostics::Diagnostics;
+use crate::encoding::Encoding;
+use crate::envelope::Envelope;
+use crate::gcp_iot::http_bridge::HttpBridge;
+use crate::gcp_iot::message;
+use crate::manufacturing_components::EventKind;
+use crate::metrics::CycleMetrics;
+use crate::mirror::Mirror;
+use crate::offline_buffer::OfflineBuffer;
+use crate::rate_limiter::RateLimiter;
+use crate::transport::{Client, Message, Properties, MQTT_VERSION_5, QOS_0, QOS_1};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::{self, Instant};
+use tracing::{error, info, warn};
+
+/// How often the offline buffer is checked for messages to flush when no new events arrive
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+/// How often messages held back by the rate limiter are released
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// QoS each kind of event is published with, overridable with QOS_TELEMETRY, QOS_POSITION,
+/// QOS_ALARM and QOS_ACK.
+///
+/// By default everything is delivered at least once, except position updates which are frequent
+/// and superseded by the next one anyway
+#[derive(Debug, Clone)]
+pub struct QosPolicy {
+    pub telemetry: i32,
+    pub position: i32,
+    pub alarm: i32,
+    pub ack: i32,
+}
+
+impl Default for QosPolicy {
+    fn default() -> Self {
+        Self {
+            telemetry: QOS_1,
+            position: QOS_0,
+            alarm: QOS_1,
+            ack: QOS_1,
+        }
+    }
+}
+
+impl QosPolicy {
+    pub fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        let default = Self::default();
+        let qos = |key: &str, default: i32| qos_setting(settings, key, default);
+
+        Ok(Self {
+            telemetry: qos("QOS_TELEMETRY", default.telemetry)?,
+            position: qos("QOS_POSITION", default.position)?,
+            alarm: qos("QOS_ALARM", default.alarm)?,
+            ack: qos("QOS_ACK", default.ack)?,
+        })
+    }
+
+    pub fn qos(&self, kind: EventKind) -> i32 {
+        match kind {
+            EventKind::Telemetry => self.telemetry,
+            EventKind::Position => self.position,
+            EventKind::Alarm => self.alarm,
+            EventKind::Ack => self.ack,
+        }
+    }
+}
+
+fn qos_setting(settings: &Settings, key: &str, default: i32) -> Result<i32, ConfigError> {
+    match settings.get(key) {
+        Some(qos) => match qos.parse() {
+            Ok(qos @ 0..=2) => Ok(qos),
+            _ => Err(ConfigError::invalid(key, "a QoS level of 0, 1 or 2", qos)),
+        },
+        None => Ok(default),
+    }
+}
+
+/// A message ready to be published, kept independent of the MQTT client so it can be buffered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Outbound {
+    pub topic: String,
+    /// already encoded, stored as base64 when buffered
+    #[serde(with = "crate::utils::base64_bytes")]
+    pub payload: Vec<u8>,
+    pub qos: i32,
+    pub component: String,
+    pub sequence: u64,
+    /// failed publish attempts, the message is moved to the dead letters after PUBLISH_MAX_ATTEMPTS
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+#[cfg(test)]
+impl Outbound {
+    /// An event of the feeder published on topic, its payload being the sequence number
+    pub fn test(topic: &str, sequence: u64) -> Self {
+        Self {
+            topic: topic.to_string(),
+            payload: sequence.to_string().into_bytes(),
+            qos: 0,
+            component: "feeder".to_string(),
+            sequence,
+            attempts: 0,
+        }
+    }
+}
+
+/// Requests the event publisher to re-attempt delivery of the dead letters
+#[derive(Debug, Clone)]
+pub struct Redrive {
+    tx: UnboundedSender<oneshot::Sender<Result<usize>>>,
+}
+
+impl Redrive {
+    /// Returns the number of dead letters queued for delivery again
+    pub async fn request(&self) -> Result<usize> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.tx
+            .send(reply_tx)
+            .map_err(|_| eyre!("Event publisher has stopped"))?;
+        reply_rx
+            .await
+            .map_err(|_| eyre!("Event publisher has stopped"))?
+    }
+}
+
+/// Publishes component events to their topic on the backend, batched and rate limited per topic.
+///
+/// When connected with MQTT v5 every event carries the component name and a sequence number as
+/// user properties, and expires after MESSAGE_EXPIRY seconds if it hasn't been delivered
+pub struct EventPublisher {
+    client: Client,
+    backend: Backend,
+    qos: QosPolicy,
+    message_expiry: Option<i32>,
+    buffer: OfflineBuffer,
+    dead_letters: OfflineBuffer,
+    max_attempts: u32,
+    redrive_tx: UnboundedSender<oneshot::Sender<Result<usize>>>,
+    redrive_rx: Option<UnboundedReceiver<oneshot::Sender<Result<usize>>>>,
+    batcher: Batcher,
+    rate_limiter: RateLimiter,
+    encoding: Encoding,
+    compression: Compression,
+    diagnostics: Diagnostics,
+    mirror: Option<Mirror>,
+    fallback: Option<HttpFallback>,
+    metrics: Option<CycleMetrics>,
+}
+
+/// Publishes through the Google IoT HTTP bridge once HTTP_FALLBACK_AFTER reconnection attempts in a
+/// row have failed, e.g. when the plant firewall blocks MQTT egress. MQTT takes over again as soon
+/// as the connection is back
+pub struct HttpFallback {
+    bridge: HttpBridge,
+    after_attempts: u32,
+}
+
+impl HttpFallback {
+    /// Only available with the Google IoT backend, when HTTP_FALLBACK_AFTER is set
+    pub fn from_backend(backend: &Backend) -> Result<Option<Self>> {
+        if !matches!(backend, Backend::Gcp { .. }) {
+            return Ok(None);
+        }
+
+        let settings = backend.settings();
+        let after_attempts = match settings.parse("HTTP_FALLBACK_AFTER", "unsigned integer")? {
+            Some(after_attempts) => after_attempts,
+            None => return Ok(None),
+        };
+        Ok(Some(Self {
+            bridge: HttpBridge::from_settings(settings)?,
+            after_attempts,
+        }))
+    }
+}
+
+impl EventPublisher {
+    pub fn new(
+        client: Client,
+        backend: Backend,
+        diagnostics: Diagnostics,
+    ) -> Result<Self, ConfigError> {
+        let settings = backend.settings().clone();
+        let message_expiry = settings.parse("MESSAGE_EXPIRY", "seconds")?;
+
+        let encoding = Encoding::from_settings(&settings)?;
+        let dead_letters = OfflineBuffer::new(
+            settings
+                .get("DEAD_LETTER_PATH")
+                .unwrap_or("dead_letters.jsonl"),
+        );
+        let max_attempts = settings
+            .parse("PUBLISH_MAX_ATTEMPTS", "unsigned integer")?
+            .unwrap_or(DEFAULT_MAX_ATTEMPTS);
+        let (redrive_tx, redrive_rx) = unbounded_channel();
+
+        Ok(Self {
+            client,
+            backend,
+            qos: QosPolicy::from_settings(&settings)?,
+            message_expiry,
+            buffer: OfflineBuffer::from_settings(&settings),
+            dead_letters,
+            max_attempts,
+            redrive_tx,
+            redrive_rx: Some(redrive_rx),
+            batcher: Batcher::from_settings(&settings, encoding)?,
+            rate_limiter: RateLimiter::from_settings(&settings)?,
+            encoding,
+            compression: Compression::from_settings(&settings)?,
+            diagnostics,
+            mirror: None,
+            fallback: None,
+            metrics: None,
+        })
+    }
+
+    /// Handle to move the dead letters back to the offline buffer while the publisher runs
+    pub fn redrive(&self) -> Redrive {
+        Redrive {
+            tx: self.redrive_tx.clone(),
+        }
+    }
+
+    /// Publish over HTTP while MQTT can't reconnect
+    pub fn with_fallback(mut self, fallback: HttpFallback) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Also publish every event to a local broker
+    pub fn with_mirror(mut self, mirror: Mirror) -> Self {
+        self.mirror = Some(mirror);
+        self
+    }
+
+    /// Time the cycles from the events as they're received, see CycleMetrics
+    pub fn with_metrics(mut self, metrics: CycleMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Publish events received on the channel until every sender is dropped, or until done is
+    /// cancelled, once the events already sent are published.
+    ///
+    /// While the client is disconnected, or older messages are still waiting in the offline buffer,
+    /// new messages are buffered so they are always delivered in order. Open batches are published
+    /// before returning
+    pub async fn run(mut self, mut rx: UnboundedReceiver<Envelope>, done: CancellationToken) {
+        let mut flush_interval = time::interval(FLUSH_INTERVAL);
+        let mut batch_interval = time::interval(self.batcher.window());
+        let mut rate_limit_interval = time::interval(RATE_LIMIT_INTERVAL);
+        // the publisher holds a sender, the channel never closes while it runs
+        let mut redrive_rx = self.redrive_rx.take().unwrap();
+
+        loop {
+            tokio::select! {
+                event = rx.recv() => {
+                    match event {
+                        Some(event) => {
+                            if let Some(metrics) = &self.metrics {
+                                metrics.observe(&event.event);
+                            }
+                            if let Err(e) = self.publish(&event).await {
+                                error!("Failed to publish {event:?}: {e}");
+                            }
+                        }
+                        None => break,
+                    }
+                }
+                // a sender may be kept past shutdown, the events already queued are drained
+                _ = done.cancelled() => {
+                    while let Ok(event) = rx.try_recv() {
+                        if let Some(metrics) = &self.metrics {
+                            metrics.observe(&event.event);
+                        }
+                        if let Err(e) = self.publish(&event).await {
+                            error!("Failed to publish {event:?}: {e}");
+                        }
+                    }
+                    break;
+                }
+                _ = flush_interval.tick() => {}
+                Some(reply) = redrive_rx.recv() => {
+                    reply.send(self.redrive_dead_letters().await).ok();
+                }
+                _ = batch_interval.tick() => {
+                    for batch in self.batcher.take_expired(Instant::now()) {
+                        if let Some(batch) = self.rate_limiter.admit(batch, Instant::now()) {
+                            if let Err(e) = self.dispatch(&batch).await {
+                                error!("Failed to publish batch on {}: {e}", batch.topic);
+                            }
+                        }
+                    }
+                }
+                _ = rate_limit_interval.tick() => {
+                    for outbound in self.rate_limiter.take_ready(Instant::now()) {
+                        if let Err(e) = self.dispatch(&outbound).await {
+                            error!("Failed to publish on {}: {e}", outbound.topic);
+                        }
+                    }
+                }
+            }
+
+            if let Err(e) = self.flush().await {
+                warn!("Failed to flush the offline buffer: {e}");
+            }
+        }
+
+        let held_back = self.rate_limiter.take_all();
+        for batch in held_back.into_iter().chain(self.batcher.take_all()) {
+            if let Err(e) = self.dispatch(&batch).await {
+                error!("Failed to publish batch on {}: {e}", batch.topic);
+            }
+        }
+    }
+
+    pub async fn publish(&mut self, event: &Envelope) -> Result<()> {
+        let outbound = self.prepare(event)?;
+        if let Some(mirror) = &self.mirror {
+            mirror.mirror(event, &outbound);
+        }
+
+        let ready = match self.batcher.push(outbound) {
+            Some(ready) => ready,
+            None => return Ok(()),
+        };
+
+        match self.rate_limiter.admit(ready, Instant::now()) {
+            Some(admitted) => self.dispatch(&admitted).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Send the message right away, or buffer it if it can't be sent
+    async fn dispatch(&self, outbound: &Outbound) -> Result<()> {
+        if !self.can_deliver() || !self.buffer.is_empty().await {
+            return self.buffer.push(outbound).await;
+        }
+
+        if let Err(e) = self.deliver(outbound).await {
+            self.diagnostics.record_publish_failure();
+            warn!(
+                "Buffering event {} after failed publish: {e}",
+                outbound.sequence
+            );
+            let mut outbound = outbound.clone();
+            outbound.attempts += 1;
+            self.buffer.push(&outbound).await?;
+        }
+        Ok(())
+    }
+
+    fn prepare(&self, envelope: &Envelope) -> Result<Outbound> {
+        let event = &envelope.event;
+
+        Ok(Outbound {
+            topic: self.backend.event_topic(event.topic())?,
+            payload: match self.encoding {
+                // binary encodings have their own schema, only JSON events are sent in the message
+                // envelope
+                Encoding::Json => message::seal(&self.backend.device_id(), envelope)?,
+                encoding => encoding.encode_event(envelope)?,
+            },
+            qos: self.qos.qos(event.kind()),
+            component: event.component().to_string(),
+            sequence: envelope.sequence,
+            attempts: 0,
+        })
+    }
+
+    fn fallback_active(&self) -> bool {
+        match &self.fallback {
+            Some(fallback) => {
+                !self.client.is_connected()
+                    && self.diagnostics.failed_reconnect_attempts() >= fallback.after_attempts
+            }
+            None => false,
+        }
+    }
+
+    fn can_deliver(&self) -> bool {
+        self.client.is_connected() || self.fallback_active()
+    }
+
+    /// Send over MQTT, or through the HTTP fallback while it's active
+    async fn deliver(&self, outbound: &Outbound) -> Result<()> {
+        match &self.fallback {
+            Some(fallback) if self.fallback_active() => {
+                // without properties the markers are added to the subfolder
+                let sub_folder = self.marked(&outbound.component);
+                let payload = self.compression.compress(&outbound.payload)?;
+                fallback.bridge.publish_event(&sub_folder, &payload).await
+            }
+            _ if self.client.is_connected() => self.send(outbound).await,
+            _ => Err(eyre!("Not connected")),
+        }
+    }
+
+    /// Marks non default formats and encodings with extra topic levels
+    fn marked(&self, topic: &str) -> String {
+        let mut topic = topic.to_string();
+        for marker in [self.encoding.marker(), self.compression.encoding()]
+            .into_iter()
+            .flatten()
+        {
+            topic = format!("{topic}/{marker}");
+        }
+        topic
+    }
+
+    async fn send(&self, outbound: &Outbound) -> Result<()> {
+        let is_v5 = self.client.mqtt_version() == MQTT_VERSION_5;
+
+        // without properties the format and encoding are only known from the topic
+        let topic = if is_v5 {
+            outbound.topic.clone()
+        } else {
+            self.marked(&outbound.topic)
+        };
+        let payload = self.compression.compress(&outbound.payload)?;
+
+        let mut msg = Message::new(topic, payload, outbound.qos);
+        if is_v5 {
+            msg = msg.with_properties(self.properties(outbound));
+        }
+
+        // completes once the broker acknowledges the message, or right away with QoS 0
+        let sent = Instant::now();
+        self.client.publish(msg).await?;
+        if outbound.qos > QOS_0 {
+            self.diagnostics.record_round_trip(sent.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Publish the buffered messages in order, stopping at the first failure so the rest are kept.
+    /// A message failing for the PUBLISH_MAX_ATTEMPTS time is moved to the dead letters instead
+    async fn flush(&self) -> Result<()> {
+        if !self.can_deliver() || self.buffer.is_empty().await {
+            return Ok(());
+        }
+
+        let mut pending = self.buffer.pending().await?;
+        for i in 0..pending.len() {
+            if let Err(e) = self.deliver(&pending[i]).await {
+                pending[i].attempts += 1;
+                if pending[i].attempts >= self.max_attempts {
+                    error!(
+                        "Moving event {} to the dead letters after {} attempts: {e}",
+                        pending[i].sequence, pending[i].attempts
+                    );
+                    self.dead_letters.push(&pending[i]).await?;
+                    self.buffer.retain(&pending[i + 1..]).await?;
+                } else {
+                    self.buffer.retain(&pending[i..]).await?;
+                }
+                return Err(e);
+            }
+        }
+
+        info!("Flushed {} buffered events", pending.len());
+        self.buffer.retain(&[]).await
+    }
+
+    /// Move the dead letters back to the offline buffer with a fresh number of attempts, returning
+    /// how many there were
+    async fn redrive_dead_letters(&self) -> Result<usize> {
+        let dead_letters = self.dead_letters.pending().await?;
+        for outbound in &dead_letters {
+            let mut outbound = outbound.clone();
+            outbound.attempts = 0;
+            self.buffer.push(&outbound).await?;
+        }
+
+        self.dead_letters.retain(&[]).await?;
+        info!("Redriving {} dead letters", dead_letters.len());
+        Ok(dead_letters.len())
+    }
+
+    fn properties(&self, outbound: &Outbound) -> Properties {
+        let mut user = vec![
+            ("component".to_string(), outbound.component.clone()),
+            ("sequence".to_string(), outbound.sequence.to_string()),
+        ];
+        if let Some(encoding) = self.compression.encoding() {
+            user.push(("content-encoding".to_string(), encoding.to_string()));
+        }
+
+        Properties {
+            content_type: Some(self.encoding.content_type().to_string()),
+            user,
+            message_expiry: self.message_expiry,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn position_updates_are_fire_and_forget_by_default() {
+        let policy = QosPolicy::default();
+
+        assert_eq!(policy.qos(EventKind::Position), QOS_0);
+        assert_eq!(policy.qos(EventKind::Alarm), QOS_1);
+        assert_eq!(policy.qos(EventKind::Ack), QOS_1);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::config::{ConfigError, Settings};
+use crate::publisher::Outbound;
+use std::collections::{HashMap, VecDeque};
+use tokio::time::Instant;
+use tracing::warn;
+
+const DEFAULT_QUEUE_SIZE: usize = 100;
+
+/// What happens to the messages of a topic once it exceeds its rate, selected with
+/// RATE_LIMIT_POLICY
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// queue up to RATE_LIMIT_QUEUE messages, dropping the oldest ones beyond that
+    DropOldest,
+    /// only keep the latest message, for topics where each message supersedes the previous ones
+    Coalesce,
+    /// queue every message until it can be sent, nothing is ever dropped
+    Buffer,
+}
+
+impl OverflowPolicy {
+    fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        match settings.get("RATE_LIMIT_POLICY") {
+            Some("drop-oldest") | None => Ok(OverflowPolicy::DropOldest),
+            Some("coalesce") => Ok(OverflowPolicy::Coalesce),
+            Some("buffer") => Ok(OverflowPolicy::Buffer),
+            Some(other) => Err(ConfigError::invalid(
+                "RATE_LIMIT_POLICY",
+                "drop-oldest, coalesce or buffer",
+                other,
+            )),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    queue: VecDeque<Outbound>,
+}
+
+/// Token bucket limiting how many messages per second are published on each topic, so a runaway
+/// GPIO line can't exhaust the IoT Core quotas.
+///
+/// RATE_LIMIT sets the messages per second allowed on every topic, and RATE_LIMIT_<COMPONENT>
+/// overrides it for the topic of a component. A topic can burst up to a second worth of messages.
+/// Rate limiting is disabled when neither is set, in which case messages pass through untouched
+pub struct RateLimiter {
+    default_rate: Option<f64>,
+    rates: HashMap<String, f64>,
+    policy: OverflowPolicy,
+    queue_size: usize,
+    buckets: HashMap<String, Bucket>,
+}
+
+fn rate_setting(settings: &Settings, key: &str) -> Result<Option<f64>, ConfigError> {
+    settings.positive(key, "messages per second")
+}
+
+impl RateLimiter {
+    pub fn new(
+        default_rate: Option<f64>,
+        rates: HashMap<String, f64>,
+        policy: OverflowPolicy,
+        queue_size: usize,
+    ) -> Self {
+        Self {
+            default_rate,
+            rates,
+            policy,
+            queue_size: queue_size.max(1),
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        let mut rates = HashMap::new();
+        for component in ["feeder", "robot", "piston", "conveyor"] {
+            let key = format!("RATE_LIMIT_{}", component.to_uppercase());
+            if let Some(rate) = rate_setting(settings, &key)? {
+                rates.insert(component.to_string(), rate);
+            }
+        }
+        let queue_size = settings
+            .parse("RATE_LIMIT_QUEUE", "unsigned integer")?
+            .unwrap_or(DEFAULT_QUEUE_SIZE);
+
+        Ok(Self::new(
+            rate_setting(settings, "RATE_LIMIT")?,
+            rates,
+            OverflowPolicy::from_settings(settings)?,
+            queue_size,
+        ))
+    }
+
+    fn rate(&self, component: &str) -> Option<f64> {
+        self.rates.get(component).copied().or(self.default_rate)
+    }
+
+    /// Returns the message if it can be published right away, otherwise it's held back according
+    /// to the overflow policy until take_ready releases it
+    pub fn admit(&mut self, outbound: Outbound, now: Instant) -> Option<Outbound> {
+        let rate = match self.rate(&outbound.component) {
+            Some(rate) => rate,
+            None => return Some(outbound),
+        };
+
+        let bucket = self
+            .buckets
+            .entry(outbound.topic.clone())
+            .or_insert_with(|| Bucket {
+                tokens: rate.max(1.0),
+                refilled: now,
+                queue: VecDeque::new(),
+            });
+        refill(bucket, rate, now);
+
+        if bucket.queue.is_empty() && bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Some(outbound);
+        }
+
+        match self.policy {
+            OverflowPolicy::DropOldest => {
+                bucket.queue.push_back(outbound);
+                if bucket.queue.len() > self.queue_size {
+                    if let Some(dropped) = bucket.queue.pop_front() {
+                        warn!(
+                            "Rate limit exceeded on {}, dropping event {}",
+                            dropped.topic, dropped.sequence
+                        );
+                    }
+                }
+            }
+            OverflowPolicy::Coalesce => {
+                bucket.queue.clear();
+                bucket.queue.push_back(outbound);
+            }
+            OverflowPolicy::Buffer => bucket.queue.push_back(outbound),
+        }
+        None
+    }
+
+    /// Removes and returns the held back messages that can now be published
+    pub fn take_ready(&mut self, now: Instant) -> Vec<Outbound> {
+        let mut ready = Vec::new();
+
+        for bucket in self.buckets.values_mut() {
+            let rate = bucket.queue.front().and_then(|outbound| {
+                self.rates
+                    .get(&outbound.component)
+                    .copied()
+                    .or(self.default_rate)
+            });
+            let rate = match rate {
+                Some(rate) => rate,
+                None => continue,
+            };
+
+            refill(bucket, rate, now);
+            while bucket.tokens >= 1.0 {
+                match bucket.queue.pop_front() {
+                    Some(outbound) => {
+                        bucket.tokens -= 1.0;
+                        ready.push(outbound);
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        ready
+    }
+
+    /// Removes and returns every held back message, used to flush on shutdown
+    pub fn take_all(&mut self) -> Vec<Outbound> {
+        self.buckets
+            .drain()
+            .flat_map(|(_, bucket)| bucket.queue)
+            .collect()
+    }
+}
+
+fn refill(bucket: &mut Bucket, rate: f64, now: Instant) {
+    let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(rate.max(1.0));
+    bucket.refilled = now;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn sequences(outbounds: Vec<Outbound>) -> Vec<u64> {
+        outbounds.iter().map(|outbound| outbound.sequence).collect()
+    }
+
+    #[tokio::test]
+    async fn oldest_messages_are_dropped_beyond_the_queue_size() {
+        let mut limiter =
+            RateLimiter::new(Some(1.0), HashMap::new(), OverflowPolicy::DropOldest, 2);
+        let now = Instant::now();
+
+        assert!(limiter.admit(Outbound::test("feeder", 0), now).is_some());
+        for sequence in 1..4 {
+            assert!(limiter
+                .admit(Outbound::test("feeder", sequence), now)
+                .is_none());
+        }
+
+        assert!(limiter.take_ready(now).is_empty());
+        let later = now + Duration::from_secs(1);
+        assert_eq!(sequences(limiter.take_ready(later)), vec![2]);
+        assert_eq!(sequences(limiter.take_all()), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn coalescing_keeps_the_latest_message() {
+        let mut limiter = RateLimiter::new(Some(1.0), HashMap::new(), OverflowPolicy::Coalesce, 1);
+        let now = Instant::now();
+
+        limiter.admit(Outbound::test("feeder", 0), now);
+        limiter.admit(Outbound::test("feeder", 1), now);
+        limiter.admit(Outbound::test("feeder", 2), now);
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(sequences(limiter.take_ready(later)), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn messages_pass_through_without_a_rate() {
+        let mut limiter = RateLimiter::new(None, HashMap::new(), OverflowPolicy::Buffer, 1);
+        let now = Instant::now();
+
+        for sequence in 0..10 {
+            assert!(limiter
+                .admit(Outbound::test("feeder", sequence), now)
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn rates_must_be_above_zero() {
+        for rate in ["0", "-1", "NaN"] {
+            let settings = Settings::new([("RATE_LIMIT_FEEDER", rate)]);
+            assert!(RateLimiter::from_settings(&settings).is_err(), "{rate}");
+        }
+    }
+}
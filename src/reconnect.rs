@@ -0,0 +1,134 @@
+use crate::backend::Backend;
+use crate::config::{ConfigError, Settings};
+use crate::diagnostics::Diagnostics;
+use crate::transport::{Client, MqttTransport};
+use crate::vitals::Vital;
+use rand::Rng;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{info, info_span, warn, Instrument};
+
+const DEFAULT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Exponential backoff between reconnection attempts.
+///
+/// The delay doubles on every attempt up to the max delay, and is randomized between half and the
+/// full delay so a fleet of devices losing the broker at once doesn't reconnect in lockstep
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Reads RECONNECT_INITIAL_DELAY and RECONNECT_MAX_DELAY in milliseconds
+    pub fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        let millis = |key: &str, default: Duration| -> Result<Duration, ConfigError> {
+            Ok(settings
+                .parse(key, "milliseconds")?
+                .map(Duration::from_millis)
+                .unwrap_or(default))
+        };
+
+        Ok(Self::new(
+            millis("RECONNECT_INITIAL_DELAY", DEFAULT_INITIAL_DELAY)?,
+            millis("RECONNECT_MAX_DELAY", DEFAULT_MAX_DELAY)?,
+        ))
+    }
+
+    /// Upper bound of the next delay, before jitter is applied
+    fn ceiling(&self) -> Duration {
+        self.initial
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max)
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling();
+        self.attempt = self.attempt.saturating_add(1);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Spawn a task that reconnects the client with exponential backoff whenever the connection is lost,
+/// whatever the reason: network loss, broker restarts, expired credentials or TLS failures.
+///
+/// Reconnecting goes through the backend so credentials are renewed and subscriptions restored.
+/// The vital is held as long as the supervisor runs
+pub fn spawn_supervisor(
+    backend: Backend,
+    client: Client,
+    diagnostics: Diagnostics,
+    vital: Vital,
+) -> Result<JoinHandle<()>, ConfigError> {
+    let mut backoff = Backoff::from_settings(backend.settings())?;
+
+    Ok(tokio::task::spawn(async move {
+        let _vital = vital;
+        loop {
+            let reason = client.connection_lost().await;
+            diagnostics.record_disconnect(reason);
+
+            // the attempts to restore a connection are logged under the outage they end
+            async {
+                warn!("Connection lost, reconnecting");
+                loop {
+                    time::sleep(backoff.next_delay()).await;
+
+                    match backend.reconnect(client.as_ref()).await {
+                        Ok(()) => {
+                            info!("Reconnected after {} attempts", backoff.attempts());
+                            diagnostics.record_reconnect();
+                            backoff.reset();
+                            break;
+                        }
+                        Err(e) => {
+                            warn!("Reconnect attempt {} failed: {e}", backoff.attempts());
+                            diagnostics.record_reconnect_failure();
+                        }
+                    }
+                }
+            }
+            .instrument(info_span!("outage"))
+            .await;
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delays_grow_exponentially_up_to_the_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+
+        for ceiling in [1, 2, 4, 8, 10, 10] {
+            let delay = backoff.next_delay();
+            assert!(delay <= Duration::from_secs(ceiling));
+            assert!(delay >= Duration::from_secs(ceiling) / 2);
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
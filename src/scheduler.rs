@@ -0,0 +1,102 @@
+use crate::backend::Backend;
+use crate::gcp_iot::message;
+use crate::transport::{Message, MqttTransport, QOS_1};
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{info, warn};
+
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(60);
+
+/// Published on the schedule events topic while a start is held, so planners can follow the
+/// countdown to the run
+#[derive(Debug, Serialize)]
+struct Countdown<'a> {
+    /// the id the start command was sent with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    start_at: String,
+    remaining_s: u64,
+}
+
+/// Holds commands until the time they were scheduled for, publishing a countdown every
+/// SCHEDULE_HEARTBEAT milliseconds in the meantime
+#[derive(Clone)]
+pub struct Scheduler {
+    topic: String,
+    device_id: String,
+    client: Arc<dyn MqttTransport>,
+    heartbeat: Duration,
+}
+
+impl Scheduler {
+    pub fn new(backend: &Backend, client: impl MqttTransport + 'static) -> Result<Self> {
+        let heartbeat = backend
+            .settings()
+            .parse("SCHEDULE_HEARTBEAT", "milliseconds")?
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_HEARTBEAT);
+
+        Ok(Self {
+            topic: backend.event_topic("schedule")?,
+            device_id: backend.device_id(),
+            client: Arc::new(client),
+            heartbeat,
+        })
+    }
+
+    /// Wait until start_at, then run the given closure, typically queueing the command for the
+    /// executor
+    pub fn hold<F>(&self, id: Option<String>, start_at: DateTime<Utc>, due: F) -> JoinHandle<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let scheduler = self.clone();
+        info!("Holding a start until {}", start_at.to_rfc3339());
+
+        tokio::task::spawn(async move {
+            loop {
+                let remaining = match (start_at - Utc::now()).to_std() {
+                    Ok(remaining) if !remaining.is_zero() => remaining,
+                    // the time has come, or passed already
+                    _ => break,
+                };
+
+                scheduler
+                    .publish_countdown(id.as_deref(), start_at, remaining)
+                    .await;
+                time::sleep(remaining.min(scheduler.heartbeat)).await;
+            }
+
+            due();
+        })
+    }
+
+    /// Failing to publish the countdown is only logged, the start is held either way
+    async fn publish_countdown(&self, id: Option<&str>, start_at: DateTime<Utc>, left: Duration) {
+        let countdown = Countdown {
+            id,
+            start_at: start_at.to_rfc3339(),
+            remaining_s: left.as_secs(),
+        };
+
+        let payload = match message::seal(&self.device_id, &countdown) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Failed to encode the countdown: {e}");
+                return;
+            }
+        };
+        if let Err(e) = self
+            .client
+            .publish(Message::new(&self.topic, payload, QOS_1))
+            .await
+        {
+            warn!("Failed to publish the countdown: {e}");
+        }
+    }
+}
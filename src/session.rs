@@ -0,0 +1,33 @@
+use crate::config::Settings;
+
+/// Whether the broker keeps the session of the twin while it's disconnected, set with
+/// PERSISTENT_SESSION.
+///
+/// With a persistent session the subscriptions and the QoS 1 commands sent while the twin was down
+/// are delivered as soon as it reconnects, and the messages in flight are kept in
+/// SESSION_PERSISTENCE_DIR (the working directory by default) so they survive a restart. The client
+/// id must be stable across restarts for the broker to find the session, which is the case for
+/// every backend. Google IoT doesn't support persistent sessions, the session is always clean there
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub persistent: bool,
+    persistence_dir: Option<String>,
+}
+
+impl Session {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            persistent: settings.get("PERSISTENT_SESSION") == Some("true"),
+            persistence_dir: settings.get("SESSION_PERSISTENCE_DIR").map(str::to_string),
+        }
+    }
+
+    pub fn clean(&self) -> bool {
+        !self.persistent
+    }
+
+    /// Where the messages in flight are stored, only with a persistent session
+    pub fn persistence_dir(&self) -> Option<String> {
+        self.persistence_dir.clone().filter(|_| self.persistent)
+    }
+}
@@ -0,0 +1,169 @@
+use crate::config::Settings;
+use base64::decode;
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use ed25519_compact::{PublicKey, Signature};
+use hmac::{Hmac, Mac};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::fmt::{Display, Formatter};
+
+/// Field of a command payload carrying its base64 encoded signature
+const SIGNATURE_FIELD: &str = "signature";
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Unsigned,
+    /// the signature doesn't match the payload, or wasn't made with the configured key
+    Tampered,
+    Malformed(String),
+}
+
+impl Display for SignatureError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::Unsigned => write!(f, "Error: The command isn't signed"),
+            SignatureError::Tampered => {
+                write!(f, "Error: The signature doesn't match the command")
+            }
+            SignatureError::Malformed(e) => write!(f, "Error: Malformed signed command, {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Verifies the signature of the commands and configs from the cloud before they're run, configured
+/// with one of:
+///
+/// * COMMAND_PUBLIC_KEY, the base64 encoded Ed25519 public key the cloud signs with
+/// * COMMAND_HMAC_SECRET, a secret shared with the cloud for HMAC-SHA256 signatures
+///
+/// The signature covers the payload without its signature field, as compact JSON with the keys
+/// sorted, so it doesn't depend on how the cloud formats the payload
+pub enum CommandVerifier {
+    Ed25519(PublicKey),
+    Hmac(Vec<u8>),
+}
+
+impl CommandVerifier {
+    /// None when no key is configured, in which case commands are run unsigned
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        match (
+            settings.get("COMMAND_PUBLIC_KEY"),
+            settings.get("COMMAND_HMAC_SECRET"),
+        ) {
+            (Some(_), Some(_)) => Err(eyre!(
+                "Only one of COMMAND_PUBLIC_KEY and COMMAND_HMAC_SECRET can be set"
+            )),
+            (Some(key), None) => {
+                let key = decode(key.trim()).wrap_err("COMMAND_PUBLIC_KEY isn't valid base64")?;
+                let key = PublicKey::from_slice(&key)
+                    .map_err(|e| eyre!("COMMAND_PUBLIC_KEY isn't an Ed25519 public key, {e}"))?;
+                Ok(Some(Self::Ed25519(key)))
+            }
+            (None, Some(secret)) => Ok(Some(Self::Hmac(secret.as_bytes().to_vec()))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub fn verify(&self, payload: &str) -> Result<(), SignatureError> {
+        let (content, signature) = signed_content(payload)?;
+
+        let verified = match self {
+            CommandVerifier::Ed25519(key) => Signature::from_slice(&signature)
+                .map(|signature| key.verify(&content, &signature).is_ok())
+                .unwrap_or(false),
+            CommandVerifier::Hmac(secret) => {
+                // HMAC takes keys of any length
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+                mac.update(&content);
+                mac.verify_slice(&signature).is_ok()
+            }
+        };
+
+        if verified {
+            Ok(())
+        } else {
+            Err(SignatureError::Tampered)
+        }
+    }
+}
+
+/// Splits the payload into the canonical content the signature covers and the decoded signature
+fn signed_content(payload: &str) -> Result<(Vec<u8>, Vec<u8>), SignatureError> {
+    if payload.trim().is_empty() {
+        return Err(SignatureError::Unsigned);
+    }
+
+    let mut payload: Map<String, Value> =
+        serde_json::from_str(payload).map_err(|e| SignatureError::Malformed(e.to_string()))?;
+
+    let signature = match payload.remove(SIGNATURE_FIELD) {
+        Some(Value::String(signature)) => {
+            decode(signature).map_err(|e| SignatureError::Malformed(e.to_string()))?
+        }
+        Some(_) => {
+            let e = "the signature isn't a base64 string".to_string();
+            return Err(SignatureError::Malformed(e));
+        }
+        None => return Err(SignatureError::Unsigned),
+    };
+
+    // serde_json keeps the keys of objects sorted
+    let content =
+        serde_json::to_vec(&payload).map_err(|e| SignatureError::Malformed(e.to_string()))?;
+    Ok((content, signature))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use base64::encode;
+    use ed25519_compact::{KeyPair, Seed};
+
+    const COMMAND: &str = r#"{ "type": "start", "count": 5 }"#;
+    const CANONICAL: &[u8] = br#"{"count":5,"type":"start"}"#;
+
+    fn signed(signature: &[u8]) -> String {
+        format!(
+            r#"{{ "count": 5, "type": "start", "signature": "{}" }}"#,
+            encode(signature)
+        )
+    }
+
+    #[test]
+    fn ed25519_signatures_are_verified() {
+        let key_pair = KeyPair::from_seed(Seed::new([7; 32]));
+        let verifier = CommandVerifier::Ed25519(key_pair.pk);
+
+        let signature = key_pair.sk.sign(CANONICAL, None);
+        assert!(verifier.verify(&signed(signature.as_ref())).is_ok());
+
+        let tampered = signed(signature.as_ref()).replace(r#""count": 5"#, r#""count": 6"#);
+        assert!(matches!(
+            verifier.verify(&tampered),
+            Err(SignatureError::Tampered)
+        ));
+        assert!(matches!(
+            verifier.verify(COMMAND),
+            Err(SignatureError::Unsigned)
+        ));
+    }
+
+    #[test]
+    fn hmac_signatures_are_verified() {
+        let verifier = CommandVerifier::Hmac(b"secret".to_vec());
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(CANONICAL);
+        let signature = mac.finalize().into_bytes();
+        assert!(verifier.verify(&signed(&signature)).is_ok());
+
+        let other = CommandVerifier::Hmac(b"other secret".to_vec());
+        assert!(matches!(
+            other.verify(&signed(&signature)),
+            Err(SignatureError::Tampered)
+        ));
+    }
+}
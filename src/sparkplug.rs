@@ -0,0 +1,375 @@
+use crate::config::Settings;
+use crate::reconnect::Backoff;
+use crate::transport::{self, Client, ConnectOptions, Message, MessageStream, QOS_0, QOS_1};
+use color_eyre::Result;
+use futures::StreamExt;
+use prost::Message as _;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{error, info, warn};
+
+/// Types generated from proto/sparkplug_b.proto
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/sparkplug_b.rs"));
+}
+
+use proto::payload::{metric, Metric};
+use proto::Payload;
+
+const NAMESPACE: &str = "spBv1.0";
+const REBIRTH_METRIC: &str = "Node Control/Rebirth";
+
+/// Sparkplug B metric data types
+const UINT32: u32 = 7;
+const UINT64: u32 = 8;
+const BOOLEAN: u32 = 11;
+const STRING: u32 = 12;
+
+/// Every component is a Sparkplug device of the edge node
+const DEVICES: [&str; 5] = ["feeder", "feeder_b", "robot", "piston", "conveyor"];
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn metric(name: &str, datatype: u32, value: metric::Value) -> Metric {
+    Metric {
+        name: Some(name.to_string()),
+        datatype: Some(datatype),
+        value: Some(value),
+        ..Default::default()
+    }
+}
+
+fn topic(group_id: &str, edge_node_id: &str, message_type: &str, device: Option<&str>) -> String {
+    let topic = format!("{NAMESPACE}/{group_id}/{message_type}/{edge_node_id}");
+    match device {
+        Some(device) => format!("{topic}/{device}"),
+        None => topic,
+    }
+}
+
+/// Payload of the node births and deaths, only births carry the rebirth control metric
+fn node_payload(bd_seq: u64, birth: bool) -> Payload {
+    let mut metrics = vec![metric("bdSeq", UINT64, metric::Value::LongValue(bd_seq))];
+    if birth {
+        metrics.push(metric(
+            REBIRTH_METRIC,
+            BOOLEAN,
+            metric::Value::BooleanValue(false),
+        ));
+    }
+
+    Payload {
+        timestamp: Some(now_millis()),
+        metrics,
+        ..Default::default()
+    }
+}
+
+/// Metrics of a device, taken from its section of the twin state. None if the component isn't part
+/// of the twin
+fn device_metrics(device: &str, state: &Value) -> Option<Vec<Metric>> {
+    let section = state.get(device)?;
+
+    let metrics = match device {
+        "feeder" | "feeder_b" => vec![metric(
+            "Count",
+            UINT32,
+            metric::Value::IntValue(section["count"].as_u64()? as u32),
+        )],
+        "robot" => vec![metric(
+            "Position",
+            STRING,
+            metric::Value::StringValue(section["position"].as_str()?.to_string()),
+        )],
+        "piston" => vec![metric(
+            "State",
+            STRING,
+            metric::Value::StringValue(section["state"].as_str()?.to_string()),
+        )],
+        "conveyor" => vec![
+            metric(
+                "State",
+                STRING,
+                metric::Value::StringValue(section["state"].as_str()?.to_string()),
+            ),
+            metric(
+                "Speed",
+                UINT32,
+                metric::Value::IntValue(section["speed"].as_u64()? as u32),
+            ),
+        ],
+        _ => return None,
+    };
+    Some(metrics)
+}
+
+/// The twin as a Sparkplug B edge node, with a device per component, enabled by setting
+/// SPARKPLUG_BROKER_URI to the SCADA broker.
+///
+/// The node is identified by SPARKPLUG_GROUP_ID and SPARKPLUG_EDGE_NODE_ID. Births are published on
+/// every connection and whenever a host application requests a rebirth, data messages are only
+/// published for devices whose metrics changed
+pub struct SparkplugNode {
+    client: Client,
+    msg_stream: MessageStream,
+    /// the options of the first connection, reused on every reconnection
+    options: ConnectOptions,
+    group_id: String,
+    edge_node_id: String,
+    /// message sequence number, wraps around at 256 as required by the specification
+    seq: u64,
+    /// birth/death sequence number, ties an NDEATH to the NBIRTH of the same session
+    bd_seq: u64,
+    last_metrics: HashMap<&'static str, Vec<Metric>>,
+    /// delays between the attempts to reconnect, reset with each outage
+    backoff: Backoff,
+}
+
+impl SparkplugNode {
+    pub async fn connect(settings: &Settings) -> Result<Self> {
+        let broker_uri = settings.require("SPARKPLUG_BROKER_URI", "the node connects to it")?;
+        let group_id = settings
+            .require("SPARKPLUG_GROUP_ID", "the node is identified by it")?
+            .to_string();
+        let edge_node_id = settings
+            .require("SPARKPLUG_EDGE_NODE_ID", "the node is identified by it")?
+            .to_string();
+
+        // the NDEATH is the last will, carrying the bdSeq of the birth that will follow
+        let death_topic = topic(&group_id, &edge_node_id, "NDEATH", None);
+        let death = node_payload(0, false).encode_to_vec();
+        let mut options = ConnectOptions::new(broker_uri, format!("{group_id}-{edge_node_id}"));
+        options.will = Some(Message::new(death_topic, death, QOS_1));
+        if let Some(user_name) = settings.get("SPARKPLUG_USERNAME") {
+            options.user_name = Some(user_name.to_string());
+            options.password = settings.get("SPARKPLUG_PASSWORD").map(str::to_string);
+        }
+
+        let (client, msg_stream) = transport::connect(&options).await?;
+        client
+            .subscribe(&topic(&group_id, &edge_node_id, "NCMD", None), QOS_1)
+            .await?;
+
+        Ok(Self {
+            client,
+            msg_stream,
+            options,
+            group_id,
+            edge_node_id,
+            seq: 0,
+            bd_seq: 0,
+            last_metrics: HashMap::new(),
+            backoff: Backoff::from_settings(settings)?,
+        })
+    }
+
+    fn topic(&self, message_type: &str, device: Option<&str>) -> String {
+        topic(&self.group_id, &self.edge_node_id, message_type, device)
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % 256;
+        seq
+    }
+
+    async fn publish(
+        &mut self,
+        message_type: &str,
+        device: Option<&str>,
+        mut payload: Payload,
+    ) -> Result<()> {
+        payload.seq = Some(self.next_seq());
+        let msg = Message::new(
+            self.topic(message_type, device),
+            payload.encode_to_vec(),
+            QOS_0,
+        );
+        self.client.publish(msg).await
+    }
+
+    /// Publish the NBIRTH followed by a DBIRTH for every component, restarting the sequence numbers
+    pub async fn birth(&mut self, state: &Value) -> Result<()> {
+        self.seq = 0;
+        self.last_metrics.clear();
+
+        let node_birth = node_payload(self.bd_seq, true);
+        self.publish("NBIRTH", None, node_birth).await?;
+
+        for device in DEVICES {
+            if let Some(metrics) = device_metrics(device, state) {
+                let payload = Payload {
+                    timestamp: Some(now_millis()),
+                    metrics: metrics.clone(),
+                    ..Default::default()
+                };
+                self.publish("DBIRTH", Some(device), payload).await?;
+                self.last_metrics.insert(device, metrics);
+            }
+        }
+
+        info!("Published Sparkplug births with bdSeq {}", self.bd_seq);
+        Ok(())
+    }
+
+    /// Publish a DDATA for every component whose metrics changed since they were last published. A
+    /// component that wasn't part of the births requires a rebirth to be described
+    pub async fn publish_data(&mut self, state: &Value) -> Result<()> {
+        for device in DEVICES {
+            let metrics = match device_metrics(device, state) {
+                Some(metrics) => metrics,
+                None => continue,
+            };
+
+            match self.last_metrics.get(device) {
+                Some(last) if *last == metrics => continue,
+                Some(_) => {}
+                None => return self.birth(state).await,
+            }
+
+            let payload = Payload {
+                timestamp: Some(now_millis()),
+                metrics: metrics.clone(),
+                ..Default::default()
+            };
+            self.publish("DDATA", Some(device), payload).await?;
+            self.last_metrics.insert(device, metrics);
+        }
+
+        Ok(())
+    }
+
+    /// Reconnect with the original connect options, so the last will keeps matching the bdSeq of the
+    /// births, which are published again for the new session
+    async fn reconnect(&mut self, state: &Value) {
+        self.backoff.reset();
+
+        loop {
+            time::sleep(self.backoff.next_delay()).await;
+
+            let reconnected = match self.client.reconnect(&self.options).await {
+                Ok(()) => {
+                    self.client
+                        .subscribe(&self.topic("NCMD", None), QOS_1)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match reconnected {
+                Ok(_) => break,
+                Err(e) => warn!(
+                    "Sparkplug reconnect attempt {} failed: {e}",
+                    self.backoff.attempts()
+                ),
+            }
+        }
+
+        if let Err(e) = self.birth(state).await {
+            error!("Failed to publish the Sparkplug births: {e}");
+        }
+    }
+
+    /// Spawn the node's task, publishing data whenever the twin state changes and births when a host
+    /// application asks for a rebirth through an NCMD
+    pub fn spawn(mut self, mut state_rx: watch::Receiver<Value>) -> JoinHandle<()> {
+        tokio::task::spawn(async move {
+            let state = state_rx.borrow_and_update().clone();
+            if let Err(e) = self.birth(&state).await {
+                error!("Failed to publish the Sparkplug births: {e}");
+            }
+
+            loop {
+                tokio::select! {
+                    changed = state_rx.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+
+                        let state = state_rx.borrow_and_update().clone();
+                        if let Err(e) = self.publish_data(&state).await {
+                            error!("Failed to publish Sparkplug data: {e}");
+                        }
+                    }
+                    Some(msg) = self.msg_stream.next() => {
+                        match msg {
+                            Some(msg) if is_rebirth_request(msg.payload()) => {
+                                let state = state_rx.borrow().clone();
+                                if let Err(e) = self.birth(&state).await {
+                                    error!("Failed to publish the Sparkplug births: {e}");
+                                }
+                            }
+                            Some(_) => {}
+                            None => {
+                                warn!("Lost the connection to the Sparkplug broker");
+                                let state = state_rx.borrow().clone();
+                                self.reconnect(&state).await;
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+fn is_rebirth_request(payload: &[u8]) -> bool {
+    match Payload::decode(payload) {
+        Ok(payload) => payload.metrics.iter().any(|metric| {
+            metric.name.as_deref() == Some(REBIRTH_METRIC)
+                && metric.value == Some(metric::Value::BooleanValue(true))
+        }),
+        Err(e) => {
+            warn!("Ignoring malformed Sparkplug command: {e}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn metrics_are_taken_from_the_twin_state() {
+        let state = json!({
+            "feeder": { "name": "Material feeder", "count": 7 },
+            "robot": { "name": "robot", "position": "position 15" },
+        });
+
+        let feeder = device_metrics("feeder", &state).unwrap();
+        assert_eq!(feeder[0].value, Some(metric::Value::IntValue(7)));
+
+        let robot = device_metrics("robot", &state).unwrap();
+        assert_eq!(
+            robot[0].value,
+            Some(metric::Value::StringValue("position 15".to_string()))
+        );
+
+        assert!(device_metrics("piston", &state).is_none());
+    }
+
+    #[test]
+    fn rebirth_requests_are_detected() {
+        let request = Payload {
+            metrics: vec![metric(
+                REBIRTH_METRIC,
+                BOOLEAN,
+                metric::Value::BooleanValue(true),
+            )],
+            ..Default::default()
+        };
+
+        assert!(is_rebirth_request(&request.encode_to_vec()));
+        assert!(!is_rebirth_request(&Payload::default().encode_to_vec()));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::backend::Backend;
+use crate::config::ConfigError;
+use crate::encoding::Encoding;
+use crate::gcp_iot::message;
+use crate::manufacturing_components::program::ProgramStatus;
+use crate::transport::{Client, Message, MqttTransport, QOS_1};
+use crate::utils::{Iso8601Utc, SystemTime};
+use color_eyre::Result;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tracing::error;
+
+/// Google IoT only allows a device to update its state once per second
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Spawn a task reporting the latest twin state to the backend whenever it changes, and every
+/// STATE_REPORT_INTERVAL milliseconds even if it hasn't.
+///
+/// Reports are never sent closer together than STATE_MIN_INTERVAL milliseconds, changes arriving
+/// within that window are coalesced and only the latest state is sent. The task ends once the
+/// sender is dropped
+pub fn spawn(
+    backend: Backend,
+    client: Client,
+    mut state_rx: watch::Receiver<Value>,
+) -> Result<JoinHandle<()>, ConfigError> {
+    let settings = backend.settings();
+    let min_interval = settings
+        .parse("STATE_MIN_INTERVAL", "milliseconds")?
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_MIN_INTERVAL);
+    // the period of the reports, which a timer can't have at zero
+    let report_interval = settings
+        .positive("STATE_REPORT_INTERVAL", "milliseconds")?
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_REPORT_INTERVAL);
+    let encoding = Encoding::from_setting(settings, "STATE_PAYLOAD_ENCODING")?;
+
+    Ok(tokio::task::spawn(async move {
+        let mut interval = time::interval(report_interval);
+        let mut last_report: Option<Instant> = None;
+
+        loop {
+            tokio::select! {
+                changed = state_rx.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+                _ = interval.tick() => {}
+            }
+
+            if let Some(last_report) = last_report {
+                time::sleep_until(last_report + min_interval).await;
+            }
+
+            let state = state_rx.borrow_and_update().clone();
+            // nothing has been reported by the components yet
+            if state.is_null() {
+                continue;
+            }
+
+            if let Err(e) = backend.report_state(&client, state, encoding).await {
+                error!("Failed to report the twin state: {e}");
+            }
+            last_report = Some(Instant::now());
+        }
+    }))
+}
+
+/// Publishes the whole twin on the state-snapshot events topic on demand, so dashboards can resync
+/// without waiting for the next change. Snapshots are always JSON, the protobuf state has no room
+/// for the program status
+#[derive(Clone)]
+pub struct SnapshotPublisher {
+    topic: String,
+    device_id: String,
+    client: Arc<dyn MqttTransport>,
+    state_rx: watch::Receiver<Value>,
+    status_rx: watch::Receiver<ProgramStatus>,
+}
+
+impl SnapshotPublisher {
+    pub fn new(
+        backend: &Backend,
+        client: impl MqttTransport + 'static,
+        state_rx: watch::Receiver<Value>,
+        status_rx: watch::Receiver<ProgramStatus>,
+    ) -> Result<Self> {
+        Ok(Self {
+            topic: backend.event_topic("state-snapshot")?,
+            device_id: backend.device_id(),
+            client: Arc::new(client),
+            state_rx,
+            status_rx,
+        })
+    }
+
+    pub async fn publish(&self) -> Result<()> {
+        // both are borrowed only while they're read, not across the publish
+        let snapshot = snapshot(&self.state_rx.borrow(), *self.status_rx.borrow());
+        let payload = message::seal(&self.device_id, &snapshot)?;
+        self.client
+            .publish(Message::new(&self.topic, payload, QOS_1))
+            .await
+    }
+}
+
+/// Every component of the latest state along with the program status
+fn snapshot(state: &Value, status: ProgramStatus) -> Value {
+    let mut snapshot = match state {
+        Value::Object(components) => components.clone(),
+        _ => Map::new(),
+    };
+    snapshot.insert("program".to_string(), json!({ "status": status }));
+    snapshot.insert("timestamp".to_string(), json!(SystemTime::iso8601_now()));
+    Value::Object(snapshot)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshots_include_the_program_status() {
+        let state = json!({ "feeder": { "count": 4 } });
+
+        let snapshot = snapshot(&state, ProgramStatus::EmergencyStopped);
+        assert_eq!(snapshot["feeder"]["count"], 4);
+        assert_eq!(snapshot["program"]["status"], "emergency-stopped");
+    }
+}
@@ -0,0 +1,138 @@
+use crate::config::Settings;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum TlsError {
+    UnknownVersion(String),
+    /// a certificate or key file that can't be used, e.g. missing or unreadable
+    InvalidFile {
+        kind: &'static str,
+        path: String,
+        source: color_eyre::Report,
+    },
+    /// the broker and the client couldn't agree on a secure connection, e.g. an untrusted server
+    /// certificate, a rejected client certificate or no common cipher suite
+    Handshake(color_eyre::Report),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::UnknownVersion(version) => write!(
+                f,
+                "Error: Unknown TLS_VERSION {version}, expected 1.2 or default"
+            ),
+            TlsError::InvalidFile { kind, path, source } => {
+                write!(f, "Error: Unable to use {path} as the {kind}, {source}")
+            }
+            TlsError::Handshake(e) => write!(f, "Error: TLS handshake with the broker failed, {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    /// the highest version supported by both ends
+    Default,
+    Tls12,
+}
+
+/// TLS settings shared by every connection.
+///
+/// TLS_VERSION pins the protocol version to "1.2", by default the highest version supported by both
+/// ends is negotiated, which is 1.3 for any recent broker. Paho can't pin 1.3 itself.
+/// TLS_CIPHER_SUITES restricts the cipher suites, using the OpenSSL cipher list format
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub version: TlsVersion,
+    pub cipher_suites: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn from_settings(settings: &Settings) -> Result<Self, TlsError> {
+        let version = match settings.get("TLS_VERSION") {
+            Some(version) => parse_version(version)?,
+            None => TlsVersion::Default,
+        };
+
+        Ok(Self {
+            version,
+            cipher_suites: settings.get("TLS_CIPHER_SUITES").map(str::to_string),
+        })
+    }
+
+    /// A TLS connection with these settings, trusting the system trust store until a trust store
+    /// is given
+    pub fn builder(&self) -> Tls {
+        Tls {
+            config: self.clone(),
+            trust_store: None,
+            key_store: None,
+            private_key: None,
+        }
+    }
+}
+
+fn parse_version(version: &str) -> Result<TlsVersion, TlsError> {
+    match version {
+        "default" => Ok(TlsVersion::Default),
+        "1.2" => Ok(TlsVersion::Tls12),
+        other => Err(TlsError::UnknownVersion(other.to_string())),
+    }
+}
+
+/// The files of a TLS connection, read by the client when it connects
+#[derive(Debug, Clone)]
+pub struct Tls {
+    pub config: TlsConfig,
+    /// the CA certificates the broker's certificate is checked against
+    pub trust_store: Option<String>,
+    /// the client certificate
+    pub key_store: Option<String>,
+    pub private_key: Option<String>,
+}
+
+impl Tls {
+    pub fn trust_store(mut self, path: impl Into<String>) -> Self {
+        self.trust_store = Some(path.into());
+        self
+    }
+
+    pub fn key_store(mut self, path: impl Into<String>) -> Self {
+        self.key_store = Some(path.into());
+        self
+    }
+
+    pub fn private_key(mut self, path: impl Into<String>) -> Self {
+        self.private_key = Some(path.into());
+        self
+    }
+}
+
+/// Reports connection failures happening during the TLS handshake as such, the clients only
+/// describe them in the error message
+pub fn connect_error(e: color_eyre::Report) -> color_eyre::Report {
+    let message = e.to_string();
+    if message.contains("SSL") || message.contains("TLS") {
+        TlsError::Handshake(e).into()
+    } else {
+        e
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_tls_versions() {
+        assert_eq!(parse_version("1.2").unwrap(), TlsVersion::Tls12);
+        assert_eq!(parse_version("default").unwrap(), TlsVersion::Default);
+        assert!(matches!(
+            parse_version("1.1"),
+            Err(TlsError::UnknownVersion(version)) if version == "1.1"
+        ));
+    }
+}
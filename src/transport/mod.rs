@@ -0,0 +1,200 @@
+use crate::tls::Tls;
+use async_trait::async_trait;
+use color_eyre::Result;
+use futures::Stream;
+use std::borrow::Cow;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "paho")]
+pub mod paho;
+#[cfg(feature = "rumqttc")]
+pub mod rumqtt;
+
+#[cfg(not(any(feature = "paho", feature = "rumqttc")))]
+compile_error!("the twin needs an MQTT client, enable the paho or the rumqttc feature");
+
+pub const QOS_0: i32 = 0;
+pub const QOS_1: i32 = 1;
+
+pub const MQTT_VERSION_3_1_1: u32 = 4;
+pub const MQTT_VERSION_5: u32 = 5;
+
+/// A connected client, shared by every task publishing through it
+pub type Client = Arc<dyn MqttTransport>;
+
+/// Messages received on the subscribed topics, None when the connection is lost
+pub type MessageStream = Pin<Box<dyn Stream<Item = Option<Incoming>> + Send>>;
+
+/// A message to publish, or the last will the broker publishes when the connection is lost without
+/// a clean disconnect
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: i32,
+    pub retained: bool,
+    pub properties: Properties,
+}
+
+impl Message {
+    pub fn new(topic: impl Into<String>, payload: impl Into<Vec<u8>>, qos: i32) -> Self {
+        Self {
+            topic: topic.into(),
+            payload: payload.into(),
+            qos,
+            retained: false,
+            properties: Properties::default(),
+        }
+    }
+
+    pub fn new_retained(topic: impl Into<String>, payload: impl Into<Vec<u8>>, qos: i32) -> Self {
+        Self {
+            retained: true,
+            ..Self::new(topic, payload, qos)
+        }
+    }
+
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+}
+
+/// MQTT v5 properties of a message, left out on 3.1.1 connections
+#[derive(Debug, Clone, Default)]
+pub struct Properties {
+    pub content_type: Option<String>,
+    pub user: Vec<(String, String)>,
+    /// seconds the broker keeps the message for subscribers that haven't received it yet
+    pub message_expiry: Option<i32>,
+}
+
+/// A message received on one of the subscribed topics
+#[derive(Debug, Clone)]
+pub struct Incoming {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+impl Incoming {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn payload_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.payload)
+    }
+}
+
+/// How to connect to a broker, whichever client the twin is built with
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// e.g. tcp://localhost:1883, or ssl://emqx.local:8883 with TLS
+    pub server_uri: String,
+    pub client_id: String,
+    pub mqtt_version: u32,
+    pub keep_alive: Duration,
+    pub clean_session: bool,
+    /// seconds the broker keeps the session after a disconnect, only used with MQTT v5
+    pub session_expiry: Option<i32>,
+    pub user_name: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<Tls>,
+    pub will: Option<Message>,
+    /// where the messages in flight of a persistent session are kept so they survive a restart
+    pub persistence_dir: Option<String>,
+}
+
+impl ConnectOptions {
+    /// MQTT 3.1.1 with a clean session and a keep alive of a minute
+    pub fn new(server_uri: impl Into<String>, client_id: impl Into<String>) -> Self {
+        Self {
+            server_uri: server_uri.into(),
+            client_id: client_id.into(),
+            mqtt_version: MQTT_VERSION_3_1_1,
+            keep_alive: Duration::from_secs(60),
+            clean_session: true,
+            session_expiry: None,
+            user_name: None,
+            password: None,
+            tls: None,
+            will: None,
+            persistence_dir: None,
+        }
+    }
+}
+
+/// The operations the twin needs from an MQTT client, so nothing above the connection depends on
+/// paho.
+///
+/// paho-mqtt is the default client, the pure Rust rumqttc client is used when the twin is built
+/// without the paho feature, for targets where building the paho C library is a hassle
+#[async_trait]
+pub trait MqttTransport: Send + Sync {
+    /// Completes once the broker acknowledges the message, or right away with QoS 0
+    async fn publish(&self, message: Message) -> Result<()>;
+
+    async fn subscribe(&self, topic_filter: &str, qos: i32) -> Result<()>;
+
+    fn is_connected(&self) -> bool;
+
+    /// The version of MQTT the connection was established with
+    fn mqtt_version(&self) -> u32;
+
+    /// Connect again with fresh options, e.g. a new JWT, the stream of received messages is kept
+    async fn reconnect(&self, options: &ConnectOptions) -> Result<()>;
+
+    /// Wait for the connection to be lost, whatever the reason: network loss, broker restarts,
+    /// expired credentials or TLS failures. Returns why, when the client knows
+    async fn connection_lost(&self) -> String;
+
+    /// Disconnect cleanly, giving the messages in flight up to the timeout to be delivered
+    async fn disconnect(&self, timeout: Duration) -> Result<()>;
+}
+
+#[async_trait]
+impl<T: MqttTransport + ?Sized> MqttTransport for Arc<T> {
+    async fn publish(&self, message: Message) -> Result<()> {
+        (**self).publish(message).await
+    }
+
+    async fn subscribe(&self, topic_filter: &str, qos: i32) -> Result<()> {
+        (**self).subscribe(topic_filter, qos).await
+    }
+
+    fn is_connected(&self) -> bool {
+        (**self).is_connected()
+    }
+
+    fn mqtt_version(&self) -> u32 {
+        (**self).mqtt_version()
+    }
+
+    async fn reconnect(&self, options: &ConnectOptions) -> Result<()> {
+        (**self).reconnect(options).await
+    }
+
+    async fn connection_lost(&self) -> String {
+        (**self).connection_lost().await
+    }
+
+    async fn disconnect(&self, timeout: Duration) -> Result<()> {
+        (**self).disconnect(timeout).await
+    }
+}
+
+/// Connect with paho, or with rumqttc when the twin is built without the paho feature
+pub async fn connect(options: &ConnectOptions) -> Result<(Client, MessageStream)> {
+    #[cfg(feature = "paho")]
+    let (client, stream) = paho::PahoTransport::connect(options).await?;
+    #[cfg(not(feature = "paho"))]
+    let (client, stream) = rumqtt::RumqttTransport::connect(options).await?;
+
+    Ok((Arc::new(client), stream))
+}
@@ -0,0 +1,211 @@
+use super::{
+    ConnectOptions, Incoming, Message, MessageStream, MqttTransport, Properties, MQTT_VERSION_5,
+};
+use crate::tls::{Tls, TlsError, TlsVersion};
+use async_trait::async_trait;
+use color_eyre::Result;
+use futures::StreamExt;
+use paho_mqtt::{
+    AsyncClient, ConnectOptionsBuilder, CreateOptionsBuilder, DisconnectOptionsBuilder,
+    MessageBuilder, PropertyCode, ReasonCode, SslOptions, SslOptionsBuilder, SslVersion,
+};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// Messages received before the listener gets to them are queued up to this capacity
+const STREAM_CAPACITY: usize = 100;
+
+/// MQTT transport built on the paho C client.
+///
+/// paho tells when the connection is lost through callbacks, which forward the reason to whoever
+/// waits for it
+pub struct PahoTransport {
+    client: AsyncClient,
+    lost: Mutex<UnboundedReceiver<String>>,
+}
+
+impl PahoTransport {
+    pub async fn connect(options: &ConnectOptions) -> Result<(Self, MessageStream)> {
+        let create_options = CreateOptionsBuilder::new()
+            .server_uri(&options.server_uri)
+            .client_id(&options.client_id)
+            .mqtt_version(options.mqtt_version);
+        let create_options = match &options.persistence_dir {
+            Some(dir) => create_options.persistence(dir.as_str()),
+            None => create_options,
+        };
+        let mut client = AsyncClient::new(create_options.finalize())?;
+
+        // the stream is opened before connecting since the messages queued in a persistent session
+        // are delivered right after the connection is established
+        let stream = client
+            .get_stream(STREAM_CAPACITY)
+            .map(|msg| {
+                msg.map(|msg| Incoming {
+                    topic: msg.topic().to_string(),
+                    payload: msg.payload().to_vec(),
+                })
+            })
+            .boxed();
+
+        let (lost_tx, lost) = unbounded_channel();
+        let notify = lost_tx.clone();
+        client.set_connection_lost_callback(move |_client: &AsyncClient| {
+            notify.send("connection lost".to_string()).ok();
+        });
+        // MQTT v5 brokers tell us why they disconnect us instead of just dropping the connection
+        client.set_disconnected_callback(
+            move |_client: &AsyncClient, _properties, reason_code: ReasonCode| {
+                warn!("Disconnected by the broker: {reason_code:?}");
+                lost_tx.send(format!("{reason_code:?}")).ok();
+            },
+        );
+
+        client.connect(connect_options(options)?).await?;
+        let transport = Self {
+            client,
+            lost: Mutex::new(lost),
+        };
+        Ok((transport, stream))
+    }
+}
+
+fn connect_options(options: &ConnectOptions) -> Result<paho_mqtt::ConnectOptions> {
+    let mut builder = ConnectOptionsBuilder::new();
+    builder
+        // the server of the client is replaced, e.g. by the next endpoint on a failover
+        .server_uris(&[options.server_uri.as_str()])
+        .mqtt_version(options.mqtt_version)
+        .keep_alive_interval(options.keep_alive);
+
+    if options.mqtt_version == MQTT_VERSION_5 {
+        let mut properties = paho_mqtt::Properties::new();
+        if let Some(expiry) = options.session_expiry {
+            properties.push_int(PropertyCode::SessionExpiryInterval, expiry)?;
+        }
+        builder
+            .clean_start(options.clean_session)
+            .properties(properties);
+    } else {
+        builder.clean_session(options.clean_session);
+    }
+
+    if let Some(user_name) = &options.user_name {
+        builder.user_name(user_name.as_str());
+    }
+    if let Some(password) = &options.password {
+        builder.password(password.as_str());
+    }
+    if let Some(tls) = &options.tls {
+        builder.ssl_options(ssl_options(tls)?);
+    }
+    if let Some(will) = &options.will {
+        builder.will_message(message(will.clone(), false)?);
+    }
+    Ok(builder.finalize())
+}
+
+fn ssl_options(tls: &Tls) -> Result<SslOptions, TlsError> {
+    let mut builder = SslOptionsBuilder::new();
+    builder.ssl_version(match tls.config.version {
+        TlsVersion::Default => SslVersion::Default,
+        TlsVersion::Tls12 => SslVersion::Tls_1_2,
+    });
+    if let Some(cipher_suites) = &tls.config.cipher_suites {
+        builder.enabled_cipher_suites(cipher_suites.as_str());
+    }
+
+    let invalid = |kind, path: &String| {
+        let path = path.clone();
+        move |source: paho_mqtt::Error| TlsError::InvalidFile {
+            kind,
+            path,
+            source: source.into(),
+        }
+    };
+    if let Some(path) = &tls.trust_store {
+        builder
+            .trust_store(path)
+            .map_err(invalid("trust store", path))?;
+    }
+    if let Some(path) = &tls.key_store {
+        builder
+            .key_store(path)
+            .map_err(invalid("client certificate", path))?;
+    }
+    if let Some(path) = &tls.private_key {
+        builder
+            .private_key(path)
+            .map_err(invalid("private key", path))?;
+    }
+    Ok(builder.finalize())
+}
+
+fn message(message: Message, v5: bool) -> Result<paho_mqtt::Message> {
+    let mut builder = MessageBuilder::new()
+        .topic(message.topic)
+        .payload(message.payload)
+        .qos(message.qos)
+        .retained(message.retained);
+    if v5 {
+        builder = builder.properties(properties(&message.properties)?);
+    }
+    Ok(builder.finalize())
+}
+
+fn properties(properties: &Properties) -> Result<paho_mqtt::Properties> {
+    let mut paho = paho_mqtt::Properties::new();
+    for (key, value) in &properties.user {
+        paho.push_string_pair(PropertyCode::UserProperty, key, value)?;
+    }
+    if let Some(content_type) = &properties.content_type {
+        paho.push_string(PropertyCode::ContentType, content_type)?;
+    }
+    if let Some(expiry) = properties.message_expiry {
+        paho.push_int(PropertyCode::MessageExpiryInterval, expiry)?;
+    }
+    Ok(paho)
+}
+
+#[async_trait]
+impl MqttTransport for PahoTransport {
+    async fn publish(&self, msg: Message) -> Result<()> {
+        let v5 = self.mqtt_version() == MQTT_VERSION_5;
+        self.client.publish(message(msg, v5)?).await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic_filter: &str, qos: i32) -> Result<()> {
+        self.client.subscribe(topic_filter, qos).await?;
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
+    fn mqtt_version(&self) -> u32 {
+        self.client.mqtt_version()
+    }
+
+    async fn reconnect(&self, options: &ConnectOptions) -> Result<()> {
+        self.client.connect(connect_options(options)?).await?;
+        Ok(())
+    }
+
+    async fn connection_lost(&self) -> String {
+        match self.lost.lock().await.recv().await {
+            Some(reason) => reason,
+            // the callbacks live as long as the client
+            None => futures::future::pending().await,
+        }
+    }
+
+    async fn disconnect(&self, timeout: Duration) -> Result<()> {
+        let options = DisconnectOptionsBuilder::new().timeout(timeout).finalize();
+        self.client.disconnect(options).await?;
+        Ok(())
+    }
+}
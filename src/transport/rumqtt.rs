@@ -0,0 +1,338 @@
+use super::{ConnectOptions, Incoming, Message, MessageStream, MqttTransport, MQTT_VERSION_3_1_1};
+use crate::tls::{Tls, TlsError, TlsVersion};
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use rumqttc::{
+    AsyncClient, ConnectReturnCode, ConnectionError, Event, EventLoop, Key, LastWill, MqttOptions,
+    Outgoing, Packet, QoS, Transport,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Mutex};
+use tracing::warn;
+
+/// Requests made before the event loop gets to them are queued up to this capacity
+const REQUEST_CAPACITY: usize = 100;
+
+fn qos(qos: i32) -> Result<QoS> {
+    match qos {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        other => Err(eyre!("Invalid QoS level {other}")),
+    }
+}
+
+/// Splits a broker URI such as tcp://localhost:1883 into its host and port, the port defaults to
+/// 1883, or 8883 for ssl:// URIs
+fn host_and_port(broker_uri: &str) -> Result<(String, u16)> {
+    let (address, default_port) = match broker_uri.split_once("://") {
+        Some(("ssl" | "mqtts", address)) => (address, 8883),
+        Some((_, address)) => (address, 1883),
+        None => (broker_uri, 1883),
+    };
+
+    match address.rsplit_once(':') {
+        Some((host, port)) => Ok((host.to_string(), port.parse()?)),
+        None => Ok((address.to_string(), default_port)),
+    }
+}
+
+fn read(kind: &'static str, path: &str) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|source| TlsError::InvalidFile {
+        kind,
+        path: path.to_string(),
+        source: source.into(),
+    })
+}
+
+/// The certificates of the system trust store, in the PEM format rumqttc reads the CA certificates
+/// in
+fn system_roots() -> Result<Vec<u8>> {
+    let certificates = rustls_native_certs::load_native_certs()?;
+    if certificates.is_empty() {
+        return Err(eyre!("No trust store given and the system has none"));
+    }
+
+    let mut pem = Vec::new();
+    for certificate in certificates {
+        pem.extend_from_slice(b"-----BEGIN CERTIFICATE-----\n");
+        for line in base64::encode(&certificate.0).as_bytes().chunks(64) {
+            pem.extend_from_slice(line);
+            pem.push(b'\n');
+        }
+        pem.extend_from_slice(b"-----END CERTIFICATE-----\n");
+    }
+    Ok(pem)
+}
+
+/// rustls negotiates the TLS version and cipher suites itself, a pinned version is refused rather
+/// than ignored. Without a trust store the broker is checked against the system's
+fn transport(tls: &Tls) -> Result<Transport> {
+    if tls.config.version == TlsVersion::Tls12 {
+        return Err(eyre!("The rumqttc client can't pin TLS_VERSION to 1.2"));
+    }
+    if tls.config.cipher_suites.is_some() {
+        warn!("TLS_CIPHER_SUITES is ignored by the rumqttc client");
+    }
+    let ca = match &tls.trust_store {
+        Some(trust_store) => read("trust store", trust_store)?,
+        None => system_roots()?,
+    };
+
+    let client_auth = match (&tls.key_store, &tls.private_key) {
+        (Some(certificate), Some(key)) => {
+            let certificate = read("client certificate", certificate)?;
+            let key = read("private key", key)?;
+            let key = if String::from_utf8_lossy(&key).contains("BEGIN RSA PRIVATE KEY") {
+                Key::RSA(key)
+            } else {
+                Key::ECC(key)
+            };
+            Some((certificate, key))
+        }
+        // e.g. the key Google IoT JWTs are signed with, which isn't used for the handshake
+        _ => None,
+    };
+    Ok(Transport::tls(ca, client_auth, None))
+}
+
+fn mqtt_options(options: &ConnectOptions) -> Result<MqttOptions> {
+    if options.mqtt_version != MQTT_VERSION_3_1_1 {
+        return Err(eyre!("The rumqttc client only supports MQTT 3.1.1"));
+    }
+    if options.persistence_dir.is_some() {
+        warn!("The rumqttc client keeps the messages in flight in memory only");
+    }
+
+    let (host, port) = host_and_port(&options.server_uri)?;
+    let mut mqtt_options = MqttOptions::new(options.client_id.as_str(), host, port);
+    mqtt_options
+        .set_keep_alive(options.keep_alive)
+        .set_clean_session(options.clean_session);
+    if let Some(user_name) = &options.user_name {
+        let password = options.password.clone().unwrap_or_default();
+        mqtt_options.set_credentials(user_name.as_str(), password);
+    }
+    if let Some(tls) = &options.tls {
+        mqtt_options.set_transport(transport(tls)?);
+    }
+    if let Some(will) = &options.will {
+        mqtt_options.set_last_will(LastWill::new(
+            will.topic.as_str(),
+            will.payload.clone(),
+            qos(will.qos)?,
+            will.retained,
+        ));
+    }
+    Ok(mqtt_options)
+}
+
+/// Describes refused connections the way paho does, so they're told apart the same way
+fn connection_error(e: ConnectionError) -> color_eyre::Report {
+    match e {
+        ConnectionError::ConnectionRefused(ConnectReturnCode::NotAuthorized) => {
+            eyre!("Not authorized")
+        }
+        ConnectionError::ConnectionRefused(ConnectReturnCode::BadUserNamePassword) => {
+            eyre!("Bad user name or password")
+        }
+        e => eyre!("{e}"),
+    }
+}
+
+/// Where the event loops of the successive connections report to
+#[derive(Clone)]
+struct Events {
+    connected: Arc<AtomicBool>,
+    incoming: UnboundedSender<Option<Incoming>>,
+    lost: UnboundedSender<String>,
+}
+
+impl Events {
+    /// Poll the event loop of a connection until it's lost or closed, the first connection attempt
+    /// is reported on established
+    async fn drive(self, mut event_loop: EventLoop, established: oneshot::Sender<Result<()>>) {
+        let mut established = Some(established);
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    self.connected.store(true, Ordering::SeqCst);
+                    if let Some(established) = established.take() {
+                        established.send(Ok(())).ok();
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let incoming = Incoming {
+                        topic: publish.topic,
+                        payload: publish.payload.to_vec(),
+                    };
+                    // nobody listens for messages, keep the connection alive for publishing
+                    self.incoming.send(Some(incoming)).ok();
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                    self.connected.store(false, Ordering::SeqCst);
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.connected.store(false, Ordering::SeqCst);
+                    match established.take() {
+                        Some(established) => {
+                            established.send(Err(connection_error(e))).ok();
+                        }
+                        None => {
+                            self.incoming.send(None).ok();
+                            self.lost.send(e.to_string()).ok();
+                        }
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Start a connection on a task of its own, done once the broker accepts it
+    async fn start(&self, options: &ConnectOptions) -> Result<AsyncClient> {
+        let (client, event_loop) = AsyncClient::new(mqtt_options(options)?, REQUEST_CAPACITY);
+        let (established_tx, established) = oneshot::channel();
+        tokio::task::spawn(self.clone().drive(event_loop, established_tx));
+
+        established
+            .await
+            .map_err(|_| eyre!("The MQTT event loop stopped"))??;
+        Ok(client)
+    }
+}
+
+/// MQTT transport built on the pure Rust rumqttc client.
+///
+/// rumqttc drives each connection from its event loop, which is polled by a dedicated task that
+/// forwards incoming messages. A reconnection starts a new event loop with the new options, so
+/// nothing but MQTT 3.1.1 is supported, and messages are queued rather than acknowledged by the time
+/// a publish completes
+pub struct RumqttTransport {
+    client: RwLock<AsyncClient>,
+    events: Events,
+    lost: Mutex<UnboundedReceiver<String>>,
+}
+
+impl RumqttTransport {
+    pub async fn connect(options: &ConnectOptions) -> Result<(Self, MessageStream)> {
+        let (incoming, incoming_rx) = unbounded_channel();
+        let (lost_tx, lost) = unbounded_channel();
+        let events = Events {
+            connected: Arc::new(AtomicBool::new(false)),
+            incoming,
+            lost: lost_tx,
+        };
+
+        let client = events.start(options).await?;
+        let stream = futures::stream::unfold(incoming_rx, |mut rx| async move {
+            rx.recv().await.map(|msg| (msg, rx))
+        });
+        let transport = Self {
+            client: RwLock::new(client),
+            events,
+            lost: Mutex::new(lost),
+        };
+        Ok((transport, Box::pin(stream)))
+    }
+
+    /// The client of the current connection, not held across an await
+    fn client(&self) -> AsyncClient {
+        self.client.read().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl MqttTransport for RumqttTransport {
+    async fn publish(&self, message: Message) -> Result<()> {
+        // the event loop isn't polled anymore, the request would only wait in the queue
+        if !self.is_connected() {
+            return Err(eyre!("Not connected"));
+        }
+        self.client()
+            .publish(
+                message.topic,
+                qos(message.qos)?,
+                message.retained,
+                message.payload,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic_filter: &str, qos: i32) -> Result<()> {
+        self.client()
+            .subscribe(topic_filter, self::qos(qos)?)
+            .await?;
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.events.connected.load(Ordering::SeqCst)
+    }
+
+    fn mqtt_version(&self) -> u32 {
+        MQTT_VERSION_3_1_1
+    }
+
+    async fn reconnect(&self, options: &ConnectOptions) -> Result<()> {
+        let client = self.events.start(options).await?;
+        *self.client.write().unwrap() = client;
+        Ok(())
+    }
+
+    async fn connection_lost(&self) -> String {
+        match self.lost.lock().await.recv().await {
+            Some(reason) => reason,
+            // the transport holds a sender
+            None => futures::future::pending().await,
+        }
+    }
+
+    /// The requests queued before the disconnect are sent first, rumqttc doesn't wait for their
+    /// acknowledgements so the timeout isn't used
+    async fn disconnect(&self, _timeout: Duration) -> Result<()> {
+        self.client().disconnect().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Settings;
+    use crate::tls::TlsConfig;
+
+    #[test]
+    fn broker_uris_are_split_into_host_and_port() {
+        assert_eq!(
+            host_and_port("tcp://localhost:1884").unwrap(),
+            ("localhost".to_string(), 1884)
+        );
+        assert_eq!(
+            host_and_port("broker.local").unwrap(),
+            ("broker.local".to_string(), 1883)
+        );
+        assert_eq!(
+            host_and_port("ssl://emqx.local").unwrap(),
+            ("emqx.local".to_string(), 8883)
+        );
+    }
+
+    #[test]
+    fn pinned_tls_versions_are_refused() {
+        let settings = Settings::new([("TLS_VERSION", "1.2")]);
+        let tls = TlsConfig::from_settings(&settings)
+            .unwrap()
+            .builder()
+            .trust_store("ca.pem");
+
+        assert!(transport(&tls).is_err());
+    }
+}
@@ -0,0 +1,622 @@
+//! The twin itself: the cell, the programs driving it and the clients of the backend wired together
+//! from the settings, following the cell and taking commands from the cloud until it's shut down
+
+use crate::ack::{AckStatus, Acknowledger};
+use crate::backend::Backend;
+use crate::cancellation::CancellationToken;
+use crate::config::Config;
+use crate::diagnostics::{self, Diagnostics};
+use crate::dispatcher::{Dispatcher, Pending, QueuePolicy, Queued};
+use crate::gcp_iot::message::{self, Command, StartRequest};
+use crate::handlers::{
+    discard_queued, home_stepper, move_robot, move_stepper, refill_feeder, reload_config,
+    report_error, shutdown_signal, watch_estop,
+};
+use crate::health::{self, Health};
+use crate::idempotency::IdempotencyStore;
+use crate::manufacturing_components::camera::Camera;
+use crate::manufacturing_components::cycle::{
+    emergency_stop, safe_state, stop_program, supervise, CycleContext, Interrupted, Report,
+};
+use crate::manufacturing_components::dry_run::DryRun;
+use crate::manufacturing_components::estop::{EmergencyStopButton, Latched};
+use crate::manufacturing_components::gpio::{CdevGpio, GpioProvider};
+use crate::manufacturing_components::heartbeat::Heartbeat;
+use crate::manufacturing_components::plc::PlcGpio;
+use crate::manufacturing_components::program::{ProgramRegistry, ProgramStatus, DEFAULT_SCENARIO};
+use crate::manufacturing_components::registry::ComponentRegistry;
+use crate::metrics;
+use crate::opcua_server::TwinServer;
+use crate::pipeline::{
+    self, CycleMetrics, EventPublisher, EventSender, HttpFallback, Mirror, Redrive,
+    SnapshotPublisher,
+};
+use crate::scheduler::Scheduler;
+use crate::signature::CommandVerifier;
+use crate::sparkplug::SparkplugNode;
+use crate::transport::{Client, MessageStream};
+use crate::vitals::Vitals;
+use crate::{reconnect, rotation};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures::stream::StreamExt;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{watch, Mutex};
+use tracing::{error, info, info_span, Instrument};
+use uuid::Uuid;
+
+/// Follow the cell and take commands from the cloud until the twin is shut down
+pub async fn run(config: Config) -> Result<()> {
+    // the settings the twin starts with, those changed later are applied by reload_config
+    let settings = config.settings.clone();
+
+    // the probes are answered while the twin starts, it's only ready once it's done
+    let vitals = Vitals::new();
+    let health = Health::new(vitals.clone());
+    health::spawn(&settings, health.clone())?;
+
+    let backend = Backend::from_settings(&settings)?;
+
+    let (client, msg_stream) = backend.connect().await?;
+    health.require("mqtt", {
+        let client = client.clone();
+        move || {
+            if client.is_connected() {
+                Ok(())
+            } else {
+                Err(eyre!("Disconnected from the broker"))
+            }
+        }
+    });
+
+    // any events we wish to sent to the cloud is sent across the channel to be processed by a
+    // dedicated task
+    let (tx, rx) = pipeline::channel();
+
+    // connection health shared by the publisher and the reconnect supervisor, published
+    // periodically for fleet operators
+    let diagnostics = Diagnostics::new();
+    diagnostics::spawn(diagnostics.clone(), backend.clone(), client.clone())?;
+
+    // a dedicated task just to publish events to the cloud
+    let mut publisher = EventPublisher::new(client.clone(), backend.clone(), diagnostics.clone())?;
+
+    // cycle timings taken from the events as they're published, aggregated periodically for
+    // engineers to spot drifts in cycle time
+    let metrics = CycleMetrics::new();
+    metrics::spawn(metrics.clone(), backend.clone(), client.clone())?;
+    publisher = publisher.with_metrics(metrics);
+
+    // plant floor systems can get the same events from a local broker
+    if let Some(mirror) = Mirror::from_settings(&settings).await? {
+        publisher = publisher.with_mirror(mirror);
+    }
+
+    // keep reporting over HTTPS when the plant firewall blocks MQTT
+    if let Some(fallback) = HttpFallback::from_backend(&backend)? {
+        publisher = publisher.with_fallback(fallback);
+    }
+    let redrive = publisher.redrive();
+
+    // the lines are the PLC's nodes when the cell is wired to one, the board's GPIO otherwise
+    let mut gpio: Box<dyn GpioProvider> = match PlcGpio::from_settings(&settings)? {
+        Some(plc) => Box::new(plc),
+        None => {
+            health.require("gpio", || Ok(CdevGpio::probe("/dev/gpiochip0")?));
+            Box::new(CdevGpio::new("/dev/gpiochip0")?)
+        }
+    };
+
+    // the PLC interlocks the cell once the heartbeat stops, which it does when the publisher, the
+    // command listener, the executor or the reconnect supervisor is gone
+    let heartbeat = Heartbeat::from_settings(&settings, gpio.as_mut(), vitals.clone())?;
+    let publisher_vital = vitals.watch("event publisher");
+    // the events left are published once the executor drove the cell to safe states on shutdown
+    let published = CancellationToken::new();
+    let event_processor = tokio::task::spawn({
+        let published = published.clone();
+        async move {
+            let _vital = publisher_vital;
+            publisher.run(rx, published).await
+        }
+    });
+
+    // the latest twin state, reported to the backend on change and periodically
+    let (state_tx, state_rx) = watch::channel(Value::Null);
+    let state_reporter =
+        pipeline::state_reporter::spawn(backend.clone(), client.clone(), state_rx)?;
+
+    // SCADA systems can follow the twin as a Sparkplug B edge node on their own broker
+    if settings.get("SPARKPLUG_BROKER_URI").is_some() {
+        let node = SparkplugNode::connect(&settings).await?;
+        node.spawn(state_tx.subscribe());
+    }
+
+    // config used to ease development, feel free to change to any more appropriate topic names
+    let config_topic = backend.config_topic();
+
+    // the backend subscribes to every topic on connect, the supervisor restores them after any
+    // reconnection
+    let supervisor_vital = vitals.watch("reconnect supervisor");
+    reconnect::spawn_supervisor(
+        backend.clone(),
+        client.clone(),
+        diagnostics,
+        supervisor_vital,
+    )?;
+
+    let program_controller = config.lines.program_control;
+
+    let mut cell = ComponentRegistry::from_config(&config, gpio.as_mut())?;
+    // the e-stop button latches the cell until it's cleared
+    let estop_button = EmergencyStopButton::from_settings(&settings, gpio.as_mut())?;
+    cell.estop = estop_button.as_ref().map(EmergencyStopButton::latch);
+    // snapshots are captured and uploaded on a task of their own, the cycles only queue them
+    let camera_worker = match Camera::from_settings(&settings)? {
+        Some((camera, worker)) => {
+            cell.camera = Some(camera);
+            Some(tokio::task::spawn(worker.run(tx.clone())))
+        }
+        None => None,
+    };
+
+    // the cloud picks the program to run with each start, the default one holds the control line
+    // low until then
+    let mut programs = ProgramRegistry::new(&settings, gpio, program_controller);
+    programs.select(DEFAULT_SCENARIO, &Value::Null)?;
+    // queries are answered from the reported state, which should be there before any cycle is run
+    state_tx.send(cell.state()).ok();
+
+    // shutting the twin down cancels whatever the executor waits on, each command is run with a
+    // token of its own which stops and emergency stops cancel
+    let shutdown = CancellationToken::new();
+    let (running_tx, running_rx) = watch::channel(shutdown.child_token());
+    tokio::task::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            match shutdown_signal().await {
+                Ok(signal) => {
+                    info!("Shutting down on {signal}");
+                    shutdown.cancel();
+                }
+                Err(e) => error!("Unable to wait for the shutdown signals: {e}"),
+            }
+        }
+    });
+
+    // commands are run one after the other, except for emergency stops which go ahead of
+    // everything, stops, pauses and resumes which control the running cycle, and queries which are
+    // answered right away
+    let idempotency = IdempotencyStore::from_settings(&settings).await?;
+    // the dispatcher holds the keys of the commands it accepts, the acks record them once the
+    // commands complete
+    let idempotency = Arc::new(Mutex::new(idempotency));
+    let acks =
+        Acknowledger::new(backend.clone(), client.clone())?.with_idempotency(idempotency.clone());
+    let queue_policy = QueuePolicy::from_settings(&settings)?;
+    let scheduler = Scheduler::new(&backend, client.clone())?;
+    let (status_tx, status_rx) = watch::channel(ProgramStatus::Idle);
+    // MES systems on the plant network can browse the twin as an OPC UA server
+    if let Some(server) = TwinServer::from_settings(&settings)? {
+        server.spawn(state_tx.subscribe(), status_tx.subscribe())?;
+    }
+    let snapshots =
+        SnapshotPublisher::new(&backend, client.clone(), state_tx.subscribe(), status_rx)?;
+    let (dispatcher, queues) = Dispatcher::new(
+        state_tx.subscribe(),
+        acks.clone(),
+        queue_policy,
+        scheduler,
+        snapshots,
+        idempotency,
+        running_rx,
+    );
+    // the button is watched until the executor is done, the cell is driven safe on shutdown
+    let watching = CancellationToken::new();
+    let estop_watcher = estop_button.map(|button| {
+        tokio::task::spawn(watch_estop(
+            button,
+            dispatcher.emergency_trigger(),
+            tx.clone(),
+            watching.clone(),
+        ))
+    });
+    // the settings are reloaded between commands whenever their file changes
+    let reload = dispatcher.reload_trigger();
+    config.watch(move || reload.trigger());
+
+    let listener = Listener {
+        backend: backend.clone(),
+        client: client.clone(),
+        acks: acks.clone(),
+        // commands have to be signed when a key is configured
+        verifier: CommandVerifier::from_settings(&settings)?,
+        dispatcher,
+        config_topic,
+    };
+    let listener_vital = vitals.watch("command listener");
+    let gcp_listener = tokio::task::spawn(async move {
+        let _vital = listener_vital;
+        listener.run(msg_stream).await
+    });
+
+    if let Some(heartbeat) = heartbeat {
+        tokio::task::spawn(heartbeat.run());
+    }
+
+    let executor = Executor {
+        config,
+        cell,
+        programs,
+        tx,
+        state_tx,
+        status_tx,
+        running_tx,
+        commands: queues.commands,
+        controls: queues.controls,
+        emergency: queues.emergency,
+        pending: queues.pending,
+        acks,
+        redrive,
+        backend: backend.clone(),
+        client: client.clone(),
+        shutdown,
+    };
+    let executor_vital = vitals.watch("executor");
+    let executor = tokio::task::spawn(async move {
+        let _vital = executor_vital;
+        executor.run().await
+    });
+
+    health.started();
+
+    // the executor ends on shutdown, once the listener is gone, or when the cell couldn't be
+    // re-created with changed settings. Either way the events left are published and the twin
+    // disconnects cleanly before its error is returned
+    let executed = executor.await;
+    gcp_listener.abort();
+
+    // the tasks still holding a sender are done before the publisher is, the camera worker once
+    // the snapshots the cell queued before it was dropped are uploaded
+    watching.cancel();
+    for task in [estop_watcher, camera_worker].into_iter().flatten() {
+        if let Err(e) = task.await {
+            error!("A task sending events failed: {e}");
+        }
+    }
+    published.cancel();
+    if let Err(e) = event_processor.await {
+        error!("The event publisher failed: {e}");
+    }
+    if let Err(e) = state_reporter.await {
+        error!("The state reporter failed: {e}");
+    }
+
+    let disconnected = backend.disconnect(&client).await;
+    executed??;
+    disconnected
+}
+
+/// Takes the messages from the cloud, dispatching the commands and rejecting everything else
+struct Listener {
+    backend: Backend,
+    client: Client,
+    acks: Acknowledger,
+    verifier: Option<CommandVerifier>,
+    dispatcher: Dispatcher,
+    config_topic: String,
+}
+
+impl Listener {
+    async fn run(mut self, mut msg_stream: MessageStream) {
+        while let Some(msg) = msg_stream.next().await {
+            let msg = match msg {
+                Some(msg) => msg,
+                // the connection is lost, the reconnect supervisor restores it
+                None => continue,
+            };
+            let topic = msg.topic();
+            let from_config = topic == self.config_topic;
+            if !from_config && !self.backend.is_command_topic(topic) {
+                // e.g. errors reported on the gateway's error topic
+                info!("Message on {topic}: {}", msg.payload_str());
+                continue;
+            }
+
+            // replies are sent in the schema version of the latest message from the cloud
+            let payload = match message::open(&msg.payload_str()) {
+                Ok((version, payload)) => {
+                    message::negotiate(version);
+                    payload
+                }
+                Err(e) => {
+                    error!("Rejected message on {topic}: {e}");
+                    let e = e.to_string();
+                    report_error(&self.backend, &self.client, topic, &e, None).await;
+                    continue;
+                }
+            };
+            let id = message::command_id(&payload);
+            let key = message::idempotency_key(&payload);
+
+            let command = match self.parse(topic, from_config, &payload) {
+                Ok(command) => command,
+                Err((e, path)) => {
+                    error!("Rejected message on {topic}: {e}");
+                    let path = path.as_deref();
+                    report_error(&self.backend, &self.client, topic, &e, path).await;
+                    self.acks
+                        .send(id.as_deref(), AckStatus::Failed, Some(e))
+                        .await;
+                    continue;
+                }
+            };
+
+            self.dispatcher.dispatch(id, key, command).await;
+        }
+    }
+
+    /// The command of a verified message, or why it's rejected with the path of the offending
+    /// field when there's one
+    fn parse(
+        &self,
+        topic: &str,
+        from_config: bool,
+        payload: &str,
+    ) -> Result<Command, (String, Option<String>)> {
+        if let Some(verifier) = &self.verifier {
+            verifier
+                .verify(payload)
+                .map_err(|e| (e.to_string(), None))?;
+        }
+
+        if from_config {
+            message::parse_config(payload)
+                .map(Command::Start)
+                .map_err(|e| (e.to_string(), None))
+        } else {
+            let subfolder = self.backend.command_subfolder(topic);
+            message::route(subfolder, payload)
+                .map_err(|e| (e.to_string(), e.path().map(str::to_string)))
+        }
+    }
+}
+
+/// Runs the commands on the cell one after the other, ahead of which the emergency stops and the
+/// controls received while nothing runs are handled
+struct Executor {
+    config: Config,
+    cell: ComponentRegistry,
+    programs: ProgramRegistry,
+    tx: EventSender,
+    state_tx: watch::Sender<Value>,
+    status_tx: watch::Sender<ProgramStatus>,
+    running_tx: watch::Sender<CancellationToken>,
+    commands: UnboundedReceiver<Queued>,
+    controls: UnboundedReceiver<Queued>,
+    emergency: UnboundedReceiver<Queued>,
+    pending: Pending,
+    acks: Acknowledger,
+    redrive: Redrive,
+    backend: Backend,
+    client: Client,
+    shutdown: CancellationToken,
+}
+
+impl Executor {
+    /// Run until the twin is shut down or the listener is gone. Fails when the cell couldn't be
+    /// re-created with changed settings
+    async fn run(mut self) -> Result<()> {
+        loop {
+            let (id, command) = tokio::select! {
+                biased;
+                Some(estop) = self.emergency.recv() => {
+                    self.emergency_stop(estop).await;
+                    continue;
+                }
+                // the running command was cancelled, nothing queued is run anymore
+                _ = self.shutdown.cancelled() => {
+                    if let Err(e) = safe_state(self.programs.current(), &mut self.cell, &self.tx) {
+                        error!("Failed to leave the cell safe on shutdown: {e}");
+                    }
+                    self.state_tx.send(self.cell.state()).ok();
+                    let reason = "on shutdown";
+                    discard_queued(&mut self.commands, &self.pending, &self.acks, reason).await;
+                    return Ok(());
+                }
+                Some(command) = self.commands.recv() => command,
+                Some(control) = self.controls.recv() => {
+                    self.control(control).await;
+                    continue;
+                }
+                // magazines are loaded, limit switches change, barcodes are scanned ahead of the
+                // cycle picking the material up and the inputs are sampled whether a cycle is
+                // running or not
+                Some(event) = self.cell.next_idle_event() => {
+                    match event {
+                        Ok(event) => self.tx.send(event),
+                        Err(e) => error!("{e}"),
+                    }
+                    self.state_tx.send(self.cell.state()).ok();
+                    continue;
+                }
+                else => return Ok(()),
+            };
+
+            self.acks
+                .send(id.as_deref(), AckStatus::Started, None)
+                .await;
+            let cancel = self.shutdown.child_token();
+            self.running_tx.send(cancel.clone()).ok();
+
+            // everything logged while the command runs is tagged with it, the cycles it starts too
+            let span = info_span!("command", id = id.as_deref(), kind = command.kind());
+            let result = match command {
+                // the cell is moved out to be re-created, which the command's future can't do
+                Command::ReloadConfig(request) => {
+                    let entered = span.enter();
+                    let gpio = self.programs.gpio();
+                    let (config, tx, state_tx) = (&mut self.config, &self.tx, &self.state_tx);
+                    match reload_config(config, self.cell, gpio, request, tx, state_tx) {
+                        Ok((reloaded, result)) => {
+                            self.cell = reloaded;
+                            result
+                        }
+                        // the cell can't be re-created with the reloaded settings, the twin stops
+                        Err(e) => {
+                            drop(entered);
+                            let error = eyre!("The cell couldn't be re-created, {e}");
+                            self.acks.conclude(id.as_deref(), Err(error)).await;
+                            return Err(e);
+                        }
+                    }
+                }
+                command => {
+                    self.execute(id.as_deref(), command, &cancel)
+                        .instrument(span)
+                        .await
+                }
+            };
+
+            self.acks.conclude(id.as_deref(), result).await;
+            self.pending.done();
+        }
+    }
+
+    /// Stop the cell right away, the commands queued before the emergency stop are discarded
+    async fn emergency_stop(&mut self, estop: Queued) {
+        let program = self.programs.current();
+        emergency_stop(program, &mut self.cell, estop, &self.tx, &self.acks).await;
+        self.status_tx.send(ProgramStatus::EmergencyStopped).ok();
+        self.state_tx.send(self.cell.state()).ok();
+        let reason = "by an emergency stop";
+        discard_queued(&mut self.commands, &self.pending, &self.acks, reason).await;
+    }
+
+    /// Handle a control received while no cycle is running
+    async fn control(&mut self, (id, control): Queued) {
+        let id = id.as_deref();
+        self.acks.send(id, AckStatus::Started, None).await;
+        let result = match control {
+            // nothing is running, make sure the program is stopped anyway
+            Command::Stop(request) => {
+                self.status_tx.send(ProgramStatus::Idle).ok();
+                match self.programs.current() {
+                    Some(program) => stop_program(program, &request, &self.tx)
+                        .map(|_| Some(json!({ "remaining": 0 }))),
+                    None => Ok(Some(json!({ "remaining": 0 }))),
+                }
+            }
+            _ => Err(eyre!("No cycle is running")),
+        };
+        self.acks.conclude(id, result).await;
+    }
+
+    /// Run a command on the cell, the result is what its completion is acknowledged with
+    async fn execute(
+        &mut self,
+        id: Option<&str>,
+        command: Command,
+        cancel: &CancellationToken,
+    ) -> Result<Option<Value>> {
+        let (cell, tx, state_tx) = (&mut self.cell, &self.tx, &self.state_tx);
+        match command {
+            // nothing drives the cell until the latched e-stop is cleared
+            command if command.drives_cell() && cell.check_estop().is_err() => Err(Latched.into()),
+            Command::Start(request) => self.start(id, request, cancel).await,
+            Command::Stop(_)
+            | Command::EmergencyStop(_)
+            | Command::Pause(_)
+            | Command::Resume(_)
+            | Command::Query(_)
+            | Command::QueryState => {
+                unreachable!("controls and queries are handled by the dispatcher")
+            }
+            Command::ClearEStop => cell.clear_estop().map(|event| {
+                tx.send(event);
+                self.status_tx.send(ProgramStatus::Idle).ok();
+                state_tx.send(cell.state()).ok();
+                None
+            }),
+            Command::RefillFeeder(request) => refill_feeder(cell, request, tx, state_tx),
+            Command::MoveRobot(request) => move_robot(cell, request, tx, state_tx, cancel).await,
+            Command::MoveStepper(request) => {
+                move_stepper(cell, request, tx, state_tx, cancel).await
+            }
+            Command::HomeStepper => home_stepper(cell, tx, state_tx, cancel).await,
+            // planned without requesting a line, the current program is kept
+            Command::DryRun(request) => {
+                let scenario = request.scenario.as_deref().unwrap_or(DEFAULT_SCENARIO);
+                self.programs
+                    .stages(scenario, &request.parameters, request.count, cell)
+                    .map(|stages| Some(json!(DryRun::new(stages))))
+            }
+            Command::RotateKey(request) => rotation::rotate(&self.backend, &self.client, request)
+                .await
+                .map(|_| None),
+            Command::Redrive => self
+                .redrive
+                .request()
+                .await
+                .map(|count| Some(json!({ "redriven": count }))),
+            Command::ReloadConfig(_) => {
+                unreachable!("reloads are run outside of the future")
+            }
+        }
+    }
+
+    /// Run the cycles of the program the start picks, reporting how many materials were processed
+    async fn start(
+        &mut self,
+        id: Option<&str>,
+        request: StartRequest,
+        cancel: &CancellationToken,
+    ) -> Result<Option<Value>> {
+        let scenario = request.scenario.as_deref().unwrap_or(DEFAULT_SCENARIO);
+        let mut cx = CycleContext {
+            count: request.count,
+            // starts without an id still get one for their cycle
+            cycle_id: id.map_or_else(|| Uuid::new_v4().to_string(), str::to_string),
+            cell: &mut self.cell,
+            tx: &self.tx,
+            state_tx: &self.state_tx,
+            status_tx: &self.status_tx,
+            control_rx: &mut self.controls,
+            emergency_rx: &mut self.emergency,
+            acks: &self.acks,
+            cancel,
+            // running cycles report their progress every few materials
+            progress_every: self.config.cycle.progress_every,
+        };
+        let report = match self.programs.select(scenario, &request.parameters) {
+            Ok(program) => supervise(program, &mut cx).await,
+            Err(e) => Err(e),
+        };
+
+        if let Ok(Report {
+            interrupted: Some(Interrupted::EmergencyStop),
+            ..
+        }) = &report
+        {
+            self.status_tx.send(ProgramStatus::EmergencyStopped).ok();
+            self.state_tx.send(self.cell.state()).ok();
+            let reason = "by an emergency stop";
+            discard_queued(&mut self.commands, &self.pending, &self.acks, reason).await;
+        } else {
+            self.status_tx.send(ProgramStatus::Idle).ok();
+        }
+
+        report.map(|report| {
+            let reason = report.interrupted;
+            Some(json!({
+                "processed": report.processed,
+                "remaining": request.count.saturating_sub(report.processed),
+                "stopped": reason.is_some(),
+                "emergency": reason == Some(Interrupted::EmergencyStop),
+                "stalled": reason == Some(Interrupted::Stalled),
+            }))
+        })
+    }
+}
@@ -1,6 +1,41 @@
 use chrono::{DateTime, Utc};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 pub use std::time::SystemTime;
 
+/// The path of the file with suffix appended to its name, so files differing only by their
+/// extension, e.g. key.pem and key.crt, get distinct siblings
+pub fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Replace the file with content, written to a sibling temporary file and synced to disk before
+/// it's renamed over the file, so a crash mid write leaves either the old or the new content
+pub fn write_atomic(path: &Path, content: impl AsRef<[u8]>) -> io::Result<()> {
+    let tmp_path = sibling(path, ".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(content.as_ref())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Serializes binary payloads as base64 strings, for use with #[serde(with = "base64_bytes")]
+pub mod base64_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
 pub trait Iso8601Utc {
     fn iso8601_now() -> String;
 }
@@ -12,3 +47,21 @@ impl Iso8601Utc for SystemTime {
         now.to_rfc3339()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn files_are_replaced_as_a_whole() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("counters.json");
+        fs::write(&path, "old")?;
+
+        write_atomic(&path, "new")?;
+
+        assert_eq!(fs::read_to_string(&path)?, "new");
+        assert!(!sibling(&path, ".tmp").exists());
+        Ok(())
+    }
+}
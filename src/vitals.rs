@@ -0,0 +1,74 @@
+use std::sync::{Arc, Mutex, Weak};
+
+/// Held by a watched task, dropped along with the task when it ends or panics
+pub struct Vital {
+    _alive: Arc<()>,
+}
+
+/// Every watched task by name, with its vital
+type Watched = Vec<(String, Weak<()>)>;
+
+/// The tasks the twin is alive with, by name, e.g. the event publisher. Shared by everything
+/// vouching for the twin, the heartbeat line and the health probes, cheap to clone
+#[derive(Clone, Default)]
+pub struct Vitals {
+    watched: Arc<Mutex<Watched>>,
+}
+
+impl Vitals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Watch the named task for as long as it holds the returned vital
+    pub fn watch(&self, name: &str) -> Vital {
+        let vital = Arc::new(());
+        self.watched
+            .lock()
+            .unwrap()
+            .push((name.to_string(), Arc::downgrade(&vital)));
+        Vital { _alive: vital }
+    }
+
+    /// Every watched task, with whether it's still running
+    pub fn tasks(&self) -> Vec<(String, bool)> {
+        self.watched
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, vital)| (name.clone(), vital.strong_count() > 0))
+            .collect()
+    }
+
+    /// The first watched task that's gone
+    pub fn dead(&self) -> Option<String> {
+        self.tasks()
+            .into_iter()
+            .find(|(_, running)| !running)
+            .map(|(name, _)| name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tasks_are_gone_once_their_vital_is_dropped() {
+        let vitals = Vitals::new();
+        let publisher = vitals.watch("event publisher");
+        let _listener = vitals.watch("command listener");
+        assert_eq!(vitals.dead(), None);
+
+        // clones watch the same tasks
+        drop(publisher);
+        assert_eq!(vitals.clone().dead().as_deref(), Some("event publisher"));
+        assert_eq!(
+            vitals.tasks(),
+            [
+                ("event publisher".to_string(), false),
+                ("command listener".to_string(), true)
+            ]
+        );
+    }
+}